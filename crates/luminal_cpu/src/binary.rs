@@ -3,9 +3,14 @@ use luminal::{
     prelude::{petgraph::visit::EdgeRef, *},
 };
 
-use super::other::ARange;
+use rustc_hash::FxHashMap;
 
-#[derive(Debug, Clone, Default, PartialEq)]
+use super::{
+    memory_planner::{get_index, CPUKernel},
+    other::ARange,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Sub;
 
 impl Operator for Sub {
@@ -40,14 +45,15 @@ pub struct SubtractionCompiler;
 
 impl Compiler for SubtractionCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let (lhs, rhs) = (node(), node());
         let mul = binary::<Mul>(rhs.clone(), super::constant(-1.));
         let add = binary::<Add>(lhs.clone(), mul.clone());
         let mut s = add.clone().search(graph);
 
         while s.next_match() {
-            if s.check_no_delete(&[add.id]) {
+            // The inputs are kept, so they can be outputs
+            if s.check_no_delete(&[add.id, lhs.id, rhs.id]) {
                 s.clear_cached_results();
                 continue;
             }
@@ -82,6 +88,7 @@ impl Compiler for SubtractionCompiler {
                 .input(b, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(add, sub, &mut graph.graph);
+            remap(add, sub, &mut ids, graph);
 
             graph.graph.remove_node(add);
             s.try_delete();
@@ -89,7 +96,7 @@ impl Compiler for SubtractionCompiler {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Equal;
 
 impl Operator for Equal {
@@ -124,7 +131,7 @@ pub struct EqualCompiler;
 
 impl Compiler for EqualCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let one = super::constant(1.);
         let (lhs, rhs) = (node(), node());
         let lt1 = binary::<LessThan>(lhs.clone(), rhs.clone());
@@ -133,7 +140,7 @@ impl Compiler for EqualCompiler {
 
         let mut s = eq.clone().search(graph);
         while s.next_match() {
-            if s.check_no_delete(&[eq.id]) {
+            if s.check_no_delete(&[eq.id, lhs.id, rhs.id]) {
                 continue;
             }
             let (lhs, rhs) = (s.get(&lhs), s.get(&rhs));
@@ -160,6 +167,7 @@ impl Compiler for EqualCompiler {
                 .input(rhs, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(eq, equals, &mut graph.graph);
+            remap(eq, equals, &mut ids, graph);
 
             graph.graph.remove_node(eq);
             s.try_delete();
@@ -167,80 +175,141 @@ impl Compiler for EqualCompiler {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Take values from a source along an axis, at positions given by an index tensor. Inputs are the indexes, viewed in the shape of the output, and the source
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Gather {
-    pub embed_dim: usize,
+    pub axis: usize,
 }
 
-impl Operator for Gather {
-    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
-        let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let weights = tensors[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-
-        let mut out = vec![0.; indexes.len() * self.embed_dim];
-        for token in 0..indexes.len() {
-            let e = indexes[token] as usize;
-            for dim in 0..self.embed_dim {
-                out[token * self.embed_dim + dim] = weights[e * self.embed_dim + dim];
-            }
+impl Gather {
+    fn gather(&self, inputs: &[(&[f32], ShapeTracker)], out: &mut [f32]) {
+        let dims = inputs[0].1.shape_usize();
+        let (n_indexes, inner) = (
+            dims[self.axis],
+            dims.iter().skip(self.axis + 1).product::<usize>(),
+        );
+        let size = inputs[1].1.shape_usize()[self.axis];
+        let index_expr = (
+            inputs[0].1.index_expression(),
+            inputs[0].1.valid_expression(),
+        );
+        let src_expr = (
+            inputs[1].1.index_expression(),
+            inputs[1].1.valid_expression(),
+        );
+        let mut stack = vec![];
+        for (i, out) in out.iter_mut().enumerate() {
+            let target = get_index(inputs[0].0, &index_expr, &mut stack, i);
+            // Out of range indexes never match a position
+            *out = if target < 0. || target.fract() != 0. || target as usize >= size {
+                0.
+            } else {
+                let (outer, rest) = (i / (n_indexes * inner), i % inner);
+                get_index(
+                    inputs[1].0,
+                    &src_expr,
+                    &mut stack,
+                    (outer * size + target as usize) * inner + rest,
+                )
+            };
         }
+    }
+}
 
+impl Operator for Gather {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut out = vec![0.; tensors[0].1.n_elements().to_usize().unwrap()];
+        self.gather(
+            &[
+                (get_vec(&tensors[0].0), tensors[0].1),
+                (get_vec(&tensors[1].0), tensors[1].1),
+            ],
+            &mut out,
+        );
         vec![Tensor::new(out)]
     }
 }
 
+/// Replace the one-hot select and sum reduce of `gather` / `index_select` / `take_along_axis` with a direct lookup
 #[derive(Debug, Default)]
 pub struct GatherCompiler;
 
 impl Compiler for GatherCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let indexes = node();
-        let eq = binary::<Equal>(indexes.clone(), op::<ARange>());
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let (indexes, arange) = (node(), op::<ARange>());
+        let eq = binary::<Equal>(indexes.clone(), arange.clone());
         let embedding = node();
         let mul = binary::<Mul>(embedding.clone(), eq.clone());
         let sum_reduce = unary::<SumReduce>(mul.clone());
         let mut s = sum_reduce.clone().search(graph);
         while s.next_match() {
-            if s.check_no_delete(&[embedding.id]) {
+            // The output gets remapped to the gather, so it can be kept
+            if s.check_no_delete(&[embedding.id, indexes.id, sum_reduce.id]) {
                 continue;
             }
-            let emb_shape = graph
-                .edges_connecting(s.get(&embedding), s.get(&mul))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let index_shape = graph
-                .edges_connecting(s.get(&indexes), s.get(&eq))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let embed_dim = graph
-                .graph
-                .edges_connecting(s.get(&embedding), s.get(&mul))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2
-                .dims()[2]
-                .to_usize()
-                .unwrap();
+            let edge_shape = |graph: &Graph, a, b| {
+                graph
+                    .edges_connecting(s.get(a), s.get(b))
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2
+            };
+            // The product is [..., K, N, ...], summed over the positions along N
+            let axis = match graph.get_op::<SumReduce>(s.get(&sum_reduce)).0 {
+                0 => continue,
+                n => n - 1,
+            };
+            let one_hot_shape = edge_shape(graph, &eq, &mul);
+            let (arange_shape, mut index_shape, mut src_shape) = (
+                edge_shape(graph, &arange, &eq),
+                edge_shape(graph, &indexes, &eq),
+                edge_shape(graph, &embedding, &mul),
+            );
+            let real = |sh: &ShapeTracker, i: usize| !sh.fake[sh.indexes[i]];
+            if !src_shape.fake[src_shape.indexes[axis]] || !real(&src_shape, axis + 1) {
+                continue;
+            }
+            if !one_hot_shape.is_reshaped() {
+                // A full [..., K, N, ...] one-hot, from take_along_axis
+                if !real(&arange_shape, axis + 1) || real(&index_shape, axis + 1) {
+                    continue;
+                }
+                index_shape.remove_dim(axis + 1);
+            } else {
+                // A [K, N] one-hot broadcast over the other dims, from index_select
+                let broadcast = arange_shape.len() == 2
+                    && !one_hot_shape.is_sliced()
+                    && !one_hot_shape.is_padded()
+                    && one_hot_shape.indexes[axis] == 0
+                    && one_hot_shape.indexes[axis + 1] == 1
+                    && (0..one_hot_shape.len())
+                        .all(|i| real(&one_hot_shape, i) == (i == axis || i == axis + 1));
+                if !broadcast || !real(&arange_shape, 1) || real(&index_shape, 1) {
+                    continue;
+                }
+                index_shape.remove_dim(1);
+                let dims = one_hot_shape.dims();
+                for (i, d) in dims.into_iter().enumerate() {
+                    if i < axis {
+                        index_shape.expand_dim(i, d);
+                    } else if i > axis + 1 {
+                        index_shape.expand_dim(i - 1, d);
+                    }
+                }
+            }
+            src_shape.remove_dim(axis);
 
             let gather = graph
-                .add_op(Gather { embed_dim })
+                .add_op(Gather { axis })
                 .input(s.get(&indexes), 0, index_shape)
-                .input(s.get(&embedding), 0, emb_shape)
+                .input(s.get(&embedding), 0, src_shape)
                 .finish();
             move_outgoing_edge(s.get(&sum_reduce), gather, &mut graph.graph);
+            remap(s.get(&sum_reduce), gather, &mut ids, graph);
             graph.remove_node(s.get(&sum_reduce));
             s.try_delete();
         }
@@ -250,3 +319,168 @@ impl Compiler for GatherCompiler {
 fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
     tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
 }
+
+impl CPUKernel for Gather {
+    fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0].n_elements()]
+    }
+    fn cpu_forward(&mut self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        self.gather(inputs, outputs[0]);
+    }
+}
+
+/// Sum values into an output along an axis, at positions given by an index tensor. Inputs are the indexes and the values, which share a shape
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScatterAdd {
+    pub axis: usize,
+    /// The output size along the axis
+    pub size: Expression,
+    pub(crate) dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl ScatterAdd {
+    fn scatter(&self, inputs: &[(&[f32], ShapeTracker)], out: &mut [f32]) {
+        let dims = inputs[0].1.shape_usize();
+        let (n_indexes, inner) = (
+            dims[self.axis],
+            dims.iter().skip(self.axis + 1).product::<usize>(),
+        );
+        let others = dims.iter().product::<usize>() / n_indexes.max(1);
+        let size = out.len() / others.max(1);
+        let index_expr = (
+            inputs[0].1.index_expression(),
+            inputs[0].1.valid_expression(),
+        );
+        let src_expr = (
+            inputs[1].1.index_expression(),
+            inputs[1].1.valid_expression(),
+        );
+        let mut stack = vec![];
+        out.fill(0.);
+        for i in 0..dims.iter().product::<usize>() {
+            let target = get_index(inputs[0].0, &index_expr, &mut stack, i);
+            // Out of range indexes never match a position
+            if target < 0. || target.fract() != 0. || target as usize >= size {
+                continue;
+            }
+            let (outer, rest) = (i / (n_indexes * inner), i % inner);
+            out[(outer * size + target as usize) * inner + rest] +=
+                get_index(inputs[1].0, &src_expr, &mut stack, i);
+        }
+    }
+}
+
+impl Operator for ScatterAdd {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let size = self
+            .size
+            .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+            .unwrap();
+        let dims = tensors[0].1.shape_usize();
+        let others = dims.iter().product::<usize>() / dims[self.axis].max(1);
+        let mut out = vec![0.; others * size];
+        self.scatter(
+            &[
+                (get_vec(&tensors[0].0), tensors[0].1),
+                (get_vec(&tensors[1].0), tensors[1].1),
+            ],
+            &mut out,
+        );
+        vec![Tensor::new(out)]
+    }
+}
+
+impl CPUKernel for ScatterAdd {
+    fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0]
+            .dims()
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| *i != self.axis)
+            .fold(self.size, |acc, (_, d)| acc * d)]
+    }
+    fn cpu_forward(&mut self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        self.scatter(inputs, outputs[0]);
+    }
+}
+
+/// Replace the one-hot mul and sum reduce of `scatter_add` / `scatter` / `index_put` with a direct scatter
+#[derive(Debug, Default)]
+pub struct ScatterAddCompiler;
+
+impl Compiler for ScatterAddCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let (indexes, arange, src) = (node(), op::<ARange>(), node());
+        let mut zero = op::<Constant>();
+        zero.check(|o, _| {
+            matches!(
+                o.as_any().downcast_ref::<Constant>(),
+                Some(Constant(ConstantValue::Float(f), _)) if *f == 0.
+            )
+        });
+        let eq = binary::<Equal>(indexes.clone(), arange.clone());
+        // Parents are matched from the most recently connected, so connect the src wildcard first to keep it from taking the one-hot
+        let select = zero
+            .clone()
+            .connect(eq.clone().connect(src.clone().connect(op::<Select>())));
+        let sum_reduce = unary::<SumReduce>(select.clone());
+        let mut s = sum_reduce.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[indexes.id, src.id, zero.id, sum_reduce.id]) {
+                continue;
+            }
+            // The one-hot must be the condition, with src selected where it's set
+            let sources = graph
+                .get_sources(s.get(&select))
+                .into_iter()
+                .map(|(n, _, _)| n)
+                .collect::<Vec<_>>();
+            if sources != [s.get(&eq), s.get(&src), s.get(&zero)] {
+                continue;
+            }
+            let edge_shape = |graph: &Graph, a, b| {
+                graph
+                    .edges_connecting(s.get(a), s.get(b))
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2
+            };
+            let axis = graph.get_op::<SumReduce>(s.get(&sum_reduce)).0;
+            let arange_shape = edge_shape(graph, &arange, &eq);
+            let (mut index_shape, mut src_shape) = (
+                edge_shape(graph, &indexes, &eq),
+                edge_shape(graph, &src, &select),
+            );
+            // The one-hot must be [..., K, N, ...], with the positions along N right after the summed index axis
+            if arange_shape.len() < axis + 2
+                || !arange_shape.fake[arange_shape.indexes[axis]]
+                || arange_shape.fake[arange_shape.indexes[axis + 1]]
+                || !index_shape.fake[index_shape.indexes[axis + 1]]
+                || !src_shape.fake[src_shape.indexes[axis + 1]]
+                || edge_shape(graph, &eq, &select).is_reshaped()
+            {
+                continue;
+            }
+            index_shape.remove_dim(axis + 1);
+            src_shape.remove_dim(axis + 1);
+            let size = graph.get_op::<ARange>(s.get(&arange)).size;
+            let scatter = graph
+                .add_op(ScatterAdd {
+                    axis,
+                    size,
+                    dyn_map: &graph.dyn_map,
+                })
+                .input(s.get(&indexes), 0, index_shape)
+                .input(s.get(&src), 0, src_shape)
+                .finish();
+            move_outgoing_edge(s.get(&sum_reduce), scatter, &mut graph.graph);
+            remap(s.get(&sum_reduce), scatter, &mut ids, graph);
+            graph.remove_node(s.get(&sum_reduce));
+            s.try_delete();
+        }
+    }
+}
@@ -1,11 +1,19 @@
 mod binary;
 mod matmul;
+mod memory_planner;
 mod other;
+mod quantized;
+
+pub use memory_planner::{CPUKernel, MemoryPlan, MemoryPlanCompiler};
+pub use quantized::{
+    CPUQuantizedCompiler, Dequantize, QuantType, QuantizedGather, QuantizedMatmul,
+};
 
 use std::any::Any;
 
 use itertools::Itertools;
 use petgraph::visit::EdgeRef;
+use serde::{Deserialize, Serialize};
 
 use luminal::{
     op::{Constant, ConstantValue, Exp2, InputTensor, Log2, Operator, Recip, Sin},
@@ -19,10 +27,49 @@ pub type CPUCompiler = (
     binary::SubtractionCompiler,
     binary::EqualCompiler,
     other::ARangeCompiler,
+    binary::ScatterAddCompiler,
     binary::GatherCompiler,
     UnaryFusionCompiler,
 );
 
+/// Register all CPU ops in an op registry, so graphs compiled with the `CPUCompiler` can be saved and loaded
+pub fn register_ops(registry: &mut OpRegistry) {
+    registry.register::<matmul::MatMul2D>("MatMul2D");
+    registry.register::<matmul::BatchedMatMul2D>("BatchedMatMul2D");
+    registry.register::<binary::Sub>("CPUSub");
+    registry.register::<binary::Equal>("CPUEqual");
+    registry.register::<binary::Gather>("CPUGather");
+    registry.register_with::<other::ARange>(
+        "CPUARange",
+        |op| serde_json::to_value(op.as_any().downcast_ref::<other::ARange>().unwrap().size),
+        |value, graph| {
+            Ok(Box::new(other::ARange {
+                size: serde_json::from_value(value)?,
+                dyn_map: &graph.dyn_map,
+            }))
+        },
+    );
+    registry.register_with::<binary::ScatterAdd>(
+        "CPUScatterAdd",
+        |op| {
+            let op = op.as_any().downcast_ref::<binary::ScatterAdd>().unwrap();
+            serde_json::to_value((op.axis, op.size))
+        },
+        |value, graph| {
+            let (axis, size) = serde_json::from_value(value)?;
+            Ok(Box::new(binary::ScatterAdd {
+                axis,
+                size,
+                dyn_map: &graph.dyn_map,
+            }))
+        },
+    );
+    registry.register::<FusedUnary>("FusedUnary");
+    registry.register::<QuantizedMatmul>("CPUQuantizedMatmul");
+    registry.register::<QuantizedGather>("CPUQuantizedGather");
+    registry.register::<Dequantize>("CPUDequantize");
+}
+
 pub(crate) fn constant(num: f32) -> SelectGraph {
     let mut n = op::<Constant>();
     n.check(move |o, _| {
@@ -45,15 +92,15 @@ pub struct UnaryFusionCompiler;
 impl Compiler for UnaryFusionCompiler {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
-        fn is_unary(op: &dyn Any) -> Option<fn(f32) -> f32> {
+        fn is_unary(op: &dyn Any) -> Option<UnaryOp> {
             if op.is::<Exp2>() {
-                Some(|i| i.exp2())
+                Some(UnaryOp::Exp2)
             } else if op.is::<Log2>() {
-                Some(|i| i.log2())
+                Some(UnaryOp::Log2)
             } else if op.is::<Recip>() {
-                Some(|i| i.recip())
+                Some(UnaryOp::Recip)
             } else if op.is::<Sin>() {
-                Some(|i| i.sin())
+                Some(UnaryOp::Sin)
             } else {
                 None
             }
@@ -116,16 +163,36 @@ impl Compiler for UnaryFusionCompiler {
     }
 }
 
+/// A unary op that can be fused
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum UnaryOp {
+    Exp2,
+    Log2,
+    Recip,
+    Sin,
+}
+
+impl UnaryOp {
+    fn apply(self, a: f32) -> f32 {
+        match self {
+            UnaryOp::Exp2 => a.exp2(),
+            UnaryOp::Log2 => a.log2(),
+            UnaryOp::Recip => a.recip(),
+            UnaryOp::Sin => a.sin(),
+        }
+    }
+}
+
 /// Multiple unary ops applied in sequence
-#[derive(Debug, Clone, PartialEq)]
-pub struct FusedUnary(Vec<fn(f32) -> f32>);
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FusedUnary(Vec<UnaryOp>);
 
 impl Operator for FusedUnary {
     fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let mut t = inp.pop().unwrap().0.cloned();
         for a in t.downcast_mut::<Vec<f32>>().unwrap().iter_mut() {
             for f in &self.0 {
-                *a = (f)(*a);
+                *a = f.apply(*a);
             }
         }
 
@@ -133,6 +200,25 @@ impl Operator for FusedUnary {
     }
 }
 
+impl CPUKernel for FusedUnary {
+    fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0].n_elements()]
+    }
+    fn cpu_forward(&mut self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        let expr = (
+            inputs[0].1.index_expression(),
+            inputs[0].1.valid_expression(),
+        );
+        let mut stack = vec![];
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            *out = self.0.iter().fold(
+                memory_planner::get_index(inputs[0].0, &expr, &mut stack, i),
+                |a, f| f.apply(a),
+            );
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::{rngs::StdRng, SeedableRng};
@@ -190,4 +276,364 @@ mod tests {
         cx.execute();
         assert_close(&c.data(), &unoptimized_c);
     }
+
+    #[test]
+    fn test_matmul_views_compiled() {
+        // Sliced and padded inputs can't be indexed by the matmul kernels, so they have to stay correct unfused
+        let build = |cx: &mut Graph| {
+            let mut rng = StdRng::seed_from_u64(0);
+            let a = cx.tensor((3, 4)).set(random_vec_rng(12, &mut rng));
+            let b = cx.tensor((4, 5)).set(random_vec_rng(20, &mut rng));
+            let c = cx.tensor((2, 3, 4)).set(random_vec_rng(24, &mut rng));
+            vec![
+                a.slice((1.., ..)).matmul(b).retrieve(),
+                a.matmul(b.slice((.., ..3))).retrieve(),
+                a.pad(((0, 1), (0, 0))).matmul(b).retrieve(),
+                c.slice((.., 1.., ..)).matmul(b).retrieve(),
+                c.matmul(b.pad(((0, 0), (1, 0)))).retrieve(),
+            ]
+        };
+        let mut cx = Graph::new();
+        let outputs = build(&mut cx);
+        cx.execute();
+
+        let mut compiled = Graph::new();
+        let mut compiled_outputs = build(&mut compiled);
+        compiled.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            &mut compiled_outputs,
+        );
+        compiled.execute();
+
+        for (a, b) in outputs.iter().zip(&compiled_outputs) {
+            assert_close(&a.data(), &b.data());
+        }
+    }
+
+    #[test]
+    fn test_einsum_compiled() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(random_vec(6));
+        let b = cx.tensor((4, 3)).set(random_vec(12));
+        let c = cx.tensor((2, 5, 4)).set(random_vec(40));
+        let mut outputs = vec![
+            GraphTensor::einsum("mk,nk->mn", &[a, b]).retrieve(),
+            GraphTensor::einsum("bmk,kn->bmn", &[c, b]).retrieve(),
+            GraphTensor::einsum("kn,bmk->bnm", &[b, c]).retrieve(),
+        ];
+
+        cx.execute();
+        let unoptimized = outputs.iter().map(|o| o.data()).collect::<Vec<_>>();
+
+        cx.compile(CPUCompiler::default(), &mut outputs);
+        // Free labels on a side are merged, so every contraction is a 2D matmul
+        assert_eq!(
+            cx.graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<crate::matmul::MatMul2D>())
+                .count(),
+            3
+        );
+        cx.execute();
+        for (output, expected) in outputs.iter().zip(unoptimized) {
+            assert_close(&output.data(), &expected);
+        }
+    }
+
+    #[test]
+    fn test_parallel_compiled() {
+        let mut cx = Graph::new();
+        let x = cx.tensor((3, 16)).set(random_vec(48));
+        let (w_q, w_k, w_v) = (
+            cx.tensor((16, 16)).set(random_vec(256)),
+            cx.tensor((16, 16)).set(random_vec(256)),
+            cx.tensor((16, 16)).set(random_vec(256)),
+        );
+        let (q, k, v) = (x.matmul(w_q), x.matmul(w_k), x.matmul(w_v));
+        let mut out = q
+            .matmul(k.permute((1, 0)))
+            .softmax(1)
+            .matmul(v)
+            .sin()
+            .retrieve();
+        cx.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            &mut out,
+        );
+
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+        // Safety: the graph only contains primitive and CPU ops
+        unsafe { cx.execute_parallel(4) };
+        assert_exact(&out.data(), &expected);
+        out.drop();
+        unsafe { cx.execute_parallel_deterministic(4) };
+        assert_exact(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_save_load_compiled() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("A", ('M', 4));
+        let b = cx.named_tensor("B", (4, 3));
+        let indexes = cx.named_tensor("Indexes", 2);
+        let mut c = (a.matmul(b).exp2().sin() * 2.).retrieve();
+        let mut d = b.gather(indexes).retrieve();
+        cx.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            (&mut c, &mut d),
+        );
+
+        let mut registry = OpRegistry::default();
+        crate::register_ops(&mut registry);
+        let path = std::env::temp_dir().join(format!("luminal_cpu_{}.json", std::process::id()));
+        cx.save(&path, &registry).unwrap();
+        let mut loaded = Graph::new();
+        loaded.load(&path, &registry).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (a_data, b_data) = (random_vec(8), random_vec(12));
+        a.set_dyn(a_data.clone(), (2, 4));
+        b.set(b_data.clone());
+        indexes.set(vec![3., 1.]);
+        cx.execute();
+
+        GraphTensor::from_id(a.id, a.shape, &mut loaded).set_dyn(a_data, (2, 4));
+        GraphTensor::from_id(b.id, b.shape, &mut loaded).set(b_data);
+        GraphTensor::from_id(indexes.id, indexes.shape, &mut loaded).set(vec![3., 1.]);
+        loaded.execute();
+
+        assert_exact(
+            &c.data(),
+            &GraphTensor::from_id(c.id, c.shape, &mut loaded).data(),
+        );
+        assert_exact(
+            &d.data(),
+            &GraphTensor::from_id(d.id, d.shape, &mut loaded).data(),
+        );
+    }
+
+    #[test]
+    fn test_scatter_compiled() {
+        let build = |cx: &mut Graph| {
+            let mut rng = StdRng::seed_from_u64(0);
+            let a = cx
+                .tensor(('a', 3))
+                .set_dyn(random_vec_rng(12, &mut rng), (4, 3));
+            let rows = cx.tensor((2, 3)).set(vec![3., 0., 1., 1., 0., 9.]);
+            let src = cx.tensor((2, 3)).set(random_vec_rng(6, &mut rng));
+            let indexes = cx.tensor(2).set(vec![2., 0.]);
+            let values = cx
+                .tensor(('a', 2))
+                .set_dyn(random_vec_rng(8, &mut rng), (4, 2));
+            let embeddings = cx.tensor((4, 3)).set(random_vec_rng(12, &mut rng));
+            vec![
+                a.scatter(0, rows, src).retrieve(),
+                a.scatter_add(0, rows, src).retrieve(),
+                a.index_put(1, indexes, values).retrieve(),
+                embeddings.gather(indexes).retrieve(),
+            ]
+        };
+        let mut cx = Graph::new();
+        let outputs = build(&mut cx);
+        cx.execute();
+
+        let mut compiled = Graph::new();
+        let mut compiled_outputs = build(&mut compiled);
+        compiled.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            &mut compiled_outputs,
+        );
+        // The scatter and scatter_add along rows share their one-hot sum after CSE
+        assert_eq!(
+            compiled
+                .graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<crate::binary::ScatterAdd>())
+                .count(),
+            2
+        );
+        assert!(compiled
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<crate::binary::Gather>()));
+        compiled.execute();
+        for (a, b) in outputs.iter().zip(&compiled_outputs) {
+            assert_close(&a.data(), &b.data());
+        }
+    }
+
+    #[test]
+    fn test_gather_compiled() {
+        let build = |cx: &mut Graph| {
+            let mut rng = StdRng::seed_from_u64(0);
+            let a = cx
+                .tensor((2, 'a', 3))
+                .set_dyn(random_vec_rng(24, &mut rng), (2, 4, 3));
+            let indexes = cx.tensor(3).set(vec![3., 0., 3.]);
+            let along = cx.tensor((2, 'a', 2)).set_dyn(
+                vec![
+                    2., 0., 1., 1., 0., 0., 2., 1., 1., 2., 0., 2., 0., 1., 2., 2.,
+                ],
+                (2, 4, 2),
+            );
+            vec![
+                a.index_select(0, cx.tensor(2).set(vec![1., 0.])).retrieve(),
+                a.index_select(1, indexes).retrieve(),
+                a.index_select(2, indexes - 1.).retrieve(),
+                a.take_along_axis(2, along).retrieve(),
+                a.permute((0, 2, 1))
+                    .take_along_axis(1, along.permute((0, 2, 1)))
+                    .retrieve(),
+                a.slice((.., 1..2, ..))
+                    .reshape((2, 3))
+                    .gather(indexes - 1.)
+                    .retrieve(),
+            ]
+        };
+        let mut cx = Graph::new();
+        let outputs = build(&mut cx);
+        cx.set_dyn_dim('a', 4);
+        cx.execute();
+
+        let mut compiled = Graph::new();
+        let mut compiled_outputs = build(&mut compiled);
+        compiled.set_dyn_dim('a', 4);
+        compiled.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            &mut compiled_outputs,
+        );
+        assert_eq!(
+            compiled
+                .graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<crate::binary::Gather>())
+                .count(),
+            6
+        );
+        compiled.execute();
+        for (a, b) in outputs.iter().zip(&compiled_outputs) {
+            assert_close(&a.data(), &b.data());
+        }
+    }
+
+    #[test]
+    fn test_sort_compiled() {
+        let build = |cx: &mut Graph| {
+            let mut rng = StdRng::seed_from_u64(0);
+            let a = cx
+                .tensor((3, 'a'))
+                .set_dyn(random_vec_rng(15, &mut rng), (3, 5));
+            let (values, indexes) = a.topk(2, 1);
+            vec![
+                a.argsort(1, false).retrieve(),
+                a.permute((1, 0)).argsort(0, false).retrieve(),
+                a.sort(1, true).retrieve(),
+                values.retrieve(),
+                indexes.retrieve(),
+            ]
+        };
+        let mut cx = Graph::new();
+        let outputs = build(&mut cx);
+        cx.set_dyn_dim('a', 5);
+        cx.execute();
+
+        let mut compiled = Graph::new();
+        let mut compiled_outputs = build(&mut compiled);
+        compiled.set_dyn_dim('a', 5);
+        compiled.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            &mut compiled_outputs,
+        );
+        // Sorted values are gathered rather than multiplied by a one-hot
+        assert_eq!(
+            compiled
+                .graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<crate::binary::Gather>())
+                .count(),
+            2
+        );
+        compiled.execute();
+        for (a, b) in outputs.iter().zip(&compiled_outputs) {
+            assert_exact(&a.data(), &b.data());
+        }
+    }
+
+    #[test]
+    fn test_retrieved_rewrites_compiled() {
+        // Outputs replaced by the subtraction, equal and arange passes are still retrievable
+        let build = |cx: &mut Graph| {
+            let a = cx.tensor(4).set(vec![1., 2., 3., 4.]);
+            let b = cx.tensor(4).set(vec![4., 2., 1., 4.]);
+            vec![
+                (a - b).retrieve(),
+                a.eq(b).retrieve(),
+                cx.arange(4).retrieve(),
+            ]
+        };
+        let mut cx = Graph::new();
+        let outputs = build(&mut cx);
+        cx.execute();
+
+        let mut compiled = Graph::new();
+        let mut compiled_outputs = build(&mut compiled);
+        compiled.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            &mut compiled_outputs,
+        );
+        for ty in [
+            std::any::TypeId::of::<crate::binary::Sub>(),
+            std::any::TypeId::of::<crate::binary::Equal>(),
+            std::any::TypeId::of::<crate::other::ARange>(),
+        ] {
+            assert!(compiled
+                .graph
+                .node_weights()
+                .any(|op| op.as_any().type_id() == ty));
+        }
+        compiled.execute();
+
+        for (a, b) in outputs.iter().zip(&compiled_outputs) {
+            assert_exact(&a.data(), &b.data());
+        }
+    }
+
+    #[test]
+    fn test_retrieved_inputs_fused() {
+        // Retrieved inputs aren't deleted by the fusion, so they don't stop it
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(vec![1., 2., 3., 4.]).retrieve();
+        let b = cx.tensor(4).set(vec![4., 2., 1., 4.]).retrieve();
+        let mut outputs = vec![a, b, (a - b).retrieve(), a.eq(b).retrieve()];
+        cx.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            &mut outputs,
+        );
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<crate::binary::Sub>()));
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<crate::binary::Equal>()));
+        cx.execute();
+
+        assert_exact(&outputs[0].data(), &[1., 2., 3., 4.]);
+        assert_exact(&outputs[1].data(), &[4., 2., 1., 4.]);
+        assert_exact(&outputs[2].data(), &[-3., 0., 2., 0.]);
+        assert_exact(&outputs[3].data(), &[0., 1., 0., 1.]);
+    }
+
+    #[test]
+    fn test_fuzz_cpu_compiler() {
+        luminal::tests::fuzz::fuzz_compiler(
+            || (GenericCompiler::default(), CPUCompiler::default()),
+            "(GenericCompiler::default(), luminal_cpu::CPUCompiler::default())",
+            100,
+            0,
+        );
+    }
 }
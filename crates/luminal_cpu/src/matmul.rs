@@ -3,6 +3,8 @@ use luminal::{
     prelude::*,
 };
 
+use crate::memory_planner::CPUKernel;
+
 pub type MatMulCompiler = (MatMul2DCompiler, BatchMatMul2DCompiler);
 
 #[derive(Debug, Default)]
@@ -36,6 +38,13 @@ impl Compiler for MatMul2DCompiler {
             let (mul, sum_reduce) = (s.get(&mul), s.get(&sum_reduce));
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
+            // The kernel only understands strides, so sliced or padded inputs stay unfused
+            if srcs
+                .iter()
+                .any(|(_, _, sh)| sh.is_sliced() || sh.is_padded())
+            {
+                continue;
+            }
             // Undo expansions and permute
             srcs[0].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
@@ -58,7 +67,7 @@ impl Compiler for MatMul2DCompiler {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct MatMul2D;
 
 impl Operator for MatMul2D {
@@ -122,6 +131,13 @@ impl Compiler for BatchMatMul2DCompiler {
             let (mul, sum_reduce) = (s.get(&mul), s.get(&sum_reduce));
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
+            // The kernel only understands strides, so sliced or padded inputs stay unfused
+            if srcs
+                .iter()
+                .any(|(_, _, sh)| sh.is_sliced() || sh.is_padded())
+            {
+                continue;
+            }
             // Undo expansions and permute
             srcs[0].2.remove_dim(2);
             srcs[1].2.remove_dim(1);
@@ -145,7 +161,7 @@ impl Compiler for BatchMatMul2DCompiler {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct BatchedMatMul2D;
 
 // ABCxCD -> ABD
@@ -187,3 +203,66 @@ impl Operator for BatchedMatMul2D {
         vec![Tensor::new(c)]
     }
 }
+
+impl CPUKernel for MatMul2D {
+    fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0].dims()[0] * input_shapes[1].dims()[1]]
+    }
+    fn cpu_forward(&mut self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        let (a_shape, b_shape) = (inputs[0].1.shape_usize(), inputs[1].1.shape_usize());
+        let (a_strides, b_strides) = (inputs[0].1.strides(), inputs[1].1.strides());
+        unsafe {
+            matrixmultiply::sgemm(
+                a_shape[0],
+                a_shape[1],
+                b_shape[1],
+                1.0,
+                inputs[0].0.as_ptr(),
+                a_strides[0].to_usize().unwrap() as isize,
+                a_strides[1].to_usize().unwrap() as isize,
+                inputs[1].0.as_ptr(),
+                b_strides[0].to_usize().unwrap() as isize,
+                b_strides[1].to_usize().unwrap() as isize,
+                0.0,
+                outputs[0].as_mut_ptr(),
+                b_shape[1] as isize,
+                1,
+            );
+        }
+    }
+}
+
+impl CPUKernel for BatchedMatMul2D {
+    fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        let (a_shape, b_shape) = (input_shapes[0].dims(), input_shapes[1].dims());
+        vec![a_shape[0] * a_shape[1] * b_shape[1]]
+    }
+    fn cpu_forward(&mut self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        let (a_shape, b_shape) = (inputs[0].1.shape_usize(), inputs[1].1.shape_usize());
+        let (a_strides, b_strides) = (inputs[0].1.strides(), inputs[1].1.strides());
+        let mat_size = a_shape[1] * b_shape[1];
+        for i in 0..a_shape[0] {
+            unsafe {
+                matrixmultiply::sgemm(
+                    a_shape[1],
+                    a_shape[2],
+                    b_shape[1],
+                    1.0,
+                    inputs[0]
+                        .0
+                        .as_ptr()
+                        .add(i * a_strides[0].to_usize().unwrap()),
+                    a_strides[1].to_usize().unwrap() as isize,
+                    a_strides[2].to_usize().unwrap() as isize,
+                    inputs[1].0.as_ptr(),
+                    b_strides[0].to_usize().unwrap() as isize,
+                    b_strides[1].to_usize().unwrap() as isize,
+                    0.0,
+                    outputs[0].as_mut_ptr().add(i * mat_size),
+                    b_shape[1] as isize,
+                    1,
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,607 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use itertools::Itertools;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use luminal::{
+    op::{
+        Add, ArgSort, Contiguous, Exp2, InputTensor, LessThan, Log2, MaxReduce, Mod, Mul, Operator,
+        Recip, Sin, Sqrt, SumReduce,
+    },
+    prelude::{
+        petgraph::{algo::toposort, visit::EdgeRef, Direction},
+        *,
+    },
+};
+
+use crate::{
+    binary::{Equal, Gather, ScatterAdd, Sub},
+    matmul::{BatchedMatMul2D, MatMul2D},
+    FusedUnary,
+};
+
+/// A CPU op that can write its outputs into preallocated buffers. Inputs with other element types than f32 are ran with the op's normal `process`
+pub trait CPUKernel: Operator {
+    /// The number of elements in each output, given the input shapes
+    fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression>;
+    /// Run the op, writing every element of the output buffers
+    fn cpu_forward(&mut self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]);
+}
+
+/// Get a kernel version of an op, if it has one
+fn to_kernel(op: &dyn Operator) -> Option<Box<dyn CPUKernel>> {
+    macro_rules! try_kernel {
+        ($($t:ty),*) => {
+            $(if let Some(o) = op.as_any().downcast_ref::<$t>() {
+                return Some(Box::new(o.clone()));
+            })*
+        };
+    }
+    try_kernel!(
+        Contiguous,
+        Log2,
+        Exp2,
+        Sin,
+        Recip,
+        Sqrt,
+        Add,
+        Mul,
+        Mod,
+        LessThan,
+        SumReduce,
+        MaxReduce,
+        MatMul2D,
+        BatchedMatMul2D,
+        Sub,
+        Equal,
+        Gather,
+        ScatterAdd,
+        ArgSort,
+        FusedUnary
+    );
+    None
+}
+
+/// Statically plan intermediate buffers for CPU kernels.
+///
+/// Tensor lifetimes are computed from the execution order, and outputs whose lifetimes don't overlap share an arena slot.
+/// Slots are sized at execution time once the dyn dims are known, and only reallocated when they need to grow.
+/// This wraps ops, so it should be ran after all other compilers.
+#[derive(Debug, Default)]
+pub struct MemoryPlanCompiler;
+
+impl Compiler for MemoryPlanCompiler {
+    type Output = MemoryPlan;
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) -> MemoryPlan {
+        let order = toposort(&graph.graph, None).unwrap();
+        let kernels = order
+            .iter()
+            .filter_map(|n| {
+                to_kernel(graph.graph.node_weight(*n).unwrap().as_ref()).map(|k| (*n, k))
+            })
+            .collect::<FxHashMap<_, _>>();
+        let consumers = |node| {
+            graph
+                .graph
+                .edges_directed(node, Direction::Outgoing)
+                .filter(|e| !e.weight().is_schedule())
+                .map(|e| e.target())
+                .collect::<FxHashSet<_>>()
+        };
+        // Only plan outputs that don't need to outlive execution and are only read by kernels
+        let planned = order
+            .iter()
+            .filter(|n| kernels.contains_key(n) && !graph.no_delete.contains(n))
+            .filter(|n| consumers(**n).iter().all(|c| kernels.contains_key(c)))
+            .copied()
+            .collect::<FxHashSet<_>>();
+
+        // Assign each planned output to a slot that is free for its whole lifetime
+        let step = order
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (*n, i))
+            .collect::<FxHashMap<_, _>>();
+        let mut plan = MemoryPlan::default();
+        // The nodes currently occupying each slot
+        let mut occupants: Vec<NodeIndex> = vec![];
+        let mut output_slots = FxHashMap::default();
+        let mut schedule_deps = FxHashSet::default();
+        for (i, node) in order
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| planned.contains(n))
+        {
+            let node_consumers = consumers(*node);
+            let last_use = node_consumers.iter().map(|c| step[c]).max().unwrap_or(i);
+            let input_shapes = graph
+                .get_sources(*node)
+                .into_iter()
+                .map(|(_, _, sh)| sh)
+                .collect_vec();
+            let mut slots = vec![];
+            for size in kernels[node].output_sizes(&input_shapes) {
+                let size = size.simplify();
+                plan.tensors.push((size, i, last_use));
+                let free = (0..occupants.len())
+                    .filter(|s| !slots.contains(s))
+                    .filter(|s| {
+                        consumers(occupants[*s])
+                            .iter()
+                            .map(|c| step[c])
+                            .max()
+                            .unwrap_or(step[&occupants[*s]])
+                            < i
+                    })
+                    .collect_vec();
+                // Prefer a slot that already holds a tensor of the same size
+                let slot = if let Some(slot) = free
+                    .iter()
+                    .find(|s| plan.slots[**s].contains(&size))
+                    .or(free.first())
+                {
+                    // Make sure the previous occupant is fully consumed before we overwrite it
+                    for c in consumers(occupants[*slot]) {
+                        schedule_deps.insert((c, *node));
+                    }
+                    plan.slots[*slot].push(size);
+                    occupants[*slot] = *node;
+                    *slot
+                } else {
+                    plan.slots.push(vec![size]);
+                    occupants.push(*node);
+                    occupants.len() - 1
+                };
+                slots.push(slot);
+            }
+            output_slots.insert(*node, slots);
+        }
+        for (from, to) in schedule_deps {
+            if from != to {
+                graph.add_schedule_dependency(from, to);
+            }
+        }
+
+        // Wrap kernels
+        let arena = Arc::new(Arena(
+            (0..plan.slots.len())
+                .map(|_| Mutex::new(Arc::new(vec![])))
+                .collect(),
+        ));
+        for (node, kernel) in kernels {
+            *graph.graph.node_weight_mut(node).unwrap() = Box::new(PlannedKernel {
+                kernel,
+                arena: arena.clone(),
+                output_slots: output_slots.remove(&node),
+            });
+        }
+        plan
+    }
+}
+
+/// The result of memory planning
+#[derive(Debug, Default, Clone)]
+pub struct MemoryPlan {
+    /// The sizes (in elements) of the tensors assigned to each slot
+    pub slots: Vec<Vec<Expression>>,
+    /// Each planned tensor's size, first step and last step
+    tensors: Vec<(Expression, usize, usize)>,
+}
+
+impl MemoryPlan {
+    /// Peak bytes used by the planned arena for the given dyn dims
+    pub fn planned_bytes(&self, dyn_map: &FxHashMap<char, usize>) -> usize {
+        self.slots
+            .iter()
+            .map(|s| {
+                s.iter()
+                    .map(|e| e.exec(dyn_map).unwrap())
+                    .max()
+                    .unwrap_or_default()
+            })
+            .sum::<usize>()
+            * std::mem::size_of::<f32>()
+    }
+
+    /// Peak bytes used by the same tensors when each is allocated on creation and freed after its last use
+    pub fn naive_bytes(&self, dyn_map: &FxHashMap<char, usize>) -> usize {
+        let sizes = self
+            .tensors
+            .iter()
+            .map(|(s, _, _)| s.exec(dyn_map).unwrap())
+            .collect_vec();
+        self.tensors
+            .iter()
+            .map(|(_, step, _)| {
+                self.tensors
+                    .iter()
+                    .zip(&sizes)
+                    .filter(|((_, start, end), _)| start <= step && end >= step)
+                    .map(|(_, s)| *s)
+                    .sum::<usize>()
+            })
+            .max()
+            .unwrap_or_default()
+            * std::mem::size_of::<f32>()
+    }
+}
+
+/// Buffers shared between planned kernels.
+///
+/// The plan (and the schedule dependencies it adds) makes sure a slot's previous tensor is consumed before the slot is written again, so normally its buffer is reused in place.
+/// If that tensor is still alive (kept by `execute_no_delete` or cached by `execute_incremental`), the slot gets a fresh buffer instead.
+struct Arena(Vec<Mutex<Arc<Vec<f32>>>>);
+
+/// A tensor living in an arena slot
+#[derive(Clone)]
+pub(crate) struct ArenaBuffer {
+    buffer: Arc<Vec<f32>>,
+    len: usize,
+}
+
+impl ArenaBuffer {
+    pub(crate) fn as_slice(&self) -> &[f32] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl Debug for ArenaBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ArenaBuffer(len: {})", self.len)
+    }
+}
+
+impl Data for ArenaBuffer {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+    fn num_bytes(&self) -> usize {
+        self.len * std::mem::size_of::<f32>()
+    }
+}
+
+/// A kernel that reads inputs from either arena buffers or vectors, and writes outputs into its planned slots
+struct PlannedKernel {
+    kernel: Box<dyn CPUKernel>,
+    arena: Arc<Arena>,
+    /// Slots to write outputs into. If not planned, outputs are allocated as normal
+    output_slots: Option<Vec<usize>>,
+}
+
+impl Debug for PlannedKernel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.kernel.fmt(f)
+    }
+}
+
+impl Operator for PlannedKernel {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let Some(inputs) = inp
+            .iter()
+            .map(|(t, sh)| as_slice(t.borrowed()).map(|s| (s, *sh)))
+            .collect::<Option<Vec<_>>>()
+        else {
+            // Kernels only work on f32, so run the op as normal without using its slots
+            return self.kernel.process(inp);
+        };
+        let sizes = self
+            .kernel
+            .output_sizes(&inp.iter().map(|(_, sh)| *sh).collect_vec())
+            .into_iter()
+            .map(|s| s.to_usize().unwrap())
+            .collect_vec();
+        if let Some(slots) = &self.output_slots {
+            let mut buffers = slots
+                .iter()
+                .map(|slot| self.arena.0[*slot].lock().unwrap())
+                .collect_vec();
+            let mut outputs = buffers
+                .iter_mut()
+                .zip(&sizes)
+                .map(|(buffer, size)| {
+                    if Arc::get_mut(buffer).is_none() {
+                        // The slot's previous tensor is still alive, so leave it be
+                        **buffer = Arc::new(vec![]);
+                    }
+                    let buffer = Arc::get_mut(buffer).unwrap();
+                    if buffer.len() < *size {
+                        buffer.resize(*size, 0.);
+                    }
+                    &mut buffer[..*size]
+                })
+                .collect_vec();
+            self.kernel.cpu_forward(&inputs, &mut outputs);
+            buffers
+                .iter()
+                .zip(sizes)
+                .map(|(buffer, len)| {
+                    Tensor::new(ArenaBuffer {
+                        buffer: Arc::clone(buffer),
+                        len,
+                    })
+                })
+                .collect()
+        } else {
+            let mut outputs = sizes.into_iter().map(|s| vec![0.; s]).collect_vec();
+            self.kernel.cpu_forward(
+                &inputs,
+                &mut outputs.iter_mut().map(|o| o.as_mut_slice()).collect_vec(),
+            );
+            outputs.into_iter().map(Tensor::new).collect()
+        }
+    }
+}
+
+/// The f32 data of a vector or arena tensor
+fn as_slice(tensor: &Tensor) -> Option<&[f32]> {
+    if let Some(v) = tensor.downcast_ref::<Vec<f32>>() {
+        Some(v)
+    } else {
+        tensor.downcast_ref::<ArenaBuffer>().map(|b| b.as_slice())
+    }
+}
+
+pub(crate) fn get_index(
+    data: &[f32],
+    (ind, val): &(Expression, Expression),
+    stack: &mut Vec<i64>,
+    index: usize,
+) -> f32 {
+    if val.exec_single_var_stack(index, stack) != 0 {
+        data[ind.exec_single_var_stack(index, stack)]
+    } else {
+        0.0
+    }
+}
+
+/// Kernels for primitive unary ops
+macro_rules! unary_kernel {
+    ($op:ty, $f:expr) => {
+        impl CPUKernel for $op {
+            fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+                vec![input_shapes[0].n_elements()]
+            }
+            fn cpu_forward(
+                &mut self,
+                inputs: &[(&[f32], ShapeTracker)],
+                outputs: &mut [&mut [f32]],
+            ) {
+                let expr = (
+                    inputs[0].1.index_expression(),
+                    inputs[0].1.valid_expression(),
+                );
+                let mut stack = vec![];
+                let f: fn(f32) -> f32 = $f;
+                for (i, out) in outputs[0].iter_mut().enumerate() {
+                    *out = f(get_index(inputs[0].0, &expr, &mut stack, i));
+                }
+            }
+        }
+    };
+}
+
+unary_kernel!(Contiguous, |a| a);
+unary_kernel!(Log2, f32::log2);
+unary_kernel!(Exp2, f32::exp2);
+unary_kernel!(Sin, f32::sin);
+unary_kernel!(Recip, f32::recip);
+unary_kernel!(Sqrt, f32::sqrt);
+
+/// Kernels for elementwise binary ops
+macro_rules! binary_kernel {
+    ($op:ty, $f:expr) => {
+        impl CPUKernel for $op {
+            fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+                vec![input_shapes[0].n_elements()]
+            }
+            fn cpu_forward(
+                &mut self,
+                inputs: &[(&[f32], ShapeTracker)],
+                outputs: &mut [&mut [f32]],
+            ) {
+                let lexpr = (
+                    inputs[0].1.index_expression(),
+                    inputs[0].1.valid_expression(),
+                );
+                let rexpr = (
+                    inputs[1].1.index_expression(),
+                    inputs[1].1.valid_expression(),
+                );
+                let mut stack = vec![];
+                let f: fn(f32, f32) -> f32 = $f;
+                for (i, out) in outputs[0].iter_mut().enumerate() {
+                    *out = f(
+                        get_index(inputs[0].0, &lexpr, &mut stack, i),
+                        get_index(inputs[1].0, &rexpr, &mut stack, i),
+                    );
+                }
+            }
+        }
+    };
+}
+
+binary_kernel!(Add, |a, b| a + b);
+binary_kernel!(Mul, |a, b| a * b);
+binary_kernel!(Mod, |a, b| a % b);
+binary_kernel!(LessThan, |a, b| (a < b) as i32 as f32);
+binary_kernel!(Sub, |a, b| a - b);
+binary_kernel!(Equal, |a, b| if (a - b).abs() < 1e-6 { 1. } else { 0. });
+
+/// Kernels for reductions along a dimension
+macro_rules! reduce_kernel {
+    ($op:ty, $init:expr, $f:expr) => {
+        impl CPUKernel for $op {
+            fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+                let mut sh = input_shapes[0];
+                sh.remove_dim(self.0);
+                vec![sh.n_elements()]
+            }
+            fn cpu_forward(
+                &mut self,
+                inputs: &[(&[f32], ShapeTracker)],
+                outputs: &mut [&mut [f32]],
+            ) {
+                let sh = inputs[0].1.shape_usize();
+                let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
+                let dim_size = sh[self.0];
+                let expr = (
+                    inputs[0].1.index_expression(),
+                    inputs[0].1.valid_expression(),
+                );
+                let mut stack = vec![];
+                let f: fn(f32, f32) -> f32 = $f;
+                for (i, out) in outputs[0].iter_mut().enumerate() {
+                    let (front, back) = (i / back_size, i % back_size);
+                    *out = (0..dim_size).fold($init, |acc, k| {
+                        let orig_index = front * dim_size * back_size + k * back_size + back;
+                        f(acc, get_index(inputs[0].0, &expr, &mut stack, orig_index))
+                    });
+                }
+            }
+        }
+    };
+}
+
+reduce_kernel!(SumReduce, 0.0, |a, b| a + b);
+reduce_kernel!(MaxReduce, -f32::INFINITY, f32::max);
+
+impl CPUKernel for ArgSort {
+    fn output_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0].n_elements()]
+    }
+    fn cpu_forward(&mut self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        let expr = (
+            inputs[0].1.index_expression(),
+            inputs[0].1.valid_expression(),
+        );
+        let mut stack = vec![];
+        let sorted = self.sort_lanes(&inputs[0].1.shape_usize(), |i| {
+            get_index(inputs[0].0, &expr, &mut stack, i) as f64
+        });
+        outputs[0].copy_from_slice(&sorted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::*;
+
+    use crate::CPUCompiler;
+
+    use super::MemoryPlanCompiler;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_memory_plan() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(('B', 8));
+        let w1 = cx.tensor((8, 16)).set(random_vec(128)).keep();
+        let w2 = cx.tensor((16, 8)).set(random_vec(128)).keep();
+        let h = a.matmul(w1).relu();
+        let h = (h.matmul(w2) + a).exp2().sin();
+        let mut out = (h.softmax(1).sum(0) * 3.).retrieve();
+        cx.compile(
+            (GenericCompiler::default(), CPUCompiler::default()),
+            &mut out,
+        );
+
+        let first_batch = random_vec(16);
+        a.set_dyn(first_batch.clone(), (2, 8));
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+
+        let plan = cx.compile(MemoryPlanCompiler, &mut out);
+        for batch in [2, 5, 3] {
+            if batch == 2 {
+                a.set_dyn(first_batch.clone(), (2, 8));
+            } else {
+                a.set_dyn(random_vec(batch * 8), (batch, 8));
+            }
+            cx.execute();
+            let planned = out.data();
+            out.drop();
+            // Safety: the graph only contains primitive and CPU ops
+            unsafe { cx.execute_parallel(3) };
+            assert_exact(&out.data(), &planned);
+            out.drop();
+            if batch == 2 {
+                assert_close(&planned, &expected);
+            }
+            assert!(plan.planned_bytes(&cx.dyn_map) >= plan.naive_bytes(&cx.dyn_map));
+        }
+        assert!(plan.slots.iter().any(|s| s.len() > 1));
+    }
+
+    #[test]
+    fn test_memory_plan_retained_tensors() {
+        let a_data = random_vec(8);
+        let build = |cx: &mut Graph| {
+            let a = cx.tensor(8).set(a_data.clone());
+            let b = cx.tensor(8).set(random_vec(8));
+            let x = a.exp2().sin().sqrt().exp2().sin();
+            let y = b.sin().exp2().sin();
+            ((x + y).sin() * (y + 1.).sqrt(), b)
+        };
+        let mut cx = Graph::new();
+        let (out, b) = build(&mut cx);
+        let mut out = out.retrieve();
+        cx.compile(
+            (
+                GenericCompiler::default(),
+                CPUCompiler::default(),
+                MemoryPlanCompiler,
+            ),
+            &mut out,
+        );
+
+        // Every tensor is kept, so none can be overwritten by later kernels
+        cx.execute_no_delete();
+        let expected = out.data();
+        cx.execute();
+        assert_exact(&out.data(), &expected);
+
+        // Cached tensors from the last run must survive the recomputed nodes reusing their slots
+        cx.execute_incremental();
+        let new_b = random_vec(8);
+        b.set(new_b.clone());
+        cx.execute_incremental();
+        let mut fresh = Graph::new();
+        let (fresh_out, fresh_b) = build(&mut fresh);
+        let fresh_out = fresh_out.retrieve();
+        fresh_b.set(new_b);
+        fresh.execute();
+        assert_close(&out.data(), &fresh_out.data());
+    }
+
+    #[test]
+    fn test_memory_plan_non_f32() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(vec![1.5, -2.5, 3.7, 4.2]);
+        let b = cx.tensor(4).set(vec![1., 2., 3., 4.]);
+        let ints = (a.cast(DType::I32) + b.cast(DType::I32)) * b.cast(DType::I32);
+        let mut out = (ints.cast(DType::F32).exp2() + a).retrieve();
+        cx.compile(
+            (
+                GenericCompiler::default(),
+                CPUCompiler::default(),
+                MemoryPlanCompiler,
+            ),
+            &mut out,
+        );
+        cx.execute();
+
+        let expected = [2_f32, 0., 18., 32.]
+            .into_iter()
+            .zip([1.5, -2.5, 3.7, 4.2])
+            .map(|(i, a)| i.exp2() + a)
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+    }
+}
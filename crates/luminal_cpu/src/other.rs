@@ -9,7 +9,7 @@ use super::binary::Sub;
 #[derive(Debug, Clone, PartialEq)]
 pub struct ARange {
     pub size: Expression,
-    dyn_map: *const FxHashMap<char, usize>,
+    pub(crate) dyn_map: *const FxHashMap<char, usize>,
 }
 
 impl Operator for ARange {
@@ -29,7 +29,7 @@ pub struct ARangeCompiler;
 
 impl Compiler for ARangeCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         // TODO: Make sure this actually checks the shape transformations to ensure pooling happens
         let one1 = super::constant(1.);
         let one2 = super::constant(1.);
@@ -66,6 +66,7 @@ impl Compiler for ARangeCompiler {
                 })
                 .finish();
             move_outgoing_edge(s.get(&sub), arange_op, &mut graph.graph);
+            remap(s.get(&sub), arange_op, &mut ids, graph);
             graph.graph.remove_node(s.get(&sub));
             s.try_delete();
         }
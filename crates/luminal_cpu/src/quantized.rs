@@ -0,0 +1,383 @@
+use serde::{Deserialize, Serialize};
+
+use luminal::{
+    op::{InputTensor, Operator},
+    prelude::{petgraph::visit::EdgeRef, *},
+};
+
+use crate::{
+    binary::Gather,
+    matmul::{BatchedMatMul2D, MatMul2D},
+};
+
+/// Number of weights in a quantized block
+pub const QK: usize = 32;
+
+/// A GGML block quantization format. Weights are stored as the raw GGUF bytes in a `Vec<u8>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuantType {
+    /// f16 delta followed by 32 i8 quants
+    Q8_0,
+    /// f16 delta followed by 16 bytes of packed 4 bit quants (offset by 8)
+    Q4_0,
+}
+
+impl QuantType {
+    /// Size of a single block of 32 weights in bytes
+    pub fn block_bytes(&self) -> usize {
+        match self {
+            QuantType::Q8_0 => 2 + QK,
+            QuantType::Q4_0 => 2 + QK / 2,
+        }
+    }
+
+    /// Number of bytes needed to store `n_elements` weights
+    pub fn n_bytes(&self, n_elements: usize) -> usize {
+        n_elements / QK * self.block_bytes()
+    }
+
+    /// Dequantize a single block into f32s
+    pub fn dequantize_block(&self, block: &[u8], out: &mut [f32; QK]) {
+        let d = f16::from_le_bytes([block[0], block[1]]).to_f32();
+        let qs = &block[2..self.block_bytes()];
+        match self {
+            QuantType::Q8_0 => {
+                for (o, q) in out.iter_mut().zip(qs) {
+                    *o = *q as i8 as f32 * d;
+                }
+            }
+            QuantType::Q4_0 => {
+                for (i, q) in qs.iter().enumerate() {
+                    out[i] = ((q & 0xF) as i32 - 8) as f32 * d;
+                    out[i + QK / 2] = ((q >> 4) as i32 - 8) as f32 * d;
+                }
+            }
+        }
+    }
+
+    /// Dequantize a whole buffer of blocks
+    pub fn dequantize(&self, bytes: &[u8]) -> Vec<f32> {
+        let mut block = [0.; QK];
+        bytes
+            .chunks_exact(self.block_bytes())
+            .flat_map(|b| {
+                self.dequantize_block(b, &mut block);
+                block
+            })
+            .collect()
+    }
+}
+
+fn get_bytes<'a>(tensor: &'a InputTensor<'a>) -> &'a [u8] {
+    tensor
+        .borrowed()
+        .downcast_ref::<Vec<u8>>()
+        .expect("Quantized weights must be raw block bytes (Vec<u8>)")
+}
+
+/// Multiplies a (B)xMxK matrix with a KxN matrix, resulting in a (B)xMxN matrix. This expects the second input to be a quantized, column-major (NxK in memory) matrix
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantizedMatmul(pub QuantType);
+
+impl Operator for QuantizedMatmul {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        assert!(
+            !inp[1].1.is_contiguous(),
+            "Weight matrix must be column-major"
+        );
+        let (a_shape, b_shape) = (inp[0].1.shape_usize(), inp[1].1.shape_usize());
+        let a_strides = inp[0]
+            .1
+            .strides()
+            .into_iter()
+            .map(|s| s.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let (k, n) = (b_shape[0], b_shape[1]);
+        assert_eq!(
+            k % QK,
+            0,
+            "Quantized matmul inner dimension must be a multiple of {QK}"
+        );
+        let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+        let weights = get_bytes(&inp[1].0);
+
+        // Offsets of each row of A, flattening any batch dimension
+        let (m, k_stride) = (a_shape[a_shape.len() - 2], a_strides[a_strides.len() - 1]);
+        let rows = if a_shape.len() == 3 {
+            (0..a_shape[0])
+                .flat_map(|b| {
+                    (0..m)
+                        .map(|i| b * a_strides[0] + i * a_strides[1])
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        } else {
+            (0..m).map(|i| i * a_strides[0]).collect()
+        };
+
+        let mut c = vec![0.; rows.len() * n];
+        let block_bytes = self.0.block_bytes();
+        let mut block = [0.; QK];
+        for (col, row_blocks) in weights.chunks_exact(k / QK * block_bytes).enumerate() {
+            for (b, block_data) in row_blocks.chunks_exact(block_bytes).enumerate() {
+                // Dequantize each block once and use it for every row of A
+                self.0.dequantize_block(block_data, &mut block);
+                for (r, row) in rows.iter().enumerate() {
+                    let start = row + b * QK * k_stride;
+                    c[r * n + col] += block
+                        .iter()
+                        .enumerate()
+                        .map(|(i, w)| w * a_data[start + i * k_stride])
+                        .sum::<f32>();
+                }
+            }
+        }
+
+        vec![Tensor::new(c)]
+    }
+}
+
+/// Gather embeddings out of a quantized embedding table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantizedGather {
+    pub embed_dim: usize,
+    pub quant_type: QuantType,
+}
+
+impl Operator for QuantizedGather {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+        let weights = get_bytes(&tensors[1].0);
+        let block_bytes = self.quant_type.block_bytes();
+
+        let mut out = vec![0.; indexes.len() * self.embed_dim];
+        let mut block = [0.; QK];
+        for (o, index) in out.chunks_exact_mut(self.embed_dim).zip(indexes) {
+            let start = *index as usize * self.embed_dim;
+            for (dim, o) in o.iter_mut().enumerate() {
+                let (b, i) = ((start + dim) / QK, (start + dim) % QK);
+                if i == 0 || dim == 0 {
+                    self.quant_type
+                        .dequantize_block(&weights[b * block_bytes..], &mut block);
+                }
+                *o = block[i];
+            }
+        }
+
+        vec![Tensor::new(out)]
+    }
+}
+
+/// Dequantize a whole quantized weight into f32s, ignoring the input view
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dequantize(pub QuantType);
+
+impl Operator for Dequantize {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![Tensor::new(self.0.dequantize(get_bytes(&inp[0].0)))]
+    }
+}
+
+/// Swap the matmuls and gathers consuming quantized weights for block-dequantizing versions. Run this after the `CPUCompiler`.
+///
+/// Any other consumers (including matmuls and gathers over sliced or padded weights) read a dequantized copy of the weights.
+#[derive(Debug)]
+pub struct CPUQuantizedCompiler(Vec<NodeIndex>, QuantType);
+
+impl CPUQuantizedCompiler {
+    /// Weights stored as Q8_0 blocks
+    pub fn new<To: ToIds>(weights: To) -> Self {
+        Self(weights.to_ids(), QuantType::Q8_0)
+    }
+
+    /// Weights stored as Q4_0 blocks
+    pub fn q4_0<To: ToIds>(weights: To) -> Self {
+        Self(weights.to_ids(), QuantType::Q4_0)
+    }
+}
+
+impl Compiler for CPUQuantizedCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for weight in downstream(&self.0, graph) {
+            let mut dequantized = None;
+            for (edge, target, (inp_ind, out_ind, shape)) in graph
+                .edges_directed(weight, petgraph::Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|i| (e.id(), e.target(), i)))
+                .collect::<Vec<_>>()
+            {
+                let op_node = graph.node_weight_mut(target).unwrap();
+                let unviewed = !shape.is_sliced() && !shape.is_padded();
+                if let Some(gather) = op_node.as_any().downcast_ref::<Gather>() {
+                    // Rows of a matrix
+                    if inp_ind == 1
+                        && gather.axis == 0
+                        && shape.len() == 2
+                        && shape.is_contiguous()
+                        && unviewed
+                    {
+                        *op_node = Box::new(QuantizedGather {
+                            embed_dim: shape.dims()[1].to_usize().unwrap(),
+                            quant_type: self.1,
+                        });
+                        continue;
+                    }
+                } else if (op_node.as_any().is::<MatMul2D>()
+                    || op_node.as_any().is::<BatchedMatMul2D>())
+                    && inp_ind == 1
+                    && !shape.is_contiguous()
+                    && unviewed
+                {
+                    // A column-major right hand side
+                    *op_node = Box::new(QuantizedMatmul(self.1));
+                    continue;
+                }
+                let dequantized = *dequantized.get_or_insert_with(|| {
+                    graph
+                        .add_op(Dequantize(self.1))
+                        .input(weight, out_ind, shape)
+                        .finish()
+                });
+                graph.remove_edge(edge);
+                graph.add_edge(
+                    dequantized,
+                    target,
+                    Dependency::Data {
+                        input_order: inp_ind,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use luminal::prelude::*;
+
+    use super::QuantType;
+    use crate::{CPUCompiler, CPUQuantizedCompiler};
+    luminal::test_imports!();
+
+    /// Quantize rows of weights with a delta of 0.5
+    fn quantize(weights: &[i8], quant_type: QuantType) -> Vec<u8> {
+        let d = f16::from_f32(0.5).to_le_bytes();
+        weights
+            .chunks_exact(32)
+            .flat_map(|block| {
+                let qs = match quant_type {
+                    QuantType::Q8_0 => block.iter().map(|q| *q as u8).collect::<Vec<_>>(),
+                    QuantType::Q4_0 => (0..16)
+                        .map(|i| (block[i] + 8) as u8 | (((block[i + 16] + 8) as u8) << 4))
+                        .collect(),
+                };
+                d.into_iter().chain(qs)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_quantized_unfused_consumers() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut cx = Graph::new();
+        let weight = cx.tensor((48, 64));
+        let reference = cx.tensor((48, 64));
+        let inp = cx.tensor((3, 32)).set(random_vec_rng(96, &mut rng));
+        let build = |w: GraphTensor| {
+            (
+                inp.matmul(w.slice((..16, ..32)).permute((1, 0))).retrieve(),
+                w.sum(1).retrieve(),
+            )
+        };
+        let mut outs = build(weight);
+        let mut refs = build(reference);
+
+        let weights = (0..48 * 64)
+            .map(|_| rng.gen_range(-128..=127))
+            .collect::<Vec<i8>>();
+        weight.set(quantize(&weights, QuantType::Q8_0));
+        reference.set(weights.iter().map(|w| *w as f32 * 0.5).collect::<Vec<_>>());
+
+        cx.compile(
+            (
+                GenericCompiler::default(),
+                CPUCompiler::default(),
+                CPUQuantizedCompiler::new(weight),
+            ),
+            (&mut outs, &mut refs),
+        );
+        cx.execute();
+
+        assert_close(&outs.0.data(), &refs.0.data());
+        assert_close(&outs.1.data(), &refs.1.data());
+    }
+
+    #[test]
+    fn test_quantized_matmul() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut cx = Graph::new();
+        let (q8, q4) = (cx.tensor((48, 64)), cx.tensor((48, 64)));
+        let (q8_ref, q4_ref) = (cx.tensor((48, 64)), cx.tensor((48, 64)));
+        let vocab = cx.tensor((10, 64));
+        let vocab_ref = cx.tensor((10, 64));
+        let inp = cx.tensor((2, 3, 64)).set(random_vec_rng(384, &mut rng));
+        let tokens = cx.tensor(3).set(vec![4., 0., 9.]);
+
+        let mut outs = (
+            inp.matmul(q8.permute((1, 0))).retrieve(),
+            inp.slice((..1, .., ..))
+                .reshape((3, 64))
+                .matmul(q4.permute((1, 0)))
+                .retrieve(),
+            vocab.gather(tokens).retrieve(),
+        );
+        let mut refs = (
+            inp.matmul(q8_ref.permute((1, 0))).retrieve(),
+            inp.slice((..1, .., ..))
+                .reshape((3, 64))
+                .matmul(q4_ref.permute((1, 0)))
+                .retrieve(),
+            vocab_ref.retrieve(),
+        );
+
+        // Random quants in each format, and the equivalent f32 weights
+        for (quant, reference, quant_type, range) in [
+            (q8, q8_ref, QuantType::Q8_0, -128..=127),
+            (q4, q4_ref, QuantType::Q4_0, -8..=7),
+            (vocab, vocab_ref, QuantType::Q8_0, -128..=127),
+        ] {
+            let n = quant.shape.n_elements().to_usize().unwrap();
+            let weights = (0..n)
+                .map(|_| rng.gen_range(range.clone()))
+                .collect::<Vec<i8>>();
+            let bytes = quantize(&weights, quant_type);
+            assert_eq!(bytes.len(), quant_type.n_bytes(n));
+            quant.set(bytes);
+            reference.set(weights.iter().map(|w| *w as f32 * 0.5).collect::<Vec<_>>());
+        }
+
+        cx.compile(
+            (
+                GenericCompiler::default(),
+                CPUCompiler::default(),
+                CPUQuantizedCompiler::new((q8, vocab)),
+                CPUQuantizedCompiler::q4_0(q4),
+            ),
+            (&mut outs, &mut refs),
+        );
+        cx.execute();
+
+        assert_close(&outs.0.data(), &refs.0.data());
+        assert_close(&outs.1.data(), &refs.1.data());
+        let vocab_data = refs.2.data();
+        let expected = [4, 0, 9]
+            .into_iter()
+            .flat_map(|t| vocab_data[t * 64..(t + 1) * 64].to_vec())
+            .collect::<Vec<_>>();
+        assert_exact(&outs.2.data(), &expected);
+    }
+}
@@ -494,6 +494,85 @@ impl<T: CudaFloat> Operator for CudaLessThan<T> {
     }
 }
 
+/// Pick from the second input where the first is nonzero, otherwise from the third
+#[derive(Clone)]
+pub struct CudaSelect<T> {
+    function: CudaFunction,
+    device: Arc<CudaContext>,
+    _phantom: PhantomData<T>,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+crate::debug_type!(CudaSelect);
+
+impl<T: CudaFloat> CudaSelect<T> {
+    pub fn new(
+        cond_shape: ShapeTracker,
+        a_shape: ShapeTracker,
+        b_shape: ShapeTracker,
+        device: Arc<CudaContext>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (cond_idx, cond_valid) = get_idx_valid_exps(cond_shape);
+        let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
+        let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[cond_shape, a_shape, b_shape]);
+        let type_name = T::type_name();
+        let code = format!("
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_cond, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
+    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        {type_name} cond_t = (({cond_valid}) != 0) ? inp_cond[{cond_idx}] : ({type_name})0.0;
+        if (cond_t != ({type_name})0.0) {{
+            out[idx] = (({a_valid}) != 0) ? inp_a[{a_idx}] : ({type_name})0.0;
+        }} else {{
+            out[idx] = (({b_valid}) != 0) ? inp_b[{b_idx}] : ({type_name})0.0;
+        }}
+    }}
+}}");
+        Self {
+            function: compile_and_load_kernel(code, &device),
+            device,
+            _phantom: Default::default(),
+            dyn_symbols,
+            dyn_map,
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaSelect<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let cond = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let a = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let b = get_buffer_from_tensor::<T>(&tensors[2].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let stream = self.device.default_stream();
+        let mut out = unsafe { stream.alloc::<T>(inp_size).unwrap() };
+        let mut launch_args = stream.launch_builder(&self.function);
+        launch_args.arg(&mut out);
+        launch_args.arg(cond);
+        launch_args.arg(a);
+        launch_args.arg(b);
+        launch_args.arg(&inp_size);
+        input_dyn_dims(&mut launch_args, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            launch_args
+                .launch(LaunchConfig::for_num_elems(inp_size as u32))
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise" {
+            return Some(Box::new("(input0 != 0.0 ? input1 : input2)".to_string()));
+        }
+        None
+    }
+}
+
 #[derive(Clone)]
 pub struct CudaSumReduce<T> {
     function: CudaFunction,
@@ -824,6 +903,14 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
                     dev.clone(),
                     &graph.dyn_map,
                 ));
+            } else if is::<Select>(op) {
+                *op_ref = Box::new(CudaSelect::<T>::new(
+                    shapes[0],
+                    shapes[1],
+                    shapes[2],
+                    dev.clone(),
+                    &graph.dyn_map,
+                ));
             } else if is::<Contiguous>(op) {
                 *op_ref = Box::new(CudaContiguous::<T>::new(
                     shapes[0],
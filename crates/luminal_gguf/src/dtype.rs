@@ -0,0 +1,416 @@
+use luminal::prelude::{bf16, f16};
+
+use crate::GgufError;
+
+/// Number of elements in a K-quant super-block
+pub const QK_K: usize = 256;
+
+/// The type of a tensor stored in a GGUF file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GgmlDType {
+    F32,
+    F16,
+    BF16,
+    F64,
+    I8,
+    I16,
+    I32,
+    I64,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q8_1,
+    Q2K,
+    Q3K,
+    Q4K,
+    Q5K,
+    Q6K,
+    Q8K,
+}
+
+impl GgmlDType {
+    pub fn from_u32(u: u32) -> Result<Self, GgufError> {
+        Ok(match u {
+            0 => Self::F32,
+            1 => Self::F16,
+            2 => Self::Q4_0,
+            3 => Self::Q4_1,
+            6 => Self::Q5_0,
+            7 => Self::Q5_1,
+            8 => Self::Q8_0,
+            9 => Self::Q8_1,
+            10 => Self::Q2K,
+            11 => Self::Q3K,
+            12 => Self::Q4K,
+            13 => Self::Q5K,
+            14 => Self::Q6K,
+            15 => Self::Q8K,
+            24 => Self::I8,
+            25 => Self::I16,
+            26 => Self::I32,
+            27 => Self::I64,
+            28 => Self::F64,
+            30 => Self::BF16,
+            u => return Err(GgufError::TensorType(u)),
+        })
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Self::F32 => 0,
+            Self::F16 => 1,
+            Self::Q4_0 => 2,
+            Self::Q4_1 => 3,
+            Self::Q5_0 => 6,
+            Self::Q5_1 => 7,
+            Self::Q8_0 => 8,
+            Self::Q8_1 => 9,
+            Self::Q2K => 10,
+            Self::Q3K => 11,
+            Self::Q4K => 12,
+            Self::Q5K => 13,
+            Self::Q6K => 14,
+            Self::Q8K => 15,
+            Self::I8 => 24,
+            Self::I16 => 25,
+            Self::I32 => 26,
+            Self::I64 => 27,
+            Self::F64 => 28,
+            Self::BF16 => 30,
+        }
+    }
+
+    /// Number of elements in a block
+    pub fn block_size(&self) -> usize {
+        match self {
+            Self::F32
+            | Self::F16
+            | Self::BF16
+            | Self::F64
+            | Self::I8
+            | Self::I16
+            | Self::I32
+            | Self::I64 => 1,
+            Self::Q4_0 | Self::Q4_1 | Self::Q5_0 | Self::Q5_1 | Self::Q8_0 | Self::Q8_1 => 32,
+            Self::Q2K | Self::Q3K | Self::Q4K | Self::Q5K | Self::Q6K | Self::Q8K => QK_K,
+        }
+    }
+
+    /// Size of a block in bytes
+    pub fn type_size(&self) -> usize {
+        match self {
+            Self::I8 => 1,
+            Self::F16 | Self::BF16 | Self::I16 => 2,
+            Self::F32 | Self::I32 => 4,
+            Self::F64 | Self::I64 => 8,
+            Self::Q4_0 => 2 + 16,
+            Self::Q4_1 => 2 + 2 + 16,
+            Self::Q5_0 => 2 + 4 + 16,
+            Self::Q5_1 => 2 + 2 + 4 + 16,
+            Self::Q8_0 => 2 + 32,
+            Self::Q8_1 => 2 + 2 + 32,
+            Self::Q2K => QK_K / 16 + QK_K / 4 + 2 + 2,
+            Self::Q3K => QK_K / 8 + QK_K / 4 + 12 + 2,
+            Self::Q4K => 2 + 2 + 12 + QK_K / 2,
+            Self::Q5K => 2 + 2 + 12 + QK_K / 8 + QK_K / 2,
+            Self::Q6K => QK_K / 2 + QK_K / 4 + QK_K / 16 + 2,
+            Self::Q8K => 4 + QK_K + QK_K / 16 * 2,
+        }
+    }
+
+    /// Number of bytes taken up by `n_elements` elements
+    pub fn n_bytes(&self, n_elements: usize) -> usize {
+        n_elements / self.block_size() * self.type_size()
+    }
+
+    /// Convert raw tensor data of this type into f32s
+    pub fn dequantize(&self, bytes: &[u8]) -> Result<Vec<f32>, GgufError> {
+        if bytes.len() % self.type_size() != 0 {
+            return Err(GgufError::BlockSize {
+                dtype: *self,
+                n_bytes: bytes.len(),
+            });
+        }
+        Ok(self.dequantize_blocks(bytes))
+    }
+
+    /// Dequantize whole blocks, ignoring any trailing partial block
+    pub(crate) fn dequantize_blocks(&self, bytes: &[u8]) -> Vec<f32> {
+        let mut out = Vec::with_capacity(bytes.len() / self.type_size() * self.block_size());
+        for block in bytes.chunks_exact(self.type_size()) {
+            match self {
+                Self::F32 => out.push(f32::from_le_bytes(block.try_into().unwrap())),
+                Self::F16 => out.push(f16::from_le_bytes([block[0], block[1]]).to_f32()),
+                Self::BF16 => out.push(bf16::from_le_bytes([block[0], block[1]]).to_f32()),
+                Self::F64 => out.push(f64::from_le_bytes(block.try_into().unwrap()) as f32),
+                Self::I8 => out.push(block[0] as i8 as f32),
+                Self::I16 => out.push(i16::from_le_bytes([block[0], block[1]]) as f32),
+                Self::I32 => out.push(i32::from_le_bytes(block.try_into().unwrap()) as f32),
+                Self::I64 => out.push(i64::from_le_bytes(block.try_into().unwrap()) as f32),
+                Self::Q4_0 => dequantize_q4(block, false, &mut out),
+                Self::Q4_1 => dequantize_q4(block, true, &mut out),
+                Self::Q5_0 => dequantize_q5(block, false, &mut out),
+                Self::Q5_1 => dequantize_q5(block, true, &mut out),
+                Self::Q8_0 => {
+                    let d = half(block, 0);
+                    out.extend(block[2..].iter().map(|q| *q as i8 as f32 * d));
+                }
+                Self::Q8_1 => {
+                    let d = half(block, 0);
+                    out.extend(block[4..].iter().map(|q| *q as i8 as f32 * d));
+                }
+                Self::Q2K => dequantize_q2k(block, &mut out),
+                Self::Q3K => dequantize_q3k(block, &mut out),
+                Self::Q4K => dequantize_q4k(block, &mut out),
+                Self::Q5K => dequantize_q5k(block, &mut out),
+                Self::Q6K => dequantize_q6k(block, &mut out),
+                Self::Q8K => {
+                    let d = f32::from_le_bytes(block[..4].try_into().unwrap());
+                    out.extend(block[4..4 + QK_K].iter().map(|q| *q as i8 as f32 * d));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Read an f16 at a byte offset
+fn half(block: &[u8], offset: usize) -> f32 {
+    f16::from_le_bytes([block[offset], block[offset + 1]]).to_f32()
+}
+
+// The block layouts and dequantization below follow ggml's reference implementations
+
+fn dequantize_q4(block: &[u8], has_min: bool, out: &mut Vec<f32>) {
+    let (d, m, qs) = if has_min {
+        (half(block, 0), half(block, 2), &block[4..])
+    } else {
+        (half(block, 0), 0., &block[2..])
+    };
+    let offset = if has_min { 0 } else { 8 };
+    out.extend(
+        qs.iter()
+            .map(|q| ((q & 0xF) as i32 - offset) as f32 * d + m),
+    );
+    out.extend(qs.iter().map(|q| ((q >> 4) as i32 - offset) as f32 * d + m));
+}
+
+fn dequantize_q5(block: &[u8], has_min: bool, out: &mut Vec<f32>) {
+    let (d, m, rest) = if has_min {
+        (half(block, 0), half(block, 2), &block[4..])
+    } else {
+        (half(block, 0), 0., &block[2..])
+    };
+    let qh = u32::from_le_bytes(rest[..4].try_into().unwrap());
+    let qs = &rest[4..];
+    let offset = if has_min { 0 } else { 16 };
+    out.extend(qs.iter().enumerate().map(|(j, q)| {
+        let h = ((qh >> j) << 4) & 0x10;
+        (((q & 0xF) as u32 | h) as i32 - offset) as f32 * d + m
+    }));
+    out.extend(qs.iter().enumerate().map(|(j, q)| {
+        let h = (qh >> (j + 12)) & 0x10;
+        (((q >> 4) as u32 | h) as i32 - offset) as f32 * d + m
+    }));
+}
+
+fn dequantize_q2k(block: &[u8], out: &mut Vec<f32>) {
+    let (scales, qs) = (&block[..QK_K / 16], &block[QK_K / 16..QK_K / 16 + QK_K / 4]);
+    let (d, min) = (
+        half(block, QK_K / 16 + QK_K / 4),
+        half(block, QK_K / 16 + QK_K / 4 + 2),
+    );
+    let mut is = 0;
+    for q in qs.chunks_exact(32) {
+        for shift in (0..8).step_by(2) {
+            for half_q in [&q[..16], &q[16..]] {
+                let sc = scales[is];
+                is += 1;
+                let (dl, ml) = (d * (sc & 0xF) as f32, min * (sc >> 4) as f32);
+                out.extend(half_q.iter().map(|q| dl * ((q >> shift) & 3) as f32 - ml));
+            }
+        }
+    }
+}
+
+fn dequantize_q3k(block: &[u8], out: &mut Vec<f32>) {
+    const KMASK1: u32 = 0x03030303;
+    const KMASK2: u32 = 0x0f0f0f0f;
+    let hmask = &block[..QK_K / 8];
+    let qs = &block[QK_K / 8..QK_K / 8 + QK_K / 4];
+    let raw_scales = &block[QK_K / 8 + QK_K / 4..QK_K / 8 + QK_K / 4 + 12];
+    let d_all = half(block, QK_K / 8 + QK_K / 4 + 12);
+
+    // Unpack the 16 6-bit scales
+    let mut aux = [0_u32; 4];
+    for (i, a) in aux.iter_mut().take(3).enumerate() {
+        *a = u32::from_le_bytes(raw_scales[i * 4..(i + 1) * 4].try_into().unwrap());
+    }
+    let tmp = aux[2];
+    aux[2] = ((aux[0] >> 4) & KMASK2) | (((tmp >> 4) & KMASK1) << 4);
+    aux[3] = ((aux[1] >> 4) & KMASK2) | (((tmp >> 6) & KMASK1) << 4);
+    aux[0] = (aux[0] & KMASK2) | ((tmp & KMASK1) << 4);
+    aux[1] = (aux[1] & KMASK2) | (((tmp >> 2) & KMASK1) << 4);
+    let scales = aux
+        .iter()
+        .flat_map(|a| a.to_le_bytes())
+        .map(|s| s as i8 as i32 - 32)
+        .collect::<Vec<_>>();
+
+    let (mut is, mut m) = (0, 1_u8);
+    for q in qs.chunks_exact(32) {
+        for shift in (0..8).step_by(2) {
+            for offset in [0, 16] {
+                let dl = d_all * scales[is] as f32;
+                is += 1;
+                out.extend((offset..offset + 16).map(|l| {
+                    let high = if hmask[l] & m != 0 { 0 } else { 4 };
+                    dl * (((q[l] >> shift) & 3) as i32 - high) as f32
+                }));
+            }
+            m <<= 1;
+        }
+    }
+}
+
+/// Get the 6-bit scale and min of sub-block `j` in Q4_K / Q5_K
+fn scale_min_k4(j: usize, q: &[u8]) -> (f32, f32) {
+    let (d, m) = if j < 4 {
+        (q[j] & 63, q[j + 4] & 63)
+    } else {
+        (
+            (q[j + 4] & 0xF) | ((q[j - 4] >> 6) << 4),
+            (q[j + 4] >> 4) | ((q[j] >> 6) << 4),
+        )
+    };
+    (d as f32, m as f32)
+}
+
+fn dequantize_q4k(block: &[u8], out: &mut Vec<f32>) {
+    let (d, min) = (half(block, 0), half(block, 2));
+    let (scales, qs) = (&block[4..16], &block[16..]);
+    for (i, q) in qs.chunks_exact(32).enumerate() {
+        let (sc1, m1) = scale_min_k4(i * 2, scales);
+        let (sc2, m2) = scale_min_k4(i * 2 + 1, scales);
+        out.extend(q.iter().map(|q| d * sc1 * (q & 0xF) as f32 - min * m1));
+        out.extend(q.iter().map(|q| d * sc2 * (q >> 4) as f32 - min * m2));
+    }
+}
+
+fn dequantize_q5k(block: &[u8], out: &mut Vec<f32>) {
+    let (d, min) = (half(block, 0), half(block, 2));
+    let (scales, qh, qs) = (
+        &block[4..16],
+        &block[16..16 + QK_K / 8],
+        &block[16 + QK_K / 8..],
+    );
+    for (i, ql) in qs.chunks_exact(32).enumerate() {
+        let (sc1, m1) = scale_min_k4(i * 2, scales);
+        let (sc2, m2) = scale_min_k4(i * 2 + 1, scales);
+        let (u1, u2) = (1 << (i * 2), 2 << (i * 2));
+        out.extend(ql.iter().zip(qh).map(|(q, h)| {
+            let high = if h & u1 != 0 { 16 } else { 0 };
+            d * sc1 * ((q & 0xF) + high) as f32 - min * m1
+        }));
+        out.extend(ql.iter().zip(qh).map(|(q, h)| {
+            let high = if h & u2 != 0 { 16 } else { 0 };
+            d * sc2 * ((q >> 4) + high) as f32 - min * m2
+        }));
+    }
+}
+
+fn dequantize_q6k(block: &[u8], out: &mut Vec<f32>) {
+    let (ql, qh) = (&block[..QK_K / 2], &block[QK_K / 2..QK_K / 2 + QK_K / 4]);
+    let scales = &block[QK_K / 2 + QK_K / 4..QK_K / 2 + QK_K / 4 + QK_K / 16];
+    let d = half(block, QK_K / 2 + QK_K / 4 + QK_K / 16);
+    for n in 0..QK_K / 128 {
+        let (ql, qh, sc) = (&ql[n * 64..], &qh[n * 32..], &scales[n * 8..]);
+        let mut y = [0.; 128];
+        for l in 0..32 {
+            let is = l / 16;
+            let q1 = ((ql[l] & 0xF) | ((qh[l] & 3) << 4)) as i32 - 32;
+            let q2 = ((ql[l + 32] & 0xF) | (((qh[l] >> 2) & 3) << 4)) as i32 - 32;
+            let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i32 - 32;
+            let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i32 - 32;
+            y[l] = d * (sc[is] as i8) as f32 * q1 as f32;
+            y[l + 32] = d * (sc[is + 2] as i8) as f32 * q2 as f32;
+            y[l + 64] = d * (sc[is + 4] as i8) as f32 * q3 as f32;
+            y[l + 96] = d * (sc[is + 6] as i8) as f32 * q4 as f32;
+        }
+        out.extend(y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::f16;
+
+    use super::{GgmlDType, QK_K};
+
+    #[test]
+    fn test_block_sizes() {
+        // Sizes from ggml's block structs
+        for (dtype, size) in [
+            (GgmlDType::Q4_0, 18),
+            (GgmlDType::Q4_1, 20),
+            (GgmlDType::Q5_0, 22),
+            (GgmlDType::Q5_1, 24),
+            (GgmlDType::Q8_0, 34),
+            (GgmlDType::Q8_1, 36),
+            (GgmlDType::Q2K, 84),
+            (GgmlDType::Q3K, 110),
+            (GgmlDType::Q4K, 144),
+            (GgmlDType::Q5K, 176),
+            (GgmlDType::Q6K, 210),
+            (GgmlDType::Q8K, 292),
+        ] {
+            assert_eq!(dtype.type_size(), size, "{dtype:?}");
+            assert_eq!(GgmlDType::from_u32(dtype.to_u32()).unwrap(), dtype);
+            let zeros = vec![0; size * 2];
+            assert_eq!(
+                dtype.dequantize(&zeros).unwrap().len(),
+                dtype.block_size() * 2
+            );
+        }
+        assert!(GgmlDType::Q4_0.dequantize(&[0; 19]).is_err());
+        assert!(GgmlDType::from_u32(4).is_err());
+    }
+
+    #[test]
+    fn test_dequantize() {
+        let one = f16::from_f32(1.).to_le_bytes();
+        let quants = (0..16_u8).map(|i| i | ((15 - i) << 4)).collect::<Vec<_>>();
+        let nibbles = |offset: f32| {
+            (0..16)
+                .chain((0..16).rev())
+                .map(|i| i as f32 - offset)
+                .collect::<Vec<_>>()
+        };
+
+        // Q4_0: nibbles offset by 8
+        let q4_0 = [&one[..], &quants].concat();
+        assert_eq!(GgmlDType::Q4_0.dequantize(&q4_0).unwrap(), nibbles(8.));
+
+        // Q4_1: scaled nibbles plus a min
+        let two = f16::from_f32(2.).to_le_bytes();
+        let q4_1 = [&one[..], &two, &quants].concat();
+        assert_eq!(GgmlDType::Q4_1.dequantize(&q4_1).unwrap(), nibbles(-2.));
+
+        // Q5_0: the high bit is set for every element
+        let q5_0 = [&one[..], &[0xFF; 4], &quants].concat();
+        assert_eq!(GgmlDType::Q5_0.dequantize(&q5_0).unwrap(), nibbles(0.));
+
+        // Q4_K: unit scales without mins just return the nibbles
+        let k_scales = [1, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1];
+        let k_quants = (0..QK_K / 2).map(|i| quants[i % 16]).collect::<Vec<_>>();
+        let q4k = [&one[..], &[0, 0], &k_scales, &k_quants].concat();
+        let (low, high) = (nibbles(0.)[..16].to_vec(), nibbles(0.)[16..].to_vec());
+        let expected = (0..QK_K / 64).flat_map(|_| [&low[..], &low, &high, &high].concat());
+        assert_eq!(
+            GgmlDType::Q4K.dequantize(&q4k).unwrap(),
+            expected.collect::<Vec<_>>()
+        );
+    }
+}
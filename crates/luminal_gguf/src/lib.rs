@@ -0,0 +1,110 @@
+//! Support for the GGUF file format.
+//!
+//! Spec: https://github.com/ggerganov/ggml/blob/master/docs/gguf.md
+
+mod dtype;
+mod load;
+mod read;
+mod write;
+
+pub use dtype::*;
+pub use load::*;
+pub use read::*;
+pub use write::*;
+
+use std::fmt::Display;
+
+pub const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// An error encountered when reading or writing a GGUF file
+#[derive(Debug)]
+pub enum GgufError {
+    /// Reading or writing the file failed
+    Io(std::io::Error),
+    /// The file doesn't start with the GGUF magic number
+    Magic(u32),
+    /// The file is big endian, which isn't supported
+    BigEndian,
+    /// The file has an unsupported GGUF version
+    Version(u32),
+    /// A metadata value has an unknown type
+    ValueType(u32),
+    /// A tensor has an unknown ggml type
+    TensorType(u32),
+    /// A bool value isn't 0 or 1
+    InvalidBool(u8),
+    /// A metadata key isn't present
+    MissingKey(String),
+    /// A metadata value isn't the requested type
+    WrongType { key: String, expected: &'static str },
+    /// A tensor isn't present
+    MissingTensor(String),
+    /// The tensor data isn't a whole number of blocks of its type
+    BlockSize { dtype: GgmlDType, n_bytes: usize },
+    /// A tensor's rows aren't a whole number of blocks of its type
+    PartialBlock {
+        name: String,
+        dtype: GgmlDType,
+        row_len: usize,
+    },
+    /// A tensor's data runs past the end of the file
+    TruncatedTensor(String),
+    /// A tensor in the file has a different shape than its weight
+    Shape {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+}
+
+impl Display for GgufError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GgufError::Io(e) => write!(f, "IO error: {e}"),
+            GgufError::Magic(m) => write!(f, "Unknown magic 0x{m:08x}, not a GGUF file"),
+            GgufError::BigEndian => write!(f, "Big endian GGUF files aren't supported"),
+            GgufError::Version(v) => write!(f, "Unsupported GGUF version {v}"),
+            GgufError::ValueType(t) => write!(f, "Unknown metadata value type {t}"),
+            GgufError::TensorType(t) => write!(f, "Unknown ggml tensor type {t}"),
+            GgufError::InvalidBool(b) => write!(f, "Invalid bool value {b}"),
+            GgufError::MissingKey(k) => write!(f, "Metadata key {k} not found"),
+            GgufError::WrongType { key, expected } => {
+                write!(f, "Metadata value {key} isn't a {expected}")
+            }
+            GgufError::MissingTensor(t) => write!(f, "Tensor {t} not found"),
+            GgufError::BlockSize { dtype, n_bytes } => write!(
+                f,
+                "{n_bytes} bytes isn't a whole number of {dtype:?} blocks ({} bytes each)",
+                dtype.type_size()
+            ),
+            GgufError::PartialBlock {
+                name,
+                dtype,
+                row_len,
+            } => write!(
+                f,
+                "Tensor {name} has rows of {row_len} elements, which isn't a whole number of {dtype:?} blocks ({} elements each)",
+                dtype.block_size()
+            ),
+            GgufError::TruncatedTensor(t) => {
+                write!(f, "Tensor {t} runs past the end of the file")
+            }
+            GgufError::Shape {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Tensor {name} has shape {found:?}, but the model expects {expected:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GgufError {}
+
+impl From<std::io::Error> for GgufError {
+    fn from(value: std::io::Error) -> Self {
+        GgufError::Io(value)
+    }
+}
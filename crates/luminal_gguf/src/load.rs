@@ -0,0 +1,187 @@
+use std::{fs::File, io::Cursor, path::Path, sync::Arc};
+
+use luminal::{op::Function, prelude::*};
+use memmap2::Mmap;
+
+use crate::{Content, GgmlDType, GgufError};
+
+/// Set the weights of a model to be loaded from a GGUF file when the graph is ran. Weights are matched to tensors by their `param_dict` name with `/` replaced by `.`, and dequantized to f32.
+pub fn load_into<P: AsRef<Path>>(
+    path: P,
+    model: &impl SerializeModule,
+    graph: &mut Graph,
+) -> Result<(), GgufError> {
+    load_into_quantized(path, model, graph, &[]).map(|_| ())
+}
+
+/// Like `load_into`, but weights stored as one of the `keep_quantized` types are loaded as their raw blocks (`Vec<u8>`) for quantized kernels to use. Returns these weights.
+///
+/// Every weight must be in the file with the same shape. Nothing is changed in the graph if any weight can't be loaded.
+pub fn load_into_quantized<P: AsRef<Path>>(
+    path: P,
+    model: &impl SerializeModule,
+    graph: &mut Graph,
+    keep_quantized: &[GgmlDType],
+) -> Result<Vec<NodeIndex>, GgufError> {
+    let file = File::open(&path)?;
+    // Safety: the file is only read, and must not be modified while the mapping is alive
+    let mmap = Arc::new(unsafe { Mmap::map(&file)? });
+    let content = Content::read(&mut Cursor::new(&mmap[..]))?;
+
+    let shapes = param_shapes(model);
+    let mut weights = param_dict(model).into_iter().collect::<Vec<_>>();
+    weights.sort();
+    let mut loads = vec![];
+    for (weight_name, node_index) in weights {
+        if graph.try_get_op::<Function>(node_index).is_none() {
+            continue;
+        }
+        let tensor_name = weight_name.replace('/', ".");
+        let info = content.tensor_info(&tensor_name)?;
+        let mut shape = shapes[&weight_name];
+        shape.resolve_global_dyn_dims(&graph.dyn_map);
+        let expected = shape.shape_usize();
+        if expected != info.shape() {
+            return Err(GgufError::Shape {
+                name: tensor_name,
+                expected,
+                found: info.shape(),
+            });
+        }
+        let row_len = info.dims.first().copied().unwrap_or(1);
+        if row_len % info.dtype.block_size() != 0 {
+            return Err(GgufError::PartialBlock {
+                name: tensor_name,
+                dtype: info.dtype,
+                row_len,
+            });
+        }
+        let start = content.tensor_data_offset as usize + info.offset;
+        let end = start + info.n_bytes();
+        if end > mmap.len() {
+            return Err(GgufError::TruncatedTensor(tensor_name));
+        }
+        loads.push((node_index, info.dtype, start..end));
+    }
+
+    let mut quantized = vec![];
+    for (node_index, dtype, range) in loads {
+        let keep = keep_quantized.contains(&dtype);
+        if keep {
+            quantized.push(node_index);
+        }
+        let mmap = mmap.clone();
+        graph.set_loader(node_index, move |_| {
+            let bytes = &mmap[range.clone()];
+            if keep {
+                vec![Tensor::new(bytes.to_vec())]
+            } else {
+                vec![Tensor::new(dtype.dequantize_blocks(bytes))]
+            }
+        });
+    }
+    Ok(quantized)
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{prelude::*, tests::assert_exact};
+    use luminal_nn::Linear;
+
+    use crate::{
+        load_into, load_into_quantized, write, Content, GgmlDType, GgufError, WriteTensor,
+    };
+
+    #[test]
+    fn test_load_into() {
+        let mut cx = Graph::new();
+        let model = (
+            Linear::new_permuted(32, 2, false, &mut cx),
+            Linear::new(2, 3, true, &mut cx),
+        );
+        let inp = cx.tensor(32).set(vec![1.; 32]);
+        let out = model.forward(inp).retrieve();
+
+        // First layer is Q8_0 with weights of 0.5 and 1.5, second layer is f32
+        let q8_rows =
+            [0.5_f32, 1.5].map(|d| [&f16::from_f32(d).to_le_bytes()[..], &[1; 32]].concat());
+        let path = std::env::temp_dir().join(format!("luminal_gguf_{}.gguf", std::process::id()));
+        write(
+            &mut std::fs::File::create(&path).unwrap(),
+            &[],
+            &[
+                WriteTensor::new("0.weight", vec![2, 32], GgmlDType::Q8_0, q8_rows.concat()),
+                WriteTensor::from_f32("1.weight", vec![2, 3], &[1., 0., 2., 0., 1., 1.]),
+                WriteTensor::from_f32("1.bias", vec![3], &[0., 0., 1.]),
+            ],
+        )
+        .unwrap();
+
+        let quantized = load_into_quantized(&path, &model, &mut cx, &[GgmlDType::Q8_0]).unwrap();
+        assert_eq!(quantized, vec![model.0.weight.id]);
+        load_into(&path, &model, &mut cx).unwrap();
+        cx.execute();
+        std::fs::remove_file(&path).unwrap();
+
+        // Hidden = [16, 48]
+        assert_exact(&out.data(), &[16., 48., 81.]);
+    }
+
+    #[test]
+    fn test_load_into_errors() {
+        let mut cx = Graph::new();
+        let model = (
+            Linear::new(2, 3, false, &mut cx),
+            Linear::new(3, 1, true, &mut cx),
+        );
+        model.0.weight.set(vec![1., 0., 2., 0., 1., 1.]);
+        model.1.weight.set(vec![1., 1., 1.]);
+        model.1.bias.unwrap().set(vec![1.]);
+        let inp = cx.tensor(2).set(vec![2., 3.]);
+        let out = model.forward(inp).retrieve();
+
+        let path =
+            std::env::temp_dir().join(format!("luminal_gguf_errors_{}.gguf", std::process::id()));
+        let try_load = |tensors: &[WriteTensor], cx: &mut Graph| {
+            write(&mut std::fs::File::create(&path).unwrap(), &[], tensors).unwrap();
+            load_into(&path, &model, cx).unwrap_err()
+        };
+        let weight_0 = WriteTensor::from_f32("0.weight", vec![2, 3], &[0.; 6]);
+        let weight_1 = WriteTensor::from_f32("1.weight", vec![3, 1], &[0.; 3]);
+        let bias_1 = WriteTensor::from_f32("1.bias", vec![1], &[0.]);
+
+        let err = try_load(&[weight_0.clone(), weight_1.clone()], &mut cx);
+        assert!(matches!(err, GgufError::MissingTensor(t) if t == "1.bias"));
+        let err = try_load(
+            &[
+                weight_0.clone(),
+                WriteTensor::from_f32("1.weight", vec![1, 3], &[0.; 3]),
+                bias_1.clone(),
+            ],
+            &mut cx,
+        );
+        assert!(matches!(
+            err,
+            GgufError::Shape { name, expected, found }
+                if name == "1.weight" && expected == [3, 1] && found == [1, 3]
+        ));
+        // Cut off the end of the last tensor's data
+        write(
+            &mut std::fs::File::create(&path).unwrap(),
+            &[],
+            &[weight_0, weight_1, bias_1],
+        )
+        .unwrap();
+        let content = Content::read(&mut std::fs::File::open(&path).unwrap()).unwrap();
+        let bias_start = content.tensor_data_offset + content.tensor_infos["1.bias"].offset as u64;
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(bias_start + 2).unwrap();
+        let err = load_into(&path, &model, &mut cx).unwrap_err();
+        assert!(matches!(err, GgufError::TruncatedTensor(t) if t == "1.bias"));
+        std::fs::remove_file(&path).unwrap();
+
+        // None of the failed loads replaced the weights set before
+        cx.execute();
+        assert_exact(&out.data(), &[13.]);
+    }
+}
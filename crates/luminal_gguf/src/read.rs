@@ -0,0 +1,423 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+};
+
+use crate::{GgmlDType, GgufError, DEFAULT_ALIGNMENT};
+
+/// Little-endian "GGUF"
+pub const GGUF_MAGIC: u32 = 0x46554747;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionedMagic {
+    GgufV1,
+    GgufV2,
+    GgufV3,
+}
+
+impl VersionedMagic {
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, GgufError> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        // Big endian files have the magic reversed. Everything after is read as little endian, so reject them
+        if magic == GGUF_MAGIC.swap_bytes() {
+            return Err(GgufError::BigEndian);
+        }
+        if magic != GGUF_MAGIC {
+            return Err(GgufError::Magic(magic));
+        }
+        match reader.read_u32::<LittleEndian>()? {
+            1 => Ok(Self::GgufV1),
+            2 => Ok(Self::GgufV2),
+            3 => Ok(Self::GgufV3),
+            v => Err(GgufError::Version(v)),
+        }
+    }
+
+    /// Read a length or count, which is 32 bit in v1 and 64 bit after
+    fn read_len<R: Read>(&self, reader: &mut R) -> Result<usize, GgufError> {
+        Ok(match self {
+            VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
+            VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
+                reader.read_u64::<LittleEndian>()? as usize
+            }
+        })
+    }
+}
+
+/// Read exactly `len` bytes. The buffer only grows as data is read, so a corrupt length can't allocate more than the file holds
+fn read_vec<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>, GgufError> {
+    let mut v = vec![];
+    reader.take(len as u64).read_to_end(&mut v)?;
+    if v.len() != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(v)
+}
+
+pub fn read_string<R: Read>(reader: &mut R, magic: &VersionedMagic) -> Result<String, GgufError> {
+    let len = magic.read_len(reader)?;
+    let mut v = read_vec(reader, len)?;
+    // GGUF strings are supposed to be non-null terminated but in practice this happens.
+    while let Some(0) = v.last() {
+        v.pop();
+    }
+    // GGUF strings are utf8 encoded but there are cases that don't seem to be valid.
+    Ok(String::from_utf8_lossy(&v).into_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    // The value is a 8-bit unsigned integer.
+    U8,
+    // The value is a 8-bit signed integer.
+    I8,
+    // The value is a 16-bit unsigned little-endian integer.
+    U16,
+    // The value is a 16-bit signed little-endian integer.
+    I16,
+    // The value is a 32-bit unsigned little-endian integer.
+    U32,
+    // The value is a 32-bit signed little-endian integer.
+    I32,
+    // The value is a 64-bit unsigned little-endian integer.
+    U64,
+    // The value is a 64-bit signed little-endian integer.
+    I64,
+    // The value is a 32-bit IEEE754 floating point number.
+    F32,
+    // The value is a 64-bit IEEE754 floating point number.
+    F64,
+    // The value is a boolean.
+    // 1-byte value where 0 is false and 1 is true.
+    // Anything else is invalid, and should be treated as either the model being invalid or the reader being buggy.
+    Bool,
+    // The value is a UTF-8 non-null-terminated string, with length prepended.
+    String,
+    // The value is an array of other values, with the length and type prepended.
+    //
+    // Arrays can be nested, and the length of the array is the number of elements in the array, not the number of bytes.
+    Array,
+}
+
+impl ValueType {
+    pub fn from_u32(v: u32) -> Result<Self, GgufError> {
+        Ok(match v {
+            0 => Self::U8,
+            1 => Self::I8,
+            2 => Self::U16,
+            3 => Self::I16,
+            4 => Self::U32,
+            5 => Self::I32,
+            6 => Self::F32,
+            7 => Self::Bool,
+            8 => Self::String,
+            9 => Self::Array,
+            10 => Self::U64,
+            11 => Self::I64,
+            12 => Self::F64,
+            v => return Err(GgufError::ValueType(v)),
+        })
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Self::U8 => 0,
+            Self::I8 => 1,
+            Self::U16 => 2,
+            Self::I16 => 3,
+            Self::U32 => 4,
+            Self::I32 => 5,
+            Self::F32 => 6,
+            Self::Bool => 7,
+            Self::String => 8,
+            Self::Array => 9,
+            Self::U64 => 10,
+            Self::I64 => 11,
+            Self::F64 => 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn read<R: Read>(
+        reader: &mut R,
+        value_type: ValueType,
+        magic: &VersionedMagic,
+    ) -> Result<Self, GgufError> {
+        Ok(match value_type {
+            ValueType::U8 => Self::U8(reader.read_u8()?),
+            ValueType::I8 => Self::I8(reader.read_i8()?),
+            ValueType::U16 => Self::U16(reader.read_u16::<LittleEndian>()?),
+            ValueType::I16 => Self::I16(reader.read_i16::<LittleEndian>()?),
+            ValueType::U32 => Self::U32(reader.read_u32::<LittleEndian>()?),
+            ValueType::I32 => Self::I32(reader.read_i32::<LittleEndian>()?),
+            ValueType::U64 => Self::U64(reader.read_u64::<LittleEndian>()?),
+            ValueType::I64 => Self::I64(reader.read_i64::<LittleEndian>()?),
+            ValueType::F32 => Self::F32(reader.read_f32::<LittleEndian>()?),
+            ValueType::F64 => Self::F64(reader.read_f64::<LittleEndian>()?),
+            ValueType::Bool => match reader.read_u8()? {
+                0 => Self::Bool(false),
+                1 => Self::Bool(true),
+                b => return Err(GgufError::InvalidBool(b)),
+            },
+            ValueType::String => Self::String(read_string(reader, magic)?),
+            ValueType::Array => {
+                let value_type = ValueType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+                let len = magic.read_len(reader)?;
+                // Every element takes at least a byte, so growing as they're read keeps a corrupt length from allocating too much
+                let mut vs = vec![];
+                for _ in 0..len {
+                    vs.push(Value::read(reader, value_type, magic)?)
+                }
+                Self::Array(vs)
+            }
+        })
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::U8(_) => ValueType::U8,
+            Value::I8(_) => ValueType::I8,
+            Value::U16(_) => ValueType::U16,
+            Value::I16(_) => ValueType::I16,
+            Value::U32(_) => ValueType::U32,
+            Value::I32(_) => ValueType::I32,
+            Value::U64(_) => ValueType::U64,
+            Value::I64(_) => ValueType::I64,
+            Value::F32(_) => ValueType::F32,
+            Value::F64(_) => ValueType::F64,
+            Value::Bool(_) => ValueType::Bool,
+            Value::String(_) => ValueType::String,
+            Value::Array(_) => ValueType::Array,
+        }
+    }
+
+    /// Get any integer value as an i64
+    pub fn as_i64(&self) -> Option<i64> {
+        Some(match self {
+            Value::U8(v) => *v as i64,
+            Value::I8(v) => *v as i64,
+            Value::U16(v) => *v as i64,
+            Value::I16(v) => *v as i64,
+            Value::U32(v) => *v as i64,
+            Value::I32(v) => *v as i64,
+            Value::U64(v) => i64::try_from(*v).ok()?,
+            Value::I64(v) => *v,
+            _ => return None,
+        })
+    }
+
+    /// Get any float value as an f64
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::F32(v) => Some(*v as f64),
+            Value::F64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// Where a tensor is in the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorInfo {
+    /// Dimensions in ggml order (innermost first)
+    pub dims: Vec<usize>,
+    pub dtype: GgmlDType,
+    /// Offset from the start of the tensor data section
+    pub offset: usize,
+}
+
+impl TensorInfo {
+    pub fn n_elements(&self) -> usize {
+        self.dims.iter().product()
+    }
+
+    pub fn n_bytes(&self) -> usize {
+        self.dtype.n_bytes(self.n_elements())
+    }
+
+    /// Dimensions in row-major order (outermost first), as used by luminal
+    pub fn shape(&self) -> Vec<usize> {
+        self.dims.iter().rev().copied().collect()
+    }
+
+    /// Read the raw bytes of this tensor, given the start of the file's tensor data section
+    pub fn read_bytes<R: Seek + Read>(
+        &self,
+        reader: &mut R,
+        tensor_data_offset: u64,
+    ) -> Result<Vec<u8>, GgufError> {
+        reader.seek(SeekFrom::Start(tensor_data_offset + self.offset as u64))?;
+        read_vec(reader, self.n_bytes())
+    }
+}
+
+#[derive(Debug)]
+pub struct Content {
+    pub magic: VersionedMagic,
+    pub metadata: HashMap<String, Value>,
+    pub tensor_infos: HashMap<String, TensorInfo>,
+    pub tensor_data_offset: u64,
+}
+
+impl Content {
+    pub fn read<R: Seek + Read>(reader: &mut R) -> Result<Self, GgufError> {
+        let magic = VersionedMagic::read(reader)?;
+        let tensor_count = magic.read_len(reader)?;
+        let metadata_kv_count = magic.read_len(reader)?;
+
+        // Read metadata
+        let mut metadata = HashMap::new();
+        for _ in 0..metadata_kv_count {
+            let key = read_string(reader, &magic)?;
+            let value_type = ValueType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+            let value = Value::read(reader, value_type, &magic)?;
+            metadata.insert(key, value);
+        }
+        // Read tensor infos
+        let mut tensor_infos = HashMap::new();
+        for _ in 0..tensor_count {
+            let tensor_name = read_string(reader, &magic)?;
+            let n_dimensions = reader.read_u32::<LittleEndian>()?;
+            let dims = (0..n_dimensions)
+                .map(|_| magic.read_len(reader))
+                .collect::<Result<Vec<_>, _>>()?;
+            let dtype = GgmlDType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+            let offset = reader.read_u64::<LittleEndian>()? as usize;
+            tensor_infos.insert(
+                tensor_name,
+                TensorInfo {
+                    dims,
+                    dtype,
+                    offset,
+                },
+            );
+        }
+        let position = reader.stream_position()?;
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(|v| v.as_i64())
+            .filter(|a| *a > 0)
+            .map(|a| a as u64)
+            .unwrap_or(DEFAULT_ALIGNMENT);
+        let tensor_data_offset = position.div_ceil(alignment) * alignment;
+        Ok(Self {
+            magic,
+            metadata,
+            tensor_infos,
+            tensor_data_offset,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Result<&Value, GgufError> {
+        self.metadata
+            .get(key)
+            .ok_or_else(|| GgufError::MissingKey(key.to_string()))
+    }
+
+    fn get_as<'a, T>(
+        &'a self,
+        key: &str,
+        expected: &'static str,
+        f: impl Fn(&'a Value) -> Option<T>,
+    ) -> Result<T, GgufError> {
+        f(self.get(key)?).ok_or_else(|| GgufError::WrongType {
+            key: key.to_string(),
+            expected,
+        })
+    }
+
+    /// Get an integer metadata value of any width
+    pub fn get_int(&self, key: &str) -> Result<i64, GgufError> {
+        self.get_as(key, "integer", Value::as_i64)
+    }
+
+    /// Get an integer metadata value as a usize
+    pub fn get_usize(&self, key: &str) -> Result<usize, GgufError> {
+        self.get_as(key, "unsigned integer", |v| {
+            v.as_i64().and_then(|i| usize::try_from(i).ok())
+        })
+    }
+
+    /// Get an f32 or f64 metadata value
+    pub fn get_float(&self, key: &str) -> Result<f64, GgufError> {
+        self.get_as(key, "float", Value::as_f64)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<bool, GgufError> {
+        self.get_as(key, "bool", Value::as_bool)
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<&str, GgufError> {
+        self.get_as(key, "string", Value::as_str)
+    }
+
+    pub fn get_array(&self, key: &str) -> Result<&[Value], GgufError> {
+        self.get_as(key, "array", Value::as_array)
+    }
+
+    pub fn tensor_info(&self, name: &str) -> Result<&TensorInfo, GgufError> {
+        self.tensor_infos
+            .get(name)
+            .ok_or_else(|| GgufError::MissingTensor(name.to_string()))
+    }
+
+    /// Read the raw bytes of a tensor
+    pub fn tensor_bytes<R: Seek + Read>(
+        &self,
+        reader: &mut R,
+        name: &str,
+    ) -> Result<Vec<u8>, GgufError> {
+        self.tensor_info(name)?
+            .read_bytes(reader, self.tensor_data_offset)
+    }
+
+    /// Read a tensor and dequantize it to f32
+    pub fn tensor_f32<R: Seek + Read>(
+        &self,
+        reader: &mut R,
+        name: &str,
+    ) -> Result<Vec<f32>, GgufError> {
+        let bytes = self.tensor_bytes(reader, name)?;
+        self.tensor_info(name)?.dtype.dequantize(&bytes)
+    }
+}
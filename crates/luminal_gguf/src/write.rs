@@ -0,0 +1,232 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::Write;
+
+use crate::{GgmlDType, GgufError, Value, ValueType, DEFAULT_ALIGNMENT, GGUF_MAGIC};
+
+/// A tensor to be written to a GGUF file
+#[derive(Debug, Clone)]
+pub struct WriteTensor {
+    pub name: String,
+    /// Dimensions in row-major order (outermost first), as used by luminal
+    pub shape: Vec<usize>,
+    pub dtype: GgmlDType,
+    /// Raw data in the layout of `dtype`
+    pub data: Vec<u8>,
+}
+
+impl WriteTensor {
+    pub fn new(name: impl ToString, shape: Vec<usize>, dtype: GgmlDType, data: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            shape,
+            dtype,
+            data,
+        }
+    }
+
+    pub fn from_f32(name: impl ToString, shape: Vec<usize>, data: &[f32]) -> Self {
+        Self::new(
+            name,
+            shape,
+            GgmlDType::F32,
+            data.iter().flat_map(|f| f.to_le_bytes()).collect(),
+        )
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, s: &str) -> Result<(), GgufError> {
+    writer.write_u64::<LittleEndian>(s.len() as u64)?;
+    writer.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn write_value<W: Write>(writer: &mut W, value: &Value) -> Result<(), GgufError> {
+    match value {
+        Value::U8(v) => writer.write_u8(*v)?,
+        Value::I8(v) => writer.write_i8(*v)?,
+        Value::U16(v) => writer.write_u16::<LittleEndian>(*v)?,
+        Value::I16(v) => writer.write_i16::<LittleEndian>(*v)?,
+        Value::U32(v) => writer.write_u32::<LittleEndian>(*v)?,
+        Value::I32(v) => writer.write_i32::<LittleEndian>(*v)?,
+        Value::U64(v) => writer.write_u64::<LittleEndian>(*v)?,
+        Value::I64(v) => writer.write_i64::<LittleEndian>(*v)?,
+        Value::F32(v) => writer.write_f32::<LittleEndian>(*v)?,
+        Value::F64(v) => writer.write_f64::<LittleEndian>(*v)?,
+        Value::Bool(v) => writer.write_u8(*v as u8)?,
+        Value::String(s) => write_string(writer, s)?,
+        Value::Array(values) => {
+            let value_type = values
+                .first()
+                .map(|v| v.value_type())
+                .unwrap_or(ValueType::U8);
+            writer.write_u32::<LittleEndian>(value_type.to_u32())?;
+            writer.write_u64::<LittleEndian>(values.len() as u64)?;
+            for v in values {
+                write_value(writer, v)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write a GGUF (v3) file. Tensors are aligned to `general.alignment` if it's in the metadata.
+pub fn write<W: Write>(
+    writer: &mut W,
+    metadata: &[(&str, Value)],
+    tensors: &[WriteTensor],
+) -> Result<(), GgufError> {
+    for t in tensors {
+        let n_elements = t.shape.iter().product::<usize>();
+        if n_elements % t.dtype.block_size() != 0 || t.data.len() != t.dtype.n_bytes(n_elements) {
+            return Err(GgufError::BlockSize {
+                dtype: t.dtype,
+                n_bytes: t.data.len(),
+            });
+        }
+    }
+    let alignment = metadata
+        .iter()
+        .find(|(k, _)| *k == "general.alignment")
+        .and_then(|(_, v)| v.as_i64())
+        .filter(|a| *a > 0)
+        .map(|a| a as usize)
+        .unwrap_or(DEFAULT_ALIGNMENT as usize);
+
+    // Header
+    let mut header = vec![];
+    header.write_u32::<LittleEndian>(GGUF_MAGIC)?;
+    header.write_u32::<LittleEndian>(3)?;
+    header.write_u64::<LittleEndian>(tensors.len() as u64)?;
+    header.write_u64::<LittleEndian>(metadata.len() as u64)?;
+    for (key, value) in metadata {
+        write_string(&mut header, key)?;
+        header.write_u32::<LittleEndian>(value.value_type().to_u32())?;
+        write_value(&mut header, value)?;
+    }
+
+    // Tensor infos, with each tensor's data aligned
+    let mut offset = 0;
+    for t in tensors {
+        write_string(&mut header, &t.name)?;
+        header.write_u32::<LittleEndian>(t.shape.len() as u32)?;
+        for d in t.shape.iter().rev() {
+            header.write_u64::<LittleEndian>(*d as u64)?;
+        }
+        header.write_u32::<LittleEndian>(t.dtype.to_u32())?;
+        header.write_u64::<LittleEndian>(offset as u64)?;
+        offset = (offset + t.data.len()).div_ceil(alignment) * alignment;
+    }
+    header.resize(header.len().div_ceil(alignment) * alignment, 0);
+    writer.write_all(&header)?;
+
+    // Tensor data
+    for t in tensors {
+        writer.write_all(&t.data)?;
+        let padding = t.data.len().div_ceil(alignment) * alignment - t.data.len();
+        writer.write_all(&vec![0; padding])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{write, Content, GgmlDType, GgufError, Value, ValueType, WriteTensor};
+
+    #[test]
+    fn test_round_trip() {
+        let metadata = [
+            ("general.name", Value::String("test".to_string())),
+            ("block_count", Value::U32(12)),
+            ("rope.freq_base", Value::F32(10000.)),
+            ("use_bias", Value::Bool(true)),
+            (
+                "tokens",
+                Value::Array(vec![Value::String("a".into()), Value::String("b".into())]),
+            ),
+        ];
+        let q8 = [&[0, 0x3c][..], &[1; 32]].concat(); // f16 1.0 delta
+        let tensors = [
+            WriteTensor::from_f32("a", vec![2, 3], &[1., 2., 3., 4., 5., 6.]),
+            WriteTensor::new("b", vec![32], GgmlDType::Q8_0, q8),
+        ];
+        let mut file = vec![];
+        write(&mut file, &metadata, &tensors).unwrap();
+
+        let mut reader = Cursor::new(file);
+        let content = Content::read(&mut reader).unwrap();
+        assert_eq!(content.get_str("general.name").unwrap(), "test");
+        assert_eq!(content.get_usize("block_count").unwrap(), 12);
+        assert_eq!(content.get_float("rope.freq_base").unwrap(), 10000.);
+        assert!(content.get_bool("use_bias").unwrap());
+        assert_eq!(content.get_array("tokens").unwrap().len(), 2);
+        assert!(matches!(
+            content.get_str("block_count"),
+            Err(GgufError::WrongType { .. })
+        ));
+        assert!(matches!(
+            content.get_int("missing"),
+            Err(GgufError::MissingKey(_))
+        ));
+
+        assert_eq!(content.tensor_info("a").unwrap().shape(), vec![2, 3]);
+        assert_eq!(content.tensor_data_offset % 32, 0);
+        assert_eq!(
+            content.tensor_f32(&mut reader, "a").unwrap(),
+            vec![1., 2., 3., 4., 5., 6.]
+        );
+        assert_eq!(content.tensor_f32(&mut reader, "b").unwrap(), vec![1.; 32]);
+        assert!(content.tensor_bytes(&mut reader, "c").is_err());
+
+        // Bad data
+        assert!(matches!(
+            Content::read(&mut Cursor::new(vec![0; 16])),
+            Err(GgufError::Magic(0))
+        ));
+        assert!(Content::read(&mut Cursor::new(b"GGUF".to_vec())).is_err());
+        assert!(matches!(
+            Content::read(&mut Cursor::new(b"FUGG\0\0\0\x03".to_vec())),
+            Err(GgufError::BigEndian)
+        ));
+        // Lengths larger than the file are errors rather than huge allocations
+        let header = |kvs: u64| {
+            [
+                &b"GGUF"[..],
+                &3_u32.to_le_bytes(),
+                &0_u64.to_le_bytes(),
+                &kvs.to_le_bytes(),
+            ]
+            .concat()
+        };
+        let huge_key = [header(1), (1_u64 << 60).to_le_bytes().to_vec()].concat();
+        assert!(matches!(
+            Content::read(&mut Cursor::new(huge_key)),
+            Err(GgufError::Io(_))
+        ));
+        let huge_array = [
+            header(1),
+            1_u64.to_le_bytes().to_vec(),
+            b"a".to_vec(),
+            ValueType::Array.to_u32().to_le_bytes().to_vec(),
+            ValueType::U8.to_u32().to_le_bytes().to_vec(),
+            (1_u64 << 60).to_le_bytes().to_vec(),
+        ]
+        .concat();
+        assert!(matches!(
+            Content::read(&mut Cursor::new(huge_array)),
+            Err(GgufError::Io(_))
+        ));
+        assert!(write(
+            &mut vec![],
+            &[],
+            &[WriteTensor::new(
+                "c",
+                vec![31],
+                GgmlDType::Q8_0,
+                vec![0; 34]
+            )]
+        )
+        .is_err());
+    }
+}
@@ -362,6 +362,129 @@ metal_binary_op!(|a, b| format!("{a} * {b}"), MetalMul);
 metal_binary_op!(|a, b| format!("(float)({a} < {b})"), MetalLessThan);
 metal_binary_op!(|a, b| format!("fmod({a}, {b})"), MetalMod);
 
+/// Pick from the second input where the first is nonzero, otherwise from the third
+#[derive(Clone)]
+pub struct MetalSelect<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    dyn_symbols: Vec<char>,
+    _phantom: PhantomData<T>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+crate::debug_type!(MetalSelect);
+
+impl<T: MetalFloat> MetalSelect<T> {
+    pub fn new(
+        cond_shape: ShapeTracker,
+        a_shape: ShapeTracker,
+        b_shape: ShapeTracker,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (cond_idx_exp, cond_valid_exp) = get_idx_valid_exps(cond_shape);
+        let (a_idx_exp, a_valid_exp) = get_idx_valid_exps(a_shape);
+        let (b_idx_exp, b_valid_exp) = get_idx_valid_exps(b_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[cond_shape, a_shape, b_shape], 5);
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device {type_name} *inp_cond [[buffer(0)]], device {type_name} *inp_a [[buffer(1)]], device {type_name} *inp_b [[buffer(2)]], device {type_name} *out [[buffer(3)]], device int& n_elements [[buffer(4)]], uint idx [[thread_position_in_grid]]{rendered}) {{
+	if (idx < n_elements) {{
+		if ((({cond_valid_exp}) == 0 ? 0.0h : inp_cond[{cond_idx_exp}]) != 0) {{
+			out[idx] = ({a_valid_exp}) == 0 ? 0.0h : inp_a[{a_idx_exp}];
+		}} else {{
+			out[idx] = ({b_valid_exp}) == 0 ? 0.0h : inp_b[{b_idx_exp}];
+		}}
+	}}
+}}"
+        );
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            dyn_symbols,
+            _phantom: Default::default(),
+            dyn_map,
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalSelect<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0].n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let inp_size = inputs[0].1.n_elements().to_usize().unwrap();
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(inputs[2].0), 0);
+        encoder.set_buffer(3, Some(output_buffers[0]), 0);
+        encoder.set_u32(4, inp_size as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            5,
+        );
+        encoder.dispatch_1d(inp_size);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalSelect<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+            let out = self.device.new_buffer(
+                (inp_size * size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            self.metal_forward(
+                &[
+                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                    (get_buffer_from_tensor(&tensors[2].0), tensors[2].1),
+                ],
+                command_buffer,
+                &[],
+                &[&out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        if key == "elementwise" {
+            return Some(Box::new("(input0 != 0 ? input1 : input2)".to_string()));
+        }
+        None
+    }
+}
+
 #[derive(Clone)]
 pub struct MetalSumReduce<T> {
     pipeline: ComputePipelineState,
@@ -862,6 +985,15 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                     queue.clone(),
                     &graph.dyn_map,
                 ));
+            } else if is::<Select>(op) {
+                *op_ref = Box::new(MetalSelect::<T>::new(
+                    src_shapes[0],
+                    src_shapes[1],
+                    src_shapes[2],
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
             } else if let Some(SumReduce(dim)) = op_ref.as_any().downcast_ref() {
                 *op_ref = Box::new(MetalSumReduce::<T>::new(
                     src_shapes[0],
@@ -40,6 +40,19 @@ binary_test!(
 binary_test!(|a, b| a.minimum(b), |a, b| a.minimum(b), test_min, f32);
 binary_test!(|a, b| a.maximum(b), |a, b| a.maximum(b), test_max, f32);
 
+#[test]
+fn test_select() {
+    let mut cx = Graph::new();
+    let c = cx.tensor(4).set(vec![1., 0., 0., 2.]);
+    let a = cx.tensor(4).set(vec![1., 2., f32::INFINITY, 4.]);
+    let b = cx.tensor(4).set(vec![5., 6., 7., f32::NAN]);
+    let mut out = c.select(a, b).retrieve();
+    cx.compile(MetalCompiler::<f32>::default(), &mut out);
+    cx.execute();
+
+    assert_exact(&out.data(), &[1., 6., 7., 4.]);
+}
+
 #[test]
 fn test_contiguous() {
     let mut cx = Graph::new();
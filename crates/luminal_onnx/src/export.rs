@@ -0,0 +1,510 @@
+use std::{collections::HashMap, f32::consts::LN_2, path::Path};
+
+use luminal::{
+    op::{
+        Add, Cast, Constant, ConstantValue, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce,
+        Mod, Mul, Recip, Select, Sin, Sqrt, SumReduce,
+    },
+    prelude::{
+        petgraph::{algo::toposort, Direction},
+        *,
+    },
+};
+use prost::Message;
+
+use crate::{
+    proto::{
+        attribute_type, data_type, AttributeProto, Dimension, GraphProto, ModelProto, NodeProto,
+        OperatorSetIdProto, TensorProto, TensorShapeProto, TypeProto, TypeProtoTensor,
+        ValueInfoProto,
+    },
+    OnnxError,
+};
+
+/// The ONNX opset exported graphs target
+const OPSET: i64 = 13;
+
+/// Export an un-compiled graph to an ONNX file.
+///
+/// Source tensors in `inputs`, along with any that haven't been set, become graph inputs. All other source tensors become initializers, using their data in `graph.tensors` or by running their loader. Retrieved tensors become graph outputs. Dyn dims are fixed to their current values in `graph.dyn_map`.
+pub fn export<P: AsRef<Path>>(graph: &Graph, inputs: impl ToIds, path: P) -> Result<(), OnnxError> {
+    std::fs::write(path, export_bytes(graph, inputs)?)?;
+    Ok(())
+}
+
+/// Export an un-compiled graph to an encoded ONNX model. See `export`
+pub fn export_bytes(graph: &Graph, inputs: impl ToIds) -> Result<Vec<u8>, OnnxError> {
+    let inputs = inputs.to_ids();
+    let mut exporter = Exporter {
+        graph,
+        onnx: GraphProto {
+            name: "luminal".to_string(),
+            ..Default::default()
+        },
+        shapes: HashMap::new(),
+        n_values: 0,
+    };
+    let order = toposort(&graph.graph, None).map_err(|_| {
+        OnnxError::UnsupportedOp("Graphs with cycles can't be exported".to_string())
+    })?;
+    for id in order {
+        exporter.node(id, inputs.contains(&id))?;
+    }
+    let mut retrieved = graph.to_retrieve.iter().collect::<Vec<_>>();
+    retrieved.sort_by_key(|(id, _)| **id);
+    for (id, (output, shape)) in retrieved {
+        let value = exporter.view(&value_name(*id, *output), *shape)?;
+        let name = format!("output{}", exporter.onnx.output.len());
+        let dims = exporter.shape(&value)?;
+        exporter.push("Identity", &[value], vec![], &name);
+        exporter.onnx.output.push(value_info(&name, &dims));
+    }
+
+    Ok(ModelProto {
+        ir_version: 7,
+        producer_name: "luminal".to_string(),
+        graph: Some(exporter.onnx),
+        opset_import: vec![OperatorSetIdProto {
+            domain: String::new(),
+            version: OPSET,
+        }],
+        ..Default::default()
+    }
+    .encode_to_vec())
+}
+
+struct Exporter<'a> {
+    graph: &'a Graph,
+    onnx: GraphProto,
+    /// The shape of every ONNX value
+    shapes: HashMap<String, Vec<usize>>,
+    n_values: usize,
+}
+
+fn value_name(id: NodeIndex, output: u8) -> String {
+    format!("n{}_{output}", id.index())
+}
+
+fn value_info(name: &str, dims: &[usize]) -> ValueInfoProto {
+    ValueInfoProto {
+        name: name.to_string(),
+        r#type: Some(TypeProto {
+            tensor_type: Some(TypeProtoTensor {
+                elem_type: data_type::FLOAT,
+                shape: Some(TensorShapeProto {
+                    dim: dims
+                        .iter()
+                        .map(|d| Dimension {
+                            dim_value: Some(*d as i64),
+                            dim_param: None,
+                        })
+                        .collect(),
+                }),
+            }),
+        }),
+    }
+}
+
+fn int_attr(name: &str, i: i64) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        i,
+        r#type: attribute_type::INT,
+        ..Default::default()
+    }
+}
+
+fn ints_attr(name: &str, ints: Vec<i64>) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        ints,
+        r#type: attribute_type::INTS,
+        ..Default::default()
+    }
+}
+
+/// The ONNX data type matching a dtype
+fn onnx_type(dtype: DType) -> i32 {
+    match dtype {
+        DType::F32 => data_type::FLOAT,
+        DType::F16 => data_type::FLOAT16,
+        DType::Bf16 => data_type::BFLOAT16,
+        DType::I32 => data_type::INT32,
+        DType::I64 => data_type::INT64,
+        DType::U8 => data_type::UINT8,
+        DType::Bool => data_type::BOOL,
+    }
+}
+
+/// Convert tensor data of any element type to f32
+fn to_f32(tensor: &Tensor) -> Option<Vec<f32>> {
+    fn convert<T: Element>(tensor: &Tensor) -> Option<Vec<f32>> {
+        tensor
+            .downcast_ref::<Vec<T>>()
+            .map(|v| v.iter().map(|x| x.to_f64() as f32).collect())
+    }
+    match tensor.dtype()? {
+        DType::F32 => convert::<f32>(tensor),
+        DType::F16 => convert::<f16>(tensor),
+        DType::Bf16 => convert::<bf16>(tensor),
+        DType::I32 => convert::<i32>(tensor),
+        DType::I64 => convert::<i64>(tensor),
+        DType::U8 => convert::<u8>(tensor),
+        DType::Bool => convert::<bool>(tensor),
+    }
+}
+
+impl Exporter<'_> {
+    /// Add a node with a single output
+    fn push(&mut self, op: &str, inputs: &[String], attribute: Vec<AttributeProto>, output: &str) {
+        self.onnx.node.push(NodeProto {
+            input: inputs.to_vec(),
+            output: vec![output.to_string()],
+            name: output.to_string(),
+            op_type: op.to_string(),
+            attribute,
+            ..Default::default()
+        });
+    }
+
+    /// Add a node with a fresh output name and a known output shape
+    fn push_value(
+        &mut self,
+        op: &str,
+        inputs: &[String],
+        attribute: Vec<AttributeProto>,
+        shape: Vec<usize>,
+    ) -> String {
+        self.n_values += 1;
+        let output = format!("v{}", self.n_values);
+        self.push(op, inputs, attribute, &output);
+        self.shapes.insert(output.clone(), shape);
+        output
+    }
+
+    fn initializer(&mut self, name: &str, dims: &[usize], data_type: i32, raw_data: Vec<u8>) {
+        self.onnx.initializer.push(TensorProto {
+            name: name.to_string(),
+            dims: dims.iter().map(|d| *d as i64).collect(),
+            data_type,
+            raw_data,
+            ..Default::default()
+        });
+        self.shapes.insert(name.to_string(), dims.to_vec());
+    }
+
+    /// Add an int64 initializer, used for shapes and axes
+    fn ints(&mut self, values: &[i64]) -> String {
+        self.n_values += 1;
+        let name = format!("v{}", self.n_values);
+        let raw = values.iter().flat_map(|i| i.to_le_bytes()).collect();
+        self.initializer(&name, &[values.len()], data_type::INT64, raw);
+        name
+    }
+
+    fn float(&mut self, value: f32) -> String {
+        self.n_values += 1;
+        let name = format!("v{}", self.n_values);
+        self.initializer(&name, &[], data_type::FLOAT, value.to_le_bytes().to_vec());
+        name
+    }
+
+    /// The shape of an ONNX value that's already been added
+    fn shape(&self, value: &str) -> Result<Vec<usize>, OnnxError> {
+        self.shapes
+            .get(value)
+            .cloned()
+            .ok_or_else(|| OnnxError::MissingValue(value.to_string()))
+    }
+
+    fn resolve(&self, e: Expression) -> Result<usize, OnnxError> {
+        e.exec(&self.graph.dyn_map)
+            .ok_or_else(|| OnnxError::UnresolvedDim(format!("{e:?}")))
+    }
+
+    /// Apply a shape tracker's view to a contiguous value with explicit ONNX ops
+    fn view(&mut self, input: &str, shape: ShapeTracker) -> Result<String, OnnxError> {
+        let mut value = input.to_string();
+        let n = shape.len();
+        // Physical dims in storage order, with fake dims as 1s
+        let storage = (0..n)
+            .map(|i| {
+                if shape.fake[i] {
+                    Ok(1)
+                } else {
+                    self.resolve(shape.dims[i])
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.shape(&value)? != storage {
+            let target = self.ints(&storage.iter().map(|d| *d as i64).collect::<Vec<_>>());
+            value = self.push_value("Reshape", &[value, target], vec![], storage.clone());
+        }
+
+        // Padding, then slicing in padded coordinates
+        let mut padded = storage.clone();
+        let (mut pads, mut starts, mut ends) = (vec![0; 2 * n], vec![0; n], padded.clone());
+        for i in (0..n).filter(|i| !shape.fake[*i]) {
+            let (b, e) = (
+                self.resolve(shape.padding[i].0)?,
+                self.resolve(shape.padding[i].1)?,
+            );
+            (pads[i], pads[i + n]) = (b as i64, e as i64);
+            padded[i] += b + e;
+            starts[i] = self.resolve(shape.mask[i].0)?;
+            ends[i] = self.resolve(shape.mask[i].1.min(padded[i] as i32))?;
+        }
+        if pads.iter().any(|p| *p != 0) {
+            let pads = self.ints(&pads);
+            value = self.push_value("Pad", &[value, pads], vec![], padded.clone());
+        }
+        if (0..n).any(|i| starts[i] != 0 || ends[i] != padded[i]) {
+            let sliced = (0..n).map(|i| ends[i] - starts[i]).collect::<Vec<_>>();
+            let to_i64 = |v: &[usize]| v.iter().map(|i| *i as i64).collect::<Vec<_>>();
+            let inputs = [
+                value,
+                self.ints(&to_i64(&starts)),
+                self.ints(&to_i64(&ends)),
+            ];
+            value = self.push_value("Slice", &inputs, vec![], sliced);
+        }
+
+        // Permute into logical order
+        if shape.indexes.iter().enumerate().any(|(a, b)| a != *b) {
+            let current = self.shape(&value)?;
+            let permuted = shape.indexes.iter().map(|i| current[*i]).collect();
+            let perm = shape.indexes.iter().map(|i| *i as i64).collect();
+            value = self.push_value(
+                "Transpose",
+                &[value],
+                vec![ints_attr("perm", perm)],
+                permuted,
+            );
+        }
+
+        // Expand fake dims
+        if shape.fake.iter().any(|f| *f) {
+            let logical = shape
+                .dims()
+                .into_iter()
+                .map(|d| self.resolve(d))
+                .collect::<Result<Vec<_>, _>>()?;
+            let target = self.ints(&logical.iter().map(|d| *d as i64).collect::<Vec<_>>());
+            value = self.push_value("Expand", &[value, target], vec![], logical);
+        }
+        Ok(value)
+    }
+
+    fn node(&mut self, id: NodeIndex, is_input: bool) -> Result<(), OnnxError> {
+        let op = self.graph.graph.node_weight(id).unwrap();
+        let op = op.as_any();
+        let output = value_name(id, 0);
+        let sources = self.graph.get_sources(id);
+
+        // Source tensors
+        if let Some(function) = op.downcast_ref::<Function>() {
+            if !sources.is_empty() {
+                return Err(OnnxError::UnsupportedOp(function.0.clone()));
+            }
+            // Physical shape as seen by the first consumer, or as retrieved if nothing consumes it
+            let Some(consumer_shape) = self
+                .graph
+                .graph
+                .edges_directed(id, Direction::Outgoing)
+                .find_map(|e| e.weight().as_data().map(|(_, _, s)| s))
+                .or_else(|| self.graph.to_retrieve.get(&id).map(|(_, s)| *s))
+            else {
+                return Ok(());
+            };
+            let dims = (0..consumer_shape.len())
+                .filter(|i| !consumer_shape.fake[*i])
+                .map(|i| self.resolve(consumer_shape.dims[i]))
+                .collect::<Result<Vec<_>, _>>()?;
+            if is_input || self.graph.is_unset_input(id) {
+                self.onnx.input.push(value_info(&output, &dims));
+                self.shapes.insert(output, dims);
+                return Ok(());
+            }
+            let data = match self.graph.tensors.get(&(id, 0)) {
+                Some(tensor) => to_f32(tensor),
+                None => to_f32(&(function.1)(vec![]).remove(0)),
+            }
+            .ok_or_else(|| OnnxError::UnsupportedOp(format!("{} data", function.0)))?;
+            let raw = data.iter().flat_map(|f| f.to_le_bytes()).collect();
+            self.initializer(&output, &dims, data_type::FLOAT, raw);
+            return Ok(());
+        }
+        if let Some(constant) = op.downcast_ref::<Constant>() {
+            let value = match &constant.0 {
+                ConstantValue::Float(f) => *f,
+                ConstantValue::Expression(e) => self.resolve(*e)? as f32,
+            };
+            self.initializer(&output, &[], data_type::FLOAT, value.to_le_bytes().to_vec());
+            return Ok(());
+        }
+
+        let mut inputs = vec![];
+        let mut logical = vec![];
+        for (source, source_output, shape) in sources {
+            inputs.push(self.view(&value_name(source, source_output), shape)?);
+            logical.push(self.shape(inputs.last().unwrap())?);
+        }
+        let shape = logical.first().cloned().unwrap_or_default();
+        if op.is::<Contiguous>() {
+            self.push("Identity", &inputs, vec![], &output);
+        } else if op.is::<Log2>() {
+            let log = self.push_value("Log", &inputs, vec![], shape.clone());
+            let scale = self.float(1. / LN_2);
+            self.push("Mul", &[log, scale], vec![], &output);
+        } else if op.is::<Exp2>() {
+            let scale = self.float(LN_2);
+            let scaled = self.push_value("Mul", &[inputs[0].clone(), scale], vec![], shape.clone());
+            self.push("Exp", &[scaled], vec![], &output);
+        } else if op.is::<Sin>() {
+            self.push("Sin", &inputs, vec![], &output);
+        } else if op.is::<Sqrt>() {
+            self.push("Sqrt", &inputs, vec![], &output);
+        } else if op.is::<Recip>() {
+            self.push("Reciprocal", &inputs, vec![], &output);
+        } else if op.is::<Add>() {
+            self.push("Add", &inputs, vec![], &output);
+        } else if op.is::<Mul>() {
+            self.push("Mul", &inputs, vec![], &output);
+        } else if op.is::<Mod>() {
+            self.push("Mod", &inputs, vec![int_attr("fmod", 1)], &output);
+        } else if op.is::<LessThan>() {
+            let less = self.push_value("Less", &inputs, vec![], shape.clone());
+            self.push(
+                "Cast",
+                &[less],
+                vec![int_attr("to", data_type::FLOAT as i64)],
+                &output,
+            );
+        } else if op.is::<Select>() {
+            let cond = self.push_value(
+                "Cast",
+                &inputs[..1],
+                vec![int_attr("to", data_type::BOOL as i64)],
+                shape.clone(),
+            );
+            let inputs = [cond, inputs[1].clone(), inputs[2].clone()];
+            self.push("Where", &inputs, vec![], &output);
+        } else if let Some(Cast(dtype)) = op.downcast_ref::<Cast>() {
+            // Everything is kept in f32, so cast to the target and back to get its rounding
+            if *dtype == DType::F32 {
+                self.push("Identity", &inputs, vec![], &output);
+            } else {
+                let cast = self.push_value(
+                    "Cast",
+                    &inputs,
+                    vec![int_attr("to", onnx_type(*dtype) as i64)],
+                    shape.clone(),
+                );
+                self.push(
+                    "Cast",
+                    &[cast],
+                    vec![int_attr("to", data_type::FLOAT as i64)],
+                    &output,
+                );
+            }
+        } else if let Some(SumReduce(dim)) = op.downcast_ref::<SumReduce>() {
+            let axes = self.ints(&[*dim as i64]);
+            let inputs = [inputs[0].clone(), axes];
+            self.push("ReduceSum", &inputs, vec![int_attr("keepdims", 0)], &output);
+        } else if let Some(MaxReduce(dim)) = op.downcast_ref::<MaxReduce>() {
+            let attributes = vec![
+                ints_attr("axes", vec![*dim as i64]),
+                int_attr("keepdims", 0),
+            ];
+            self.push("ReduceMax", &inputs, attributes, &output);
+        } else {
+            return Err(OnnxError::UnsupportedOp(format!(
+                "{:?}",
+                self.graph.graph.node_weight(id).unwrap()
+            )));
+        }
+
+        let mut shape = shape;
+        if let Some(dim) = op
+            .downcast_ref::<SumReduce>()
+            .map(|s| s.0)
+            .or(op.downcast_ref::<MaxReduce>().map(|m| m.0))
+        {
+            shape.remove(dim);
+        }
+        self.shapes.insert(output, shape);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{prelude::*, tests::assert_close};
+
+    use prost::Message;
+
+    use crate::{export_bytes, import_bytes, proto::ModelProto};
+
+    #[test]
+    fn test_export_round_trip() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor((2, 3))
+            .set(vec![1., -2., 3., 0.5, 4., -1.5])
+            .keep();
+        let b = cx.tensor((3, 2)).set(vec![2., 1., -1., 3., 0.25, 2.]);
+        let outputs = [
+            // Permute, slice, pad
+            (a.permute((1, 0)) * b)
+                .slice((1.., ..))
+                .pad(((0, 1), (1, 0)))
+                .exp2()
+                .log2(),
+            // Expand and reduce
+            a.expand_dim(0, 2).sum(0).max(1),
+            (b * b + 1.).sqrt().reciprocal().sin(),
+            a.lt(b.permute((1, 0))) + a % 2.,
+            a.cast(DType::F16).cast(DType::F32).sum((0, 1)),
+        ]
+        .map(|t| t.retrieve());
+        let bytes = export_bytes(&cx, b).unwrap();
+        cx.execute();
+
+        // Views are written as explicit movement ops
+        let onnx = ModelProto::decode(bytes.as_slice()).unwrap().graph.unwrap();
+        for op in ["Transpose", "Slice", "Pad", "Expand"] {
+            assert!(onnx.node.iter().any(|n| n.op_type == op), "missing {op}");
+        }
+
+        let mut imported = Graph::new();
+        let model = import_bytes(&bytes, &mut imported).unwrap();
+        assert!(model.unsupported.is_empty(), "{:?}", model.unsupported);
+        assert_eq!(model.inputs.len(), 1);
+        assert_eq!(model.outputs.len(), outputs.len());
+        model.inputs[0].1.set(vec![2., 1., -1., 3., 0.25, 2.]);
+        imported.execute();
+        for (expected, (_, out)) in outputs.iter().zip(&model.outputs) {
+            assert_eq!(out.dims(), expected.dims());
+            assert_close(&out.data(), &expected.data());
+        }
+    }
+
+    #[test]
+    fn test_export_unconsumed_sources() {
+        // Retrieved sources that nothing else reads still need their shapes
+        let mut cx = Graph::new();
+        cx.tensor((2, 2)).set(vec![1., 2., 3., 4.]).retrieve();
+        cx.named_tensor("Input", 3).retrieve();
+        let bytes = export_bytes(&cx, ()).unwrap();
+
+        let mut imported = Graph::new();
+        let model = import_bytes(&bytes, &mut imported).unwrap();
+        assert_eq!(model.inputs.len(), 1);
+        assert_eq!(model.outputs.len(), 2);
+        model.inputs[0].1.set(vec![5., 6., 7.]);
+        imported.execute();
+        assert_eq!(model.outputs[0].1.dims(), [2, 2].map(Expression::from));
+        assert_close(&model.outputs[0].1.data(), &[1., 2., 3., 4.]);
+        assert_close(&model.outputs[1].1.data(), &[5., 6., 7.]);
+    }
+}
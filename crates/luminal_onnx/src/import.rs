@@ -0,0 +1,1397 @@
+use std::{collections::HashMap, path::Path};
+
+use luminal::prelude::*;
+use prost::Message;
+
+use crate::{
+    proto::{
+        attribute_type, data_type, AttributeProto, GraphProto, ModelProto, NodeProto, TensorProto,
+    },
+    OnnxError,
+};
+
+/// An ONNX model imported into a graph
+#[derive(Debug)]
+pub struct OnnxModel {
+    /// Graph inputs in model order. These need to be set before running
+    pub inputs: Vec<(String, GraphTensor)>,
+    /// Graph outputs in model order, marked to be retrieved. Outputs that depend on unsupported ops are left out
+    pub outputs: Vec<(String, GraphTensor)>,
+    /// Float initializers, already set with their data
+    pub weights: Vec<(String, GraphTensor)>,
+    /// Symbolic ONNX dimensions and the dyn dims they were mapped to
+    pub dyn_dims: HashMap<String, char>,
+    /// Ops that couldn't be imported, with the reason if the op type itself is supported
+    pub unsupported: Vec<String>,
+}
+
+impl OnnxModel {
+    pub fn input(&self, name: &str) -> Option<GraphTensor> {
+        self.inputs.iter().find(|(n, _)| n == name).map(|(_, t)| *t)
+    }
+
+    pub fn output(&self, name: &str) -> Option<GraphTensor> {
+        self.outputs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, t)| *t)
+    }
+}
+
+/// Import an ONNX model file into a graph
+pub fn import<P: AsRef<Path>>(path: P, cx: &mut Graph) -> Result<OnnxModel, OnnxError> {
+    import_bytes(&std::fs::read(path)?, cx)
+}
+
+/// Import an encoded ONNX model into a graph
+pub fn import_bytes(bytes: &[u8], cx: &mut Graph) -> Result<OnnxModel, OnnxError> {
+    let model = ModelProto::decode(bytes)?;
+    let graph = model.graph.ok_or(OnnxError::MissingGraph)?;
+    Importer {
+        cx,
+        values: HashMap::new(),
+        dyn_dims: HashMap::new(),
+    }
+    .import(&graph)
+}
+
+/// A value flowing between ONNX nodes
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+enum Value {
+    Tensor(GraphTensor),
+    /// An integer tensor known at import time, like shapes, axes and indices. These are kept off the graph so shape computations fold away
+    Ints {
+        values: Vec<Expression>,
+        shape: Vec<usize>,
+    },
+}
+
+impl Value {
+    fn ints(values: Vec<Expression>) -> Self {
+        let shape = vec![values.len()];
+        Value::Ints { values, shape }
+    }
+}
+
+struct Importer<'a> {
+    cx: &'a mut Graph,
+    values: HashMap<String, Value>,
+    dyn_dims: HashMap<String, char>,
+}
+
+impl Importer<'_> {
+    fn import(mut self, graph: &GraphProto) -> Result<OnnxModel, OnnxError> {
+        let mut weights = vec![];
+        for init in &graph.initializer {
+            let value = self.constant(init)?;
+            if let Value::Tensor(t) = value {
+                weights.push((init.name.clone(), t));
+            }
+            self.values.insert(init.name.clone(), value);
+        }
+
+        let mut inputs = vec![];
+        for input in &graph.input {
+            if self.values.contains_key(&input.name) {
+                continue;
+            }
+            let dims = input
+                .r#type
+                .as_ref()
+                .and_then(|t| t.tensor_type.as_ref())
+                .and_then(|t| t.shape.as_ref())
+                .map(|s| s.dim.clone())
+                .unwrap_or_default();
+            let shape = dims
+                .iter()
+                .enumerate()
+                .map(|(i, d)| match (d.dim_value, &d.dim_param) {
+                    (Some(v), _) if v > 0 => Ok(Expression::from(v as usize)),
+                    (_, Some(p)) if !p.is_empty() => self.dyn_dim(p).map(Expression::from),
+                    _ => self
+                        .dyn_dim(&format!("{}:{i}", input.name))
+                        .map(Expression::from),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let tensor = self.cx.named_tensor(&input.name, shape);
+            self.values
+                .insert(input.name.clone(), Value::Tensor(tensor));
+            inputs.push((input.name.clone(), tensor));
+        }
+
+        let mut unsupported = vec![];
+        for node in &graph.node {
+            let mut node_inputs = vec![];
+            for name in &node.input {
+                if name.is_empty() {
+                    node_inputs.push(None);
+                } else if let Some(v) = self.values.get(name) {
+                    node_inputs.push(Some(v.clone()));
+                } else {
+                    // An upstream op wasn't imported
+                    break;
+                }
+            }
+            if node_inputs.len() != node.input.len() {
+                continue;
+            }
+            match self.node(node, &node_inputs) {
+                Ok(outputs) => {
+                    for (name, value) in node.output.iter().zip(outputs) {
+                        self.values.insert(name.clone(), value);
+                    }
+                }
+                Err(reason) => {
+                    let entry = if reason.is_empty() {
+                        node.op_type.clone()
+                    } else {
+                        format!("{}: {reason}", node.op_type)
+                    };
+                    if !unsupported.contains(&entry) {
+                        unsupported.push(entry);
+                    }
+                }
+            }
+        }
+
+        let mut outputs = vec![];
+        for output in &graph.output {
+            if let Some(value) = self.values.get(&output.name).cloned() {
+                outputs.push((output.name.clone(), self.tensor(&value).retrieve()));
+            }
+        }
+        Ok(OnnxModel {
+            inputs,
+            outputs,
+            weights,
+            dyn_dims: self.dyn_dims,
+            unsupported,
+        })
+    }
+
+    /// Get the dyn dim for a symbolic dimension, assigning an unused one if needed
+    fn dyn_dim(&mut self, name: &str) -> Result<char, OnnxError> {
+        if let Some(c) = self.dyn_dims.get(name) {
+            return Ok(*c);
+        }
+        let c = ('a'..='z')
+            .chain('A'..='Z')
+            .find(|c| !self.dyn_dims.values().any(|d| d == c) && !self.cx.dyn_map.contains_key(c))
+            .ok_or_else(|| OnnxError::TooManyDynDims(name.to_string()))?;
+        self.dyn_dims.insert(name.to_string(), c);
+        Ok(c)
+    }
+
+    /// Turn a tensor proto into a value. Integer tensors stay known, float tensors are loaded into the graph
+    fn constant(&mut self, t: &TensorProto) -> Result<Value, OnnxError> {
+        let data = tensor_data(t)?;
+        let shape = t
+            .dims
+            .iter()
+            .map(|d| usize::try_from(*d).ok())
+            .collect::<Option<Vec<_>>>()
+            .filter(|shape| shape.iter().product::<usize>() == data.len())
+            .ok_or_else(|| OnnxError::TensorSize {
+                name: t.name.clone(),
+                dims: t.dims.clone(),
+                n_elements: data.len(),
+            })?;
+        Ok(match t.data_type {
+            data_type::FLOAT | data_type::FLOAT16 | data_type::BFLOAT16 | data_type::DOUBLE => {
+                let name = if t.name.is_empty() {
+                    "Constant"
+                } else {
+                    &t.name
+                };
+                Value::Tensor(
+                    self.cx
+                        .named_tensor(name, shape)
+                        .set(data.into_iter().map(|f| f as f32).collect::<Vec<_>>()),
+                )
+            }
+            _ => Value::Ints {
+                values: data.into_iter().map(|i| expr(i as i64)).collect(),
+                shape,
+            },
+        })
+    }
+
+    /// Get a value as a tensor, putting known integers into the graph
+    fn tensor(&mut self, value: &Value) -> GraphTensor {
+        match value {
+            Value::Tensor(t) => *t,
+            Value::Ints { values, shape } => {
+                if let Some(data) = values
+                    .iter()
+                    .map(|v| v.as_num().map(|i| i as f32))
+                    .collect::<Option<Vec<_>>>()
+                {
+                    self.cx.tensor(shape.clone()).set(data)
+                } else if shape.is_empty() {
+                    self.cx.constant(values[0])
+                } else {
+                    values
+                        .iter()
+                        .map(|v| self.cx.constant(*v).expand_dim(0, 1))
+                        .reduce(|a, b| a.concat_along(b, 0))
+                        .unwrap()
+                        .reshape(shape.clone())
+                }
+            }
+        }
+    }
+
+    fn node(&mut self, node: &NodeProto, inputs: &[Option<Value>]) -> Result<Vec<Value>, String> {
+        let input = |i: usize| inputs.get(i).cloned().flatten();
+        let required = |i: usize| input(i).ok_or(format!("missing input {i}"));
+        let binary = |s: &mut Self, f: fn(GraphTensor, GraphTensor) -> GraphTensor| {
+            let (a, b) = (required(0)?, required(1)?);
+            let (a, b) = (s.tensor(&a), s.tensor(&b));
+            let (a, b) = broadcast(a, b);
+            Ok::<_, String>(vec![Value::Tensor(f(a, b))])
+        };
+        let unary = |s: &mut Self, f: fn(GraphTensor) -> GraphTensor| {
+            let a = required(0)?;
+            Ok::<_, String>(vec![Value::Tensor(f(s.tensor(&a)))])
+        };
+
+        // Integer arithmetic on known values, used for shape computations
+        if let (
+            "Add" | "Sub" | "Mul" | "Div",
+            Some(Value::Ints {
+                values: a,
+                shape: a_shape,
+            }),
+            Some(Value::Ints {
+                values: b,
+                shape: b_shape,
+            }),
+        ) = (node.op_type.as_str(), input(0), input(1))
+        {
+            let n = a.len().max(b.len());
+            if a.len() == n || a.len() == 1 && b.len() == n || b.len() == 1 {
+                let values = (0..n)
+                    .map(|i| {
+                        let (a, b) = (a[i % a.len()], b[i % b.len()]);
+                        match node.op_type.as_str() {
+                            "Add" => a + b,
+                            "Sub" => a - b,
+                            "Mul" => a * b,
+                            _ => a / b,
+                        }
+                    })
+                    .collect();
+                let shape = if a.len() == n { a_shape } else { b_shape };
+                return Ok(vec![Value::Ints { values, shape }]);
+            }
+        }
+
+        match node.op_type.as_str() {
+            "Identity" | "Dropout" => Ok(vec![required(0)?]),
+            "Cast" => {
+                let to = attr_i(node, "to", data_type::FLOAT as i64) as i32;
+                match required(0)? {
+                    Value::Ints { values, shape } if to == data_type::BOOL => {
+                        let values = values
+                            .into_iter()
+                            .map(|v| v.as_num().map(|n| Expression::from((n != 0) as usize)))
+                            .collect::<Option<Vec<_>>>()
+                            .ok_or("can't cast unknown ints to bool")?;
+                        Ok(vec![Value::Ints { values, shape }])
+                    }
+                    // Known ints are already integral
+                    ints @ Value::Ints { .. } => Ok(vec![ints]),
+                    value => {
+                        // Everything is kept in f32, so cast to the target and back to get its rounding
+                        let t = self.tensor(&value);
+                        let t = match to {
+                            data_type::FLOAT | data_type::DOUBLE => t,
+                            data_type::FLOAT16 => t.cast(DType::F16).cast(DType::F32),
+                            data_type::BFLOAT16 => t.cast(DType::Bf16).cast(DType::F32),
+                            data_type::UINT8 => t.cast(DType::U8).cast(DType::F32),
+                            data_type::INT8 | data_type::INT32 => {
+                                t.cast(DType::I32).cast(DType::F32)
+                            }
+                            data_type::INT64 => t.cast(DType::I64).cast(DType::F32),
+                            data_type::BOOL => {
+                                let cx = t.graph();
+                                let (one, zero) = (cx.constant(1.), cx.constant(0.));
+                                t.select(one.expand(t.dims()), zero.expand(t.dims()))
+                            }
+                            _ => return Err(format!("unsupported target type {to}")),
+                        };
+                        Ok(vec![Value::Tensor(t)])
+                    }
+                }
+            }
+            "Add" => binary(self, |a, b| a + b),
+            "Sub" => binary(self, |a, b| a - b),
+            "Mul" => binary(self, |a, b| a * b),
+            "Div" => binary(self, |a, b| a / b),
+            "Mod" => binary(self, |a, b| a % b),
+            "Pow" => binary(self, |a, b| a.pow(b)),
+            "Equal" => binary(self, |a, b| a.eq(b)),
+            "Less" => binary(self, |a, b| a.lt(b)),
+            "LessOrEqual" => binary(self, |a, b| a.le(b)),
+            "Greater" => binary(self, |a, b| a.gt(b)),
+            "GreaterOrEqual" => binary(self, |a, b| a.ge(b)),
+            "Max" | "Min" => {
+                let mut out = self.tensor(&required(0)?);
+                for i in 1..inputs.len() {
+                    let (a, b) = broadcast(out, self.tensor(&required(i)?));
+                    out = if node.op_type == "Max" {
+                        a.maximum(b)
+                    } else {
+                        a.minimum(b)
+                    };
+                }
+                Ok(vec![Value::Tensor(out)])
+            }
+            "Neg" => unary(self, |a| -a),
+            "Abs" => unary(self, |a| a.abs()),
+            "Sqrt" => unary(self, |a| a.sqrt()),
+            "Exp" => unary(self, |a| a.exp()),
+            "Log" => unary(self, |a| a.log()),
+            "Sin" => unary(self, |a| a.sin()),
+            "Cos" => unary(self, |a| a.cos()),
+            "Reciprocal" => unary(self, |a| a.reciprocal()),
+            "Relu" => unary(self, |a| a.relu()),
+            "Sigmoid" => unary(self, |a| a.sigmoid()),
+            "Tanh" => unary(self, |a| a.tanh()),
+            "Gelu" => unary(self, |a| a.gelu()),
+            "LeakyRelu" => {
+                let alpha = attr_f(node, "alpha", 0.01);
+                Ok(vec![Value::Tensor(
+                    self.tensor(&required(0)?).leaky_relu(alpha),
+                )])
+            }
+            "Clip" => {
+                let mut out = self.tensor(&required(0)?);
+                if let Some(min) = input(1) {
+                    let (a, b) = broadcast(out, self.tensor(&min));
+                    out = a.maximum(b);
+                } else if let Some(min) = attr(node, "min") {
+                    out = out.maximum_f32(min.f);
+                }
+                if let Some(max) = input(2) {
+                    let (a, b) = broadcast(out, self.tensor(&max));
+                    out = a.minimum(b);
+                } else if let Some(max) = attr(node, "max") {
+                    out = out.minimum_f32(max.f);
+                }
+                Ok(vec![Value::Tensor(out)])
+            }
+            "Where" => {
+                let (c, x, y) = (required(0)?, required(1)?, required(2)?);
+                let (c, x, y) = (self.tensor(&c), self.tensor(&x), self.tensor(&y));
+                let dims = broadcast_dims(&broadcast_dims(&c.dims(), &x.dims()), &y.dims());
+                let (c, x, y) = (
+                    broadcast_to(c, &dims),
+                    broadcast_to(x, &dims),
+                    broadcast_to(y, &dims),
+                );
+                Ok(vec![Value::Tensor(c.select(x, y))])
+            }
+            "MatMul" => {
+                let (a, b) = (required(0)?, required(1)?);
+                Ok(vec![Value::Tensor(matmul(
+                    self.tensor(&a),
+                    self.tensor(&b),
+                ))])
+            }
+            "Gemm" => {
+                let (mut a, mut b) = (self.tensor(&required(0)?), self.tensor(&required(1)?));
+                if attr_i(node, "transA", 0) != 0 {
+                    a = a.permute((1, 0));
+                }
+                if attr_i(node, "transB", 0) != 0 {
+                    b = b.permute((1, 0));
+                }
+                let mut out = a.matmul(b);
+                let alpha = attr_f(node, "alpha", 1.0);
+                if alpha != 1.0 {
+                    out = out * alpha;
+                }
+                if let Some(c) = input(2) {
+                    let c = broadcast_to(self.tensor(&c), &out.dims()) * attr_f(node, "beta", 1.0);
+                    out += c;
+                }
+                Ok(vec![Value::Tensor(out)])
+            }
+            "Conv" => self.conv(node, inputs),
+            "Softmax" | "LogSoftmax" => {
+                let a = self.tensor(&required(0)?);
+                let axis = norm_axis(attr_i(node, "axis", -1), a.shape.len())?;
+                Ok(vec![Value::Tensor(if node.op_type == "Softmax" {
+                    a.softmax(axis)
+                } else {
+                    a.log_softmax(axis)
+                })])
+            }
+            "LayerNormalization" => {
+                let a = self.tensor(&required(0)?);
+                let rank = a.shape.len();
+                let axes = (norm_axis(attr_i(node, "axis", -1), rank)?..rank).collect::<Vec<_>>();
+                let mut out = a.layer_norm(axes, attr_f(node, "epsilon", 1e-5));
+                out *= broadcast_to(self.tensor(&required(1)?), &out.dims());
+                if let Some(bias) = input(2) {
+                    out += broadcast_to(self.tensor(&bias), &out.dims());
+                }
+                Ok(vec![Value::Tensor(out)])
+            }
+            "ReduceSum" | "ReduceMean" | "ReduceMax" => {
+                let a = self.tensor(&required(0)?);
+                let rank = a.shape.len();
+                let axes = match (input(1), attr(node, "axes")) {
+                    (Some(v), _) => known_ints(&v)?,
+                    (None, Some(a)) => a.ints.clone(),
+                    _ => vec![],
+                };
+                let mut axes = axes
+                    .into_iter()
+                    .map(|a| norm_axis(a, rank))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if axes.is_empty() {
+                    axes = (0..rank).collect();
+                }
+                axes.sort();
+                let mut out = match node.op_type.as_str() {
+                    "ReduceSum" => a.sum(axes.clone()),
+                    "ReduceMean" => a.mean(axes.clone()),
+                    _ => a.max(axes.clone()),
+                };
+                if attr_i(node, "keepdims", 1) != 0 {
+                    for axis in axes {
+                        out = out.expand_dim(axis, 1);
+                    }
+                }
+                Ok(vec![Value::Tensor(out)])
+            }
+            "Constant" => {
+                if let Some(t) = attr(node, "value").and_then(|a| a.t.as_ref()) {
+                    Ok(vec![self.constant(t).map_err(|e| e.to_string())?])
+                } else if let Some(a) = attr(node, "value_int") {
+                    Ok(vec![Value::Ints {
+                        values: vec![expr(a.i)],
+                        shape: vec![],
+                    }])
+                } else if let Some(a) = attr(node, "value_ints") {
+                    Ok(vec![Value::ints(a.ints.iter().map(|i| expr(*i)).collect())])
+                } else if let Some(a) = attr(node, "value_float") {
+                    Ok(vec![Value::Tensor(self.cx.constant(a.f))])
+                } else if let Some(a) = attr(node, "value_floats") {
+                    Ok(vec![Value::Tensor(
+                        self.cx.tensor(a.floats.len()).set(a.floats.clone()),
+                    )])
+                } else {
+                    Err("unsupported value".to_string())
+                }
+            }
+            "ConstantOfShape" => {
+                let dims = known(&required(0)?)?;
+                let value = match attr(node, "value").and_then(|a| a.t.as_ref()) {
+                    Some(t) => (tensor_data(t).map_err(|e| e.to_string())?[0], t.data_type),
+                    None => (0., data_type::FLOAT),
+                };
+                if let (Some(shape), data_type::INT64 | data_type::INT32) = (
+                    dims.iter()
+                        .map(|d| d.to_usize())
+                        .collect::<Option<Vec<_>>>(),
+                    value.1,
+                ) {
+                    return Ok(vec![Value::Ints {
+                        values: vec![expr(value.0 as i64); shape.iter().product()],
+                        shape,
+                    }]);
+                }
+                let mut out = self.cx.constant(value.0);
+                for (i, d) in dims.into_iter().enumerate() {
+                    out = out.expand_dim(i, d);
+                }
+                Ok(vec![Value::Tensor(out)])
+            }
+            "Shape" => {
+                let dims = match required(0)? {
+                    Value::Tensor(t) => t.dims(),
+                    Value::Ints { shape, .. } => shape.into_iter().map(Expression::from).collect(),
+                };
+                let rank = dims.len() as i64;
+                let clamp = |i: i64| (if i < 0 { i + rank } else { i }).clamp(0, rank) as usize;
+                let (start, end) = (
+                    clamp(attr_i(node, "start", 0)),
+                    clamp(attr_i(node, "end", rank)),
+                );
+                Ok(vec![Value::ints(dims[start..end.max(start)].to_vec())])
+            }
+            "Reshape" => {
+                let a = required(0)?;
+                let dims = value_dims(&a);
+                let mut shape = known_ints(&required(1)?)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, d)| match d {
+                        0 => Some(dims[i]),
+                        -1 => None,
+                        d => Some(expr(d)),
+                    })
+                    .collect::<Vec<_>>();
+                if let Some(infer) = shape.iter().position(|d| d.is_none()) {
+                    let known = shape.iter().flatten().copied().product::<Expression>();
+                    shape[infer] =
+                        Some((dims.iter().copied().product::<Expression>() / known).simplify());
+                }
+                let shape = shape.into_iter().flatten().collect::<Vec<_>>();
+                Ok(vec![match a {
+                    Value::Ints { values, .. } => Value::Ints {
+                        values,
+                        shape: shape
+                            .iter()
+                            .map(|d| d.to_usize())
+                            .collect::<Option<_>>()
+                            .ok_or("symbolic shape for known values")?,
+                    },
+                    Value::Tensor(t) => Value::Tensor(t.reshape(shape)),
+                }])
+            }
+            "Flatten" => {
+                let a = self.tensor(&required(0)?);
+                let dims = a.dims();
+                // Flattening at the rank puts everything in the outer dimension
+                let axis = match attr_i(node, "axis", 1) {
+                    a if a == dims.len() as i64 => dims.len(),
+                    a => norm_axis(a, dims.len())?,
+                };
+                let outer = dims[..axis].iter().copied().product::<Expression>();
+                let inner = dims[axis..].iter().copied().product::<Expression>();
+                Ok(vec![Value::Tensor(a.reshape((outer, inner)))])
+            }
+            "Transpose" => {
+                let a = self.tensor(&required(0)?);
+                let rank = a.shape.len();
+                let perm = attr(node, "perm")
+                    .map(|p| p.ints.iter().map(|i| *i as usize).collect())
+                    .unwrap_or_else(|| (0..rank).rev().collect::<Vec<_>>());
+                Ok(vec![Value::Tensor(a.permute(perm))])
+            }
+            "Unsqueeze" | "Squeeze" => {
+                let a = required(0)?;
+                let dims = value_dims(&a);
+                let axes = match (input(1), attr(node, "axes")) {
+                    (Some(v), _) => Some(known_ints(&v)?),
+                    (None, Some(a)) => Some(a.ints.clone()),
+                    _ => None,
+                };
+                let shape = if node.op_type == "Unsqueeze" {
+                    let axes = axes.ok_or("missing axes")?;
+                    let rank = dims.len() + axes.len();
+                    let mut axes = axes
+                        .into_iter()
+                        .map(|a| norm_axis(a, rank))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    axes.sort();
+                    let mut shape = dims.clone();
+                    for axis in axes {
+                        shape.insert(axis, 1.into());
+                    }
+                    shape
+                } else {
+                    let axes = match axes {
+                        Some(axes) => axes
+                            .into_iter()
+                            .map(|a| norm_axis(a, dims.len()))
+                            .collect::<Result<Vec<_>, _>>()?,
+                        None => (0..dims.len()).filter(|i| dims[*i] == 1).collect(),
+                    };
+                    dims.iter()
+                        .enumerate()
+                        .filter(|(i, _)| !axes.contains(i))
+                        .map(|(_, d)| *d)
+                        .collect()
+                };
+                Ok(vec![match a {
+                    Value::Ints { values, .. } => Value::Ints {
+                        values,
+                        shape: shape
+                            .iter()
+                            .map(|d| d.to_usize())
+                            .collect::<Option<_>>()
+                            .ok_or("symbolic shape for known values")?,
+                    },
+                    Value::Tensor(t) => Value::Tensor(t.reshape(shape)),
+                }])
+            }
+            "Expand" => {
+                let a = self.tensor(&required(0)?);
+                let dims = broadcast_dims(&a.dims(), &known(&required(1)?)?);
+                Ok(vec![Value::Tensor(broadcast_to(a, &dims))])
+            }
+            "Pad" => {
+                let a = self.tensor(&required(0)?);
+                if attr(node, "mode").is_some_and(|m| m.s != b"constant") {
+                    return Err("non-constant padding".to_string());
+                }
+                if input(2).is_some_and(|v| known(&v).map_or(true, |c| c.iter().any(|c| *c != 0))) {
+                    return Err("non-zero padding value".to_string());
+                }
+                let pads = match input(1) {
+                    Some(v) => known_ints(&v)?,
+                    None => attr(node, "pads").ok_or("missing pads")?.ints.clone(),
+                };
+                let rank = a.shape.len();
+                if pads.len() != 2 * rank || pads.iter().any(|p| *p < 0) {
+                    return Err("negative or mis-sized pads".to_string());
+                }
+                let padding = (0..rank)
+                    .map(|i| (expr(pads[i]), expr(pads[i + rank])))
+                    .collect::<Vec<_>>();
+                Ok(vec![Value::Tensor(a.pad(padding))])
+            }
+            "Concat" => {
+                let values = inputs.iter().flatten().cloned().collect::<Vec<_>>();
+                if values
+                    .iter()
+                    .all(|v| matches!(v, Value::Ints { shape, .. } if shape.len() == 1))
+                {
+                    return Ok(vec![Value::ints(
+                        values.iter().flat_map(|v| known(v).unwrap()).collect(),
+                    )]);
+                }
+                let tensors = values.iter().map(|v| self.tensor(v)).collect::<Vec<_>>();
+                let axis = norm_axis(attr_i(node, "axis", 0), tensors[0].shape.len())?;
+                Ok(vec![Value::Tensor(
+                    tensors
+                        .into_iter()
+                        .reduce(|a, b| a.concat_along(b, axis))
+                        .unwrap(),
+                )])
+            }
+            "Slice" => {
+                let a = required(0)?;
+                let dims = value_dims(&a);
+                let (starts, ends, axes, steps) = if let Some(starts) = input(1) {
+                    (
+                        known_ints(&starts)?,
+                        known_ints(&required(2)?)?,
+                        input(3).map(|v| known_ints(&v)).transpose()?,
+                        input(4).map(|v| known_ints(&v)).transpose()?,
+                    )
+                } else {
+                    (
+                        attr(node, "starts").ok_or("missing starts")?.ints.clone(),
+                        attr(node, "ends").ok_or("missing ends")?.ints.clone(),
+                        attr(node, "axes").map(|a| a.ints.clone()),
+                        None,
+                    )
+                };
+                if steps.is_some_and(|s| s.iter().any(|s| *s != 1)) {
+                    return Err("steps other than 1".to_string());
+                }
+                let axes = axes.unwrap_or_else(|| (0..starts.len() as i64).collect());
+                let mut ranges =
+                    vec![(Expression::from(0), Expression::from(i32::MAX)); dims.len()];
+                for ((start, end), axis) in starts.into_iter().zip(ends).zip(axes) {
+                    let axis = norm_axis(axis, dims.len())?;
+                    let bound = |i: i64| {
+                        if i < 0 {
+                            (dims[axis] + expr(i)).max(0)
+                        } else if i >= i32::MAX as i64 {
+                            dims[axis]
+                        } else {
+                            expr(i).min(dims[axis])
+                        }
+                    };
+                    ranges[axis] = (bound(start).simplify(), bound(end).simplify());
+                }
+                Ok(vec![match a {
+                    Value::Ints { values, shape } if shape.len() == 1 => {
+                        let (start, end) = (ranges[0].0.as_num(), ranges[0].1.as_num());
+                        let (start, end) = (start.unwrap() as usize, end.unwrap() as usize);
+                        Value::ints(values[start..end.max(start)].to_vec())
+                    }
+                    a => Value::Tensor(self.tensor(&a).slice(ranges)),
+                }])
+            }
+            "Gather" => {
+                let (a, indexes) = (required(0)?, required(1)?);
+                let dims = value_dims(&a);
+                let axis = norm_axis(attr_i(node, "axis", 0), dims.len())?;
+                match (a, indexes) {
+                    (
+                        Value::Ints { values, shape },
+                        Value::Ints {
+                            values: indexes,
+                            shape: index_shape,
+                        },
+                    ) if shape.len() == 1 => Ok(vec![Value::Ints {
+                        values: indexes
+                            .iter()
+                            .map(|i| {
+                                let i = i.as_num().ok_or("symbolic index")?;
+                                Ok(values[norm_axis(i as i64, values.len())?])
+                            })
+                            .collect::<Result<_, String>>()?,
+                        shape: index_shape,
+                    }]),
+                    (a, Value::Ints { values, shape }) if shape.is_empty() => {
+                        // Single known index, take a slice
+                        let i = values[0].as_num().ok_or("symbolic index")? as i64;
+                        let i = if i < 0 { dims[axis] + expr(i) } else { expr(i) };
+                        let mut out_dims = dims.clone();
+                        out_dims.remove(axis);
+                        Ok(vec![Value::Tensor(
+                            self.tensor(&a)
+                                .slice_along(i..i + 1, axis)
+                                .reshape(out_dims),
+                        )])
+                    }
+                    (a, indexes) => {
+                        let (a, indexes) = (self.tensor(&a), self.tensor(&indexes));
+                        Ok(vec![Value::Tensor(gather(a, indexes, axis))])
+                    }
+                }
+            }
+            _ => Err(String::new()),
+        }
+    }
+
+    fn conv(&mut self, node: &NodeProto, inputs: &[Option<Value>]) -> Result<Vec<Value>, String> {
+        if attr_i(node, "group", 1) != 1 {
+            return Err("grouped convolutions".to_string());
+        }
+        if attr(node, "auto_pad").is_some_and(|a| a.s != b"NOTSET") {
+            return Err("auto_pad".to_string());
+        }
+        let (Some(Some(x)), Some(Some(w))) = (inputs.first(), inputs.get(1)) else {
+            return Err("missing input".to_string());
+        };
+        let (x, w) = (self.tensor(x), self.tensor(w));
+        let w_dims = w.dims();
+        let n_spatial = w_dims.len().saturating_sub(2);
+        if n_spatial == 0 || n_spatial > 2 || x.shape.len() != n_spatial + 2 {
+            return Err(format!("{n_spatial}D convolutions"));
+        }
+        let kernel = w_dims[2..]
+            .iter()
+            .map(|d| d.to_usize())
+            .collect::<Option<Vec<_>>>()
+            .ok_or("symbolic kernel size")?;
+        let ints = |name, default| {
+            attr(node, name)
+                .map(|a| a.ints.iter().map(|i| *i as usize).collect::<Vec<_>>())
+                .unwrap_or(vec![default; n_spatial])
+        };
+        let (strides, dilations) = (ints("strides", 1), ints("dilations", 1));
+        let pads = attr(node, "pads")
+            .map(|a| a.ints.iter().map(|i| *i as usize).collect::<Vec<_>>())
+            .unwrap_or(vec![0; n_spatial * 2]);
+
+        let mut padding = vec![(0, 0); 2];
+        padding.extend((0..n_spatial).map(|i| (pads[i], pads[i + n_spatial])));
+        let x = if pads.iter().any(|p| *p != 0) {
+            x.pad(padding)
+        } else {
+            x
+        };
+        let (batch, ch_out) = (x.dims()[0], w_dims[0]);
+        let ch_in_kernel = w_dims[1..].iter().copied().product::<Expression>();
+        let weight = w.reshape((ch_out, ch_in_kernel));
+        let mut out = if n_spatial == 1 {
+            // (batch, ch_in, len) -> (batch, len_out, ch_in * kernel)
+            let pooled = x.pool_last_dim(kernel[0], strides[0], dilations[0]);
+            let len_out = pooled.dims()[2];
+            pooled
+                .permute((0, 2, 1, 3))
+                .reshape((batch, len_out, ch_in_kernel))
+                .matmul(weight.permute((1, 0)))
+                .permute((0, 2, 1))
+        } else {
+            // (batch, ch_in, x, y) -> (batch, ch_in * kernel_x * kernel_y, x_out * y_out)
+            let pooled = x
+                .pool_last_dim(kernel[1], strides[1], dilations[1])
+                .permute((0, 1, 3, 4, 2))
+                .pool_last_dim(kernel[0], strides[0], dilations[0])
+                .permute((0, 1, 5, 3, 4, 2));
+            let (x_out, y_out) = (pooled.dims()[4], pooled.dims()[5]);
+            weight
+                .expand_dim(0, batch)
+                .matmul(pooled.reshape((batch, ch_in_kernel, x_out * y_out)))
+                .reshape((batch, ch_out, x_out, y_out))
+        };
+        if let Some(Some(b)) = inputs.get(2) {
+            let mut b = self.tensor(b).expand_dim(0, batch);
+            for (i, d) in out.dims().into_iter().enumerate().skip(2) {
+                b = b.expand_dim(i, d);
+            }
+            out += b;
+        }
+        Ok(vec![Value::Tensor(out)])
+    }
+}
+
+fn expr(i: i64) -> Expression {
+    Expression::from(i.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+}
+
+fn norm_axis(axis: i64, rank: usize) -> Result<usize, String> {
+    let normed = if axis < 0 { axis + rank as i64 } else { axis };
+    if (0..rank as i64).contains(&normed) {
+        Ok(normed as usize)
+    } else {
+        Err(format!("axis {axis} out of range for rank {rank}"))
+    }
+}
+
+fn attr<'a>(node: &'a NodeProto, name: &str) -> Option<&'a AttributeProto> {
+    node.attribute.iter().find(|a| a.name == name)
+}
+
+fn attr_i(node: &NodeProto, name: &str, default: i64) -> i64 {
+    attr(node, name)
+        .filter(|a| a.r#type == attribute_type::INT)
+        .map(|a| a.i)
+        .unwrap_or(default)
+}
+
+fn attr_f(node: &NodeProto, name: &str, default: f32) -> f32 {
+    attr(node, name)
+        .filter(|a| a.r#type == attribute_type::FLOAT)
+        .map(|a| a.f)
+        .unwrap_or(default)
+}
+
+fn known(value: &Value) -> Result<Vec<Expression>, String> {
+    match value {
+        Value::Ints { values, .. } => Ok(values.clone()),
+        Value::Tensor(_) => Err("needs a constant input".to_string()),
+    }
+}
+
+fn known_ints(value: &Value) -> Result<Vec<i64>, String> {
+    known(value)?
+        .into_iter()
+        .map(|v| v.as_num().map(|i| i as i64))
+        .collect::<Option<_>>()
+        .ok_or("needs a non-symbolic input".to_string())
+}
+
+fn value_dims(value: &Value) -> Vec<Expression> {
+    match value {
+        Value::Tensor(t) => t.dims(),
+        Value::Ints { shape, .. } => shape.iter().map(Expression::from).collect(),
+    }
+}
+
+/// Read the data of a tensor proto, from either its raw data or typed fields
+fn tensor_data(t: &TensorProto) -> Result<Vec<f64>, OnnxError> {
+    let raw = &t.raw_data;
+    let err = || OnnxError::TensorType {
+        name: t.name.clone(),
+        data_type: t.data_type,
+    };
+    Ok(match t.data_type {
+        data_type::FLOAT if !raw.is_empty() => raw
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f64)
+            .collect(),
+        data_type::FLOAT => t.float_data.iter().map(|f| *f as f64).collect(),
+        data_type::DOUBLE if !raw.is_empty() => raw
+            .chunks_exact(8)
+            .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+            .collect(),
+        data_type::DOUBLE => t.double_data.clone(),
+        data_type::FLOAT16 | data_type::BFLOAT16 => {
+            let bits = if raw.is_empty() {
+                t.int32_data.iter().map(|i| *i as u16).collect::<Vec<_>>()
+            } else {
+                raw.chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect()
+            };
+            bits.into_iter()
+                .map(|b| {
+                    if t.data_type == data_type::FLOAT16 {
+                        f16::from_bits(b).to_f64()
+                    } else {
+                        bf16::from_bits(b).to_f64()
+                    }
+                })
+                .collect()
+        }
+        data_type::INT64 if !raw.is_empty() => raw
+            .chunks_exact(8)
+            .map(|c| i64::from_le_bytes(c.try_into().unwrap()) as f64)
+            .collect(),
+        data_type::INT64 => t.int64_data.iter().map(|i| *i as f64).collect(),
+        data_type::INT32 if !raw.is_empty() => raw
+            .chunks_exact(4)
+            .map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f64)
+            .collect(),
+        data_type::INT8 if !raw.is_empty() => raw.iter().map(|i| *i as i8 as f64).collect(),
+        data_type::UINT8 | data_type::BOOL if !raw.is_empty() => {
+            raw.iter().map(|i| *i as f64).collect()
+        }
+        data_type::INT32 | data_type::INT8 | data_type::UINT8 | data_type::BOOL => {
+            t.int32_data.iter().map(|i| *i as f64).collect()
+        }
+        _ => return Err(err()),
+    })
+}
+
+/// The shape two shapes broadcast to, following numpy rules
+fn broadcast_dims(a: &[Expression], b: &[Expression]) -> Vec<Expression> {
+    let rank = a.len().max(b.len());
+    (0..rank)
+        .map(|i| {
+            let a = (i + a.len()).checked_sub(rank).map(|i| a[i]);
+            let b = (i + b.len()).checked_sub(rank).map(|i| b[i]);
+            match (a, b) {
+                (Some(a), Some(b)) if a == 1 => b,
+                (Some(a), _) => a,
+                (None, Some(b)) => b,
+                _ => unreachable!(),
+            }
+        })
+        .collect()
+}
+
+/// Broadcast a tensor to a shape with at least as many dimensions
+fn broadcast_to(mut tensor: GraphTensor, dims: &[Expression]) -> GraphTensor {
+    let current = tensor.dims();
+    let offset = dims.len() - current.len();
+    // Size 1 dimensions need to be removed before being expanded
+    let ones = (0..current.len())
+        .filter(|i| current[*i] == 1 && dims[i + offset] != 1)
+        .collect::<Vec<_>>();
+    if !ones.is_empty() {
+        tensor = tensor.reshape(
+            (0..current.len())
+                .filter(|i| !ones.contains(i))
+                .map(|i| current[i])
+                .collect::<Vec<_>>(),
+        );
+        for i in &ones {
+            tensor = tensor.expand_dim(*i, dims[i + offset]);
+        }
+    }
+    for (i, d) in dims[..offset].iter().enumerate() {
+        tensor = tensor.expand_dim(i, *d);
+    }
+    tensor
+}
+
+fn broadcast(a: GraphTensor, b: GraphTensor) -> (GraphTensor, GraphTensor) {
+    let dims = broadcast_dims(&a.dims(), &b.dims());
+    (broadcast_to(a, &dims), broadcast_to(b, &dims))
+}
+
+/// Numpy-style matmul with broadcasted batch dimensions
+fn matmul(a: GraphTensor, b: GraphTensor) -> GraphTensor {
+    let (a_vec, b_vec) = (a.shape.len() == 1, b.shape.len() == 1);
+    let a = if a_vec { a.expand_dim(0, 1) } else { a };
+    let b = if b_vec { b.expand_dim(1, 1) } else { b };
+    let (a_dims, b_dims) = (a.dims(), b.dims());
+    let (m, n) = (a_dims[a_dims.len() - 2], b_dims[b_dims.len() - 1]);
+    let batch = broadcast_dims(&a_dims[..a_dims.len() - 2], &b_dims[..b_dims.len() - 2]);
+    let rank = batch.len() + 2;
+    let a = broadcast_to(a, &[&batch[..], &a_dims[a_dims.len() - 2..]].concat());
+    let b = broadcast_to(b, &[&batch[..], &b_dims[b_dims.len() - 2..]].concat());
+    let mut b_perm = (0..rank).collect::<Vec<_>>();
+    b_perm.swap(rank - 1, rank - 2);
+    // Broadcasted multiply, then sum reduce
+    let mut out = (a.expand_dim(rank - 1, n) * b.permute(b_perm).expand_dim(rank - 2, m)).sum(rank);
+    if a_vec || b_vec {
+        let mut dims = out.dims();
+        if b_vec {
+            dims.pop();
+        }
+        if a_vec {
+            dims.remove(dims.len() - 1 - b_vec as usize);
+        }
+        out = out.reshape(dims);
+    }
+    out
+}
+
+/// Gather slices along an axis with a tensor of indexes, using a one-hot matmul
+fn gather(a: GraphTensor, indexes: GraphTensor, axis: usize) -> GraphTensor {
+    let dims = a.dims();
+    let index_dims = indexes.dims();
+    let n = index_dims.iter().copied().product::<Expression>();
+    let size = dims[axis];
+    let one_hot = a
+        .graph()
+        .arange(size)
+        .expand_dim(0, n)
+        .eq(indexes.reshape(n).expand_dim(1, size));
+    let rest = (0..dims.len())
+        .filter(|i| *i != axis)
+        .map(|i| dims[i])
+        .collect::<Vec<_>>();
+    let mut perm = vec![axis];
+    perm.extend((0..dims.len()).filter(|i| *i != axis));
+    let flat = a
+        .permute(perm)
+        .reshape((size, rest.iter().copied().product::<Expression>()));
+    // (indexes.., before.., after..) -> (before.., indexes.., after..)
+    let out = one_hot
+        .matmul(flat)
+        .reshape([&index_dims[..], &rest].concat());
+    let k = index_dims.len();
+    let mut perm = (k..k + axis).collect::<Vec<_>>();
+    perm.extend(0..k);
+    perm.extend(k + axis..k + rest.len());
+    out.permute(perm)
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{prelude::*, tests::assert_close};
+    use prost::Message;
+
+    use crate::{
+        import_bytes,
+        proto::{
+            attribute_type, data_type, AttributeProto, Dimension, GraphProto, ModelProto,
+            NodeProto, TensorProto, TensorShapeProto, TypeProto, TypeProtoTensor, ValueInfoProto,
+        },
+    };
+
+    fn node(op: &str, inputs: &[&str], output: &str, attributes: Vec<AttributeProto>) -> NodeProto {
+        NodeProto {
+            input: inputs.iter().map(|s| s.to_string()).collect(),
+            output: vec![output.to_string()],
+            name: output.to_string(),
+            op_type: op.to_string(),
+            attribute: attributes,
+            ..Default::default()
+        }
+    }
+
+    fn ints(name: &str, ints: &[i64]) -> AttributeProto {
+        AttributeProto {
+            name: name.to_string(),
+            ints: ints.to_vec(),
+            r#type: attribute_type::INTS,
+            ..Default::default()
+        }
+    }
+
+    fn int(name: &str, i: i64) -> AttributeProto {
+        AttributeProto {
+            name: name.to_string(),
+            i,
+            r#type: attribute_type::INT,
+            ..Default::default()
+        }
+    }
+
+    fn floats(name: &str, dims: &[i64], data: &[f32]) -> TensorProto {
+        TensorProto {
+            name: name.to_string(),
+            dims: dims.to_vec(),
+            data_type: data_type::FLOAT,
+            raw_data: data.iter().flat_map(|f| f.to_le_bytes()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn int64s(name: &str, dims: &[i64], data: &[i64]) -> TensorProto {
+        TensorProto {
+            name: name.to_string(),
+            dims: dims.to_vec(),
+            data_type: data_type::INT64,
+            int64_data: data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn value_info(name: &str, dims: &[Result<i64, &str>]) -> ValueInfoProto {
+        ValueInfoProto {
+            name: name.to_string(),
+            r#type: Some(TypeProto {
+                tensor_type: Some(TypeProtoTensor {
+                    elem_type: data_type::FLOAT,
+                    shape: Some(TensorShapeProto {
+                        dim: dims
+                            .iter()
+                            .map(|d| Dimension {
+                                dim_value: d.ok(),
+                                dim_param: d.err().map(|s| s.to_string()),
+                            })
+                            .collect(),
+                    }),
+                }),
+            }),
+        }
+    }
+
+    fn encode(graph: GraphProto) -> Vec<u8> {
+        ModelProto {
+            ir_version: 8,
+            graph: Some(graph),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn test_import_mlp() {
+        // x (batch, 4) -> Gemm(transB) -> Relu -> MatMul -> Softmax, plus an unsupported branch
+        let w1 = [1., 0., -1., 2., 0.5, 0.5, 0.5, 0.5, -1., 1., 0., 0.];
+        let b1 = [0., 1., -1.];
+        let w2 = [1., -1., 0., 2., 1., 1.];
+        let graph = GraphProto {
+            node: vec![
+                node("Gemm", &["x", "w1", "b1"], "h", vec![int("transB", 1)]),
+                node("Relu", &["h"], "r", vec![]),
+                node("MatMul", &["r", "w2"], "logits", vec![]),
+                node("Softmax", &["logits"], "probs", vec![int("axis", -1)]),
+                node("Erf", &["x"], "erf", vec![]),
+                node("Add", &["erf", "x"], "unused", vec![]),
+            ],
+            initializer: vec![
+                floats("w1", &[3, 4], &w1),
+                floats("b1", &[3], &b1),
+                floats("w2", &[3, 2], &w2),
+            ],
+            input: vec![value_info("x", &[Err("batch"), Ok(4)])],
+            output: vec![
+                value_info("probs", &[Err("batch"), Ok(2)]),
+                value_info("unused", &[Err("batch"), Ok(4)]),
+            ],
+            ..Default::default()
+        };
+        let mut cx = Graph::new();
+        let model = import_bytes(&encode(graph), &mut cx).unwrap();
+        assert_eq!(model.unsupported, vec!["Erf".to_string()]);
+        assert_eq!(model.outputs.len(), 1);
+        assert_eq!(model.weights.len(), 3);
+        let batch = model.dyn_dims["batch"];
+
+        let x = [1., 2., 3., 4., -1., 0., 1., 0.];
+        cx.set_dyn_dim(batch, 2);
+        model.input("x").unwrap().set_dyn(x.to_vec(), (2, 4));
+        cx.execute();
+
+        let mut expected = vec![];
+        for row in x.chunks(4) {
+            let r = (0..3)
+                .map(|j| (0..4).map(|k| row[k] * w1[j * 4 + k]).sum::<f32>() + b1[j])
+                .map(|h| h.max(0.))
+                .collect::<Vec<_>>();
+            let logits = (0..2)
+                .map(|j| (0..3).map(|k| r[k] * w2[k * 2 + j]).sum::<f32>())
+                .collect::<Vec<_>>();
+            let sum = logits.iter().map(|l| l.exp()).sum::<f32>();
+            expected.extend(logits.iter().map(|l| l.exp() / sum));
+        }
+        assert_close(&model.output("probs").unwrap().data(), &expected);
+    }
+
+    #[test]
+    fn test_import_movement() {
+        // Reshape using a computed shape, then Transpose, Slice, Gather, Concat and LayerNorm
+        let graph = GraphProto {
+            node: vec![
+                node("Shape", &["x"], "shape", vec![]),
+                node("Gather", &["shape", "zero"], "n", vec![int("axis", 0)]),
+                node("Unsqueeze", &["n", "zero_axes"], "n1", vec![]),
+                node(
+                    "Concat",
+                    &["n1", "minus_one"],
+                    "new_shape",
+                    vec![int("axis", 0)],
+                ),
+                node("Reshape", &["x", "new_shape"], "flat", vec![]),
+                node("Transpose", &["flat"], "t", vec![ints("perm", &[1, 0])]),
+                node("Slice", &["t", "starts", "ends", "zero_axes"], "s", vec![]),
+                node("Gather", &["s", "idx"], "g", vec![int("axis", 1)]),
+                node("Concat", &["g", "g"], "c", vec![int("axis", 0)]),
+                node("LayerNormalization", &["c", "scale"], "out", vec![]),
+            ],
+            initializer: vec![
+                int64s("zero", &[], &[0]),
+                int64s("zero_axes", &[1], &[0]),
+                int64s("minus_one", &[1], &[-1]),
+                int64s("starts", &[1], &[1]),
+                int64s("ends", &[1], &[i64::MAX]),
+                int64s("idx", &[2], &[1, 0]),
+                floats("scale", &[2], &[1., 2.]),
+            ],
+            input: vec![value_info("x", &[Ok(2), Ok(2), Ok(2)])],
+            output: vec![value_info("out", &[Ok(6), Ok(2)])],
+            ..Default::default()
+        };
+        let mut cx = Graph::new();
+        let model = import_bytes(&encode(graph), &mut cx).unwrap();
+        assert!(model.unsupported.is_empty());
+        model
+            .input("x")
+            .unwrap()
+            .set(vec![1., 2., 3., 4., 5., 7., 6., 9.]);
+        cx.execute();
+
+        // flat = [[1, 2, 3, 4], [5, 7, 6, 9]], t[1..] = [[2, 7], [3, 6], [4, 9]], gathered to [[7, 2], [6, 3], [9, 4]]
+        // Every row has a larger first value, so normalizes to [1, -1] before scaling
+        let expected = [1., -2.].repeat(6);
+        assert_close(&model.output("out").unwrap().data(), &expected);
+    }
+
+    #[test]
+    fn test_import_where_cast() {
+        let graph = GraphProto {
+            node: vec![
+                node("Where", &["cond", "x", "y"], "where", vec![]),
+                node(
+                    "Cast",
+                    &["x"],
+                    "int",
+                    vec![int("to", data_type::INT64 as i64)],
+                ),
+                node("Cast", &["x"], "string", vec![int("to", 8)]),
+            ],
+            initializer: vec![
+                floats("cond", &[4], &[1., 0., 1., 0.]),
+                floats("y", &[4], &[f32::NEG_INFINITY, 5., f32::NAN, 7.]),
+            ],
+            input: vec![value_info("x", &[Ok(4)])],
+            output: vec![
+                value_info("where", &[Ok(4)]),
+                value_info("int", &[Ok(4)]),
+                value_info("string", &[Ok(4)]),
+            ],
+            ..Default::default()
+        };
+        let mut cx = Graph::new();
+        let model = import_bytes(&encode(graph), &mut cx).unwrap();
+        assert_eq!(
+            model.unsupported,
+            vec!["Cast: unsupported target type 8".to_string()]
+        );
+        model.input("x").unwrap().set(vec![1.7, -2.5, 0.5, 3.9]);
+        cx.execute();
+
+        // The unselected infinities and NaNs don't leak into the output
+        assert_eq!(
+            model.output("where").unwrap().data(),
+            vec![1.7, 5., 0.5, 7.]
+        );
+        assert_eq!(model.output("int").unwrap().data(), vec![1., -2., 0., 3.]);
+    }
+
+    #[test]
+    fn test_import_too_many_dyn_dims() {
+        let dims = (0..53).map(|_| Err("")).collect::<Vec<_>>();
+        let graph = GraphProto {
+            node: vec![node("Relu", &["x"], "out", vec![])],
+            input: vec![value_info("x", &dims)],
+            output: vec![value_info("out", &dims)],
+            ..Default::default()
+        };
+        let mut cx = Graph::new();
+        assert!(matches!(
+            import_bytes(&encode(graph), &mut cx),
+            Err(crate::OnnxError::TooManyDynDims(_))
+        ));
+    }
+
+    #[test]
+    fn test_import_malformed_nodes() {
+        let graph = GraphProto {
+            node: vec![
+                node("Shape", &["x"], "shape", vec![]),
+                node("Gather", &["shape", "zero"], "batch", vec![]),
+                // Known values indexed by symbolic indexes
+                node("Gather", &["table", "shape"], "symbolic_gather", vec![]),
+                node("Gather", &["x", "batch"], "symbolic_slice", vec![]),
+                node("Unsqueeze", &["x"], "unsqueezed", vec![ints("axes", &[5])]),
+                node("Softmax", &["x"], "softmax", vec![int("axis", -3)]),
+                node("Conv", &["x", "bias"], "rank_1_weight", vec![]),
+                node("Conv", &["signal", "kernel"], "symbolic_kernel", vec![]),
+                node("Relu", &["x"], "out", vec![]),
+            ],
+            initializer: vec![
+                int64s("zero", &[], &[0]),
+                int64s("table", &[3], &[1, 2, 3]),
+                floats("bias", &[4], &[0.; 4]),
+            ],
+            input: vec![
+                value_info("x", &[Err("batch"), Ok(4)]),
+                value_info("signal", &[Ok(1), Ok(1), Ok(8)]),
+                value_info("kernel", &[Ok(1), Ok(1), Err("k")]),
+            ],
+            output: vec![value_info("out", &[Err("batch"), Ok(4)])],
+            ..Default::default()
+        };
+        let mut cx = Graph::new();
+        let model = import_bytes(&encode(graph), &mut cx).unwrap();
+        assert_eq!(
+            model.unsupported,
+            vec![
+                "Gather: symbolic index",
+                "Unsqueeze: axis 5 out of range for rank 3",
+                "Softmax: axis -3 out of range for rank 2",
+                "Conv: 0D convolutions",
+                "Conv: symbolic kernel size",
+            ]
+        );
+
+        // Constants must have as many elements as their dims describe
+        let graph = GraphProto {
+            node: vec![node("Relu", &["weight"], "out", vec![])],
+            initializer: vec![floats("weight", &[3], &[1., 2.])],
+            output: vec![value_info("out", &[Ok(3)])],
+            ..Default::default()
+        };
+        let mut cx = Graph::new();
+        assert!(matches!(
+            import_bytes(&encode(graph), &mut cx),
+            Err(crate::OnnxError::TensorSize { n_elements: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_import_conv() {
+        // 2D conv with padding, stride and bias
+        let w = [1., 0., 0., -1., 2., 1., 1., 0.];
+        let graph = GraphProto {
+            node: vec![node(
+                "Conv",
+                &["x", "w", "b"],
+                "y",
+                vec![ints("pads", &[1, 0, 0, 0]), ints("strides", &[1, 2])],
+            )],
+            initializer: vec![
+                floats("w", &[2, 1, 2, 2], &w),
+                floats("b", &[2], &[0.5, -0.5]),
+            ],
+            input: vec![value_info("x", &[Ok(1), Ok(1), Ok(3), Ok(4)])],
+            output: vec![value_info("y", &[Ok(1), Ok(2), Ok(3), Ok(2)])],
+            ..Default::default()
+        };
+        let mut cx = Graph::new();
+        let model = import_bytes(&encode(graph), &mut cx).unwrap();
+        assert!(model.unsupported.is_empty());
+        let x = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+        model.input("x").unwrap().set(x.clone());
+        cx.execute();
+
+        let padded = [vec![0.; 4], x].concat();
+        let mut expected = vec![];
+        for o in 0..2 {
+            for i in 0..3 {
+                for j in 0..2 {
+                    let mut sum = [0.5, -0.5][o];
+                    for ki in 0..2 {
+                        for kj in 0..2 {
+                            sum += padded[(i + ki) * 4 + j * 2 + kj] * w[o * 4 + ki * 2 + kj];
+                        }
+                    }
+                    expected.push(sum);
+                }
+            }
+        }
+        assert_close(&model.output("y").unwrap().data(), &expected);
+    }
+}
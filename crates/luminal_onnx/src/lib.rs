@@ -0,0 +1,80 @@
+//! Importing ONNX models into luminal graphs, and exporting un-compiled graphs to ONNX.
+//!
+//! Spec: https://onnx.ai/onnx/intro/concepts.html
+
+mod export;
+mod import;
+pub mod proto;
+
+pub use export::*;
+pub use import::*;
+
+use std::fmt::Display;
+
+/// An error encountered when reading or writing an ONNX model
+#[derive(Debug)]
+pub enum OnnxError {
+    /// Reading or writing the file failed
+    Io(std::io::Error),
+    /// The file isn't a valid ONNX protobuf
+    Decode(prost::DecodeError),
+    /// The model doesn't contain a graph
+    MissingGraph,
+    /// A tensor has a data type that can't be read
+    TensorType { name: String, data_type: i32 },
+    /// A tensor's data doesn't have the number of elements its dims describe
+    TensorSize {
+        name: String,
+        dims: Vec<i64>,
+        n_elements: usize,
+    },
+    /// The graph contains an op that can't be exported
+    UnsupportedOp(String),
+    /// A dimension depends on a dyn dim that isn't set in the graph's `dyn_map`
+    UnresolvedDim(String),
+    /// The model has more symbolic dimensions than there are dyn dims to map them to
+    TooManyDynDims(String),
+    /// A value is used by the exported graph but was never produced
+    MissingValue(String),
+}
+
+impl Display for OnnxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnnxError::Io(e) => write!(f, "IO error: {e}"),
+            OnnxError::Decode(e) => write!(f, "Invalid ONNX protobuf: {e}"),
+            OnnxError::MissingGraph => write!(f, "Model doesn't contain a graph"),
+            OnnxError::TensorType { name, data_type } => {
+                write!(f, "Tensor {name} has unsupported data type {data_type}")
+            }
+            OnnxError::TensorSize {
+                name,
+                dims,
+                n_elements,
+            } => write!(
+                f,
+                "Tensor {name} has {n_elements} elements, which doesn't match its dims {dims:?}"
+            ),
+            OnnxError::UnsupportedOp(op) => write!(f, "Can't export op {op}"),
+            OnnxError::UnresolvedDim(dim) => write!(f, "Dimension {dim} can't be resolved"),
+            OnnxError::TooManyDynDims(dim) => {
+                write!(f, "No dyn dim is left for symbolic dimension {dim}")
+            }
+            OnnxError::MissingValue(value) => write!(f, "Value {value} is used but never produced"),
+        }
+    }
+}
+
+impl std::error::Error for OnnxError {}
+
+impl From<std::io::Error> for OnnxError {
+    fn from(value: std::io::Error) -> Self {
+        OnnxError::Io(value)
+    }
+}
+
+impl From<prost::DecodeError> for OnnxError {
+    fn from(value: prost::DecodeError) -> Self {
+        OnnxError::Decode(value)
+    }
+}
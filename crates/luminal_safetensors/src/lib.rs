@@ -0,0 +1,86 @@
+//! Loading and saving `SerializeModule` weights with the safetensors format.
+//!
+//! Spec: https://github.com/huggingface/safetensors
+
+mod load;
+mod save;
+
+pub use load::*;
+pub use save::*;
+
+use std::fmt::Display;
+
+pub use safetensors::Dtype;
+
+/// The default mapping from a `param_dict` name to a tensor name in the file
+pub fn default_name(weight_name: &str) -> String {
+    weight_name.replace('/', ".")
+}
+
+/// An error encountered when loading or saving a safetensors file
+#[derive(Debug)]
+pub enum SafetensorsError {
+    /// Reading or writing the file failed
+    Io(std::io::Error),
+    /// The file isn't valid safetensors
+    Format(safetensors::SafeTensorError),
+    /// A weight of the model isn't in the file
+    MissingTensor(String),
+    /// A tensor in the file doesn't belong to any weight of the model
+    ExtraTensor(String),
+    /// A tensor in the file has a different shape than its weight
+    Shape {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    /// A tensor has a dtype that can't be loaded or saved
+    DType { name: String, dtype: String },
+    /// A weight has no data in the graph to save
+    MissingData(String),
+}
+
+impl Display for SafetensorsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SafetensorsError::Io(e) => write!(f, "IO error: {e}"),
+            SafetensorsError::Format(e) => write!(f, "Invalid safetensors file: {e}"),
+            SafetensorsError::MissingTensor(t) => write!(f, "Tensor {t} not found in file"),
+            SafetensorsError::ExtraTensor(t) => {
+                write!(
+                    f,
+                    "Tensor {t} in file doesn't match any weight of the model"
+                )
+            }
+            SafetensorsError::Shape {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Tensor {name} has shape {found:?}, but the model expects {expected:?}"
+            ),
+            SafetensorsError::DType { name, dtype } => {
+                write!(f, "Tensor {name} has unsupported dtype {dtype}")
+            }
+            SafetensorsError::MissingData(w) => write!(
+                f,
+                "Weight {w} has no data in the graph, make sure it's kept after execution"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SafetensorsError {}
+
+impl From<std::io::Error> for SafetensorsError {
+    fn from(value: std::io::Error) -> Self {
+        SafetensorsError::Io(value)
+    }
+}
+
+impl From<safetensors::SafeTensorError> for SafetensorsError {
+    fn from(value: safetensors::SafeTensorError) -> Self {
+        SafetensorsError::Format(value)
+    }
+}
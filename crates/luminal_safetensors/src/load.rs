@@ -0,0 +1,229 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use luminal::prelude::*;
+use memmap2::Mmap;
+use safetensors::{Dtype, SafeTensors};
+
+use crate::{default_name, SafetensorsError};
+
+/// Loads the weights of a `SerializeModule` from a safetensors file.
+///
+/// By default every weight must be in the file with the same shape, and every tensor in the file must belong to a weight.
+pub struct Loader {
+    path: PathBuf,
+    rename: Box<dyn Fn(&str) -> String>,
+    allow_missing: bool,
+    allow_extra: bool,
+}
+
+impl Loader {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            rename: Box::new(default_name),
+            allow_missing: false,
+            allow_extra: false,
+        }
+    }
+
+    /// Map `param_dict` names to tensor names in the file. Defaults to replacing `/` with `.`
+    pub fn rename(mut self, rename: impl Fn(&str) -> String + 'static) -> Self {
+        self.rename = Box::new(rename);
+        self
+    }
+
+    /// Leave weights that aren't in the file unset instead of erroring
+    pub fn allow_missing(mut self) -> Self {
+        self.allow_missing = true;
+        self
+    }
+
+    /// Ignore tensors in the file that don't belong to any weight instead of erroring
+    pub fn allow_extra(mut self) -> Self {
+        self.allow_extra = true;
+        self
+    }
+
+    /// Set the weights of a model to be loaded from the file when the graph is ran. The file is memory mapped and f16 / bf16 tensors are converted to f32.
+    ///
+    /// All weights are checked against the file up front, so on error the graph is left untouched.
+    pub fn load(
+        &self,
+        model: &impl SerializeModule,
+        graph: &mut Graph,
+    ) -> Result<(), SafetensorsError> {
+        let file = File::open(&self.path)?;
+        // Safety: the file is only read, and must not be modified while the mapping is alive
+        let mmap = Arc::new(unsafe { Mmap::map(&file)? });
+        let (header_len, metadata) = SafeTensors::read_metadata(&mmap)?;
+        let data_start = 8 + header_len;
+        let mut file_tensors = metadata.tensors();
+
+        let shapes = param_shapes(model);
+        let mut weights = param_dict(model).into_iter().collect::<Vec<_>>();
+        weights.sort();
+        let mut loads = vec![];
+        for (weight_name, node_index) in weights {
+            let tensor_name = (self.rename)(&weight_name);
+            let Some(info) = file_tensors.remove(&tensor_name) else {
+                if self.allow_missing {
+                    continue;
+                }
+                return Err(SafetensorsError::MissingTensor(tensor_name));
+            };
+            let mut shape = shapes[&weight_name];
+            shape.resolve_global_dyn_dims(&graph.dyn_map);
+            let expected = shape.shape_usize();
+            if expected != info.shape {
+                return Err(SafetensorsError::Shape {
+                    name: tensor_name,
+                    expected,
+                    found: info.shape.clone(),
+                });
+            }
+            if !matches!(info.dtype, Dtype::F32 | Dtype::F16 | Dtype::BF16) {
+                return Err(SafetensorsError::DType {
+                    name: tensor_name,
+                    dtype: format!("{:?}", info.dtype),
+                });
+            }
+            let (start, end) = info.data_offsets;
+            loads.push((
+                node_index,
+                shapes[&weight_name],
+                info.dtype,
+                data_start + start..data_start + end,
+            ));
+        }
+        if !self.allow_extra {
+            if let Some(name) = file_tensors.keys().min() {
+                return Err(SafetensorsError::ExtraTensor(name.clone()));
+            }
+        }
+
+        for (node_index, shape, dtype, range) in loads {
+            let mmap = mmap.clone();
+            GraphTensor::from_id(node_index, shape, graph)
+                .set_deferred(move || to_f32(dtype, &mmap[range.clone()]));
+        }
+        Ok(())
+    }
+}
+
+/// Set the weights of a model to be loaded from a safetensors file when the graph is ran. Weights are matched to tensors by their `param_dict` name with `/` replaced by `.`
+pub fn load_into<P: AsRef<Path>>(
+    path: P,
+    model: &impl SerializeModule,
+    graph: &mut Graph,
+) -> Result<(), SafetensorsError> {
+    Loader::new(path).load(model, graph)
+}
+
+fn to_f32(dtype: Dtype, bytes: &[u8]) -> Vec<f32> {
+    match dtype {
+        Dtype::F32 => bytes
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+        Dtype::F16 => bytes
+            .chunks_exact(2)
+            .map(|c| f16::from_le_bytes([c[0], c[1]]).to_f32())
+            .collect(),
+        Dtype::BF16 => bytes
+            .chunks_exact(2)
+            .map(|c| bf16::from_le_bytes([c[0], c[1]]).to_f32())
+            .collect(),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{prelude::*, tests::assert_exact};
+    use luminal_nn::Linear;
+    use safetensors::{tensor::TensorView, Dtype};
+
+    use crate::{load_into, save, Loader, SafetensorsError};
+
+    #[test]
+    fn test_save_load() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!(
+            "luminal_safetensors_{}.safetensors",
+            std::process::id()
+        ));
+
+        // Save a model's weights
+        let mut cx = Graph::new();
+        let model = Linear::new(2, 3, true, &mut cx);
+        model.weight.set(vec![1., 0., 2., 0., 1., 1.]).keep();
+        model.bias.unwrap().set(vec![0., 0., 1.]).keep();
+        cx.execute();
+        save(&path, &model, &cx).unwrap();
+
+        // Load them into a new model
+        let mut cx = Graph::new();
+        let model = Linear::new(2, 3, true, &mut cx);
+        let inp = cx.tensor(2).set(vec![2., 3.]);
+        let out = model.forward(inp).retrieve();
+        load_into(&path, &model, &mut cx).unwrap();
+        cx.execute();
+        assert_exact(&out.data(), &[2., 3., 8.]);
+
+        // Errors
+        let mut cx = Graph::new();
+        let wrong_shape = Linear::new(3, 2, true, &mut cx);
+        assert!(matches!(
+            load_into(&path, &wrong_shape, &mut cx),
+            Err(SafetensorsError::Shape { .. })
+        ));
+        let no_bias = Linear::new(2, 3, false, &mut cx);
+        assert!(matches!(
+            load_into(&path, &no_bias, &mut cx),
+            Err(SafetensorsError::ExtraTensor(n)) if n == "bias"
+        ));
+        Loader::new(&path)
+            .allow_extra()
+            .load(&no_bias, &mut cx)
+            .unwrap();
+        let renamed = (Linear::new(2, 3, true, &mut cx),);
+        assert!(matches!(
+            load_into(&path, &renamed, &mut cx),
+            Err(SafetensorsError::MissingTensor(n)) if n == "0.bias"
+        ));
+        Loader::new(&path)
+            .rename(|n| n.trim_start_matches("0/").to_string())
+            .load(&renamed, &mut cx)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Half precision weights are converted
+        let weight = [1., 0., 2., 0., 1., 1.].map(|f: f32| bf16::from_f32(f).to_le_bytes());
+        let bias = [0., 0., 1.].map(|f: f32| f16::from_f32(f).to_le_bytes());
+        let (weight, bias) = (weight.concat(), bias.concat());
+        safetensors::serialize_to_file(
+            [
+                (
+                    "weight",
+                    TensorView::new(Dtype::BF16, vec![2, 3], &weight).unwrap(),
+                ),
+                ("bias", TensorView::new(Dtype::F16, vec![3], &bias).unwrap()),
+            ],
+            &None,
+            &path,
+        )
+        .unwrap();
+        let mut cx = Graph::new();
+        let model = Linear::new(2, 3, true, &mut cx);
+        let inp = cx.tensor(2).set(vec![2., 3.]);
+        let out = model.forward(inp).retrieve();
+        load_into(&path, &model, &mut cx).unwrap();
+        cx.execute();
+        std::fs::remove_file(&path).unwrap();
+        assert_exact(&out.data(), &[2., 3., 8.]);
+    }
+}
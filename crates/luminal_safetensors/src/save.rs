@@ -0,0 +1,59 @@
+use std::path::Path;
+
+use luminal::prelude::*;
+use safetensors::{tensor::TensorView, Dtype};
+
+use crate::{default_name, SafetensorsError};
+
+/// Save the weights of a model to a safetensors file, named by their `param_dict` name with `/` replaced by `.`
+///
+/// The weights must have data in the graph, for instance by being kept with `keep()` before execution. f32, f16 and bf16 data is saved as-is.
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    model: &impl SerializeModule,
+    graph: &Graph,
+) -> Result<(), SafetensorsError> {
+    save_renamed(path, model, graph, default_name)
+}
+
+/// Like `save`, but with a custom mapping from `param_dict` names to tensor names in the file
+pub fn save_renamed<P: AsRef<Path>>(
+    path: P,
+    model: &impl SerializeModule,
+    graph: &Graph,
+    rename: impl Fn(&str) -> String,
+) -> Result<(), SafetensorsError> {
+    let shapes = param_shapes(model);
+    let mut tensors = vec![];
+    for (weight_name, node_index) in param_dict(model) {
+        let tensor = graph
+            .get_tensor_ref(node_index, 0)
+            .ok_or_else(|| SafetensorsError::MissingData(weight_name.clone()))?;
+        let (dtype, bytes): (_, Vec<u8>) = if let Some(d) = tensor.downcast_ref::<Vec<f32>>() {
+            (Dtype::F32, d.iter().flat_map(|f| f.to_le_bytes()).collect())
+        } else if let Some(d) = tensor.downcast_ref::<Vec<f16>>() {
+            (Dtype::F16, d.iter().flat_map(|f| f.to_le_bytes()).collect())
+        } else if let Some(d) = tensor.downcast_ref::<Vec<bf16>>() {
+            (
+                Dtype::BF16,
+                d.iter().flat_map(|f| f.to_le_bytes()).collect(),
+            )
+        } else {
+            return Err(SafetensorsError::DType {
+                name: weight_name,
+                dtype: format!("{:?}", tensor.dtype()),
+            });
+        };
+        let mut shape = shapes[&weight_name];
+        shape.resolve_global_dyn_dims(&graph.dyn_map);
+        tensors.push((rename(&weight_name), dtype, shape.shape_usize(), bytes));
+    }
+    let views = tensors
+        .iter()
+        .map(|(name, dtype, shape, bytes)| {
+            Ok((name, TensorView::new(*dtype, shape.clone(), bytes)?))
+        })
+        .collect::<Result<Vec<_>, SafetensorsError>>()?;
+    safetensors::serialize_to_file(views, &None, path.as_ref())?;
+    Ok(())
+}
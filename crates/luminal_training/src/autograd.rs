@@ -6,8 +6,8 @@ use rustc_hash::{FxHashMap, FxHashSet};
 
 use luminal::{
     op::{
-        Add, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce, Mod, Mul, Recip, Sin, Sqrt,
-        SumReduce,
+        Add, ArgSort, Contiguous, Exp2, Function, If, LessThan, Log2, MaxReduce, Mod, Mul, Recip,
+        Select, Sin, Sqrt, SumReduce,
     },
     prelude::{tinyvec::ArrayVec, *},
 };
@@ -75,7 +75,10 @@ impl Compiler for Autograd {
             if op == TypeId::of::<Function>() {
                 continue;
             }
-            if op == TypeId::of::<Mod>() || op == TypeId::of::<LessThan>() {
+            if op == TypeId::of::<Mod>()
+                || op == TypeId::of::<LessThan>()
+                || op == TypeId::of::<ArgSort>()
+            {
                 assert!(
                     !weight_set.contains(&fwd_node),
                     "{fwd_node:?} is marked as a weight but is undifferentiable: {:?}",
@@ -143,6 +146,37 @@ impl Compiler for Autograd {
                     let grad = inps[0].eq(reduced) * prev_grad;
                     add_grad(grad, inps[0], graph, &mut grads);
                 }
+            } else if let Some(op) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
+                .try_get_op::<If>(fwd_node)
+                .cloned()
+            {
+                // f(p, x) = then(x) if p else else(x)
+                // f'(x) = then'(x) if p else else'(x)
+                assert_eq!(
+                    op.then_branch.arity().1,
+                    1,
+                    "Only conditionals with a single output can be differentiated: {op:?}"
+                );
+                let input_grads = inps[0].cond(
+                    &vjp(&op.then_branch),
+                    &vjp(&op.else_branch),
+                    &[&inps[1..], &[prev_grad]].concat(),
+                );
+                for (grad, inp) in input_grads.into_iter().zip(&inps[1..]) {
+                    if valid_set.contains(&inp.id) {
+                        add_grad(grad, *inp, graph, &mut grads);
+                    }
+                }
+            } else if op == TypeId::of::<Select>() {
+                // f(c, a, b) = a if c else b
+                // df/da = c, df/db = !c
+                let zeros = graph.constant(0.).expand(prev_grad.dims());
+                if valid_set.contains(&inps[1].id) {
+                    add_grad(inps[0].select(prev_grad, zeros), inps[1], graph, &mut grads);
+                }
+                if valid_set.contains(&inps[2].id) {
+                    add_grad(inps[0].select(zeros, prev_grad), inps[2], graph, &mut grads);
+                }
             } else if op == TypeId::of::<Contiguous>() {
                 if valid_set.contains(&inps[0].id) {
                     add_grad(prev_grad, inps[0], graph, &mut grads);
@@ -183,6 +217,43 @@ impl Compiler for Autograd {
     }
 }
 
+/// Build a subgraph computing the vector-Jacobian product of a single-output subgraph.
+///
+/// It takes the original inputs followed by the output's gradient, and outputs the gradient of each input.
+fn vjp(subgraph: &SubGraph) -> SubGraph {
+    subgraph.extend(
+        format!("{} Grad", subgraph.name()),
+        |cx, mut inputs, outputs| {
+            let output = outputs[0];
+            let output_grad = cx.named_tensor("Output Grad", output.dims());
+            let loss = (output * output_grad).sum((0..output.shape.len()).collect_vec());
+
+            // Inputs that don't affect the output get a zero gradient
+            let reachable = build_dfs_set(&mut vec![loss.id], cx, Direction::Incoming);
+            let params = inputs
+                .iter()
+                .map(|i| i.id)
+                .filter(|i| reachable.contains(i))
+                .collect_vec();
+            let param_grads = cx.compile(Autograd(params.clone(), loss.id), ());
+            let grads = inputs
+                .iter()
+                .map(|input| {
+                    let mut grad = match params.iter().position(|p| *p == input.id) {
+                        Some(i) => GraphTensor::from_id(param_grads[i].0, param_grads[i].1, cx),
+                        None => cx.constant(0.).expand(input.dims()),
+                    }
+                    .contiguous();
+                    grad.shape = input.shape;
+                    grad
+                })
+                .collect();
+            inputs.push(output_grad);
+            (inputs, grads)
+        },
+    )
+}
+
 fn add_grad(
     mut grad: GraphTensor,
     fwd: GraphTensor,
@@ -264,6 +335,140 @@ mod tests {
         assert_exact(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
     }
 
+    #[test]
+    fn test_autograd_select() {
+        let mut cx = Graph::new();
+        let cond = cx.named_tensor("Cond", 3).set([1., 0., 1.]);
+        let a = cx.named_tensor("A", 3).set([1., 2., 3.]);
+        let b = cx.named_tensor("B", 3).set([4., 5., 6.]);
+        let loss = (cond.select(a * a, b * 3.)).sum(0);
+
+        let grads = cx.compile(Autograd::new((a, b), loss), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        assert_exact(&get_vec(grads[0], &mut cx), &[2., 0., 6.]);
+        assert_exact(&get_vec(grads[1], &mut cx), &[0., 3., 0.]);
+    }
+
+    #[test]
+    fn test_autograd_cond() {
+        let sin_branch = SubGraph::new("Sin", |cx| {
+            let x = cx.named_tensor("X", 3);
+            let w = cx.named_tensor("W", 3);
+            (vec![x, w], vec![(x * w).sin()])
+        });
+        let square_branch = SubGraph::new("Square", |cx| {
+            let x = cx.named_tensor("X", 3);
+            let w = cx.named_tensor("W", 3);
+            (vec![x, w], vec![x * x])
+        });
+        let (x_data, w_data) = ([1., 2., 3.], [0.5, -1., 2.]);
+        let mut cx = Graph::new();
+        let x = cx.named_tensor("X", 3).set(x_data);
+        let w = cx.named_tensor("W", 3).set(w_data);
+        let yes = cx.named_tensor("True", ()).set(vec![1.]);
+        let no = cx.named_tensor("False", ()).set(vec![0.]);
+        let then_loss = yes.cond(&sin_branch, &square_branch, &[x, w])[0].sum(0);
+        let else_loss = no.cond(&sin_branch, &square_branch, &[x, w])[0].sum(0);
+
+        let then_grads = cx.compile(Autograd::new((x, w), then_loss), ());
+        let else_grads = cx.compile(Autograd::new((x, w), else_loss), ());
+        cx.keep_tensors(&then_grads);
+        cx.keep_tensors(&else_grads);
+        cx.execute();
+
+        // d/dx sin(x * w) = cos(x * w) * w, d/dw sin(x * w) = cos(x * w) * x
+        let (x_grad, w_grad): (Vec<f32>, Vec<f32>) = x_data
+            .iter()
+            .zip(&w_data)
+            .map(|(x, w): (&f32, &f32)| ((x * w).cos() * w, (x * w).cos() * x))
+            .unzip();
+        assert_close(&get_vec(then_grads[0], &mut cx), &x_grad);
+        assert_close(&get_vec(then_grads[1], &mut cx), &w_grad);
+        // d/dx x^2 = 2x, and w is unused
+        assert_close(&get_vec(else_grads[0], &mut cx), &[2., 4., 6.]);
+        assert_close(&get_vec(else_grads[1], &mut cx), &[0., 0., 0.]);
+    }
+
+    #[test]
+    fn test_autograd_gather_scatter() {
+        let mut cx = Graph::new();
+        let embeddings = cx
+            .named_tensor("Embeddings", (3, 2))
+            .set([[1., 2.], [3., 4.], [5., 6.]]);
+        let indexes = cx.named_tensor("Indexes", 3).set([2., 0., 2.]);
+        let weights = cx
+            .named_tensor("Weights", (3, 2))
+            .set([[1., 2.], [3., 4.], [5., 6.]]);
+        let gather_loss = (embeddings.gather(indexes) * weights).sum((0, 1));
+        let target = cx.named_tensor("Target", (2, 2)).set([[1., 2.], [3., 4.]]);
+        let src = cx.named_tensor("Src", (1, 2)).set([[5., 6.]]);
+        let rows = cx.named_tensor("Rows", (1, 2)).set([[1., 0.]]);
+        let scatter_loss = (target.scatter(0, rows, src) * weights.slice((..2, ..))).sum((0, 1));
+
+        let gather_grads = cx.compile(Autograd::new(embeddings, gather_loss), ());
+        let scatter_grads = cx.compile(Autograd::new((target, src), scatter_loss), ());
+        cx.keep_tensors(&gather_grads);
+        cx.keep_tensors(&scatter_grads);
+        cx.execute();
+
+        // Gather's gradient scatter-adds the output gradient back into the gathered rows
+        assert_exact(
+            &get_vec(gather_grads[0], &mut cx),
+            &[3., 4., 0., 0., 6., 8.],
+        );
+        // Overwritten positions get no gradient, and each src value gets the gradient of where it lands
+        assert_exact(&get_vec(scatter_grads[0], &mut cx), &[1., 0., 0., 4.]);
+        assert_exact(&get_vec(scatter_grads[1], &mut cx), &[3., 2.]);
+    }
+
+    #[test]
+    fn test_autograd_index_select() {
+        let mut cx = Graph::new();
+        let a = cx
+            .named_tensor("A", (2, 3))
+            .set([[1., 2., 3.], [4., 5., 6.]]);
+        let weights = cx
+            .named_tensor("Weights", (2, 3))
+            .set([[1., 2., 3.], [4., 5., 6.]]);
+        let indexes = cx.named_tensor("Indexes", 3).set([2., 0., 2.]);
+        let select_loss = (a.index_select(1, indexes) * weights).sum((0, 1));
+        let along = cx.named_tensor("Along", (2, 2)).set([[1., 1.], [0., 2.]]);
+        let along_loss = (a.take_along_axis(1, along) * weights.slice((.., ..2))).sum((0, 1));
+
+        let select_grads = cx.compile(Autograd::new(a, select_loss), ());
+        let along_grads = cx.compile(Autograd::new(a, along_loss), ());
+        cx.keep_tensors(&select_grads);
+        cx.keep_tensors(&along_grads);
+        cx.execute();
+
+        // Each source position collects the gradients of every output that picked it
+        assert_exact(
+            &get_vec(select_grads[0], &mut cx),
+            &[2., 0., 4., 5., 0., 10.],
+        );
+        assert_exact(&get_vec(along_grads[0], &mut cx), &[0., 3., 0., 4., 0., 5.]);
+    }
+
+    #[test]
+    fn test_autograd_topk() {
+        let mut cx = Graph::new();
+        let a = cx
+            .named_tensor("A", (2, 3))
+            .set([[1., 5., 3.], [6., 4., 2.]]);
+        let weights = cx.named_tensor("Weights", (2, 2)).set([[1., 2.], [3., 4.]]);
+        let (values, _) = a.topk(2, 1);
+        let loss = (values * weights).sum((0, 1));
+
+        let grads = cx.compile(Autograd::new(a, loss), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        // Only the kept values get a gradient, from the position they were sorted to
+        assert_exact(&get_vec(grads[0], &mut cx), &[0., 1., 2., 3., 4., 0.]);
+    }
+
     #[test]
     fn test_autograd_matmul() {
         let mut cx = Graph::new();
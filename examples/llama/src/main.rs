@@ -9,7 +9,6 @@ use itertools::Itertools;
 use model::{HEAD_DIM, N_KV_HEADS};
 use tokenizers::Tokenizer;
 
-mod gguf;
 mod loader;
 mod model;
 
@@ -48,7 +47,7 @@ fn main() {
             )
         })
         .collect();
-    cache_src.set_dyn(vec![], (1, model::N_KV_HEADS, 0, model::HEAD_DIM));
+    cache_src.set_dyn_f32(vec![], (1, model::N_KV_HEADS, 0, model::HEAD_DIM));
     let model = model::Llama::new(&mut cx);
     let mut model_weights = params(&model);
     cx.keep_tensors(&model_weights);
@@ -64,10 +63,7 @@ fn main() {
     let now = Instant::now();
 
     // Set up model loading
-    #[cfg(any(feature = "metal", feature = "cuda"))]
     let q_weights = loader::q8_load("setup/llama3-8b.gguf", &model, &mut cx);
-    #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-    loader::q8_load("setup/llama3-8b.gguf", &model, &mut cx);
 
     cx.compile(
         (
@@ -84,7 +80,10 @@ fn main() {
                 luminal_cuda::CudaQuantizedCompiler::<f16>::new(q_weights),
             ),
             #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-            luminal_cpu::CPUCompiler::default(),
+            (
+                luminal_cpu::CPUCompiler::default(),
+                luminal_cpu::CPUQuantizedCompiler::new(q_weights),
+            ),
         ),
         (
             &mut input,
@@ -1,4 +1,3 @@
-pub mod gguf;
 pub mod loader;
 pub mod model;
 pub mod setup;
@@ -56,7 +56,7 @@ impl Model {
                 )
             })
             .collect();
-        cache_src.set_dyn(vec![], (1, N_KV_HEADS, 0, HEAD_DIM));
+        cache_src.set_dyn_f32(vec![], (1, N_KV_HEADS, 0, HEAD_DIM));
         let model = Llama::new(&mut cx);
         let mut model_weights = params(&model);
         cx.keep_tensors(&model_weights);
@@ -67,10 +67,7 @@ impl Model {
         cache_dest.keep();
 
         // Set up model loading
-        #[cfg(any(feature = "metal", feature = "cuda"))]
         let q_weights = loader::q8_load(MODEL_PATH, &model, &mut cx);
-        #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-        loader::q8_load(MODEL_PATH, &model, &mut cx);
         println!("\t\t - {}ms", now.elapsed().as_millis());
 
         print!("Compiling graph");
@@ -91,7 +88,10 @@ impl Model {
                     luminal_cuda::CudaQuantizedCompiler::<f16>::new(q_weights),
                 ),
                 #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-                luminal_cpu::CPUCompiler::default(),
+                (
+                    luminal_cpu::CPUCompiler::default(),
+                    luminal_cpu::CPUQuantizedCompiler::new(q_weights),
+                ),
             ),
             (
                 &mut input,
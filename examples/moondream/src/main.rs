@@ -61,7 +61,7 @@ fn main() {
             )
         })
         .collect();
-    cache_src.set_dyn(vec![], (1, model::TXT_N_HEADS, 0, model::TXT_N_HEADS));
+    cache_src.set_dyn_f32(vec![], (1, model::TXT_N_HEADS, 0, model::TXT_N_HEADS));
     let model = model::Moondream::new(&mut cx);
     let mut model_weights = params(&model);
     cx.keep_tensors(&model_weights);
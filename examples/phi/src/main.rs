@@ -9,7 +9,6 @@ use itertools::Itertools;
 use model::{Phi, HEAD_DIM, N_HEADS};
 use tokenizers::Tokenizer;
 
-mod gguf;
 mod loader;
 mod model;
 
@@ -48,7 +47,7 @@ fn main() {
             )
         })
         .collect();
-    cache_src.set_dyn(vec![], (1, N_HEADS, 0, HEAD_DIM));
+    cache_src.set_dyn_f32(vec![], (1, N_HEADS, 0, HEAD_DIM));
     let model = Phi::new(&mut cx);
     let mut model_weights = params(&model);
     cx.keep_tensors(&model_weights);
@@ -59,10 +58,7 @@ fn main() {
     cache_dest.keep();
 
     // Set up model loading
-    #[cfg(any(feature = "metal", feature = "cuda"))]
     let q_weights = loader::q8_load("setup/phi3.gguf", &model, &mut cx);
-    #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-    loader::q8_load("setup/phi3.gguf", &model, &mut cx);
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     print!("Compiling graph");
@@ -86,7 +82,10 @@ fn main() {
                 luminal_cuda::CudaQuantizedCompiler::<f16>::new(q_weights),
             ),
             #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-            luminal_cpu::CPUCompiler::default(),
+            (
+                luminal_cpu::CPUCompiler::default(),
+                luminal_cpu::CPUQuantizedCompiler::new(q_weights),
+            ),
         ),
         (
             &mut input,
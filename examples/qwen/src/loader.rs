@@ -1,15 +1,19 @@
-use itertools::Itertools;
-use std::fs::File;
-use std::io::{Read, Seek};
 use std::path::Path;
 
-use luminal::{op::Function, prelude::*};
+use luminal::prelude::*;
 
 #[cfg(feature = "cuda")]
 use luminal_cuda::{CudaContext, CudaData};
 
-use crate::gguf::*;
+use luminal_gguf::*;
 
+#[cfg(any(feature = "metal", feature = "cuda"))]
+use {
+    itertools::Itertools,
+    luminal::op::Function,
+    std::fs::File,
+    std::io::{Read, Seek},
+};
 #[cfg(feature = "metal")]
 use {
     luminal_metal::{Device, MTLResourceOptions, MetalBuffer},
@@ -41,6 +45,7 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
             let file_path = path.as_ref().to_owned();
             let (n_elements, buffer_offset, data_type) = tensor_infos
                 .remove(&weight_name.replace('/', "."))
+                .map(|t| (t.n_elements(), t.offset, t.dtype))
                 .unwrap_or_else(|| panic!("Couldn't find weight {weight_name}"));
             let n_bytes = match data_type {
                 GgmlDType::F32 => n_elements * 4,
@@ -120,8 +125,10 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
             .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
         {
             let file_path = path.as_ref().to_owned();
-            let (n_elements, buffer_offset, data_type) =
-                tensor_infos.remove(&weight_name.replace('/', ".")).unwrap();
+            let (n_elements, buffer_offset, data_type) = tensor_infos
+                .remove(&weight_name.replace('/', "."))
+                .map(|t| (t.n_elements(), t.offset, t.dtype))
+                .unwrap();
             let n_bytes = match data_type {
                 GgmlDType::F32 => n_elements * 4,
                 GgmlDType::Q8_0 => {
@@ -171,80 +178,6 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
     model: &M,
     graph: &mut Graph,
 ) -> Vec<NodeIndex> {
-    #[repr(C, packed)]
-    #[derive(Clone, Copy)]
-    struct Q8Block {
-        delta: f16,
-        weights: [i8; 32],
-    }
-
-    // Read metadata from file
-    let mut reader = File::open(&path).unwrap();
-    let Content {
-        mut tensor_infos,
-        tensor_data_offset,
-        ..
-    } = Content::read(&mut reader).unwrap();
-
-    // Create weight loading closures
-    let mut q8_weights = vec![];
-    for (weight_name, node_index) in param_dict(model) {
-        if let Some(loading_node) = graph
-            .graph
-            .node_weight_mut(node_index)
-            .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
-        {
-            let file_path = path.as_ref().to_owned();
-            let (n_elements, buffer_offset, data_type) =
-                tensor_infos.remove(&weight_name.replace('/', ".")).unwrap();
-            let n_bytes = match data_type {
-                GgmlDType::F32 => n_elements * 4,
-                GgmlDType::Q8_0 => {
-                    q8_weights.push(node_index);
-                    n_elements + (n_elements / 16)
-                }
-                _ => panic!("Unsupported dtype: {data_type:?}"),
-            };
-            loading_node.1 = Box::new(move |_| {
-                // Load all bytes
-                let mut bytes = vec![0; n_bytes];
-                let mut file = File::open(&file_path).unwrap();
-                file.seek(std::io::SeekFrom::Start(
-                    buffer_offset as u64 + tensor_data_offset,
-                ))
-                .unwrap();
-                file.read_exact(&mut bytes).unwrap();
-                // Dequantize into f32
-                let data: Vec<f32> = match data_type {
-                    GgmlDType::F32 => bytes
-                        .into_iter()
-                        .chunks(4)
-                        .into_iter()
-                        .map(|c| {
-                            let c = c.collect::<Vec<_>>();
-                            f32::from_le_bytes([c[0], c[1], c[2], c[3]])
-                        })
-                        .collect(),
-                    GgmlDType::Q8_0 => bytes
-                        .into_iter()
-                        .chunks(34)
-                        .into_iter()
-                        .map(|c| {
-                            let chunk = c.collect::<Vec<_>>();
-                            unsafe { chunk.align_to::<Q8Block>().1[0] }
-                        })
-                        .flat_map(|chunk| {
-                            chunk
-                                .weights
-                                .into_iter()
-                                .map(move |i| i as f32 * chunk.delta.to_f32())
-                        })
-                        .collect(),
-                    _ => panic!("Unsupported dtype: {data_type:?}"),
-                };
-                vec![Tensor::new(data)]
-            });
-        }
-    }
-    q8_weights
+    // Keep the raw Q8_0 blocks for the quantized CPU kernels
+    luminal_gguf::load_into_quantized(path, model, graph, &[GgmlDType::Q8_0]).unwrap()
 }
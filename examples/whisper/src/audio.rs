@@ -218,7 +218,7 @@ pub fn log_mel_spectrogram_<T: Float>(
     let samples = {
         let mut samples_padded = samples.to_vec();
         let to_add = n_len * fft_step - samples.len();
-        samples_padded.extend(std::iter::repeat_n(zero, to_add));
+        samples_padded.extend(std::iter::repeat(zero).take(to_add));
         samples_padded
     };
 
@@ -42,7 +42,7 @@ fn main() {
             )
         })
         .collect::<Vec<_>>();
-    cache_src.set_dyn(vec![], (1, 6, 64, 0));
+    cache_src.set_dyn_f32(vec![], (1, 6, 64, 0));
     let (logits, _, mut cache_dest) = decoder.forward((encoder_output, text_input, &cache_src));
     let mut logits = logits
         .slice((.., Expression::from('s') - 1.., ..))
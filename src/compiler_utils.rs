@@ -6,11 +6,10 @@ use colored::Colorize;
 use itertools::Itertools;
 use petgraph::{
     algo::toposort,
-    stable_graph::{EdgeIndex, EdgeReference, StableGraph},
+    stable_graph::{EdgeReference, StableGraph},
     visit::EdgeRef,
     Direction,
 };
-use regex::Regex;
 use rustc_hash::FxHashMap;
 use uuid::Uuid;
 
@@ -329,7 +328,7 @@ impl Graph {
     ///     .finish();
     /// let b = GraphTensor::from_id(b_id, a.shape, a.graph());
     /// ```
-    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp {
+    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp<'_> {
         self.linearized_graph = None;
         NewOp {
             new_op_id: self.graph.add_node(Box::new(op)),
@@ -338,7 +337,7 @@ impl Graph {
         }
     }
     /// Add op on the graph, and get back a NewOp. Just like add_op, except a boxed op is expected.
-    pub fn add_boxed_op(&mut self, op: Box<dyn Operator + 'static>) -> NewOp {
+    pub fn add_boxed_op(&mut self, op: Box<dyn Operator + 'static>) -> NewOp<'_> {
         self.linearized_graph = None;
         NewOp {
             new_op_id: self.graph.add_node(op),
@@ -365,68 +364,6 @@ impl Graph {
             .and_then(|o| o.downcast::<O>().ok().map(|o| *o))
     }
 
-    /// Convert to debug-viewable graph
-    fn debug_graph(
-        &self,
-        show_shapes: bool,
-    ) -> (
-        StableGraph<String, u8>,
-        Vec<EdgeIndex>,
-        FxHashMap<NodeIndex, NodeIndex>,
-    ) {
-        let mut new_graph = StableGraph::default();
-        let mut id_map = FxHashMap::default();
-        for (id, node) in self.graph.node_indices().zip(self.graph.node_weights()) {
-            id_map.insert(id, new_graph.add_node(format!("{node:?} | {}", id.index())));
-        }
-
-        let mut schedule_edges = vec![];
-        for node in self.graph.node_indices() {
-            for edge in self
-                .graph
-                .edges_directed(node, Direction::Outgoing)
-                .sorted_by_key(|e| {
-                    if let Some(d) = e.weight().as_data() {
-                        d.0
-                    } else {
-                        0
-                    }
-                })
-            {
-                let new_edge = new_graph.add_edge(
-                    id_map[&edge.source()],
-                    id_map[&edge.target()],
-                    if let Some(d) = edge.weight().as_data() {
-                        d.0
-                    } else {
-                        0
-                    },
-                );
-                if edge.weight().is_schedule() {
-                    schedule_edges.push(new_edge);
-                }
-                if show_shapes
-                    && new_graph.contains_node(id_map[&edge.target()])
-                    && edge
-                        .weight()
-                        .as_data()
-                        .map(|d| !d.2.is_empty())
-                        .unwrap_or_default()
-                {
-                    new_graph
-                        .node_weight_mut(id_map[&edge.target()])
-                        .unwrap()
-                        .push_str(&format!(
-                            " | {:?}",
-                            edge.weight().as_data().unwrap().2.dims()
-                        ));
-                }
-            }
-        }
-
-        (new_graph, schedule_edges, id_map)
-    }
-
     pub fn check_node_type<T: Operator + 'static>(&self, node: NodeIndex) -> bool {
         self.node_weight(node)
             .expect("Node not found in graph!")
@@ -434,23 +371,26 @@ impl Graph {
             .is::<T>()
     }
 
+    /// View the graph in a local browser
     pub fn display(&self) {
-        let (g, e, _) = self.debug_graph(false);
-        display_graph(&g, &e, &[]);
+        self.display_with(&VisualizeOptions {
+            shapes: false,
+            ..Default::default()
+        });
     }
 
+    /// View the graph in a local browser, with edges labelled by shape
     pub fn display_shapes(&self) {
-        let (g, e, _) = self.debug_graph(true);
-        display_graph(&g, &e, &[]);
+        self.display_with(&VisualizeOptions::default());
     }
 
+    /// View the graph in a local browser, highlighting a set of nodes
     pub fn display_set<T: ToIds>(&self, set: T) {
-        let (g, e, id_map) = self.debug_graph(false);
-        display_graph(
-            &g,
-            &e,
-            &set.to_ids().iter().map(|i| id_map[i]).collect::<Vec<_>>(),
-        );
+        self.display_with(&VisualizeOptions {
+            shapes: false,
+            mark: set.to_ids(),
+            ..Default::default()
+        });
     }
 
     /// Remove node if it only has n dests
@@ -504,38 +444,14 @@ impl Graph {
     }
 }
 
-/// View a debug graph in the browser
+/// View a debug graph in a local browser
+#[deprecated(note = "use `Graph::display_with` instead")]
 pub fn display_graph(
-    graph: &petgraph::stable_graph::StableGraph<String, u8, petgraph::Directed, u32>,
-    schedule_edges: &[EdgeIndex],
+    graph: &StableGraph<String, u8, petgraph::Directed, u32>,
+    schedule_edges: &[petgraph::stable_graph::EdgeIndex],
     mark_nodes: &[NodeIndex],
 ) {
-    let mut graph_string =
-        petgraph::dot::Dot::with_config(&graph, &[petgraph::dot::Config::EdgeIndexLabel])
-            .to_string();
-    let re = Regex::new(r#"label\s*=\s*"\d+""#).unwrap();
-    for e in schedule_edges {
-        graph_string =
-            graph_string.replace(&format!("label = \"{}\"", e.index()), "color=\"green\"");
-    }
-    graph_string = re.replace_all(&graph_string, "").to_string();
-    for n in mark_nodes {
-        graph_string = graph_string.replace(
-            &format!("    {} [ label =", n.index()),
-            &format!(
-                "    {} [ style=\"filled\" fillcolor=\"yellow\" label =",
-                n.index()
-            ),
-        );
-    }
-
-    let url = format!(
-        "https://dreampuf.github.io/GraphvizOnline/#{}",
-        urlencoding::encode(&graph_string)
-    );
-    if let Err(e) = webbrowser::open(&url) {
-        panic!("Error displaying graph: {:?}", e);
-    }
+    crate::visualize::display_debug_graph(graph, schedule_edges, mark_nodes);
 }
 
 pub struct NewOp<'a> {
@@ -720,7 +636,7 @@ fn backtrack_match(
             if let Some(new_mapping) =
                 backtrack_match(pattern_parent, pattern_graph, *parent, main_graph)
             {
-                mapping.extend(new_mapping.into_iter());
+                mapping.extend(new_mapping);
                 continue 'pattern_loop;
             }
         }
@@ -763,7 +679,7 @@ fn test_node(
             if a_sh.len() != b_sh.dims.len() {
                 return false;
             }
-            for (a, b) in a_sh.iter().zip(b_sh.dims().into_iter()) {
+            for (a, b) in a_sh.iter().zip(b_sh.dims()) {
                 match a.to_usize() {
                     Some(n) => {
                         if b.to_usize().map(|i| i != n).unwrap_or(true) {
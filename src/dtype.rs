@@ -0,0 +1,322 @@
+// Element types tensors can hold
+
+use std::fmt::Debug;
+
+use half::{bf16, f16};
+
+/// The element type of a tensor
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Default, serde::Serialize, serde::Deserialize,
+)]
+pub enum DType {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+    I32,
+    I64,
+    U8,
+    Bool,
+}
+
+impl DType {
+    /// Size of a single element in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            DType::F32 | DType::I32 => 4,
+            DType::F16 | DType::Bf16 => 2,
+            DType::I64 => 8,
+            DType::U8 | DType::Bool => 1,
+        }
+    }
+
+    /// Is this a floating point type
+    pub fn is_float(&self) -> bool {
+        matches!(self, DType::F32 | DType::F16 | DType::Bf16)
+    }
+
+    /// The type both operands of a binary op are converted to: floats win over integers, and otherwise the wider type wins.
+    /// The two half precision float types can't represent each other, so they promote to F32
+    pub fn promote(self, other: DType) -> DType {
+        use DType::*;
+        match (self, other) {
+            (a, b) if a == b => a,
+            (F16, Bf16) | (Bf16, F16) => F32,
+            (a, b) if a.is_float() != b.is_float() => {
+                if a.is_float() {
+                    a
+                } else {
+                    b
+                }
+            }
+            (a, b) => {
+                let rank = |d| {
+                    [Bool, U8, I32, I64, F16, Bf16, F32]
+                        .iter()
+                        .position(|t| *t == d)
+                };
+                if rank(a) > rank(b) {
+                    a
+                } else {
+                    b
+                }
+            }
+        }
+    }
+}
+
+/// A scalar that can be stored in a CPU tensor (as a `Vec<T>`).
+///
+/// Arithmetic defaults to going through f64, integer types override it with wrapping integer arithmetic.
+pub trait Element: Copy + Debug + Default + PartialOrd + Send + Sync + 'static {
+    const DTYPE: DType;
+    /// The smallest value of this type (negative infinity for floats)
+    const LOWEST: Self;
+    fn to_f64(self) -> f64;
+    /// Convert from an f64. Integers are truncated and wrap, so negation and subtraction work for unsigned types
+    fn from_f64(v: f64) -> Self;
+    fn add(self, rhs: Self) -> Self {
+        Self::from_f64(self.to_f64() + rhs.to_f64())
+    }
+    fn mul(self, rhs: Self) -> Self {
+        Self::from_f64(self.to_f64() * rhs.to_f64())
+    }
+    fn rem(self, rhs: Self) -> Self {
+        Self::from_f64(self.to_f64() % rhs.to_f64())
+    }
+    /// Apply a float function to this element
+    fn map_f64(self, f: impl Fn(f64) -> f64) -> Self {
+        Self::from_f64(f(self.to_f64()))
+    }
+    fn log2(self) -> Self {
+        self.map_f64(f64::log2)
+    }
+    fn exp2(self) -> Self {
+        self.map_f64(f64::exp2)
+    }
+    fn sin(self) -> Self {
+        self.map_f64(f64::sin)
+    }
+    fn recip(self) -> Self {
+        self.map_f64(f64::recip)
+    }
+    fn sqrt(self) -> Self {
+        self.map_f64(f64::sqrt)
+    }
+}
+
+impl Element for f32 {
+    const DTYPE: DType = DType::F32;
+    const LOWEST: Self = f32::NEG_INFINITY;
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+    fn from_f64(v: f64) -> Self {
+        v as f32
+    }
+    // Keep f32 arithmetic in f32 so results match the rest of the library exactly
+    fn add(self, rhs: Self) -> Self {
+        self + rhs
+    }
+    fn mul(self, rhs: Self) -> Self {
+        self * rhs
+    }
+    fn rem(self, rhs: Self) -> Self {
+        self % rhs
+    }
+    fn log2(self) -> Self {
+        f32::log2(self)
+    }
+    fn exp2(self) -> Self {
+        f32::exp2(self)
+    }
+    fn sin(self) -> Self {
+        f32::sin(self)
+    }
+    fn recip(self) -> Self {
+        f32::recip(self)
+    }
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+}
+
+macro_rules! half_element {
+    ($t:ty, $dtype:ident) => {
+        impl Element for $t {
+            const DTYPE: DType = DType::$dtype;
+            const LOWEST: Self = <$t>::NEG_INFINITY;
+            fn to_f64(self) -> f64 {
+                self.to_f64()
+            }
+            fn from_f64(v: f64) -> Self {
+                <$t>::from_f64(v)
+            }
+        }
+    };
+}
+
+half_element!(f16, F16);
+half_element!(bf16, Bf16);
+
+macro_rules! int_element {
+    ($t:ty, $dtype:ident) => {
+        impl Element for $t {
+            const DTYPE: DType = DType::$dtype;
+            const LOWEST: Self = <$t>::MIN;
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+            fn from_f64(v: f64) -> Self {
+                v as i64 as $t
+            }
+            fn add(self, rhs: Self) -> Self {
+                self.wrapping_add(rhs)
+            }
+            fn mul(self, rhs: Self) -> Self {
+                self.wrapping_mul(rhs)
+            }
+            fn rem(self, rhs: Self) -> Self {
+                self.checked_rem(rhs).unwrap_or_default()
+            }
+        }
+    };
+}
+
+int_element!(i32, I32);
+int_element!(i64, I64);
+int_element!(u8, U8);
+
+impl Element for bool {
+    const DTYPE: DType = DType::Bool;
+    const LOWEST: Self = false;
+    fn to_f64(self) -> f64 {
+        self as i32 as f64
+    }
+    fn from_f64(v: f64) -> Self {
+        v != 0.
+    }
+    fn add(self, rhs: Self) -> Self {
+        self || rhs
+    }
+    fn mul(self, rhs: Self) -> Self {
+        self && rhs
+    }
+}
+
+/// Run an expression with a type alias bound to the element type of a `DType`
+macro_rules! with_dtype {
+    ($dtype:expr, $t:ident => $body:expr) => {
+        match $dtype {
+            $crate::dtype::DType::F32 => {
+                type $t = f32;
+                $body
+            }
+            $crate::dtype::DType::F16 => {
+                type $t = half::f16;
+                $body
+            }
+            $crate::dtype::DType::Bf16 => {
+                type $t = half::bf16;
+                $body
+            }
+            $crate::dtype::DType::I32 => {
+                type $t = i32;
+                $body
+            }
+            $crate::dtype::DType::I64 => {
+                type $t = i64;
+                $body
+            }
+            $crate::dtype::DType::U8 => {
+                type $t = u8;
+                $body
+            }
+            $crate::dtype::DType::Bool => {
+                type $t = bool;
+                $body
+            }
+        }
+    };
+}
+
+pub(crate) use with_dtype;
+
+#[cfg(test)]
+mod tests {
+    use half::f16;
+
+    crate::test_imports!();
+
+    #[test]
+    fn test_dtypes() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(
+            vec![1., 2., 3., 4.]
+                .into_iter()
+                .map(f16::from_f32)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(a.dtype, DType::F16);
+        let b = (a * 2.).sum(0).retrieve();
+        let c = a.cast(DType::F32).exp2().retrieve();
+
+        let i = cx.tensor(3).set(vec![7_i32, -3, 10]);
+        let j = (i % 4 + i).retrieve();
+        let max = (i * -1 - 5).max(0).retrieve();
+        let mask = i.lt(cx.tensor(3).set(vec![5_i32; 3])).retrieve();
+        let d = cx.tensor('n').set_dyn(vec![1_i64, 2, 3], 3);
+        assert_eq!(d.dtype, DType::I64);
+        let d_sum = d.sum(0).retrieve();
+        let bytes = i.cast(DType::U8);
+        let neg = (bytes - bytes.cast(DType::Bool).cast(DType::U8)).retrieve();
+        cx.execute();
+
+        assert_eq!(b.dtype, DType::F16);
+        assert_eq!(b.data_as::<f16>(), vec![f16::from_f32(20.)]);
+        assert_close(&c.data(), &[2., 4., 8., 16.]);
+        assert_eq!(j.data_as::<i32>(), vec![10, -6, 12]);
+        assert_eq!(max.data_as::<i32>(), vec![-2]);
+        assert_eq!(mask.data_as::<i32>(), vec![0, 1, 0]);
+        assert_eq!(neg.data_as::<u8>(), vec![6, 252, 9]);
+        assert_eq!(d_sum.data_as::<i64>(), vec![6]);
+    }
+
+    #[test]
+    fn test_promotion() {
+        assert_eq!(DType::I32.promote(DType::F32), DType::F32);
+        assert_eq!(DType::U8.promote(DType::I64), DType::I64);
+        assert_eq!(DType::F16.promote(DType::Bf16), DType::F32);
+        assert_eq!(DType::Bool.promote(DType::F16), DType::F16);
+
+        let mut cx = Graph::new();
+        let i = cx.tensor(3).set(vec![1_i32, 2, 3]);
+        let f = cx.tensor(3).set(vec![0.5_f32, 0.25, -1.5]);
+        let sum = (i + f).retrieve();
+        let product = (f * i).retrieve();
+        let lt = i.lt(f).retrieve();
+        // Scalars take the type of the tensor
+        let scaled = (i * 2.).retrieve();
+        cx.execute();
+
+        assert_eq!(sum.dtype, DType::F32);
+        assert_exact(&sum.data(), &[1.5, 2.25, 1.5]);
+        assert_exact(&product.data(), &[0.5, 0.5, -4.5]);
+        assert_exact(&lt.data(), &[0., 0., 0.]);
+        assert_eq!(scaled.data_as::<i32>(), vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn test_f32_unary_intrinsics() {
+        let mut cx = Graph::new();
+        let data = vec![0.1_f32, 1.7, 3.3, 12.9];
+        let a = cx.tensor(4).set(data.clone());
+        let outs = [a.log2(), a.exp2(), a.sin(), a.reciprocal(), a.sqrt()].map(|t| t.retrieve());
+        cx.execute();
+
+        let fns: [fn(f32) -> f32; 5] = [f32::log2, f32::exp2, f32::sin, f32::recip, f32::sqrt];
+        for (out, f) in outs.iter().zip(fns) {
+            assert_exact(&out.data(), &data.iter().map(|x| f(*x)).collect::<Vec<_>>());
+        }
+    }
+}
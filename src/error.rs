@@ -0,0 +1,218 @@
+// Structured errors for fallible compilation and execution
+
+use std::{
+    any::Any,
+    fmt::Display,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+use crate::{graph::ExecutionHooks, prelude::*};
+
+/// The panic message source tensors without data fail with
+pub(crate) const UNSET_INPUT_MESSAGE: &str = "You must set a value for this tensor!";
+
+/// Errors that can occur while compiling or running a graph
+#[derive(Debug, Clone, PartialEq)]
+pub enum LuminalError {
+    /// A source tensor was never given data
+    UnsetInput { node: NodeIndex, name: String },
+    /// A shape references a dynamic dimension that isn't set in the dyn map
+    UnresolvedDynDim { node: NodeIndex, dim: char },
+    /// The graph contains a cycle going through this node
+    Cycle(NodeIndex),
+    /// An op failed while running
+    OpFailure {
+        node: NodeIndex,
+        op: String,
+        message: String,
+    },
+    /// A compiler failed while compiling the graph
+    CompilerFailure { compiler: String, message: String },
+    /// An op has the wrong number of inputs
+    InputCount {
+        node: NodeIndex,
+        op: String,
+        expected: usize,
+        found: usize,
+    },
+    /// The inputs to an elementwise op don't have the same shape
+    ShapeMismatch {
+        node: NodeIndex,
+        op: String,
+        inputs: Vec<(String, Vec<Expression>)>,
+    },
+}
+
+impl LuminalError {
+    /// Convert a panic caught while running an op into an error
+    pub(crate) fn from_panic(node: NodeIndex, op: String, payload: Box<dyn Any + Send>) -> Self {
+        LuminalError::OpFailure {
+            node,
+            op,
+            message: panic_message(payload),
+        }
+    }
+}
+
+/// The message a panic was raised with
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
+/// Executor hooks that turn op panics into errors
+pub(crate) struct CatchPanics;
+
+impl ExecutionHooks for CatchPanics {
+    fn process(
+        &mut self,
+        node: NodeIndex,
+        op: &mut dyn Operator,
+        srcs: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        catch_unwind(AssertUnwindSafe(|| op.process(srcs)))
+            .map_err(|payload| LuminalError::from_panic(node, format!("{op:?}"), payload))
+    }
+}
+
+impl Display for LuminalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LuminalError::UnsetInput { node, name } => write!(
+                f,
+                "No value set for input tensor {name} (node {})",
+                node.index()
+            ),
+            LuminalError::UnresolvedDynDim { node, dim } => write!(
+                f,
+                "Dynamic dimension '{dim}' used by node {} is not set",
+                node.index()
+            ),
+            LuminalError::Cycle(node) => {
+                write!(f, "Graph contains a cycle through node {}", node.index())
+            }
+            LuminalError::OpFailure { node, op, message } => {
+                write!(f, "Op {op} (node {}) failed: {message}", node.index())
+            }
+            LuminalError::CompilerFailure { compiler, message } => {
+                write!(f, "Compiler {compiler} failed: {message}")
+            }
+            LuminalError::InputCount {
+                node,
+                op,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Op {op} (node {}) expects {expected} inputs but has {found}",
+                node.index()
+            ),
+            LuminalError::ShapeMismatch { node, op, inputs } => write!(
+                f,
+                "Op {op} (node {}) has mismatched input shapes: {}",
+                node.index(),
+                inputs
+                    .iter()
+                    .map(|(name, shape)| format!("{name} {shape:?}"))
+                    .collect::<Vec<_>>()
+                    .join(" vs ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LuminalError {}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_unset_input() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("Input", 3);
+        let b = cx.tensor(3);
+        cx.set_tensor(b.id, 0, crate::op::Tensor::new(vec![1_f32, 2., 3.]));
+        let c = (a + b).retrieve();
+        assert_eq!(
+            cx.try_execute(),
+            Err(LuminalError::UnsetInput {
+                node: a.id,
+                name: "Input".to_string()
+            })
+        );
+
+        // Nothing ran, so the data set directly is still there and the graph can be ran once the input is set
+        a.set(vec![1., 1., 1.]);
+        cx.try_execute().unwrap();
+        assert_exact(&c.data(), &[2., 3., 4.]);
+    }
+
+    #[test]
+    fn test_unresolved_dyn_dim() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(('a', 2)).set(vec![1., 2., 3., 4.]);
+        let b = a.exp2().retrieve();
+        assert!(matches!(
+            cx.try_execute(),
+            Err(LuminalError::UnresolvedDynDim { dim: 'a', .. })
+        ));
+        assert!(cx.tensors.is_empty());
+
+        cx.set_dyn_dim('a', 2);
+        cx.try_execute().unwrap();
+        assert_eq!(b.data().len(), 4);
+    }
+
+    #[test]
+    fn test_op_failure() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let b = cx.tensor(3).set(vec![1., 2.]);
+        (a + b).retrieve();
+        let Err(LuminalError::OpFailure { op, .. }) = cx.try_execute() else {
+            panic!("Expected op failure")
+        };
+        assert_eq!(op, "Add");
+    }
+
+    #[test]
+    fn test_compiler_failure() {
+        struct Failing;
+        impl Compiler for Failing {
+            type Output = ();
+            fn compile<T: ToIdsMut>(&self, _: &mut Graph, _: T) {
+                panic!("Nothing to compile");
+            }
+        }
+
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let mut b = a.exp2().retrieve();
+        let Err(LuminalError::CompilerFailure { compiler, message }) =
+            cx.try_compile(Failing, &mut b)
+        else {
+            panic!("Expected compiler failure")
+        };
+        assert!(compiler.ends_with("Failing"));
+        assert_eq!(message, "Nothing to compile");
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3);
+        let b = a.exp2();
+        let mut c = b.sin().retrieve();
+        cx.add_schedule_dependency(c.id, b.id);
+        assert!(matches!(
+            cx.try_compile(GenericCompiler::default(), &mut c),
+            Err(LuminalError::Cycle(_))
+        ));
+    }
+}
@@ -11,6 +11,7 @@ use petgraph::{
 use crate::{
     op::{Add, Constant, ConstantValue, Function, MaxReduce, Mul, Operator, Recip, SumReduce},
     prelude::*,
+    subgraph::compile_subgraphs,
 };
 
 /// Generic platform-agnostic optimizations. It's a good idea to use these all the time.
@@ -28,6 +29,7 @@ pub struct CSE;
 impl Compiler for CSE {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        compile_subgraphs(graph, || CSE);
         // Look for nodes that have the exact same srcs
         // Loop cause I'm lazy
         let mut eliminated = true;
@@ -62,6 +64,10 @@ impl Compiler for CSE {
                             // Sloppy way to check if ops are equal, but we only expect primops here so it's ok
                             continue;
                         }
+                        if subgraphs(a.as_ref()) != subgraphs(b.as_ref()) {
+                            // Control flow ops are only the same if they run the same subgraphs
+                            continue;
+                        }
                         let a_src_shapes = graph
                             .get_sources(node)
                             .into_iter()
@@ -164,6 +170,7 @@ pub struct RemoveUnusedNodes;
 impl Compiler for RemoveUnusedNodes {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        compile_subgraphs(graph, || RemoveUnusedNodes);
         // Reverse topo sort
         for node in toposort(&graph.graph, None).unwrap().into_iter().rev() {
             if graph.edges_directed(node, Direction::Outgoing).count() == 0
@@ -2,10 +2,12 @@ use crate::prelude::*;
 use std::{
     io::Write,
     ops::{Deref, DerefMut},
+    panic::{catch_unwind, AssertUnwindSafe},
     time::Duration,
 };
 
 use super::compiler_utils::{ToIds, ToIdsMut};
+use crate::error::{panic_message, CatchPanics, UNSET_INPUT_MESSAGE};
 use colored::Colorize;
 use itertools::Itertools;
 use petgraph::{stable_graph::StableGraph, visit::EdgeRef, Direction};
@@ -33,11 +35,28 @@ pub struct Graph {
     #[allow(clippy::type_complexity)]
     pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>>,
     /// Cached consumers (for execution only)
-    consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    pub(crate) consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    /// How each source tensor created without data has been given data since
+    pub(crate) input_states: FxHashMap<NodeIndex, InputState>,
+    /// Source tensors set since the last incremental execution
+    pub(crate) dirty: FxHashSet<NodeIndex>,
+    /// Dynamic dimensions changed since the last incremental execution
+    pub(crate) dirty_dims: FxHashSet<char>,
+}
+
+/// Where a source tensor created without data gets its data from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputState {
+    /// Neither data nor a loader has been set
+    Unset,
+    /// Data was set directly, which is only available until it's consumed
+    Data,
+    /// A loader was set, which produces data every execution
+    Loader,
 }
 
 /// A dependency between two nodes
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
 #[allow(clippy::large_enum_variant)]
 pub enum Dependency {
     /// A data dependency (transferring a tensor from one node to the next)
@@ -104,11 +123,31 @@ impl Graph {
     /// Set a tensor's data
     pub fn set_tensor(&mut self, id: NodeIndex, ind: u8, tensor: Tensor) {
         self.tensors.insert((id, ind), tensor);
+        if let Some(state @ InputState::Unset) = self.input_states.get_mut(&id) {
+            *state = InputState::Data;
+        }
+        self.dirty.insert(id);
+    }
+
+    /// Replace the loader of a source tensor, dropping any data produced by the old one
+    pub fn set_loader(
+        &mut self,
+        id: NodeIndex,
+        loader: impl Fn(Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> + 'static,
+    ) {
+        self.get_op_mut::<Function>(id).1 = Box::new(loader);
+        if let Some(state) = self.input_states.get_mut(&id) {
+            *state = InputState::Loader;
+        }
+        self.tensors.remove(&(id, 0));
+        self.dirty.insert(id);
     }
 
     /// Set a dynamic dimension
     pub fn set_dyn_dim(&mut self, dimension: char, val: usize) {
-        self.dyn_map.insert(dimension, val);
+        if self.dyn_map.insert(dimension, val) != Some(val) {
+            self.dirty_dims.insert(dimension);
+        }
     }
 
     /// Create a new tensor with shape S
@@ -119,13 +158,24 @@ impl Graph {
     /// Create a new tensor with shape S and a name. This name will show up on the graph when displayed
     pub fn named_tensor(&mut self, name: &str, shape: impl ToShape) -> GraphTensor {
         let name = name.to_string();
+        let id = self.graph.add_node(Box::new(Function(
+            format!("{name} Load"),
+            Box::new(move |_| panic!("{UNSET_INPUT_MESSAGE} ({name})")),
+        )));
+        self.input_states.insert(id, InputState::Unset);
         GraphTensor {
-            id: self.graph.add_node(Box::new(Function(
-                format!("{name} Load"),
-                Box::new(move |_| panic!("You must set a value for this tensor! ({name})")),
-            ))),
+            id,
             graph_ref: self,
             shape: ShapeTracker::new(shape),
+            dtype: DType::F32,
+        }
+    }
+
+    /// Check if a source tensor has neither data nor a loader set
+    pub fn is_unset_input(&self, id: NodeIndex) -> bool {
+        match self.input_states.get(&id) {
+            Some(InputState::Unset | InputState::Data) => !self.tensors.contains_key(&(id, 0)),
+            Some(InputState::Loader) | None => false,
         }
     }
 
@@ -137,11 +187,37 @@ impl Graph {
         output
     }
 
+    /// Compile the graph using the given compiler, returning an error if the compiler fails or the compiled graph isn't runnable.
+    ///
+    /// A compiler that fails partway through may leave the graph partially compiled. The panic is still reported by the panic hook, which callers can replace to silence it.
+    pub fn try_compile<T: ToIdsMut, C: Compiler>(
+        &mut self,
+        compiler: C,
+        remap: T,
+    ) -> Result<C::Output, LuminalError> {
+        // Compilers assume the graph is acyclic
+        self.try_toposort()?;
+        let output = catch_unwind(AssertUnwindSafe(|| compiler.compile(self, remap))).map_err(
+            |payload| LuminalError::CompilerFailure {
+                compiler: std::any::type_name::<C>().to_string(),
+                message: panic_message(payload),
+            },
+        )?;
+        self.try_toposort()?;
+        self.reset();
+        Ok(output)
+    }
+
     /// Refresh the internally sorted graph
     pub(crate) fn toposort(&mut self) {
+        self.try_toposort().unwrap();
+    }
+
+    /// Refresh the internally sorted graph, failing if the graph has a cycle
+    pub(crate) fn try_toposort(&mut self) -> Result<(), LuminalError> {
         self.linearized_graph = Some(
             petgraph::algo::toposort(&self.graph, None)
-                .unwrap()
+                .map_err(|e| LuminalError::Cycle(e.node_id()))?
                 .into_iter()
                 .map(|node| (node, self.get_sources(node)))
                 .collect(),
@@ -162,6 +238,7 @@ impl Graph {
                 })
                 .collect(),
         );
+        Ok(())
     }
 
     /// Swap the tensors with these ids
@@ -189,20 +266,83 @@ impl Graph {
 
     /// Execute the graph.
     pub fn execute(&mut self) {
-        // Track the number of views pointing to each tensor so we know when to clear
+        self.run_sequential(&[], &mut ())
+            .unwrap_or_else(|e| panic!("{e}"));
+        self.reset();
+    }
+
+    /// Execute the graph, returning an error instead of panicking if an input isn't set, a dyn dim is missing, or an op fails.
+    ///
+    /// Missing inputs and dyn dims are found before anything runs, leaving the graph untouched. If an op fails, all intermediate tensors are cleared and the graph can be ran again. The op's panic is still reported by the panic hook, which callers can replace to silence it.
+    pub fn try_execute(&mut self) -> Result<(), LuminalError> {
         if self.linearized_graph.is_none() {
-            self.toposort();
+            self.try_toposort()?;
+        }
+        self.check_runnable()?;
+        let result = self.run_sequential(&[], &mut CatchPanics);
+        self.reset();
+        result
+    }
+
+    /// Check that every node left to run has its inputs set and its dyn dims known
+    fn check_runnable(&self) -> Result<(), LuminalError> {
+        for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
+            if self.tensors.contains_key(&(*node, 0)) {
+                continue;
+            }
+            if self.is_unset_input(*node) {
+                let name = format!("{:?}", self.graph.node_weight(*node).unwrap());
+                return Err(LuminalError::UnsetInput {
+                    node: *node,
+                    name: name.strip_suffix(" Load").unwrap_or(&name).to_string(),
+                });
+            }
+            if let Some(dim) = src_ids
+                .iter()
+                .flat_map(|(_, _, st)| st.dyn_dims())
+                .find(|d| !self.dyn_map.contains_key(d))
+            {
+                return Err(LuminalError::UnresolvedDynDim { node: *node, dim });
+            }
+        }
+        Ok(())
+    }
+
+    /// Run every node that doesn't have an output yet in topological order, freeing each tensor once its last consumer has ran.
+    ///
+    /// Nodes in `external` get their tensors from outside the graph (like the inputs of a subgraph call), which are borrowed instead of ran. Remaining tensors are left for the caller to reset.
+    pub(crate) fn run_sequential(
+        &mut self,
+        external: &[(NodeIndex, &Tensor)],
+        hooks: &mut impl ExecutionHooks,
+    ) -> Result<(), LuminalError> {
+        if self.linearized_graph.is_none() {
+            self.try_toposort()?;
         }
         let mut consumers = self.consumers_map.as_ref().unwrap().clone();
         let mut dim_stack = Vec::new();
+        let external_tensor =
+            |id: &NodeIndex| external.iter().find(|(n, _)| n == id).map(|(_, t)| *t);
 
         for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
-            if self.tensors.contains_key(&(*node, 0)) {
+            if self.tensors.contains_key(&(*node, 0)) || external_tensor(node).is_some() {
                 continue;
             }
 
-            let mut srcs =
-                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
+            let mut srcs = src_ids
+                .iter()
+                .map(|src @ (id, _, sh)| match external_tensor(id) {
+                    Some(tensor) => (InputTensor::Borrowed(tensor), *sh),
+                    None => get_source_tensors(
+                        &self.no_delete,
+                        &mut self.tensors,
+                        std::slice::from_ref(src),
+                        &consumers,
+                    )
+                    .pop()
+                    .unwrap(),
+                })
+                .collect_vec();
 
             // Substitute in the dyn dims
             for (_, st) in srcs.iter_mut() {
@@ -210,7 +350,8 @@ impl Graph {
             }
 
             // Execute
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
+            let op = self.graph.node_weight_mut(*node).unwrap();
+            let tensors = hooks.process(*node, op.as_mut(), srcs)?;
             for (i, tensor) in tensors.into_iter().enumerate() {
                 self.tensors.insert((*node, i as u8), tensor);
             }
@@ -220,7 +361,7 @@ impl Graph {
                 *consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
             }
         }
-        self.reset();
+        Ok(())
     }
 
     /// Execute the graph without deleting intermediate tensors
@@ -259,24 +400,7 @@ impl Graph {
 
     /// Execute the graph with debug prints
     pub fn execute_debug(&mut self) {
-        fn format_duration(duration: &Duration) -> String {
-            if duration.as_secs() > 0 {
-                format!("{:.2}s", duration.as_secs_f32())
-            } else if duration.as_millis() > 0 {
-                format!("{}ms", duration.as_millis())
-            } else {
-                format!("{}µs", duration.as_micros())
-            }
-        }
-        // Track the number of views pointing to each tensor so we know when to clear
-        if self.linearized_graph.is_none() {
-            self.toposort();
-        }
-        let mut dim_stack = Vec::new();
-        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
-        let mut op_times = FxHashMap::default();
-        let width = term_size::dimensions().unwrap().0;
-
+        let width = term_size::dimensions().map(|(w, _)| w).unwrap_or(80);
         println!(
             "{:->2$} Executing {:->2$}",
             "",
@@ -284,65 +408,12 @@ impl Graph {
             (width.saturating_sub(" Executing ".len())) / 2
         );
         let start = std::time::Instant::now();
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap().iter() {
-            if self.tensors.contains_key(&(*node, 0)) {
-                continue;
-            }
-            let op_name = format!("{:?} | {}", self.node_weight(*node).unwrap(), node.index());
-            print!("{}", op_name.bold().bright_green());
-
-            let mut srcs =
-                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
-
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
-            }
-
-            // All sources are ready
-            let mut shapes_string = srcs
-                .iter()
-                .map(|(_, s)| {
-                    format!(
-                        "{:?}",
-                        s.dims()
-                            .into_iter()
-                            .map(|i| i.to_usize().unwrap())
-                            .collect::<Vec<_>>()
-                    )
-                })
-                .join(", ");
-            if !shapes_string.is_empty() {
-                shapes_string = format!(" ({shapes_string})");
-            }
-            print!("{shapes_string}");
-            std::io::stdout().flush().unwrap();
-            // Execute
-            let now = std::time::Instant::now();
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            let elapsed = now.elapsed();
-            println!(
-                "{:.>1$}",
-                format_duration(&elapsed).bold(),
-                width
-                    .saturating_sub(op_name.len())
-                    .saturating_sub(shapes_string.len()),
-            );
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
-            }
-            let timed_op_name = format!("{:?}", self.node_weight(*node).unwrap());
-            if let Some(t) = op_times.get_mut(&timed_op_name) {
-                *t += elapsed;
-            } else {
-                op_times.insert(timed_op_name, elapsed);
-            }
-
-            // Check if we can delete the source tensors now
-            for (id, ind, _) in src_ids {
-                *consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
-            }
-        }
+        let mut printer = DebugPrinter {
+            width,
+            op_times: FxHashMap::default(),
+        };
+        self.run_sequential(&[], &mut printer)
+            .unwrap_or_else(|e| panic!("{e}"));
 
         // Print out total times
         println!();
@@ -352,7 +423,11 @@ impl Graph {
             "",
             (width.saturating_sub(" Total Times ".len())) / 2
         );
-        for (name, elapsed) in op_times.into_iter().sorted_by(|(_, a), (_, b)| b.cmp(a)) {
+        for (name, elapsed) in printer
+            .op_times
+            .into_iter()
+            .sorted_by(|(_, a), (_, b)| b.cmp(a))
+        {
             print!("{}", name.bold().bright_green());
             println!(
                 "{:.>1$}",
@@ -365,6 +440,81 @@ impl Graph {
     }
 }
 
+/// Hooks into how the sequential executor runs each node
+pub(crate) trait ExecutionHooks {
+    /// Run a node's op on its inputs, which already have their dyn dims resolved
+    fn process(
+        &mut self,
+        _node: NodeIndex,
+        op: &mut dyn Operator,
+        srcs: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        Ok(op.process(srcs))
+    }
+}
+
+/// Just run the ops
+impl ExecutionHooks for () {}
+
+/// Executor hooks that print each node and its time as it runs
+struct DebugPrinter {
+    width: usize,
+    /// Total time spent in each op
+    op_times: FxHashMap<String, Duration>,
+}
+
+impl ExecutionHooks for DebugPrinter {
+    fn process(
+        &mut self,
+        node: NodeIndex,
+        op: &mut dyn Operator,
+        srcs: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let op_name = format!("{op:?} | {}", node.index());
+        print!("{}", op_name.bold().bright_green());
+        let mut shapes_string = srcs
+            .iter()
+            .map(|(_, s)| {
+                format!(
+                    "{:?}",
+                    s.dims()
+                        .into_iter()
+                        .map(|i| i.to_usize().unwrap())
+                        .collect::<Vec<_>>()
+                )
+            })
+            .join(", ");
+        if !shapes_string.is_empty() {
+            shapes_string = format!(" ({shapes_string})");
+        }
+        print!("{shapes_string}");
+        std::io::stdout().flush().unwrap();
+
+        let now = std::time::Instant::now();
+        let tensors = op.process(srcs);
+        let elapsed = now.elapsed();
+        println!(
+            "{:.>1$}",
+            format_duration(&elapsed).bold(),
+            self.width
+                .saturating_sub(op_name.len())
+                .saturating_sub(shapes_string.len()),
+        );
+        *self.op_times.entry(format!("{op:?}")).or_default() += elapsed;
+        Ok(tensors)
+    }
+}
+
+fn format_duration(duration: &Duration) -> String {
+    if duration.as_secs() > 0 {
+        format!("{:.2}s", duration.as_secs_f32())
+    } else if duration.as_millis() > 0 {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{}µs", duration.as_micros())
+    }
+}
+
 impl Deref for Graph {
     type Target = StorageGraph;
     fn deref(&self) -> &Self::Target {
@@ -384,8 +534,25 @@ impl Drop for Graph {
     }
 }
 
+impl Graph {
+    /// Drop the graph without cleaning up expression storage, for graphs that live inside another graph
+    pub(crate) fn drop_nested(mut self) {
+        drop(std::mem::take(&mut self.tensors));
+        drop(std::mem::take(&mut self.dyn_map));
+        drop(std::mem::take(&mut self.graph));
+        drop(std::mem::take(&mut self.no_delete));
+        drop(std::mem::take(&mut self.to_retrieve));
+        drop(self.linearized_graph.take());
+        drop(self.consumers_map.take());
+        drop(std::mem::take(&mut self.input_states));
+        drop(std::mem::take(&mut self.dirty));
+        drop(std::mem::take(&mut self.dirty_dims));
+        std::mem::forget(self);
+    }
+}
+
 /// Get source tensor array for a node
-fn get_source_tensors<'a>(
+pub(crate) fn get_source_tensors<'a>(
     no_delete: &'a FxHashSet<NodeIndex>,
     tensors: *mut FxHashMap<(NodeIndex, u8), Tensor>,
     src_ids: &'a [(NodeIndex, u8, ShapeTracker)],
@@ -19,6 +19,8 @@ pub struct GraphTensor {
     pub id: NodeIndex,
     pub graph_ref: *mut Graph,
     pub shape: ShapeTracker,
+    /// The element type of the tensor
+    pub dtype: DType,
 }
 
 impl From<&GraphTensor> for GraphTensor {
@@ -28,12 +30,13 @@ impl From<&GraphTensor> for GraphTensor {
 }
 
 impl GraphTensor {
-    /// Create a GraphTensor from a NodeIndex
+    /// Create an f32 GraphTensor from a NodeIndex
     pub fn from_id(id: NodeIndex, shape: ShapeTracker, graph_ref: *mut Graph) -> Self {
         Self {
             id,
             graph_ref,
             shape,
+            dtype: DType::F32,
         }
     }
 
@@ -61,7 +64,7 @@ impl GraphTensor {
         unsafe { self.graph_ref.as_mut().unwrap() }
     }
 
-    /// Set the value of the tensor, with dynamic dimensions.
+    /// Set the value of the tensor, with dynamic dimensions. The tensor takes the element type of the data.
     /// ```rust
     /// use luminal::prelude::*;
     /// let mut cx = Graph::new();
@@ -69,18 +72,24 @@ impl GraphTensor {
     ///     .tensor((2, 's'))
     ///     .set_dyn(vec![1., 2., 3., 4.], &[2, 2]);
     /// ```
-    pub fn set_dyn(self, data: impl Data + Clone, shape: impl ToShape) -> Self {
+    pub fn set_dyn(mut self, data: impl Data + Clone, shape: impl ToShape) -> Self {
         // Report dyn dim values to graph dyn map
-        for (d, s) in self.shape.dims().iter().zip(shape.to_shape().into_iter()) {
+        for (d, s) in self.shape.dims().iter().zip(shape.to_shape()) {
             if let Some(c) = d.to_symbols().pop() {
-                self.graph().dyn_map.insert(c, s.to_usize().unwrap());
+                self.graph().set_dyn_dim(c, s.to_usize().unwrap());
             }
         }
-        self.graph().get_op_mut::<Function>(self.id).1 =
-            Box::new(move |_| vec![Tensor::new(data.to_owned())]);
+        self.dtype = data.dtype().unwrap_or(self.dtype);
+        self.graph()
+            .set_loader(self.id, move |_| vec![Tensor::new(data.to_owned())]);
         self
     }
 
+    /// Set f32 values of the tensor, with dynamic dimensions. Unlike `set_dyn`, the element type doesn't need to be inferred, so this works with empty vectors.
+    pub fn set_dyn_f32(self, data: Vec<f32>, shape: impl ToShape) -> Self {
+        self.set_dyn(data, shape)
+    }
+
     /// Set the name of a tensor
     pub fn set_name(&self, name: &str) {
         self.graph().get_op_mut::<Function>(self.id).0 = name.to_string();
@@ -88,19 +97,28 @@ impl GraphTensor {
 
     /// Get the contiguous data of the tensor
     pub fn data(&self) -> Vec<f32> {
+        self.data_as()
+    }
+
+    /// Get the contiguous data of a tensor of any element type
+    pub fn data_as<T: Element>(&self) -> Vec<T> {
         let tensor = self
             .graph()
             .get_tensor_ref(self.id, 0)
             .expect("Tensor not found in the graph!");
-        let orig_data = tensor
-            .downcast_ref::<Vec<f32>>()
-            .expect("Data for tensor is not Vec<f32>!");
+        let orig_data = tensor.downcast_ref::<Vec<T>>().unwrap_or_else(|| {
+            panic!(
+                "Data for tensor is {:?}, not {:?}!",
+                tensor.dtype(),
+                T::DTYPE
+            )
+        });
         let mut st = self.shape;
         if !st.is_reshaped() {
             return orig_data.clone();
         }
         st.resolve_global_dyn_dims(&self.graph().dyn_map);
-        let mut data = vec![0.; st.n_elements().to_usize().unwrap()];
+        let mut data = vec![T::default(); st.n_elements().to_usize().unwrap()];
         let (ind, val) = (
             st.index_expression_no_simplify(),
             st.valid_expression_no_simplify(),
@@ -168,18 +186,19 @@ impl GraphTensor {
         (dims[0], dims[1], dims[2], dims[3], dims[4])
     }
 
-    /// Set the value of the tensor matching the constant shape
-    pub fn set<T: Data + Clone, D: ToData<T>>(self, data: D) -> Self {
+    /// Set the value of the tensor matching the constant shape. The returned tensor has the element type of the data
+    pub fn set<T: Data + Clone, D: ToData<T>>(mut self, data: D) -> Self {
         let (data, _) = data.to_data_vec();
-        self.graph().get_op_mut::<Function>(self.id).1 =
-            Box::new(move |_| vec![Tensor::new(data.to_owned())]);
+        self.dtype = data.dtype().unwrap_or(self.dtype);
+        self.graph()
+            .set_loader(self.id, move |_| vec![Tensor::new(data.to_owned())]);
         self
     }
 
     /// Set the tensor with a generating closure to be ran at runtime
     pub fn set_deferred(self, loader: impl Fn() -> Vec<f32> + 'static) -> Self {
-        self.graph().get_op_mut::<Function>(self.id).1 =
-            Box::new(move |_| vec![Tensor::new(loader())]);
+        self.graph()
+            .set_loader(self.id, move |_| vec![Tensor::new(loader())]);
         self
     }
 }
@@ -287,6 +306,10 @@ pub trait MarkTensors {
     fn drop(&self);
     /// Set data
     fn set_dyn(&self, data: impl Data + Clone, shape: impl ToShape + Copy);
+    /// Set f32 data
+    fn set_dyn_f32(&self, data: Vec<f32>, shape: impl ToShape + Copy) {
+        self.set_dyn(data, shape);
+    }
 }
 
 impl MarkTensors for GraphTensor {
@@ -397,8 +420,8 @@ pub trait ToData<T> {
     fn to_data_vec(self) -> (T, Vec<usize>);
 }
 
-impl ToData<Vec<f32>> for Vec<f32> {
-    fn to_data_vec(self) -> (Vec<f32>, Vec<usize>) {
+impl<T: Element> ToData<Vec<T>> for Vec<T> {
+    fn to_data_vec(self) -> (Vec<T>, Vec<usize>) {
         let l = self.len();
         (self, vec![l])
     }
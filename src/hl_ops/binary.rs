@@ -22,13 +22,18 @@ impl Add for GraphTensor {
         //         .collect::<Vec<_>>(),
         //     "Dims must match to add tensors."
         // );
+        let (lhs, rhs) = self.promote(rhs);
         let new_id = self
             .graph()
             .add_op(op::Add)
-            .input(self.id, 0, self.shape)
+            .input(lhs.id, 0, lhs.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: lhs.shape.contiguous(),
+            ..lhs
+        }
     }
 }
 
@@ -77,13 +82,18 @@ impl Mul for GraphTensor {
         //     rhs.dims(),
         //     "Dims must match to multiply tensors."
         // );
+        let (lhs, rhs) = self.promote(rhs);
         let new_id = self
             .graph()
             .add_op(op::Mul)
-            .input(self.id, 0, self.shape)
+            .input(lhs.id, 0, lhs.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: lhs.shape.contiguous(),
+            ..lhs
+        }
     }
 }
 
@@ -130,13 +140,18 @@ impl Rem<GraphTensor> for GraphTensor {
 
     fn rem(self, rhs: GraphTensor) -> Self::Output {
         assert_eq!(self.dims(), rhs.dims(), "Dims must match to mod tensors.");
+        let (lhs, rhs) = self.promote(rhs);
         let new_id = self
             .graph()
             .add_op(op::Mod)
-            .input(self.id, 0, self.shape)
+            .input(lhs.id, 0, lhs.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: lhs.shape.contiguous(),
+            ..lhs
+        }
     }
 }
 
@@ -150,7 +165,7 @@ impl Add<f32> for GraphTensor {
     type Output = GraphTensor;
 
     fn add(self, rhs: f32) -> Self::Output {
-        self + self.graph().constant(rhs).expand(self.shape)
+        self + self.scalar(rhs)
     }
 }
 
@@ -158,7 +173,7 @@ impl<S: Into<Expression>> Add<S> for GraphTensor {
     type Output = GraphTensor;
 
     fn add(self, rhs: S) -> Self::Output {
-        self + self.graph().constant(rhs).expand(self.shape)
+        self + self.scalar(rhs)
     }
 }
 
@@ -166,7 +181,7 @@ impl Sub<f32> for GraphTensor {
     type Output = GraphTensor;
 
     fn sub(self, rhs: f32) -> Self::Output {
-        self - self.graph().constant(rhs).expand(self.shape)
+        self - self.scalar(rhs)
     }
 }
 
@@ -174,7 +189,7 @@ impl<S: Into<Expression>> Sub<S> for GraphTensor {
     type Output = GraphTensor;
 
     fn sub(self, rhs: S) -> Self::Output {
-        self - self.graph().constant(rhs).expand(self.shape)
+        self - self.scalar(rhs)
     }
 }
 
@@ -182,7 +197,7 @@ impl Mul<f32> for GraphTensor {
     type Output = GraphTensor;
 
     fn mul(self, rhs: f32) -> Self::Output {
-        self * self.graph().constant(rhs).expand(self.shape)
+        self * self.scalar(rhs)
     }
 }
 
@@ -190,7 +205,7 @@ impl<S: Into<Expression>> Mul<S> for GraphTensor {
     type Output = GraphTensor;
 
     fn mul(self, rhs: S) -> Self::Output {
-        self * self.graph().constant(rhs).expand(self.shape)
+        self * self.scalar(rhs)
     }
 }
 
@@ -199,7 +214,7 @@ impl Div<f32> for GraphTensor {
     type Output = GraphTensor;
 
     fn div(self, rhs: f32) -> Self::Output {
-        self * self.graph().constant(rhs.recip()).expand(self.shape)
+        self * self.scalar(rhs.recip())
     }
 }
 
@@ -207,7 +222,7 @@ impl<S: Into<Expression>> Div<S> for GraphTensor {
     type Output = GraphTensor;
 
     fn div(self, rhs: S) -> Self::Output {
-        self / self.graph().constant(rhs).expand(self.shape)
+        self / self.scalar(rhs)
     }
 }
 
@@ -215,7 +230,7 @@ impl Rem<f32> for GraphTensor {
     type Output = GraphTensor;
 
     fn rem(self, rhs: f32) -> Self::Output {
-        self % self.graph().constant(rhs).expand(self.shape)
+        self % self.scalar(rhs)
     }
 }
 
@@ -223,7 +238,23 @@ impl<S: Into<Expression>> Rem<S> for GraphTensor {
     type Output = GraphTensor;
 
     fn rem(self, rhs: S) -> Self::Output {
-        self % self.graph().constant(rhs).expand(self.shape)
+        self % self.scalar(rhs)
+    }
+}
+
+impl GraphTensor {
+    /// Convert both operands of a binary op to their promoted element type
+    fn promote(self, rhs: GraphTensor) -> (GraphTensor, GraphTensor) {
+        let dtype = self.dtype.promote(rhs.dtype);
+        (self.cast(dtype), rhs.cast(dtype))
+    }
+
+    /// A constant expanded to this tensor's shape, with this tensor's element type
+    fn scalar(self, value: impl Into<ConstantValue>) -> GraphTensor {
+        self.graph()
+            .constant(value)
+            .cast(self.dtype)
+            .expand(self.shape)
     }
 }
 
@@ -231,13 +262,18 @@ impl<S: Into<Expression>> Rem<S> for GraphTensor {
 impl GraphTensor {
     pub fn lt(self, rhs: GraphTensor) -> GraphTensor {
         assert_eq!(self.dims(), rhs.dims(), "Dims must match to lt tensors.");
+        let (lhs, rhs) = self.promote(rhs);
         let new_id = self
             .graph()
             .add_op(op::LessThan)
-            .input(self.id, 0, self.shape)
+            .input(lhs.id, 0, lhs.shape)
             .input(rhs.id, 0, rhs.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: lhs.shape.contiguous(),
+            ..lhs
+        }
     }
 
     pub fn gt(self, rhs: GraphTensor) -> GraphTensor {
@@ -270,6 +306,30 @@ impl GraphTensor {
     }
 }
 
+// Selection
+impl GraphTensor {
+    /// Take elements of `on_true` where this tensor is nonzero, and elements of `on_false` elsewhere (like `torch.where`)
+    ///
+    /// Elements that aren't taken never reach the output, so infinities and NaNs in them are safe
+    pub fn select(self, on_true: GraphTensor, on_false: GraphTensor) -> GraphTensor {
+        assert_eq!(self.dims(), on_true.dims(), "Dims must match to select.");
+        assert_eq!(self.dims(), on_false.dims(), "Dims must match to select.");
+        let (on_true, on_false) = on_true.promote(on_false);
+        let new_id = self
+            .graph()
+            .add_op(op::Select)
+            .input(self.id, 0, self.shape)
+            .input(on_true.id, 0, on_true.shape)
+            .input(on_false.id, 0, on_false.shape)
+            .finish();
+        GraphTensor {
+            id: new_id,
+            shape: on_true.shape.contiguous(),
+            ..on_true
+        }
+    }
+}
+
 // Clipping ops (minimum, maximum, clip)
 impl GraphTensor {
     /// Take the elementwise maximum of two tensors
@@ -279,7 +339,7 @@ impl GraphTensor {
 
     /// Take the elementwise maximum of a tensor and a float
     pub fn maximum_f32(self, rhs: f32) -> GraphTensor {
-        self.maximum(self.graph().constant(rhs).expand(self.shape))
+        self.maximum(self.scalar(rhs))
     }
 
     /// Take the elementwise minimum of two tensors
@@ -330,6 +390,18 @@ mod tests {
         assert_close(&result.data(), &expected_result.data());
     }
 
+    #[test]
+    fn test_select() {
+        let mut cx = Graph::new();
+        let cond = cx.tensor(4).set([1., 0., 0., 2.]);
+        let on_true = cx.tensor(4).set([1., f32::INFINITY, f32::NAN, 4.]);
+        let on_false = cx.tensor(4).set([f32::NEG_INFINITY, 2., 3., f32::NAN]);
+        let result = cond.select(on_true, on_false).retrieve();
+        cx.execute();
+
+        assert_exact(&result.data(), &[1., 2., 3., 4.]);
+    }
+
     #[test]
     fn test_pow() {
         let base = 2_f32;
@@ -1,3 +1,6 @@
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
 use crate::prelude::*;
 
 impl GraphTensor {
@@ -104,6 +107,199 @@ impl GraphTensor {
     pub fn dot(self, rhs: GraphTensor) -> GraphTensor {
         (self * rhs).sum(0)
     }
+
+    /// Einstein summation over one or more tensors, like `torch.einsum`. For instance `"bhqd,bhkd->bhqk"` is a batched matmul over `d`.
+    ///
+    /// Each operand labels its dimensions with one letter each, and labels missing from the output are summed over. Without `->`, the output is the labels used only once, in alphabetical order.
+    /// Operands are contracted left to right, and pairs sharing a summed label go through `matmul`.
+    pub fn einsum(spec: &str, operands: &[GraphTensor]) -> GraphTensor {
+        let spec = spec.replace(char::is_whitespace, "");
+        let (inputs, output) = match spec.split_once("->") {
+            Some((inputs, output)) => (inputs, Some(output)),
+            None => (spec.as_str(), None),
+        };
+        let inputs = inputs
+            .split(',')
+            .map(|i| i.chars().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            inputs.len(),
+            operands.len(),
+            "Einsum spec {spec} has {} operands, but {} were given",
+            inputs.len(),
+            operands.len()
+        );
+
+        // Every use of a label must have the same size
+        let mut sizes = FxHashMap::default();
+        for (i, (labels, operand)) in inputs.iter().zip(operands).enumerate() {
+            let dims = operand.dims();
+            assert_eq!(
+                labels.len(),
+                dims.len(),
+                "Einsum operand {i} is labeled {} but has shape {dims:?}",
+                labels.iter().collect::<String>()
+            );
+            for (j, (label, size)) in labels.iter().zip(dims).enumerate() {
+                assert!(
+                    label.is_ascii_alphabetic(),
+                    "Einsum labels must be letters, found '{label}'"
+                );
+                assert!(
+                    !labels[..j].contains(label),
+                    "Einsum label '{label}' is repeated in operand {i}, which isn't supported"
+                );
+                let expected = *sizes.entry(*label).or_insert(size);
+                let same = match (expected.to_usize(), size.to_usize()) {
+                    (Some(a), Some(b)) => a == b,
+                    (None, None) => expected == size || expected.equivalent(&size),
+                    _ => false,
+                };
+                assert!(
+                    same,
+                    "Einsum label '{label}' has size {expected} but operand {i} has size {size}"
+                );
+            }
+        }
+        let output = match output {
+            Some(output) => output.chars().collect::<Vec<_>>(),
+            None => inputs
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|l| inputs.iter().flatten().filter(|i| *i == l).count() == 1)
+                .sorted()
+                .collect(),
+        };
+        for (i, label) in output.iter().enumerate() {
+            assert!(
+                sizes.contains_key(label),
+                "Einsum output label '{label}' isn't in any operand"
+            );
+            assert!(
+                !output[..i].contains(label),
+                "Einsum output label '{label}' is repeated"
+            );
+        }
+
+        let mut operands = inputs.into_iter().zip(operands.iter().copied());
+        let mut result = operands.next().expect("Einsum needs at least one operand");
+        let rest = operands.collect::<Vec<_>>();
+        for (i, operand) in rest.iter().enumerate() {
+            // Labels still used by the output or later operands
+            let keep = output
+                .iter()
+                .chain(rest[i + 1..].iter().flat_map(|(l, _)| l))
+                .copied()
+                .collect::<Vec<_>>();
+            result = contract(result, operand.clone(), &keep, &sizes);
+        }
+        let (labels, result) = sum_labels(result, &output);
+        result.permute(
+            output
+                .iter()
+                .map(|l| labels.iter().position(|i| i == l).unwrap())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+/// Sum away the labels of a tensor that aren't in `keep`
+fn sum_labels(
+    (mut labels, tensor): (Vec<char>, GraphTensor),
+    keep: &[char],
+) -> (Vec<char>, GraphTensor) {
+    let axes = (0..labels.len())
+        .filter(|i| !keep.contains(&labels[*i]))
+        .collect::<Vec<_>>();
+    labels.retain(|l| keep.contains(l));
+    (labels, tensor.sum(axes))
+}
+
+/// Put a tensor's labels in `order`, broadcasting the ones it doesn't have
+fn arrange(
+    (labels, tensor): &(Vec<char>, GraphTensor),
+    order: &[char],
+    sizes: &FxHashMap<char, Expression>,
+) -> GraphTensor {
+    let mut tensor = tensor.permute(
+        order
+            .iter()
+            .filter_map(|l| labels.iter().position(|i| i == l))
+            .collect::<Vec<_>>(),
+    );
+    for (i, label) in order.iter().enumerate() {
+        if !labels.contains(label) {
+            tensor = tensor.expand_dim(i, sizes[label]);
+        }
+    }
+    tensor
+}
+
+/// Contract two labeled tensors, keeping the labels in `keep`
+fn contract(
+    lhs: (Vec<char>, GraphTensor),
+    rhs: (Vec<char>, GraphTensor),
+    keep: &[char],
+    sizes: &FxHashMap<char, Expression>,
+) -> (Vec<char>, GraphTensor) {
+    // Labels only one side has can be summed before multiplying
+    let lhs = sum_labels(lhs, &[keep, &rhs.0].concat());
+    let rhs = sum_labels(rhs, &[keep, &lhs.0].concat());
+    let (batch, summed): (Vec<char>, Vec<char>) = lhs
+        .0
+        .iter()
+        .filter(|l| rhs.0.contains(l))
+        .partition(|l| keep.contains(l));
+    let m = lhs
+        .0
+        .iter()
+        .filter(|l| !rhs.0.contains(l))
+        .copied()
+        .collect::<Vec<_>>();
+    let n = rhs
+        .0
+        .iter()
+        .filter(|l| !lhs.0.contains(l))
+        .copied()
+        .collect::<Vec<_>>();
+    let labels = [batch.clone(), m.clone(), n.clone()].concat();
+    if summed.is_empty() {
+        // Nothing to contract, so this is a broadcasted multiply
+        let product = arrange(&lhs, &labels, sizes) * arrange(&rhs, &labels, sizes);
+        return (labels, product);
+    }
+
+    // Lay the sides out as [batch, M, K] and [batch, K, N], keeping up to two batch dims
+    let mut batch_groups = batch.iter().map(|l| vec![*l]).collect::<Vec<_>>();
+    if batch_groups.len() > 2 {
+        batch_groups = vec![batch.clone()];
+    }
+    let group = |side: &(Vec<char>, GraphTensor), groups: Vec<Vec<char>>| {
+        let tensor = arrange(side, &groups.concat(), sizes);
+        if groups.iter().all(|g| g.len() == 1) {
+            return tensor;
+        }
+        tensor.reshape(
+            groups
+                .iter()
+                .map(|g| g.iter().fold(Expression::from(1), |acc, l| acc * sizes[l]))
+                .collect::<Vec<_>>(),
+        )
+    };
+    let lhs = group(
+        &lhs,
+        [batch_groups.clone(), vec![m.clone(), summed.clone()]].concat(),
+    );
+    let rhs = group(
+        &rhs,
+        [batch_groups.clone(), vec![summed, n.clone()]].concat(),
+    );
+    let mut result = lhs.matmul(rhs);
+    if batch_groups.len() != batch.len() || m.len() != 1 || n.len() != 1 {
+        result = result.reshape(labels.iter().map(|l| sizes[l]).collect::<Vec<_>>());
+    }
+    (labels, result)
 }
 
 #[cfg(test)]
@@ -213,4 +409,92 @@ mod tests {
 
         assert_close(&c.data(), &d_c.as_vec());
     }
+
+    #[test]
+    fn test_einsum() {
+        let mut cx = Graph::new();
+        let q = cx
+            .tensor((2, 3, 'a', 4))
+            .set_dyn(random_vec(120), (2, 3, 5, 4));
+        let k = cx
+            .tensor((2, 3, 'a', 4))
+            .set_dyn(random_vec(120), (2, 3, 5, 4));
+        let (a, b, c) = (
+            cx.tensor((2, 3)).set(random_vec(6)),
+            cx.tensor((3, 4)).set(random_vec(12)),
+            cx.tensor((4, 2)).set(random_vec(8)),
+        );
+        let (x, y) = (
+            cx.tensor((2, 3, 4)).set(random_vec(24)),
+            cx.tensor((4, 2, 3)).set(random_vec(24)),
+        );
+        let (w, p, r) = (
+            cx.tensor((2, 4)).set(random_vec(8)),
+            cx.tensor(3).set(random_vec(3)),
+            cx.tensor(2).set(random_vec(2)),
+        );
+        let (u, v) = (
+            cx.tensor((2, 1, 2, 3, 4)).set(random_vec(48)),
+            cx.tensor((2, 1, 2, 4, 3)).set(random_vec(48)),
+        );
+        let cases = [
+            (
+                GraphTensor::einsum("bhqd,bhkd->bhqk", &[q, k]),
+                q.matmul(k.permute((0, 1, 3, 2))),
+            ),
+            (GraphTensor::einsum("ij,jk", &[a, b]), a.matmul(b)),
+            (
+                GraphTensor::einsum("ij,jk,kl->il", &[a, b, c]),
+                a.matmul(b).matmul(c),
+            ),
+            (
+                GraphTensor::einsum("abc,cde->ebda", &[x, y]),
+                x.reshape((6, 4))
+                    .matmul(y.reshape((4, 6)))
+                    .reshape((2, 3, 2, 3))
+                    .permute((3, 1, 2, 0)),
+            ),
+            (
+                GraphTensor::einsum("abcij,abcjk->abcik", &[u, v]),
+                u.matmul(v),
+            ),
+            (
+                GraphTensor::einsum("bij, bj -> bi", &[x, w]),
+                (x * w.expand_dim(1, 3)).sum(2),
+            ),
+            (
+                GraphTensor::einsum("i,j->ij", &[p, r]),
+                p.expand_dim(1, 2) * r.expand_dim(0, 3),
+            ),
+            (GraphTensor::einsum("ij->ji", &[a]), a.permute((1, 0))),
+            (GraphTensor::einsum("ij->", &[a]), a.sum((0, 1))),
+        ];
+        let cases = cases
+            .into_iter()
+            .map(|(a, b)| (a.retrieve(), b.retrieve()))
+            .collect::<Vec<_>>();
+        cx.set_dyn_dim('a', 5);
+
+        cx.execute();
+
+        for (einsum, expected) in cases {
+            let dims = |t: GraphTensor| {
+                t.dims()
+                    .into_iter()
+                    .map(|d| d.exec(&cx.dyn_map).unwrap())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(dims(einsum), dims(expected));
+            assert_close(&einsum.data(), &expected.data());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Einsum label 'j' has size 3 but operand 1 has size 4")]
+    fn test_einsum_mismatched_sizes() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3));
+        let b = cx.tensor((4, 2));
+        GraphTensor::einsum("ij,jk->ik", &[a, b]);
+    }
 }
@@ -1,63 +1,121 @@
-use std::{f32, path::PathBuf};
+use std::{
+    f32,
+    path::{Path, PathBuf},
+};
 
 use colored::Colorize;
 use itertools::Itertools;
 
 use crate::{
+    npy::view_f32,
     op::{self, Constant, ConstantValue},
     prelude::*,
 };
 
 impl GraphTensor {
-    /// Cumulative sum last dimension
-    pub fn cumsum_last_dim(mut self) -> Self {
-        let axis = self.shape.len() - 1;
-        if !self.shape.is_contiguous() {
-            self = self.contiguous();
-        }
-        // Pad out length
-        let orig_length = self.shape.dims[self.shape.indexes[axis]];
-        self.shape.padding[self.shape.indexes[axis]].0 = orig_length - 1;
-        self = self.contiguous();
+    /// Cumulative sum along `axis`
+    pub fn cumsum(self, axis: usize) -> Self {
+        self.cumulative(axis, false, false, false)
+    }
 
-        // Pool
-        let mut pooled = self.pool_last_dim(orig_length, 1, 1);
-        // Sum Reduce along new dimension
-        let final_id = self
-            .graph()
-            .add_op(op::SumReduce(axis))
-            .input(pooled.id, 0, pooled.shape)
-            .finish();
-        pooled.shape.remove_dim(axis + 1);
-        GraphTensor::from_id(final_id, pooled.shape, self.graph_ref)
+    /// Cumulative sum along `axis`, running from the end if `reverse`. If `exclusive`, each position leaves out its own element, so the first is 0
+    pub fn cumsum_with(self, axis: usize, reverse: bool, exclusive: bool) -> Self {
+        self.cumulative(axis, reverse, exclusive, false)
     }
 
-    /// Cumulative max last dimension
-    pub fn cummax_last_dim(mut self) -> Self {
-        let axis = self.shape.len() - 1;
-        if !self.shape.is_contiguous() {
-            self = self.contiguous();
-        }
-        // Pad out length
-        let orig_length = self.shape.dims[self.shape.indexes[axis]];
-        self.shape.padding[self.shape.indexes[axis]].0 = orig_length - 1;
-        self = self.contiguous();
+    /// Cumulative max along `axis`
+    pub fn cummax(self, axis: usize) -> Self {
+        self.cumulative(axis, false, false, true)
+    }
 
-        // Pool
-        let mut pooled = self.pool_last_dim(orig_length, 1, 1);
-        // Max Reduce along new dimension
-        let final_id = self
-            .graph()
-            .add_op(op::MaxReduce(axis))
-            .input(pooled.id, 0, pooled.shape)
-            .finish();
-        pooled.shape.remove_dim(axis + 1);
-        GraphTensor::from_id(final_id, pooled.shape, self.graph_ref)
+    /// Cumulative max along `axis`, running from the end if `reverse`. If `exclusive`, each position leaves out its own element, so the first is -inf
+    pub fn cummax_with(self, axis: usize, reverse: bool, exclusive: bool) -> Self {
+        self.cumulative(axis, reverse, exclusive, true)
+    }
+
+    /// Cumulative product along `axis`
+    pub fn cumprod(self, axis: usize) -> Self {
+        self.cumprod_with(axis, false, false)
+    }
+
+    /// Cumulative product along `axis`, running from the end if `reverse`. If `exclusive`, each position leaves out its own element, so the first is 1
+    pub fn cumprod_with(self, axis: usize, reverse: bool, exclusive: bool) -> Self {
+        // Multiply magnitudes as a sum of logs. A zero has a log of -inf, so everything after it stays 0
+        let magnitude = self.abs().log().cumsum_with(axis, reverse, exclusive).exp();
+        // The product is negative when an odd number of negative elements went into it
+        let negatives = self
+            .lt(self.graph().constant(0.).expand(self.shape))
+            .cumsum_with(axis, reverse, exclusive);
+        magnitude * (1. - (negatives % 2.) * 2.)
+    }
+
+    /// Cumulative sum last dimension
+    pub fn cumsum_last_dim(self) -> Self {
+        self.cumsum(self.shape.len() - 1)
+    }
+
+    /// Cumulative max last dimension
+    pub fn cummax_last_dim(self) -> Self {
+        self.cummax(self.shape.len() - 1)
     }
 
     /// Cumulative product last dimension
     pub fn cumprod_last_dim(self) -> Self {
-        self.log().cumsum_last_dim().exp()
+        self.cumprod(self.shape.len() - 1)
+    }
+
+    /// Sum or max reduce a window of every position along `axis`
+    fn cumulative(self, axis: usize, reverse: bool, exclusive: bool, max: bool) -> Self {
+        // Work along the last dimension
+        let last = self.shape.len() - 1;
+        let order = (0..=last)
+            .filter(|d| *d != axis)
+            .chain([axis])
+            .collect::<Vec<_>>();
+        let mut x = self.permute(order.clone());
+        let length = x.dims()[last];
+        if exclusive {
+            // No window includes the element on the far end
+            x = if reverse {
+                x.slice_along(1.., last)
+            } else {
+                x.slice_along(..length - 1, last)
+            }
+            .contiguous();
+        }
+        if !x.shape.is_contiguous() {
+            x = x.contiguous();
+        }
+        // Pad out length so every position has a full window
+        let pad = if exclusive { length } else { length - 1 };
+        let padding = if reverse {
+            (Expression::from(0), pad)
+        } else {
+            (pad, Expression::from(0))
+        };
+        if max {
+            // Padding is 0, so push it down to -inf with the log of a padded mask
+            let mut mask = self.graph().constant(1.).expand(x.shape).contiguous();
+            mask.shape.padding[mask.shape.indexes[last]] = padding;
+            x.shape.padding[x.shape.indexes[last]] = padding;
+            x += mask.log();
+        } else {
+            x.shape.padding[x.shape.indexes[last]] = padding;
+            x = x.contiguous();
+        }
+
+        // Pool
+        let pooled = x.pool_last_dim(length, 1, 1);
+        let reduced = if max {
+            pooled.max(last + 1)
+        } else {
+            pooled.sum(last + 1)
+        };
+        reduced.permute(
+            (0..=last)
+                .map(|d| order.iter().position(|o| *o == d).unwrap())
+                .collect::<Vec<_>>(),
+        )
     }
 }
 
@@ -127,14 +185,130 @@ impl Graph {
 impl GraphTensor {
     /// Gather a batch of vectors from a matrix
     pub fn gather(self, indexes: GraphTensor) -> GraphTensor {
-        let (vocab, dim) = self.dims2();
-        let batch = indexes.dims1();
-        let one_hot = indexes
+        self.index_select(0, indexes)
+    }
+
+    /// Select slices along `axis` with a 1D tensor of indexes, like `torch.index_select`. The output has the number of indexes along `axis`
+    pub fn index_select(self, axis: usize, indexes: GraphTensor) -> GraphTensor {
+        let dims = self.dims();
+        let (size, n_indexes) = (dims[axis], indexes.dims1());
+        // A [K, N] one-hot broadcast over the other dims
+        let mut one_hot = self
+            .graph()
+            .arange(size)
+            .expand_dim(0, n_indexes)
+            .eq(indexes.expand_dim(1, size));
+        for (i, d) in dims.iter().enumerate() {
+            if i != axis {
+                one_hot = one_hot.expand_dim(if i < axis { i } else { i + 1 }, *d);
+            }
+        }
+        (one_hot * self.expand_dim(axis, n_indexes)).sum(axis + 1)
+    }
+
+    /// Take values along `axis` at the positions given by `indexes`, like `torch.gather` / `numpy.take_along_axis`.
+    ///
+    /// `indexes` has the same rank as this tensor and matches it outside of `axis`. The output has the shape of `indexes`
+    pub fn take_along_axis(self, axis: usize, indexes: GraphTensor) -> GraphTensor {
+        let one_hot = self.one_hot_along(axis, indexes);
+        (one_hot * self.expand_dim(axis, indexes.dims()[axis])).sum(axis + 1)
+    }
+
+    /// Get the indexes that sort this tensor along `axis`. Equal elements keep their order
+    pub fn argsort(self, axis: usize, descending: bool) -> GraphTensor {
+        let id = self
             .graph()
-            .arange(vocab)
-            .expand_dim(0, batch)
-            .eq(indexes.expand_dim(1, vocab));
-        (one_hot.expand_dim(2, dim) * self.expand_dim(0, batch)).sum(1)
+            .add_op(op::ArgSort { axis, descending })
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor {
+            id,
+            shape: self.shape.contiguous(),
+            dtype: DType::F32,
+            ..self
+        }
+    }
+
+    /// Sort this tensor along `axis`
+    pub fn sort(self, axis: usize, descending: bool) -> GraphTensor {
+        self.take_along_axis(axis, self.argsort(axis, descending))
+    }
+
+    /// Get the `k` largest values along `axis` in descending order, and their indexes
+    pub fn topk(self, k: usize, axis: usize) -> (GraphTensor, GraphTensor) {
+        let indexes = self.argsort(axis, true).slice_along(..k, axis);
+        (self.take_along_axis(axis, indexes), indexes)
+    }
+
+    /// One-hot encode `indexes` against this tensor's `axis`, as [..., K, N, ...] with the indexes along K and positions along N
+    fn one_hot_along(self, axis: usize, indexes: GraphTensor) -> GraphTensor {
+        let (dims, index_dims) = (self.dims(), indexes.dims());
+        assert_eq!(
+            dims.len(),
+            index_dims.len(),
+            "Indexes must have the same rank as the tensor"
+        );
+        assert!(
+            dims.iter()
+                .zip(&index_dims)
+                .enumerate()
+                .all(|(i, (a, b))| i == axis || a == b),
+            "Indexes must match the tensor's shape outside of axis {axis}"
+        );
+        let size = dims[axis];
+        let mut arange = self.graph().arange(size);
+        for (i, d) in index_dims.iter().enumerate() {
+            arange = arange.expand_dim(if i <= axis { i } else { i + 1 }, *d);
+        }
+        indexes.expand_dim(axis + 1, size).eq(arange)
+    }
+
+    /// Returns the number of indexes hitting each position along `axis`, and the sum of `src` values written there
+    fn scatter_parts(
+        self,
+        axis: usize,
+        indexes: GraphTensor,
+        src: GraphTensor,
+    ) -> (GraphTensor, GraphTensor) {
+        assert_eq!(
+            indexes.dims(),
+            src.dims(),
+            "Indexes and src must have the same shape"
+        );
+        let one_hot = self.one_hot_along(axis, indexes);
+        let src = src.expand_dim(axis + 1, self.dims()[axis]);
+        // Select rather than multiply so infinities in src don't become NaNs where they aren't written
+        let zeros = self.graph().constant(0.).expand(one_hot.dims());
+        let written = one_hot.select(src, zeros).sum(axis);
+        (one_hot.sum(axis), written)
+    }
+
+    /// Write the values of `src` into this tensor at the positions along `axis` given by `indexes`, like `torch.scatter`.
+    ///
+    /// `indexes` and `src` share a shape, which matches this tensor outside of `axis`. Values written to the same position are summed, and out of range indexes are ignored.
+    pub fn scatter(self, axis: usize, indexes: GraphTensor, src: GraphTensor) -> GraphTensor {
+        let (hits, written) = self.scatter_parts(axis, indexes, src);
+        hits.select(written, self)
+    }
+
+    /// Add the values of `src` to this tensor at the positions along `axis` given by `indexes`, like `torch.scatter_add`
+    pub fn scatter_add(self, axis: usize, indexes: GraphTensor, src: GraphTensor) -> GraphTensor {
+        let (_, written) = self.scatter_parts(axis, indexes, src);
+        self + written
+    }
+
+    /// Replace whole slices of this tensor along `axis`: slice `i` of `values` is written to slice `indexes[i]`.
+    ///
+    /// `indexes` is 1D, and `values` matches this tensor outside of `axis`.
+    pub fn index_put(self, axis: usize, indexes: GraphTensor, values: GraphTensor) -> GraphTensor {
+        let value_dims = values.dims();
+        let mut expanded = indexes;
+        for (i, d) in value_dims.iter().enumerate() {
+            if i != axis {
+                expanded = expanded.expand_dim(i, *d);
+            }
+        }
+        self.scatter(axis, expanded, values)
     }
 
     /// Print the value of this tensor when the graph is ran
@@ -209,142 +383,7 @@ impl GraphTensor {
                         println!("Data Shape: {shape:?}");
                         return vec![];
                     }
-                    let data_nan = data.iter().any(|i| i.is_nan());
-                    let file_nan = bin_data.iter().any(|i| i.is_nan());
-                    if data_nan {
-                        println!(
-                            "{}",
-                            format!("{} | Data contains nan!", path.to_str().unwrap())
-                                .bold()
-                                .red()
-                        );
-                    }
-                    if file_nan {
-                        println!(
-                            "{}",
-                            format!("{} | File contains nan!", path.to_str().unwrap())
-                                .bold()
-                                .red()
-                        );
-                    }
-                    if data_nan || file_nan {
-                        return vec![];
-                    }
-                    let mut matched = true;
-                    for (i, (a, b)) in data.iter().zip(bin_data.iter()).enumerate() {
-                        let tolerance = atol + rtol * a.abs().max(b.abs());
-                        if (a - b).abs() > tolerance {
-                            println!(
-                                "{}",
-                                format!("{} | Value Mismatch!", path.to_str().unwrap())
-                                    .bold()
-                                    .red()
-                            );
-                            if let Some((i, _)) = data.iter().enumerate().find(|(_, i)| i.is_nan())
-                            {
-                                println!("Index {} is nan!", i.to_string().bold());
-                            }
-                            println!("{a} is not equal to {b}, index {i}");
-
-                            let mut diffs: Vec<f32> = data
-                                .iter()
-                                .zip(bin_data.iter())
-                                .map(|(a, b)| (a - b).abs())
-                                .collect();
-                            diffs.sort_by(|x, y| {
-                                x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal)
-                            });
-                            let len = diffs.len();
-                            // percentile indices (clamp to len-1)
-                            let p50_idx = ((len as f32) * 0.50).round() as usize;
-                            let p95_idx = ((len as f32) * 0.95).round() as usize;
-                            let p99_idx = ((len as f32) * 0.99).round() as usize;
-                            let p50 = diffs[p50_idx.min(len - 1)];
-                            let p95 = diffs[p95_idx.min(len - 1)];
-                            let p99 = diffs[p99_idx.min(len - 1)];
-
-                            // summary stats
-                            let avg_dist = diffs.iter().sum::<f32>() / len as f32;
-                            let max_dist = *diffs.last().unwrap();
-                            let sum_dist = data
-                                .iter()
-                                .zip(bin_data.iter())
-                                .map(|(a, b)| (a - b).powi(2))
-                                .sum::<f32>();
-
-                            println!(
-                                "Avg dist: {}, Max dist: {} Sum dist: {}",
-                                avg_dist.to_string().bold().red(),
-                                max_dist.to_string().bold().red(),
-                                sum_dist.to_string().bold().red(),
-                            );
-                            println!(
-                                "p50: {}  p95: {}  p99: {}",
-                                p50.to_string().bold().red(),
-                                p95.to_string().bold().red(),
-                                p99.to_string().bold().red(),
-                            );
-
-                            println!("Data Shape: {shape:?}");
-                            println!("{}: {:?}", "This".bold(), &data[..10]);
-                            println!("{}: {:?}", "File".bold(), &bin_data[..10]);
-                            println!(
-                                "Largest Mismatches: {:?}",
-                                data.iter()
-                                    .zip(bin_data.iter())
-                                    .filter(|(a, b)| (**a - **b).abs() > 0.01)
-                                    .sorted_by(|(a, b), (c, d)| (**c - **d)
-                                        .abs()
-                                        .partial_cmp(&(**a - **b).abs())
-                                        .unwrap_or(std::cmp::Ordering::Equal))
-                                    .take(10)
-                                    .collect::<Vec<_>>()
-                            );
-                            println!(
-                                "A avg: {} B avg: {}",
-                                data.iter().sum::<f32>() / data.len() as f32,
-                                bin_data.iter().sum::<f32>() / bin_data.len() as f32
-                            );
-                            println!(
-                                "A max: {} B max: {}",
-                                data.iter()
-                                    .max_by(|a, b| a
-                                        .partial_cmp(b)
-                                        .unwrap_or(std::cmp::Ordering::Equal))
-                                    .unwrap(),
-                                bin_data
-                                    .iter()
-                                    .max_by(|a, b| a
-                                        .partial_cmp(b)
-                                        .unwrap_or(std::cmp::Ordering::Equal))
-                                    .unwrap()
-                            );
-                            println!(
-                                "A min: {} B min: {}",
-                                data.iter()
-                                    .min_by(|a, b| a
-                                        .partial_cmp(b)
-                                        .unwrap_or(std::cmp::Ordering::Equal))
-                                    .unwrap(),
-                                bin_data
-                                    .iter()
-                                    .min_by(|a, b| a
-                                        .partial_cmp(b)
-                                        .unwrap_or(std::cmp::Ordering::Equal))
-                                    .unwrap()
-                            );
-                            matched = false;
-                            break;
-                        }
-                    }
-                    if matched {
-                        println!(
-                            "{}",
-                            format!("{} matched", path.to_str().unwrap())
-                                .bold()
-                                .bright_green()
-                        );
-                    }
+                    compare_data(&path, &data, &bin_data, &shape, atol, rtol);
                     vec![]
                 }),
             ))
@@ -353,6 +392,204 @@ impl GraphTensor {
         self.graph().no_delete.insert(id);
         *self
     }
+
+    /// Check the tensor value against an npy file, making sure the shapes match
+    pub fn diff_npy(&self, file: impl Into<PathBuf>, atol: f32, rtol: f32) -> Self {
+        let path = file.into();
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                format!("Diff {path:?}"),
+                Box::new(move |mut inp| {
+                    let (tensor, shape) = inp.pop().unwrap();
+                    diff_with_npy(&path, tensor.borrowed(), shape, atol, rtol);
+                    vec![]
+                }),
+            ))
+            .input(self.id, 0, self.shape)
+            .finish();
+        self.graph().no_delete.insert(id);
+        *self
+    }
+}
+
+/// Compare a tensor view against an npy file, printing a report. Returns whether they matched
+fn diff_with_npy(path: &Path, tensor: &Tensor, shape: ShapeTracker, atol: f32, rtol: f32) -> bool {
+    let Some(data) = view_f32(tensor, shape) else {
+        println!(
+            "{}",
+            format!("{} | Tensor has no numeric data!", path.to_str().unwrap())
+                .bold()
+                .red()
+        );
+        return false;
+    };
+    let file = match NpyArray::read(path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!(
+                "{}",
+                format!("{} | {e}", path.to_str().unwrap()).bold().red()
+            );
+            return false;
+        }
+    };
+    if file.shape != data.shape {
+        println!(
+            "{}",
+            format!(
+                "{} | Shape mismatch! Data: {:?}, File: {:?}",
+                path.to_str().unwrap(),
+                data.shape,
+                file.shape
+            )
+            .bold()
+            .red()
+        );
+        return false;
+    }
+    compare_data(path, &data.data, &file.data, &shape, atol, rtol)
+}
+
+/// Compare tensor data against reference data, printing a report of any mismatches. Returns whether they matched
+fn compare_data(
+    path: &Path,
+    data: &[f32],
+    bin_data: &[f32],
+    shape: &ShapeTracker,
+    atol: f32,
+    rtol: f32,
+) -> bool {
+    let data_nan = data.iter().any(|i| i.is_nan());
+    let file_nan = bin_data.iter().any(|i| i.is_nan());
+    if data_nan {
+        println!(
+            "{}",
+            format!("{} | Data contains nan!", path.to_str().unwrap())
+                .bold()
+                .red()
+        );
+    }
+    if file_nan {
+        println!(
+            "{}",
+            format!("{} | File contains nan!", path.to_str().unwrap())
+                .bold()
+                .red()
+        );
+    }
+    if data_nan || file_nan {
+        return false;
+    }
+    let mut matched = true;
+    for (i, (a, b)) in data.iter().zip(bin_data.iter()).enumerate() {
+        let tolerance = atol + rtol * a.abs().max(b.abs());
+        if (a - b).abs() > tolerance {
+            println!(
+                "{}",
+                format!("{} | Value Mismatch!", path.to_str().unwrap())
+                    .bold()
+                    .red()
+            );
+            if let Some((i, _)) = data.iter().enumerate().find(|(_, i)| i.is_nan()) {
+                println!("Index {} is nan!", i.to_string().bold());
+            }
+            println!("{a} is not equal to {b}, index {i}");
+
+            let mut diffs: Vec<f32> = data
+                .iter()
+                .zip(bin_data.iter())
+                .map(|(a, b)| (a - b).abs())
+                .collect();
+            diffs.sort_by(|x, y| x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal));
+            let len = diffs.len();
+            // percentile indices (clamp to len-1)
+            let p50_idx = ((len as f32) * 0.50).round() as usize;
+            let p95_idx = ((len as f32) * 0.95).round() as usize;
+            let p99_idx = ((len as f32) * 0.99).round() as usize;
+            let p50 = diffs[p50_idx.min(len - 1)];
+            let p95 = diffs[p95_idx.min(len - 1)];
+            let p99 = diffs[p99_idx.min(len - 1)];
+
+            // summary stats
+            let avg_dist = diffs.iter().sum::<f32>() / len as f32;
+            let max_dist = *diffs.last().unwrap();
+            let sum_dist = data
+                .iter()
+                .zip(bin_data.iter())
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f32>();
+
+            println!(
+                "Avg dist: {}, Max dist: {} Sum dist: {}",
+                avg_dist.to_string().bold().red(),
+                max_dist.to_string().bold().red(),
+                sum_dist.to_string().bold().red(),
+            );
+            println!(
+                "p50: {}  p95: {}  p99: {}",
+                p50.to_string().bold().red(),
+                p95.to_string().bold().red(),
+                p99.to_string().bold().red(),
+            );
+
+            println!("Data Shape: {shape:?}");
+            println!("{}: {:?}", "This".bold(), &data[..data.len().min(10)]);
+            println!(
+                "{}: {:?}",
+                "File".bold(),
+                &bin_data[..bin_data.len().min(10)]
+            );
+            println!(
+                "Largest Mismatches: {:?}",
+                data.iter()
+                    .zip(bin_data.iter())
+                    .filter(|(a, b)| (**a - **b).abs() > 0.01)
+                    .sorted_by(|(a, b), (c, d)| (**c - **d)
+                        .abs()
+                        .partial_cmp(&(**a - **b).abs())
+                        .unwrap_or(std::cmp::Ordering::Equal))
+                    .take(10)
+                    .collect::<Vec<_>>()
+            );
+            println!(
+                "A avg: {} B avg: {}",
+                data.iter().sum::<f32>() / data.len() as f32,
+                bin_data.iter().sum::<f32>() / bin_data.len() as f32
+            );
+            println!(
+                "A max: {} B max: {}",
+                data.iter()
+                    .max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+                    .unwrap(),
+                bin_data
+                    .iter()
+                    .max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+                    .unwrap()
+            );
+            println!(
+                "A min: {} B min: {}",
+                data.iter()
+                    .min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+                    .unwrap(),
+                bin_data
+                    .iter()
+                    .min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+                    .unwrap()
+            );
+            matched = false;
+            break;
+        }
+    }
+    if matched {
+        println!(
+            "{}",
+            format!("{} matched", path.to_str().unwrap())
+                .bold()
+                .bright_green()
+        );
+    }
+    matched
 }
 
 #[cfg(test)]
@@ -433,6 +670,61 @@ mod tests {
         cx.execute();
 
         assert_close(&b.data(), &[3., 6., 30.]);
+
+        // Negatives flip the sign and zeros stay zero
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![-2., 3., -1., 2., 0., -4.]);
+        let b = a.cumprod(1).retrieve();
+        let c = a.cumprod_with(1, true, true).retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &[-2., -6., 6., 2., 0., 0.]);
+        assert_close(&c.data(), &[-3., -1., 1., 0., -4., 1.]);
+    }
+
+    #[test]
+    fn test_cumulative() {
+        let mut cx = Graph::new();
+
+        let a = cx
+            .tensor((2, 'a'))
+            .set_dyn(vec![1., -2., 3., -4., 5., -6.], (2, 3));
+        let sums = [
+            a.cumsum(1),
+            a.cumsum(0),
+            a.cumsum_with(1, true, false),
+            a.cumsum_with(1, false, true),
+            a.cumsum_with(1, true, true),
+        ]
+        .map(|t| t.retrieve());
+        let maxes = [
+            a.cummax(1),
+            a.cummax(0),
+            a.cummax_with(1, true, false),
+            a.cummax_with(1, false, true),
+        ]
+        .map(|t| t.retrieve());
+        let b = cx
+            .tensor((2, 'a'))
+            .set_dyn(vec![1., 2., 3., 4., 5., 6.], (2, 3));
+        let prods = [b.cumprod(0), b.cumprod_with(1, true, true)].map(|t| t.retrieve());
+        cx.execute();
+
+        assert_exact(&sums[0].data(), &[1., -1., 2., -4., 1., -5.]);
+        assert_exact(&sums[1].data(), &[1., -2., 3., -3., 3., -3.]);
+        assert_exact(&sums[2].data(), &[2., 1., 3., -5., -1., -6.]);
+        assert_exact(&sums[3].data(), &[0., 1., -1., 0., -4., 1.]);
+        assert_exact(&sums[4].data(), &[1., 3., 0., -1., -6., 0.]);
+        // Negative values aren't hidden by the padding
+        assert_exact(&maxes[0].data(), &[1., 1., 3., -4., 5., 5.]);
+        assert_exact(&maxes[1].data(), &[1., -2., 3., 1., 5., 3.]);
+        assert_exact(&maxes[2].data(), &[3., 3., 3., 5., 5., -6.]);
+        assert_exact(
+            &maxes[3].data(),
+            &[f32::NEG_INFINITY, 1., 1., f32::NEG_INFINITY, -4., 5.],
+        );
+        assert_close(&prods[0].data(), &[1., 2., 3., 4., 10., 18.]);
+        assert_close(&prods[1].data(), &[6., 3., 1., 30., 6., 1.]);
     }
 
     #[test]
@@ -448,6 +740,160 @@ mod tests {
         assert_exact(&result.data(), &[5., 6., 1., 2.]);
     }
 
+    #[test]
+    fn test_index_select() {
+        let mut cx = Graph::new();
+
+        let a = cx
+            .tensor((2, 'a', 2))
+            .set_dyn((0..12).map(|i| i as f32).collect::<Vec<_>>(), (2, 3, 2));
+        let b = a
+            .index_select(1, cx.tensor(4).set(vec![2., 0., 2., 1.]))
+            .retrieve();
+        let c = a.index_select(2, cx.tensor(1).set(vec![1.])).retrieve();
+        let d = a.index_select(0, cx.tensor(1).set(vec![1.])).retrieve();
+
+        cx.execute();
+
+        assert_eq!(b.shape.shape_usize(), [2, 4, 2]);
+        assert_exact(
+            &b.data(),
+            &[
+                4., 5., 0., 1., 4., 5., 2., 3., 10., 11., 6., 7., 10., 11., 8., 9.,
+            ],
+        );
+        assert_exact(&c.data(), &[1., 3., 5., 7., 9., 11.]);
+        assert_exact(&d.data(), &[6., 7., 8., 9., 10., 11.]);
+    }
+
+    #[test]
+    fn test_take_along_axis() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = a
+            .take_along_axis(1, cx.tensor((2, 2)).set(vec![2., 2., 0., 1.]))
+            .retrieve();
+        let c = a
+            .take_along_axis(0, cx.tensor((1, 3)).set(vec![1., 0., 1.]))
+            .retrieve();
+        // Taking along an axis undoes a scatter to the same positions
+        let positions = cx.tensor((2, 3)).set(vec![2., 0., 1., 1., 2., 0.]);
+        let d = cx
+            .tensor((2, 3))
+            .set(vec![0.; 6])
+            .scatter(1, positions, a)
+            .take_along_axis(1, positions)
+            .retrieve();
+
+        cx.execute();
+
+        assert_exact(&b.data(), &[3., 3., 4., 5.]);
+        assert_exact(&c.data(), &[4., 2., 6.]);
+        assert_exact(&d.data(), &[1., 2., 3., 4., 5., 6.]);
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor((2, 4)).set(vec![3., 1., 4., 1., 5., 9., 2., 6.]);
+        let ascending = a.argsort(1, false).retrieve();
+        let columns = a.argsort(0, true).retrieve();
+        let sorted = a.sort(1, true).retrieve();
+        let transposed = a.permute((1, 0)).sort(0, false).retrieve();
+        let (values, indexes) = a.topk(2, 1);
+        let (values, indexes) = (values.retrieve(), indexes.retrieve());
+
+        cx.execute();
+
+        // Ties keep their order
+        assert_exact(&ascending.data(), &[1., 3., 0., 2., 2., 0., 3., 1.]);
+        assert_exact(&columns.data(), &[1., 1., 0., 1., 0., 0., 1., 0.]);
+        assert_exact(&sorted.data(), &[4., 3., 1., 1., 9., 6., 5., 2.]);
+        assert_exact(&transposed.data(), &[1., 2., 1., 5., 3., 6., 4., 9.]);
+        assert_eq!(values.shape.shape_usize(), [2, 2]);
+        assert_exact(&values.data(), &[4., 3., 9., 6.]);
+        assert_exact(&indexes.data(), &[2., 0., 1., 3.]);
+    }
+
+    #[test]
+    fn test_scatter() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        // Along the rows: out[indexes[i][j]][j] = src[i][j]
+        let rows = cx.tensor((1, 3)).set(vec![1., 0., 1.]);
+        let row_src = cx.tensor((1, 3)).set(vec![10., 20., 30.]);
+        let b = a.scatter(0, rows, row_src).retrieve();
+        // Along the columns, with a duplicate and an out of range index
+        let cols = cx.tensor((2, 2)).set(vec![2., 0., 1., 1.]);
+        let col_src = cx.tensor((2, 2)).set(vec![10., 20., 30., 40.]);
+        let c = a.scatter(1, cols, col_src).retrieve();
+        let d = a.scatter_add(1, cols, col_src).retrieve();
+        let oob = cx.tensor((2, 1)).set(vec![5., 0.]);
+        let e = a
+            .scatter(1, oob, cx.tensor((2, 1)).set(vec![7., 8.]))
+            .retrieve();
+
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 20., 3., 10., 5., 30.]);
+        assert_exact(&c.data(), &[20., 2., 10., 4., 70., 6.]);
+        assert_exact(&d.data(), &[21., 2., 13., 4., 75., 6.]);
+        assert_exact(&e.data(), &[1., 2., 3., 8., 5., 6.]);
+    }
+
+    #[test]
+    fn test_scatter_non_finite() {
+        let mut cx = Graph::new();
+
+        // Non-finite values that aren't overwritten are kept, and ones in src don't leak into other positions
+        let a = cx
+            .tensor((2, 3))
+            .set(vec![f32::NEG_INFINITY, 2., 3., 4., f32::INFINITY, 6.]);
+        let indexes = cx.tensor((2, 1)).set(vec![1., 0.]);
+        let src = cx.tensor((2, 1)).set(vec![f32::NEG_INFINITY, 8.]);
+        let b = a.scatter(1, indexes, src).retrieve();
+        let c = a.scatter_add(1, indexes, src).retrieve();
+
+        cx.execute();
+
+        let inf = f32::INFINITY;
+        assert_exact(&b.data(), &[-inf, -inf, 3., 8., inf, 6.]);
+        assert_exact(&c.data(), &[-inf, -inf, 3., 12., inf, 6.]);
+    }
+
+    #[test]
+    fn test_index_put() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor((3, 2)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let indexes = cx.tensor(2).set(vec![2., 0.]);
+        let rows = a
+            .index_put(0, indexes, cx.tensor((2, 2)).set(vec![10., 20., 30., 40.]))
+            .retrieve();
+        let cols = a
+            .index_put(
+                1,
+                cx.tensor(1).set(vec![1.]),
+                cx.tensor((3, 1)).set(vec![7., 8., 9.]),
+            )
+            .retrieve();
+        // Scattering gathered rows back into place rebuilds them
+        let regathered = cx
+            .tensor((3, 2))
+            .set(vec![0.; 6])
+            .index_put(0, indexes, a.gather(indexes))
+            .retrieve();
+
+        cx.execute();
+
+        assert_exact(&rows.data(), &[30., 40., 3., 4., 10., 20.]);
+        assert_exact(&cols.data(), &[1., 7., 3., 8., 5., 9.]);
+        assert_exact(&regathered.data(), &[1., 2., 0., 0., 5., 6.]);
+    }
+
     #[test]
     fn test_dyn_arange() {
         let mut cx = Graph::new();
@@ -515,4 +961,28 @@ mod tests {
                 .collect::<Vec<_>>(),
         );
     }
+
+    #[test]
+    fn test_diff_npy() {
+        let path = std::env::temp_dir().join(format!("luminal_{}_diff.npy", std::process::id()));
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = a.permute((1, 0)).retrieve();
+        b.diff_npy(&path, 1e-5, 1e-5);
+        NpyArray::new(vec![3, 2], vec![1., 4., 2., 5., 3., 6.])
+            .write(&path)
+            .unwrap();
+        cx.execute();
+
+        let tensor = cx.get_tensor_ref(a.id, 0).unwrap();
+        assert!(super::diff_with_npy(&path, tensor, b.shape, 1e-5, 1e-5));
+        assert!(!super::diff_with_npy(&path, tensor, a.shape, 1e-5, 1e-5));
+
+        // Same length, different shape
+        NpyArray::new(vec![2, 3], vec![1., 4., 2., 5., 3., 6.])
+            .write(&path)
+            .unwrap();
+        assert!(!super::diff_with_npy(&path, tensor, b.shape, 1e-5, 1e-5));
+        std::fs::remove_file(&path).unwrap();
+    }
 }
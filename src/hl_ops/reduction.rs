@@ -1,3 +1,5 @@
+use itertools::Itertools;
+
 use crate::{
     op::{self},
     prelude::*,
@@ -14,9 +16,11 @@ impl GraphTensor {
                 .add_op(op::SumReduce(dim))
                 .input(id, 0, shape)
                 .finish();
+            // Reduced outputs are written contiguously
             shape.remove_dim(dim);
+            shape = shape.contiguous();
         }
-        GraphTensor::from_id(id, shape, self.graph_ref)
+        GraphTensor { id, shape, ..self }
     }
 
     /// Reduce a dimension of the tensor by taking the maximum of all elements along that axis.
@@ -29,9 +33,11 @@ impl GraphTensor {
                 .add_op(op::MaxReduce(dim))
                 .input(id, 0, shape)
                 .finish();
+            // Reduced outputs are written contiguously
             shape.remove_dim(dim);
+            shape = shape.contiguous();
         }
-        GraphTensor::from_id(id, shape, self.graph_ref)
+        GraphTensor { id, shape, ..self }
     }
 
     /// Reduce a dimension of the tensor by taking the mean of all elements along that axis.
@@ -48,6 +54,65 @@ impl GraphTensor {
     pub fn prod(self, axes: impl ToAxes) -> GraphTensor {
         self.log().sum(axes).exp()
     }
+
+    /// Reduce a dimension of the tensor by taking the minimum of all elements along that axis.
+    pub fn min(self, axes: impl ToAxes) -> GraphTensor {
+        -(-self).max(axes)
+    }
+
+    /// Reduce a dimension of the tensor by taking the variance along that axis, dividing by the number of elements minus `correction`.
+    ///
+    /// A `correction` of 0 gives the population variance, and 1 the unbiased sample variance
+    pub fn var(self, axes: impl ToAxes, correction: usize) -> GraphTensor {
+        let axes = axes.to_axes();
+        let reduced_elements = axes.iter().map(|i| self.dims()[*i]).product::<Expression>();
+        let centered = self - self.mean(axes.clone()).expand_axes(&axes, self);
+        (centered * centered).sum(axes) / (reduced_elements - correction)
+    }
+
+    /// Reduce a dimension of the tensor by taking the standard deviation along that axis, dividing by the number of elements minus `correction`.
+    pub fn std(self, axes: impl ToAxes, correction: usize) -> GraphTensor {
+        self.var(axes, correction).sqrt()
+    }
+
+    /// Reduce a dimension of the tensor by taking the log of the sum of exponentials along that axis. The max is subtracted first so large values don't overflow
+    pub fn logsumexp(self, axes: impl ToAxes) -> GraphTensor {
+        let axes = axes.to_axes();
+        let max = self.max(axes.clone());
+        // An infinite max would turn into NaN when subtracted from itself, so leave those lanes unshifted
+        let cx = self.graph();
+        let infinite = max.lt(cx.constant(f32::MIN).expand(max.shape))
+            + max.gt(cx.constant(f32::MAX).expand(max.shape));
+        let shift = infinite.select(cx.constant(0.).expand(max.shape), max);
+        (self - shift.expand_axes(&axes, self))
+            .exp()
+            .sum(axes)
+            .log()
+            + shift
+    }
+
+    /// Reduce a dimension of the tensor to 1 if any element along that axis is nonzero, otherwise 0.
+    pub fn any(self, axes: impl ToAxes) -> GraphTensor {
+        self.nonzero().max(axes)
+    }
+
+    /// Reduce a dimension of the tensor to 1 if every element along that axis is nonzero, otherwise 0.
+    pub fn all(self, axes: impl ToAxes) -> GraphTensor {
+        self.nonzero().min(axes)
+    }
+
+    /// 1 where the tensor is nonzero, otherwise 0
+    fn nonzero(self) -> GraphTensor {
+        self.ne(self.graph().constant(0.).expand(self.shape))
+    }
+
+    /// Broadcast a reduction of `full` back over the reduced `axes`
+    fn expand_axes(mut self, axes: &[usize], full: GraphTensor) -> GraphTensor {
+        for axis in axes.iter().copied().sorted() {
+            self = self.expand_dim(axis, full.dims()[axis]);
+        }
+        self
+    }
 }
 
 #[cfg(test)]
@@ -101,4 +166,125 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_min() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor((2, 'a')).set_dyn(a_data.clone(), (2, 3));
+        let b = a.min(1).retrieve();
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        let d_b = d_a.min::<_, DAxis<1>>();
+
+        assert_close(&b.data(), &d_b.as_vec());
+    }
+
+    #[test]
+    fn test_var_std() {
+        let mut cx = Graph::new();
+        let a_data = vec![1., 2., 4., -3., 0., 5.];
+        let a = cx.tensor((2, 'a')).set_dyn(a_data.clone(), (2, 3));
+        let population = a.var(1, 0).retrieve();
+        let sample = a.var(1, 1).retrieve();
+        let std = a.std(1, 1).retrieve();
+        let all = a.var((0, 1), 1).retrieve();
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        let d_var = d_a.var::<_, DAxis<1>>();
+
+        assert_close(&population.data(), &d_var.as_vec());
+        // Row means are 7/3 and 2/3
+        assert_close(&sample.data(), &[7. / 3., 49. / 3.]);
+        assert_close(&std.data(), &[(7_f32 / 3.).sqrt(), (49_f32 / 3.).sqrt()]);
+        // Mean 1.5, squared deviations sum to 41.5
+        assert_close(&all.data(), &[8.3]);
+    }
+
+    #[test]
+    fn test_reduce_views() {
+        // Reduced outputs are written contiguously, so permutes and slices on the input don't carry over to them
+        let mut cx = Graph::new();
+        let a_data = random_vec(24);
+        let a = cx.tensor((2, 3, 4)).set(a_data.clone());
+        let b = a.permute((2, 0, 1)).sum(0).retrieve();
+        let c = a.slice((.., 1.., ..)).max(2).retrieve();
+        let d = a.permute((1, 0, 2)).slice((..2, .., 1..)).sum(2).retrieve();
+
+        cx.execute();
+
+        let at = |i: usize, j: usize, k: usize| a_data[i * 12 + j * 4 + k];
+        let expected_b = (0..2)
+            .flat_map(|i| (0..3).map(move |j| (0..4).map(|k| at(i, j, k)).sum::<f32>()))
+            .collect::<Vec<_>>();
+        let expected_c = (0..2)
+            .flat_map(|i| (1..3).map(move |j| (0..4).map(|k| at(i, j, k)).fold(f32::MIN, f32::max)))
+            .collect::<Vec<_>>();
+        let expected_d = (0..2)
+            .flat_map(|j| (0..2).map(move |i| (1..4).map(|k| at(i, j, k)).sum::<f32>()))
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &expected_b);
+        assert_close(&c.data(), &expected_c);
+        assert_close(&d.data(), &expected_d);
+    }
+
+    #[test]
+    fn test_logsumexp() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor((2, 'a')).set_dyn(a_data.clone(), (2, 3));
+        let b = a.logsumexp(1).retrieve();
+        // Large values would overflow without subtracting the max
+        let c = cx
+            .tensor(3)
+            .set(vec![1000., 1001., 1002.])
+            .logsumexp(0)
+            .retrieve();
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        let d_b = d_a.logsumexp::<_, DAxis<1>>();
+
+        assert_close(&b.data(), &d_b.as_vec());
+        assert_close(&c.data(), &[1002.4076]);
+    }
+
+    #[test]
+    fn test_logsumexp_infinite() {
+        let mut cx = Graph::new();
+        let inf = f32::INFINITY;
+        let a = cx.tensor((3, 2)).set(vec![-inf, -inf, -inf, 0., inf, 1.]);
+        let b = a.logsumexp(1).retrieve();
+
+        cx.execute();
+
+        assert_exact(&b.data(), &[-inf, 0., inf]);
+    }
+
+    #[test]
+    fn test_any_all() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor(('a', 3))
+            .set_dyn(vec![0., 1., 0., 2., 3., -1.], (2, 3));
+        let rows_any = a.any(1).retrieve();
+        let rows_all = a.all(1).retrieve();
+        let columns_any = a.any(0).retrieve();
+        let columns_all = a.all(0).retrieve();
+
+        cx.execute();
+
+        assert_exact(&rows_any.data(), &[1., 1.]);
+        assert_exact(&rows_all.data(), &[0., 1.]);
+        assert_exact(&columns_any.data(), &[1., 1., 1.]);
+        assert_exact(&columns_all.data(), &[0., 1., 0.]);
+    }
 }
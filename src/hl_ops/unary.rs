@@ -10,6 +10,24 @@ impl Neg for GraphTensor {
 }
 
 impl GraphTensor {
+    /// Convert to another element type. Does nothing if the tensor already has this type
+    pub fn cast(self, dtype: DType) -> GraphTensor {
+        if self.dtype == dtype {
+            return self;
+        }
+        let new_id = self
+            .graph()
+            .add_op(op::Cast(dtype))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor {
+            id: new_id,
+            shape: self.shape.contiguous(),
+            dtype,
+            ..self
+        }
+    }
+
     /// Base 2 log
     pub fn log2(self) -> GraphTensor {
         let new_id = self
@@ -17,7 +35,11 @@ impl GraphTensor {
             .add_op(op::Log2)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: self.shape.contiguous(),
+            ..self
+        }
     }
 
     /// Base 2 exp
@@ -27,7 +49,11 @@ impl GraphTensor {
             .add_op(op::Exp2)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: self.shape.contiguous(),
+            ..self
+        }
     }
 
     /// Natural exp
@@ -47,7 +73,11 @@ impl GraphTensor {
             .add_op(op::Recip)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: self.shape.contiguous(),
+            ..self
+        }
     }
 
     /// The sin(x) function
@@ -57,7 +87,11 @@ impl GraphTensor {
             .add_op(op::Sin)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: self.shape.contiguous(),
+            ..self
+        }
     }
 
     /// The cos(x) function
@@ -77,7 +111,11 @@ impl GraphTensor {
             .add_op(op::Sqrt)
             .input(self.id, 0, self.shape)
             .finish();
-        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+        GraphTensor {
+            id: new_id,
+            shape: self.shape.contiguous(),
+            ..self
+        }
     }
 
     /// Scale so std is 1.0
@@ -141,6 +179,11 @@ impl GraphTensor {
         (x_equal * r.expand(self.shape)).max(self.shape.len() - 1)
     }
 
+    /// Get the indicies of the min elements along the last axis
+    pub fn argmin(self) -> GraphTensor {
+        (-self).argmax()
+    }
+
     /// Take the absolute value
     pub fn abs(self) -> GraphTensor {
         self.relu() + (-self).relu()
@@ -371,4 +414,18 @@ mod tests {
         let d_b = d_a.tanh();
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_argmin() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor((2, 'a'))
+            .set_dyn(vec![3., -1., 2., 0.5, 7., -4.], (2, 3));
+        let b = a.argmin().retrieve();
+        let c = a.argmax().retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 2.]);
+        assert_exact(&c.data(), &[0., 1.]);
+    }
 }
@@ -0,0 +1,176 @@
+// Re-executing only the parts of the graph affected by changed inputs
+
+use rustc_hash::FxHashSet;
+
+use crate::{
+    op::{Constant, ConstantValue},
+    prelude::*,
+};
+
+impl Graph {
+    /// Execute the graph, only recomputing nodes downstream of inputs set (or dyn dims changed) since the last incremental execution.
+    ///
+    /// All tensors are kept in the graph between runs to be reused, so this trades memory for time. Nodes without a tensor (like on the first run) are always computed.
+    pub fn execute_incremental(&mut self) {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let stale = self.stale_nodes();
+        self.tensors.retain(|(n, _), _| !stale.contains(n));
+
+        let mut dim_stack = Vec::new();
+        for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
+            if !stale.contains(node) {
+                continue;
+            }
+
+            // Borrow all sources so they stay cached
+            let mut srcs = src_ids
+                .iter()
+                .map(|(id, ind, sh)| {
+                    (
+                        InputTensor::Borrowed(self.tensors.get(&(*id, *ind)).unwrap()),
+                        *sh,
+                    )
+                })
+                .collect::<Vec<_>>();
+            for (_, st) in srcs.iter_mut() {
+                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
+            }
+
+            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
+            for (i, tensor) in tensors.into_iter().enumerate() {
+                self.tensors.insert((*node, i as u8), tensor);
+            }
+        }
+        self.dirty.clear();
+        self.dirty_dims.clear();
+    }
+
+    /// Nodes that need to be recomputed: those without a tensor, depending on a changed dyn dim, or downstream of any of these or of an input set since the last incremental run.
+    ///
+    /// Inputs set directly with `set_tensor` already hold their new data, so only their downstream nodes are recomputed.
+    fn stale_nodes(&self) -> FxHashSet<NodeIndex> {
+        let mut stale = FxHashSet::default();
+        let mut changed = FxHashSet::default();
+        for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
+            let uses_dirty_dim = src_ids
+                .iter()
+                .flat_map(|(_, _, sh)| sh.dyn_dims())
+                .chain(
+                    self.try_get_op::<Constant>(*node)
+                        .and_then(|Constant(v, _)| match v {
+                            ConstantValue::Expression(e) => Some(e.to_symbols()),
+                            ConstantValue::Float(_) => None,
+                        })
+                        .unwrap_or_default(),
+                )
+                .any(|d| self.dirty_dims.contains(&d));
+            if uses_dirty_dim
+                || !self.tensors.contains_key(&(*node, 0))
+                || src_ids.iter().any(|(id, _, _)| changed.contains(id))
+            {
+                stale.insert(*node);
+                changed.insert(*node);
+            } else if self.dirty.contains(node) {
+                changed.insert(*node);
+            }
+        }
+        stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    crate::test_imports!();
+
+    #[test]
+    fn test_incremental() {
+        let mut cx = Graph::new();
+        let loads = Rc::new(Cell::new(0));
+        let counter = loads.clone();
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        // An expensive constant section that shouldn't be recomputed
+        let b = cx.tensor(3).set_deferred(move || {
+            counter.set(counter.get() + 1);
+            vec![0., 1., 2.]
+        });
+        let c = (a + b.exp2().sin()).retrieve();
+        let d = cx
+            .tensor(('s', 2))
+            .set_dyn(vec![1., 2., 3., 4.], (2, 2))
+            .sum(0)
+            .retrieve();
+
+        cx.execute_incremental();
+        let expected = |a: [f32; 3]| {
+            a.iter()
+                .zip([0_f32, 1., 2.])
+                .map(|(a, b)| a + b.exp2().sin())
+                .collect::<Vec<_>>()
+        };
+        assert_close(&c.data(), &expected([1., 2., 3.]));
+        assert_exact(&d.data(), &[4., 6.]);
+        assert_eq!(loads.get(), 1);
+
+        a.set(vec![4., 5., 6.]);
+        cx.execute_incremental();
+        assert_close(&c.data(), &expected([4., 5., 6.]));
+        assert_eq!(loads.get(), 1);
+
+        // Nothing changed, so nothing is recomputed
+        let c_data = cx
+            .get_tensor_ref(c.id, 0)
+            .unwrap()
+            .downcast_ref::<Vec<f32>>()
+            .unwrap()
+            .as_ptr();
+        cx.set_dyn_dim('s', 2);
+        cx.execute_incremental();
+        assert_eq!(
+            cx.get_tensor_ref(c.id, 0)
+                .unwrap()
+                .downcast_ref::<Vec<f32>>()
+                .unwrap()
+                .as_ptr(),
+            c_data
+        );
+
+        // Changing a dyn dim recomputes nodes that use it
+        cx.set_dyn_dim('s', 1);
+        cx.execute_incremental();
+        assert_exact(&d.data(), &[1., 2.]);
+        assert_eq!(loads.get(), 1);
+    }
+
+    #[test]
+    fn test_incremental_set_tensor() {
+        let mut cx = Graph::new();
+        let loads = Rc::new(Cell::new(0));
+        let counter = loads.clone();
+        let a = cx.tensor(3).set_deferred(move || {
+            counter.set(counter.get() + 1);
+            vec![1., 2., 3.]
+        });
+        let b = (a * 2.).retrieve();
+
+        cx.execute_incremental();
+        assert_exact(&b.data(), &[2., 4., 6.]);
+        assert_eq!(loads.get(), 1);
+
+        // Data set directly is used as-is instead of re-running the loader
+        cx.set_tensor(a.id, 0, crate::op::Tensor::new(vec![4_f32, 5., 6.]));
+        cx.execute_incremental();
+        assert_exact(&b.data(), &[8., 10., 12.]);
+        assert_exact(&a.data(), &[4., 5., 6.]);
+        assert_eq!(loads.get(), 1);
+
+        // Setting a new loader replaces the data set before
+        a.set(vec![0., 1., 2.]);
+        cx.execute_incremental();
+        assert_exact(&b.data(), &[0., 2., 4.]);
+        assert_eq!(loads.get(), 1);
+    }
+}
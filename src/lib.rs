@@ -1,23 +1,43 @@
 pub mod compiler_utils;
+pub mod dtype;
+pub mod error;
 pub mod generic_compiler;
 pub mod graph;
 pub mod graph_tensor;
 pub mod hl_ops;
+pub mod incremental;
 pub mod module;
+pub mod npy;
 pub mod op;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+pub mod profile;
+pub mod reference;
+pub mod serialization;
 pub mod shape;
+pub mod subgraph;
+pub mod validation;
+pub mod visualize;
 
 pub mod tests;
 
 pub mod prelude {
     pub use crate::compiler_utils::*;
+    pub use crate::dtype::{DType, Element};
+    pub use crate::error::*;
     pub use crate::generic_compiler::*;
     pub use crate::graph::*;
     pub use crate::graph_tensor::*;
     pub use crate::hl_ops::*;
     pub use crate::module::*;
+    pub use crate::npy::*;
     pub use crate::op::*;
+    pub use crate::profile::*;
+    pub use crate::reference::*;
+    pub use crate::serialization::*;
     pub use crate::shape::*;
+    pub use crate::subgraph::*;
+    pub use crate::visualize::*;
     pub use half::{bf16, f16};
     pub use petgraph;
     pub use petgraph::stable_graph::NodeIndex;
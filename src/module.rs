@@ -18,6 +18,13 @@ pub fn param_dict(model: impl SerializeModule) -> FxHashMap<String, NodeIndex> {
     s.state
 }
 
+/// Mapping from weight name to the weight's shape
+pub fn param_shapes(model: impl SerializeModule) -> FxHashMap<String, ShapeTracker> {
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    s.shapes
+}
+
 /// Set of weight node ids
 pub fn params(model: impl SerializeModule) -> Vec<NodeIndex> {
     param_dict(model)
@@ -34,7 +41,7 @@ pub fn transfer_data(
     dests: impl ToIds,
     dest_graph: &mut Graph,
 ) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = src_graph.tensors.remove(&(src, output_num)) {
             dest_graph.tensors.insert((dest, output_num), tensor);
@@ -48,7 +55,7 @@ pub fn transfer_data(
 
 /// Transfer data from one set of nodes to another set in the same graph
 pub fn transfer_data_same_graph(srcs: impl ToIds, dests: impl ToIds, graph: &mut Graph) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = graph.tensors.remove(&(src, output_num)) {
             graph.tensors.insert((dest, output_num), tensor);
@@ -227,6 +234,7 @@ impl<T: SerializeModule> SerializeModule for &T {
 pub struct Serializer {
     current_path: Vec<String>,
     pub state: FxHashMap<String, NodeIndex>,
+    pub shapes: FxHashMap<String, ShapeTracker>,
 }
 
 impl Serializer {
@@ -235,9 +243,11 @@ impl Serializer {
             // Add new path component
             self.current_path.push(name.to_string());
         }
-        // Insert tensor id
-        self.state
-            .insert(self.current_path.join("/"), tensor.into().id);
+        // Insert tensor id and shape
+        let tensor = tensor.into();
+        let path = self.current_path.join("/");
+        self.state.insert(path.clone(), tensor.id);
+        self.shapes.insert(path, tensor.shape);
         if !name.is_empty() {
             // Remove new path component
             self.current_path.pop();
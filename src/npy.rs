@@ -0,0 +1,465 @@
+// Reading and writing NumPy .npy and .npz files
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+
+use crate::{dtype::with_dtype, prelude::*};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// An error encountered when reading or writing npy / npz files
+#[derive(Debug)]
+pub enum NpyError {
+    /// Reading or writing the file failed
+    Io(std::io::Error),
+    /// The npz archive couldn't be read or written
+    Zip(zip::result::ZipError),
+    /// The file isn't a valid npy array
+    Format(String),
+    /// The array has a dtype that can't be converted to f32
+    DType(String),
+    /// An npz archive doesn't contain the requested array
+    MissingArray(String),
+    /// The array's shape doesn't match the tensor's shape
+    Shape {
+        expected: Vec<Expression>,
+        found: Vec<usize>,
+    },
+    /// The tensor has no data, or data that can't be converted to f32
+    MissingData,
+}
+
+impl Display for NpyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NpyError::Io(e) => write!(f, "IO error: {e}"),
+            NpyError::Zip(e) => write!(f, "Invalid npz archive: {e}"),
+            NpyError::Format(e) => write!(f, "Invalid npy file: {e}"),
+            NpyError::DType(d) => write!(f, "Unsupported npy dtype {d}"),
+            NpyError::MissingArray(name) => write!(f, "Array {name} not found in npz archive"),
+            NpyError::Shape { expected, found } => {
+                write!(f, "Expected shape {expected:?}, found {found:?}")
+            }
+            NpyError::MissingData => write!(f, "Tensor has no f32-convertible data"),
+        }
+    }
+}
+
+impl std::error::Error for NpyError {}
+
+impl From<std::io::Error> for NpyError {
+    fn from(value: std::io::Error) -> Self {
+        NpyError::Io(value)
+    }
+}
+
+impl From<zip::result::ZipError> for NpyError {
+    fn from(value: zip::result::ZipError) -> Self {
+        NpyError::Zip(value)
+    }
+}
+
+/// A row-major array read from or written to an npy file. Arrays of any numeric dtype are converted to f32 when read, and written as little endian f32
+#[derive(Debug, Clone, PartialEq)]
+pub struct NpyArray {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+impl NpyArray {
+    pub fn new(shape: Vec<usize>, data: Vec<f32>) -> Self {
+        assert_eq!(
+            shape.iter().product::<usize>(),
+            data.len(),
+            "Shape doesn't match the number of elements"
+        );
+        Self { shape, data }
+    }
+
+    /// Read an array from an npy file
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, NpyError> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Write the array to an npy file
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), NpyError> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Parse an array from the contents of an npy file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NpyError> {
+        let format = |e: &str| NpyError::Format(e.to_string());
+        if bytes.len() < 10 || &bytes[..6] != MAGIC {
+            return Err(format("missing magic string"));
+        }
+        let (header_len, header_start) = match bytes[6] {
+            1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+            2 | 3 if bytes.len() >= 12 => (
+                u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+                12,
+            ),
+            v => return Err(NpyError::Format(format!("unsupported version {v}"))),
+        };
+        let header = bytes
+            .get(header_start..header_start + header_len)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .ok_or(format("truncated header"))?;
+        let descr = header_value(header, "descr")
+            .map(|d| d.trim_matches(|c| c == '\'' || c == '"'))
+            .ok_or(format("missing descr"))?;
+        let fortran_order = header_value(header, "fortran_order") == Some("True");
+        let shape = header_value(header, "shape")
+            .ok_or(format("missing shape"))?
+            .trim_matches(|c| c == '(' || c == ')')
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .map(|d| d.parse::<usize>().map_err(|_| format("invalid shape")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let body = &bytes[header_start + header_len..];
+        let n = shape.iter().product::<usize>();
+        let mut data = decode(descr, body, n)?;
+        if fortran_order && shape.len() > 1 {
+            // Column-major to row-major
+            let mut row_major = vec![0.; n];
+            for (i, r) in row_major.iter_mut().enumerate() {
+                *r = data[fortran_index(i, &shape)];
+            }
+            data = row_major;
+        }
+        Ok(Self { shape, data })
+    }
+
+    /// Encode the array as the contents of an npy file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let shape = match self.shape.len() {
+            1 => format!("({},)", self.shape[0]),
+            _ => format!(
+                "({})",
+                self.shape
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {shape}, }}");
+        // The header is padded with spaces so the data starts on a 64 byte boundary
+        let total = 10 + header.len() + 1;
+        header.push_str(&" ".repeat(total.next_multiple_of(64) - total));
+        header.push('\n');
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([1, 0]);
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(self.data.iter().flat_map(|f| f.to_le_bytes()));
+        bytes
+    }
+}
+
+/// Get the raw value of a key in an npy header dict
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header
+        .find(&format!("'{key}'"))
+        .or_else(|| header.find(&format!("\"{key}\"")))?
+        + key.len()
+        + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find([',', '}'])?
+    };
+    Some(rest[..end].trim())
+}
+
+/// Map a row-major index to the matching index in column-major data
+fn fortran_index(index: usize, shape: &[usize]) -> usize {
+    let (mut rem, mut ind, mut stride) = (index, 0, 1);
+    let mut coords = vec![0; shape.len()];
+    for (c, d) in coords.iter_mut().zip(shape).rev() {
+        *c = rem % d;
+        rem /= d;
+    }
+    for (c, d) in coords.iter().zip(shape) {
+        ind += c * stride;
+        stride *= d;
+    }
+    ind
+}
+
+/// Decode n elements of the given npy dtype to f32
+fn decode(descr: &str, body: &[u8], n: usize) -> Result<Vec<f32>, NpyError> {
+    if descr.len() < 3 {
+        return Err(NpyError::DType(descr.to_string()));
+    }
+    let (order, kind) = descr.split_at(1);
+    let little = match order {
+        "<" | "|" | "=" => true,
+        ">" => false,
+        _ => return Err(NpyError::DType(descr.to_string())),
+    };
+    let size = kind[1..]
+        .parse::<usize>()
+        .map_err(|_| NpyError::DType(descr.to_string()))?;
+    let convert: fn(&[u8]) -> f32 = match (&kind[..1], size) {
+        ("f", 2) => |b| f16::from_le_bytes([b[0], b[1]]).to_f32(),
+        ("f", 4) => |b| f32::from_le_bytes(b.try_into().unwrap()),
+        ("f", 8) => |b| f64::from_le_bytes(b.try_into().unwrap()) as f32,
+        ("i", 1) => |b| b[0] as i8 as f32,
+        ("i", 2) => |b| i16::from_le_bytes([b[0], b[1]]) as f32,
+        ("i", 4) => |b| i32::from_le_bytes(b.try_into().unwrap()) as f32,
+        ("i", 8) => |b| i64::from_le_bytes(b.try_into().unwrap()) as f32,
+        ("u", 1) | ("b", 1) => |b| b[0] as f32,
+        ("u", 2) => |b| u16::from_le_bytes([b[0], b[1]]) as f32,
+        ("u", 4) => |b| u32::from_le_bytes(b.try_into().unwrap()) as f32,
+        ("u", 8) => |b| u64::from_le_bytes(b.try_into().unwrap()) as f32,
+        _ => return Err(NpyError::DType(descr.to_string())),
+    };
+    if body.len() < n * size {
+        return Err(NpyError::Format("truncated data".to_string()));
+    }
+    Ok(body[..n * size]
+        .chunks_exact(size)
+        .map(|c| {
+            if little {
+                convert(c)
+            } else {
+                convert(&c.iter().rev().copied().collect::<Vec<_>>())
+            }
+        })
+        .collect())
+}
+
+/// Read all arrays from an npz archive, keyed by name without the `.npy` extension. Both compressed and uncompressed archives are supported
+pub fn read_npz<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, NpyArray>, NpyError> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut arrays = BTreeMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().trim_end_matches(".npy").to_string();
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        arrays.insert(name, NpyArray::from_bytes(&bytes)?);
+    }
+    Ok(arrays)
+}
+
+/// Read a single array from an npz archive
+pub fn read_npz_array<P: AsRef<Path>>(path: P, name: &str) -> Result<NpyArray, NpyError> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut file = match archive.by_name(&format!("{name}.npy")) {
+        Err(zip::result::ZipError::FileNotFound) => {
+            return Err(NpyError::MissingArray(name.to_string()))
+        }
+        file => file?,
+    };
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    NpyArray::from_bytes(&bytes)
+}
+
+/// Write arrays to an uncompressed npz archive, like `numpy.savez`
+pub fn write_npz<'a, P: AsRef<Path>>(
+    path: P,
+    arrays: impl IntoIterator<Item = (&'a str, &'a NpyArray)>,
+) -> Result<(), NpyError> {
+    let mut archive = zip::ZipWriter::new(File::create(path)?);
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, array) in arrays {
+        archive.start_file(format!("{name}.npy"), options)?;
+        archive.write_all(&array.to_bytes())?;
+    }
+    archive.finish()?;
+    Ok(())
+}
+
+/// Get the contiguous data of a tensor with a view applied, converted to f32. The view's dyn dims must already be resolved
+pub(crate) fn view_f32(tensor: &Tensor, shape: ShapeTracker) -> Option<NpyArray> {
+    let dims = shape.shape_usize();
+    with_dtype!(tensor.dtype()?, T => {
+        let orig = tensor.downcast_ref::<Vec<T>>()?;
+        let (ind, val) = (
+            shape.index_expression_no_simplify(),
+            shape.valid_expression_no_simplify(),
+        );
+        let data = (0..dims.iter().product::<usize>())
+            .map(|i| {
+                if val.exec_single_var(i) != 0 {
+                    orig[ind.exec_single_var(i)].to_f64() as f32
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        Some(NpyArray::new(dims, data))
+    })
+}
+
+impl GraphTensor {
+    /// Set the value of the tensor from an npy file. The file's shape must match the tensor's shape, and is used to set any dyn dims
+    pub fn set_from_npy<P: AsRef<Path>>(self, path: P) -> Result<Self, NpyError> {
+        let array = NpyArray::read(path)?;
+        let dims = self.dims();
+        if dims.len() != array.shape.len()
+            || dims
+                .iter()
+                .zip(&array.shape)
+                .any(|(d, s)| d.to_usize().is_some_and(|d| d != *s))
+        {
+            return Err(NpyError::Shape {
+                expected: dims,
+                found: array.shape,
+            });
+        }
+        Ok(self.set_dyn(array.data, array.shape))
+    }
+
+    /// Read the tensor's data after execution as an npy array
+    pub fn to_npy(&self) -> Result<NpyArray, NpyError> {
+        let tensor = self
+            .graph()
+            .get_tensor_ref(self.id, 0)
+            .ok_or(NpyError::MissingData)?;
+        let mut shape = self.shape;
+        shape.resolve_global_dyn_dims(&self.graph().dyn_map);
+        view_f32(tensor, shape).ok_or(NpyError::MissingData)
+    }
+
+    /// Save the tensor's data to an npy file after execution
+    pub fn save_npy<P: AsRef<Path>>(&self, path: P) -> Result<(), NpyError> {
+        self.to_npy()?.write(path)
+    }
+}
+
+impl Graph {
+    /// Save every retrieved tensor from the last run to an npz archive. Tensors are named `tensor_<node index>`, so a `GraphTensor` `t` is saved as `format!("tensor_{}", t.id.index())`
+    pub fn save_retrieved_npz<P: AsRef<Path>>(&self, path: P) -> Result<(), NpyError> {
+        let mut retrieved = self.to_retrieve.iter().collect::<Vec<_>>();
+        retrieved.sort_by_key(|(id, _)| **id);
+        let arrays = retrieved
+            .into_iter()
+            .map(|(id, (output, shape))| {
+                let tensor = self
+                    .get_tensor_ref(*id, *output)
+                    .ok_or(NpyError::MissingData)?;
+                let mut shape = *shape;
+                shape.resolve_global_dyn_dims(&self.dyn_map);
+                let array = view_f32(tensor, shape).ok_or(NpyError::MissingData)?;
+                Ok((format!("tensor_{}", id.index()), array))
+            })
+            .collect::<Result<Vec<_>, NpyError>>()?;
+        write_npz(path, arrays.iter().map(|(n, a)| (n.as_str(), a)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    crate::test_imports!();
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("luminal_{}_{name}", std::process::id()))
+    }
+
+    #[test]
+    fn test_npy_round_trip() {
+        let path = temp_path("round_trip.npy");
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = a.permute((1, 0)).retrieve();
+        cx.execute();
+        b.save_npy(&path).unwrap();
+        let array = NpyArray::read(&path).unwrap();
+        assert_eq!(array.shape, vec![3, 2]);
+        assert_exact(&array.data, &[1., 4., 2., 5., 3., 6.]);
+
+        // Dyn dims are set from the file
+        let mut cx = Graph::new();
+        let a = cx.tensor(('a', 2)).set_from_npy(&path).unwrap();
+        let b = (a * 2.).retrieve();
+        cx.execute();
+        assert_eq!(cx.dyn_map[&'a'], 3);
+        assert_exact(&b.data(), &[2., 8., 4., 10., 6., 12.]);
+        assert!(matches!(
+            cx.tensor((2, 3)).set_from_npy(&path),
+            Err(NpyError::Shape { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_npy_dtypes() {
+        // Big endian int32, column-major, version 2 header
+        let header = "{'descr': '>i4', 'fortran_order': True, 'shape': (2, 3), }\n";
+        let mut bytes = b"\x93NUMPY\x02\x00".to_vec();
+        bytes.extend((header.len() as u32).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        for i in [1, 4, 2, 5, 3, -6] {
+            bytes.extend(i32::to_be_bytes(i));
+        }
+        let array = NpyArray::from_bytes(&bytes).unwrap();
+        assert_eq!(array.shape, vec![2, 3]);
+        assert_exact(&array.data, &[1., 2., 3., 4., 5., -6.]);
+
+        // Scalars and round trips through our own encoding
+        let scalar = NpyArray::new(vec![], vec![2.5]);
+        assert_eq!(NpyArray::from_bytes(&scalar.to_bytes()).unwrap(), scalar);
+        let vector = NpyArray::new(vec![4], vec![1., 2., 3., 4.]);
+        let encoded = vector.to_bytes();
+        assert_eq!((encoded.len() - 16) % 64, 0);
+        assert_eq!(NpyArray::from_bytes(&encoded).unwrap(), vector);
+
+        let header = header.replace(">i4", "<c8");
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        assert!(matches!(
+            NpyArray::from_bytes(&bytes),
+            Err(NpyError::DType(d)) if d == "<c8"
+        ));
+    }
+
+    #[test]
+    fn test_npz() {
+        let path = temp_path("retrieved.npz");
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let b = (a + 1.).retrieve();
+        let c = a.expand_dim(0, 2).retrieve();
+        cx.execute();
+        cx.save_retrieved_npz(&path).unwrap();
+        let arrays = read_npz(&path).unwrap();
+        assert_eq!(arrays.len(), 2);
+        let b_array = &arrays[&format!("tensor_{}", b.id.index())];
+        assert_eq!(b_array.shape, vec![3]);
+        assert_exact(&b_array.data, &[2., 3., 4.]);
+        let c_array = read_npz_array(&path, &format!("tensor_{}", c.id.index())).unwrap();
+        assert_eq!(c_array.shape, vec![2, 3]);
+        assert_exact(&c_array.data, &[1., 2., 3., 1., 2., 3.]);
+        assert!(matches!(
+            read_npz_array(&path, "missing"),
+            Err(NpyError::MissingArray(_))
+        ));
+
+        // Compressed archives, like numpy.savez_compressed
+        let mut archive = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        archive.start_file("x.npy", options).unwrap();
+        archive.write_all(&b_array.to_bytes()).unwrap();
+        archive.finish().unwrap();
+        assert_eq!(&read_npz_array(&path, "x").unwrap(), b_array);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
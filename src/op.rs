@@ -5,7 +5,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use crate::prelude::*;
+use crate::{dtype::with_dtype, prelude::*};
 
 use dyn_clone::{clone_trait_object, DynClone};
 use rustc_hash::FxHashMap;
@@ -31,23 +31,45 @@ impl Tensor {
     pub fn is<T: Data>(&self) -> bool {
         self.data.as_any().is::<T>()
     }
+    /// The size of the tensor's data in bytes, or 0 if unknown
+    pub fn num_bytes(&self) -> usize {
+        self.data.num_bytes()
+    }
+    /// The element type of the tensor's data, if known
+    pub fn dtype(&self) -> Option<DType> {
+        self.data.dtype()
+    }
 }
 
 /// Some sort of data, for instance a Vec<f32> on CPU, CudaSlice<f32> on Nvidia GPUs, or metal::Buffer for Apple GPUs
 pub trait Data: Any + Debug + DynClone {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// The size of the data in bytes, or 0 if unknown
+    fn num_bytes(&self) -> usize {
+        0
+    }
+    /// The element type of the data, if known
+    fn dtype(&self) -> Option<DType> {
+        None
+    }
 }
 
 clone_trait_object!(Data);
 
-impl Data for Vec<f32> {
+impl<T: Element> Data for Vec<T> {
     fn as_any(&self) -> &dyn Any {
         self
     }
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn num_bytes(&self) -> usize {
+        self.len() * T::DTYPE.size()
+    }
+    fn dtype(&self) -> Option<DType> {
+        Some(T::DTYPE)
+    }
 }
 
 /// Either an owned or borrowed tensor that gets consumed by ops
@@ -132,7 +154,7 @@ impl Debug for Function {
 }
 
 /// A constant value placed on the graph at runtime. Can either be an expression evaluated at runtime, or a constant float
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum ConstantValue {
     Expression(Expression),
     Float(f32),
@@ -182,231 +204,403 @@ impl Operator for Constant {
 // Unary Op (A -> A)
 
 /// Ensure a tensor is contiguously layed out in memory. May involve copying
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Contiguous;
 impl Operator for Contiguous {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         // Copy data over to new tensor
-        let inp_data = get_vec(&inp[0].0);
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i);
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Contiguous"), T => unary::<T>(&inp, |a| a))
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Log2;
 impl Operator for Log2 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).log2();
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Log2"), T => unary::<T>(&inp, T::log2))
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Exp2;
 impl Operator for Exp2 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).exp2();
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Exp2"), T => unary::<T>(&inp, T::exp2))
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Sin;
 impl Operator for Sin {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).sin();
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Sin"), T => unary::<T>(&inp, T::sin))
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Recip;
 impl Operator for Recip {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).recip();
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Recip"), T => unary::<T>(&inp, T::recip))
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Sqrt;
 impl Operator for Sqrt {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).sqrt();
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Sqrt"), T => unary::<T>(&inp, T::sqrt))
+    }
+}
+
+/// Convert a tensor to another element type
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Cast(pub DType);
+impl Operator for Cast {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        with_dtype!(input_dtype(&inp, "Cast"), T => {
+            let input = get_vec::<T>(&inp[0].0, "Cast");
+            let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+            let mut stack = vec![];
+            let n_elements = inp[0].1.n_elements().to_usize().unwrap();
+            with_dtype!(self.0, O => {
+                vec![Tensor::new(
+                    (0..n_elements)
+                        .map(|i| O::from_f64(get_index(input, &expr, &mut stack, i).to_f64()))
+                        .collect::<Vec<_>>(),
+                )]
+            })
+        })
     }
 }
 
 // Binary Ops (A x A -> A)
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Add;
 impl Operator for Add {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
-        let mut stack = vec![];
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) + get_index(rhs, &rexpr, &mut stack, i);
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Add"), T => binary::<T>(&inp, "Add", T::add))
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Mul;
 impl Operator for Mul {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) * get_index(rhs, &rexpr, &mut stack, i);
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Mul"), T => binary::<T>(&inp, "Mul", T::mul))
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Mod;
 impl Operator for Mod {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) % get_index(rhs, &rexpr, &mut stack, i);
-        }
-        vec![Tensor::new(out_data)]
+        with_dtype!(input_dtype(&inp, "Mod"), T => binary::<T>(&inp, "Mod", T::rem))
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+/// Outputs 1 where the first input is less than the second, otherwise 0. The output has the same type as the inputs
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct LessThan;
 impl Operator for LessThan {
+    #[allow(clippy::bool_comparison)] // Expanded for bool elements too
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+        with_dtype!(input_dtype(&inp, "LessThan"), T => binary::<T>(&inp, "LessThan", |a, b| {
+            T::from_f64((a < b) as i32 as f64)
+        }))
+    }
+}
+
+// Ternary Ops (A x B x B -> B)
+
+/// Outputs the second input where the first input is nonzero, otherwise the third input. The condition can have any type, the output has the type of the other two inputs
+///
+/// The element that isn't picked never touches the output, so infinities and NaNs in it don't leak through like they would with a multiplicative mask
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Select;
+impl Operator for Select {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let n_elements = inp[0].1.n_elements().to_usize().unwrap();
         let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = (get_index(lhs, &lexpr, &mut stack, i) < get_index(rhs, &rexpr, &mut stack, i))
-                as i32 as f32;
-        }
-        vec![Tensor::new(out_data)]
+        let cond = with_dtype!(input_dtype(&inp, "Select"), T => {
+            let data = get_vec::<T>(&inp[0].0, "Select condition");
+            let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+            (0..n_elements)
+                .map(|i| get_index(data, &expr, &mut stack, i) != T::default())
+                .collect::<Vec<_>>()
+        });
+        with_dtype!(input_dtype(&inp[1..], "Select"), T => {
+            let (a, b) = (get_vec::<T>(&inp[1].0, "Select"), get_vec::<T>(&inp[2].0, "Select"));
+            let aexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+            let bexpr = (inp[2].1.index_expression(), inp[2].1.valid_expression());
+            let out_data = cond
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    if c {
+                        get_index(a, &aexpr, &mut stack, i)
+                    } else {
+                        get_index(b, &bexpr, &mut stack, i)
+                    }
+                })
+                .collect::<Vec<_>>();
+            vec![Tensor::new(out_data)]
+        })
     }
 }
 
 // Reduce Ops (A -> B (different shape))
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct SumReduce(pub usize);
 impl Operator for SumReduce {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let sh = inp[0].1.shape_usize();
-        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
-        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
-        let dim_size = sh[self.0];
-        let mut result = vec![0.0; front_size * back_size];
-        let input = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for i in 0..front_size {
-            for j in 0..back_size {
-                for k in 0..dim_size {
-                    let orig_index = i * dim_size * back_size + k * back_size + j;
-                    result[i * back_size + j] += get_index(input, &expr, &mut stack, orig_index);
+        with_dtype!(input_dtype(&inp, "SumReduce"), T => {
+            reduce::<T>(&inp, self.0, T::default(), T::add)
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MaxReduce(pub usize);
+impl Operator for MaxReduce {
+    #[allow(clippy::bool_comparison)]
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        with_dtype!(input_dtype(&inp, "MaxReduce"), T => {
+            reduce::<T>(&inp, self.0, T::LOWEST, |a, b| {
+                if b > a {
+                    b
+                } else {
+                    a
+                }
+            })
+        })
+    }
+}
+
+// Ordering
+
+/// Outputs the f32 indexes that sort the input along an axis. Equal elements keep their order, and NaNs sort above everything else
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ArgSort {
+    pub axis: usize,
+    pub descending: bool,
+}
+
+impl ArgSort {
+    /// Sort every lane along the axis of a tensor with the given shape, reading logical elements with `value`
+    pub fn sort_lanes(&self, shape: &[usize], mut value: impl FnMut(usize) -> f64) -> Vec<f32> {
+        let (n, inner) = (
+            shape[self.axis],
+            shape.iter().skip(self.axis + 1).product::<usize>(),
+        );
+        let mut out = vec![0.; shape.iter().product::<usize>()];
+        let mut lane = Vec::with_capacity(n);
+        for outer in 0..out.len() / (n * inner).max(1) {
+            for rest in 0..inner {
+                let start = outer * n * inner + rest;
+                lane.clear();
+                lane.extend((0..n).map(|k| (k, value(start + k * inner))));
+                // Sorting is stable, so ties stay in index order both ways
+                if self.descending {
+                    lane.sort_by(|a, b| b.1.total_cmp(&a.1));
+                } else {
+                    lane.sort_by(|a, b| a.1.total_cmp(&b.1));
+                }
+                for (k, (i, _)) in lane.iter().enumerate() {
+                    out[start + k * inner] = *i as f32;
                 }
             }
         }
-        vec![Tensor::new(result)]
+        out
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct MaxReduce(pub usize);
-impl Operator for MaxReduce {
+impl Operator for ArgSort {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let sh = inp[0].1.shape_usize();
-        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
-        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
-        let dim_size = sh[self.0];
-        let mut result = vec![-f32::INFINITY; front_size * back_size];
-        let input = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
+        let dtype = inp[0].0.borrowed().dtype().unwrap_or_else(|| {
+            panic!("ArgSort only runs on CPU tensors, so it isn't supported by this backend")
+        });
+        with_dtype!(dtype, T => {
+            let input = get_vec::<T>(&inp[0].0, "ArgSort");
+            let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+            let mut stack = vec![];
+            vec![Tensor::new(self.sort_lanes(&inp[0].1.shape_usize(), |i| {
+                get_index(input, &expr, &mut stack, i).to_f64()
+            }))]
+        })
+    }
+}
 
-        for i in 0..front_size {
-            for j in 0..back_size {
-                for k in 0..dim_size {
-                    let orig_index = i * dim_size * back_size + k * back_size + j;
-                    let new_index = i * back_size + j;
-                    result[new_index] =
-                        result[new_index].max(get_index(input, &expr, &mut stack, orig_index));
-                }
+// Control flow
+
+/// Repeatedly run a subgraph body, feeding its outputs back in as inputs until its condition output is 0.
+///
+/// The first `num_carried` inputs are loop-carried, the rest are passed unchanged to every iteration. Outputs the final carried tensors.
+#[derive(Clone, PartialEq)]
+pub struct Loop {
+    pub body: SubGraph,
+    pub num_carried: usize,
+    pub max_iterations: usize,
+}
+
+impl Debug for Loop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Loop({}, max {})", self.body.name(), self.max_iterations)
+    }
+}
+
+impl Operator for Loop {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let invariants = inp.split_off(self.num_carried);
+        let shapes = inp.iter().map(|(_, sh)| *sh).collect::<Vec<_>>();
+        let mut carried = inp.into_iter().map(|(t, _)| t.cloned()).collect::<Vec<_>>();
+        for _ in 0..self.max_iterations {
+            let inputs = carried
+                .iter()
+                .zip(&shapes)
+                .map(|(t, sh)| (InputTensor::Borrowed(t), *sh))
+                .chain(
+                    invariants
+                        .iter()
+                        .map(|(t, sh)| (InputTensor::Borrowed(t.borrowed()), *sh)),
+                )
+                .collect::<Vec<_>>();
+            let mut outputs = self.body.run(&inputs);
+            let condition = outputs.pop().unwrap();
+            carried = outputs;
+            if !is_nonzero(&condition, "Loop condition") {
+                break;
             }
         }
-        vec![Tensor::new(result)]
+        carried
     }
 }
 
-fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
-    tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
+/// Run one of two subgraph branches depending on a single-element predicate (the first input). The rest of the inputs are passed to the chosen branch.
+#[derive(Clone, PartialEq)]
+pub struct If {
+    pub then_branch: SubGraph,
+    pub else_branch: SubGraph,
+}
+
+impl Debug for If {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "If({}, {})",
+            self.then_branch.name(),
+            self.else_branch.name()
+        )
+    }
+}
+
+impl Operator for If {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (predicate, _) = inp.remove(0);
+        if is_nonzero(predicate.borrowed(), "If predicate") {
+            self.then_branch.run(&inp)
+        } else {
+            self.else_branch.run(&inp)
+        }
+    }
 }
 
-fn get_index(
-    data: &[f32],
+/// The element type of an op's first input
+fn input_dtype(inp: &[(InputTensor, ShapeTracker)], op: &str) -> DType {
+    inp[0]
+        .0
+        .borrowed()
+        .dtype()
+        .unwrap_or_else(|| panic!("{op} input has an unknown element type"))
+}
+
+/// Whether the first element of a tensor of any element type is nonzero
+fn is_nonzero(tensor: &Tensor, op: &str) -> bool {
+    let dtype = tensor
+        .dtype()
+        .unwrap_or_else(|| panic!("{op} has an unknown element type"));
+    let tensor = InputTensor::Borrowed(tensor);
+    with_dtype!(dtype, T => get_vec::<T>(&tensor, op)[0] != T::default())
+}
+
+fn get_vec<'a, T: Element>(tensor: &'a InputTensor<'a>, op: &str) -> &'a Vec<T> {
+    tensor
+        .borrowed()
+        .downcast_ref::<Vec<T>>()
+        .unwrap_or_else(|| panic!("{op} inputs must all be {:?}", T::DTYPE))
+}
+
+fn get_index<T: Element>(
+    data: &[T],
     (ind, val): &(Expression, Expression),
     stack: &mut Vec<i64>,
     index: usize,
-) -> f32 {
+) -> T {
     if val.exec_single_var_stack(index, stack) != 0 {
         let i = ind.exec_single_var_stack(index, stack);
         data[i]
     } else {
-        0.0
+        T::default()
+    }
+}
+
+fn unary<T: Element>(inp: &[(InputTensor, ShapeTracker)], f: impl Fn(T) -> T) -> Vec<Tensor> {
+    let input = get_vec::<T>(&inp[0].0, "Unary op");
+    let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+    let mut stack = vec![];
+    let out_data = (0..inp[0].1.n_elements().to_usize().unwrap())
+        .map(|i| f(get_index(input, &expr, &mut stack, i)))
+        .collect::<Vec<_>>();
+    vec![Tensor::new(out_data)]
+}
+
+fn binary<T: Element>(
+    inp: &[(InputTensor, ShapeTracker)],
+    op: &str,
+    f: impl Fn(T, T) -> T,
+) -> Vec<Tensor> {
+    let (lhs, rhs) = (get_vec::<T>(&inp[0].0, op), get_vec::<T>(&inp[1].0, op));
+    let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+    let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+    let mut stack = vec![];
+    let out_data = (0..inp[0].1.n_elements().to_usize().unwrap())
+        .map(|i| {
+            f(
+                get_index(lhs, &lexpr, &mut stack, i),
+                get_index(rhs, &rexpr, &mut stack, i),
+            )
+        })
+        .collect::<Vec<_>>();
+    vec![Tensor::new(out_data)]
+}
+
+fn reduce<T: Element>(
+    inp: &[(InputTensor, ShapeTracker)],
+    dim: usize,
+    init: T,
+    f: impl Fn(T, T) -> T,
+) -> Vec<Tensor> {
+    let sh = inp[0].1.shape_usize();
+    let front_size = sh.iter().take(dim).product::<usize>().max(1);
+    let back_size = sh.iter().skip(dim + 1).product::<usize>().max(1);
+    let dim_size = sh[dim];
+    let mut result = vec![init; front_size * back_size];
+    let input = get_vec::<T>(&inp[0].0, "Reduce op");
+    let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+    let mut stack = vec![];
+    for i in 0..front_size {
+        for j in 0..back_size {
+            for k in 0..dim_size {
+                let orig_index = i * dim_size * back_size + k * back_size + j;
+                let new_index = i * back_size + j;
+                result[new_index] = f(
+                    result[new_index],
+                    get_index(input, &expr, &mut stack, orig_index),
+                );
+            }
+        }
     }
+    vec![Tensor::new(result)]
 }
@@ -0,0 +1,464 @@
+// Parallel execution of independent graph branches
+
+use std::{
+    collections::VecDeque,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    time::Instant,
+};
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{graph::ExecutionHooks, prelude::*, profile::Profiler};
+
+/// Ops and tensors aren't required to be Send / Sync, so this wrapper lets us move them between worker threads.
+/// Each op is only ever processed by one thread at a time, and tensors are only shared immutably.
+/// The parallel entry points are unsafe, so it's up to their callers to guarantee the ops and tensors can actually be used this way.
+struct ThreadSafe<T>(T);
+unsafe impl<T> Send for ThreadSafe<T> {}
+unsafe impl<T> Sync for ThreadSafe<T> {}
+
+type SharedTensor = Arc<ThreadSafe<Tensor>>;
+
+/// An input tensor claimed by a node before it runs
+enum ClaimedTensor {
+    /// This node was the last consumer, so it gets to own the tensor
+    Owned(Tensor),
+    /// Other nodes still need the tensor (or are still reading it)
+    Shared(SharedTensor),
+}
+
+/// Bookkeeping shared between workers
+#[derive(Default)]
+struct Scheduler {
+    /// All tensors currently alive
+    tensors: FxHashMap<(NodeIndex, u8), SharedTensor>,
+    /// Remaining consumers of each tensor
+    consumers: FxHashMap<(NodeIndex, u8), usize>,
+    /// Number of unfinished dependencies for each node that needs to run
+    remaining_deps: FxHashMap<NodeIndex, usize>,
+    /// Nodes whose dependencies are all finished
+    ready: VecDeque<NodeIndex>,
+    /// Number of nodes that haven't finished running
+    remaining: usize,
+    /// A panic from a worker, to be rethrown on the calling thread
+    panic: Option<Box<dyn std::any::Any + Send>>,
+    /// When profiling, the start of execution and the nodes ran so far
+    profile: Option<(Instant, Vec<ProfileEvent>)>,
+}
+
+impl Scheduler {
+    /// Claim the source tensors of a node. A tensor is handed over as owned once its last consumer claims it
+    fn claim_inputs(
+        &mut self,
+        src_ids: &[(NodeIndex, u8, ShapeTracker)],
+        no_delete: &FxHashSet<NodeIndex>,
+    ) -> Vec<ClaimedTensor> {
+        src_ids
+            .iter()
+            .map(|(id, ind, _)| {
+                let key = (*id, *ind);
+                let remaining = self.consumers.get_mut(&key).unwrap();
+                *remaining -= 1;
+                if *remaining == 0 && !no_delete.contains(id) {
+                    match Arc::try_unwrap(self.tensors.remove(&key).unwrap()) {
+                        Ok(ThreadSafe(tensor)) => ClaimedTensor::Owned(tensor),
+                        Err(shared) => ClaimedTensor::Shared(shared),
+                    }
+                } else {
+                    ClaimedTensor::Shared(self.tensors[&key].clone())
+                }
+            })
+            .collect()
+    }
+
+    /// Store the outputs of a finished node and mark its dependents as ready if possible
+    fn finish(&mut self, node: NodeIndex, outputs: Vec<Tensor>, dependents: &[NodeIndex]) {
+        for (i, tensor) in outputs.into_iter().enumerate() {
+            self.tensors
+                .insert((node, i as u8), Arc::new(ThreadSafe(tensor)));
+        }
+        for dependent in dependents {
+            let deps = self.remaining_deps.get_mut(dependent).unwrap();
+            *deps -= 1;
+            if *deps == 0 {
+                self.ready.push_back(*dependent);
+            }
+        }
+        self.remaining -= 1;
+    }
+}
+
+/// A node to be ran, along with everything a worker needs to run it
+struct Job<'a> {
+    node: NodeIndex,
+    op: *mut Box<dyn Operator>,
+    src_ids: &'a [(NodeIndex, u8, ShapeTracker)],
+    dependents: Vec<NodeIndex>,
+}
+
+/// Run an op on its claimed inputs, recording it if profiling
+fn run_job(
+    job: &Job,
+    inputs: Vec<ClaimedTensor>,
+    dyn_map: &FxHashMap<char, usize>,
+    stack: &mut Vec<i64>,
+    profiler: Option<&mut Profiler>,
+) -> Vec<Tensor> {
+    // Keep shared tensors alive while the op borrows them
+    let (mut owned, shared): (Vec<_>, Vec<_>) = inputs
+        .into_iter()
+        .map(|inp| match inp {
+            ClaimedTensor::Owned(t) => (Some(t), None),
+            ClaimedTensor::Shared(t) => (None, Some(t)),
+        })
+        .unzip();
+    let srcs = owned
+        .iter_mut()
+        .zip(&shared)
+        .zip(job.src_ids)
+        .map(|((owned, shared), (_, _, st))| {
+            let mut st = *st;
+            st.resolve_global_dyn_dims_stack(dyn_map, stack);
+            match shared {
+                Some(t) => (InputTensor::Borrowed(&t.0), st),
+                None => (InputTensor::Owned(owned.take().unwrap()), st),
+            }
+        })
+        .collect_vec();
+    let op = unsafe { job.op.as_mut().unwrap() }.as_mut();
+    match profiler {
+        Some(profiler) => profiler.process(job.node, op, srcs),
+        None => ExecutionHooks::process(&mut (), job.node, op, srcs),
+    }
+    .unwrap_or_else(|e| panic!("{e}"))
+}
+
+impl Graph {
+    /// Execute the graph, running independent nodes in parallel on `num_threads` worker threads.
+    ///
+    /// Nodes are scheduled as soon as all of their dependencies have finished. Tensors are freed with the same semantics as `execute`.
+    ///
+    /// # Safety
+    /// Every op in the graph is moved to and processed on a worker thread, and tensors are moved and shared between workers.
+    /// The caller must guarantee all ops and tensor data are safe to send and share across threads, i.e. that they don't hold
+    /// `Rc`s, `RefCell`s or other thread-unsafe state. The primitive ops, CPU ops and tensors set with `set` / `set_dyn` satisfy this.
+    pub unsafe fn execute_parallel(&mut self, num_threads: usize) {
+        self.run_parallel(num_threads, false, false);
+    }
+
+    /// Execute the graph in parallel like `execute_parallel`, but in deterministic waves.
+    ///
+    /// Each wave contains all currently ready nodes in index order, and inputs are claimed in that order, so the schedule and tensor ownership don't depend on thread timing.
+    /// Useful for testing.
+    ///
+    /// # Safety
+    /// Same requirements as [`Graph::execute_parallel`].
+    pub unsafe fn execute_parallel_deterministic(&mut self, num_threads: usize) {
+        self.run_parallel(num_threads, true, false);
+    }
+
+    /// Execute the graph in parallel like `execute_parallel`, recording when and on which worker each node ran
+    ///
+    /// # Safety
+    /// Same requirements as [`Graph::execute_parallel`].
+    pub unsafe fn execute_parallel_profiled(&mut self, num_threads: usize) -> Profile {
+        self.run_parallel(num_threads, false, true)
+    }
+
+    fn run_parallel(&mut self, num_threads: usize, deterministic: bool, profile: bool) -> Profile {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let num_threads = num_threads.max(1);
+        let linearized = self.linearized_graph.take().unwrap();
+
+        // Find which nodes need to run, and how many of their dependencies also need to run
+        let to_run = linearized
+            .iter()
+            .map(|(n, _)| *n)
+            .filter(|n| !self.tensors.contains_key(&(*n, 0)))
+            .collect::<FxHashSet<_>>();
+        let mut ops = self
+            .graph
+            .node_indices()
+            .collect_vec()
+            .into_iter()
+            .zip(self.graph.node_weights_mut())
+            .filter(|(n, _)| to_run.contains(n))
+            .map(|(n, op)| (n, op as *mut Box<dyn Operator>))
+            .collect::<FxHashMap<_, _>>();
+        let mut remaining_deps = FxHashMap::default();
+        let mut jobs = FxHashMap::default();
+        for (node, src_ids) in linearized.iter().filter(|(n, _)| to_run.contains(n)) {
+            remaining_deps.insert(
+                *node,
+                self.graph
+                    .edges_directed(*node, Direction::Incoming)
+                    .filter(|e| to_run.contains(&e.source()))
+                    .count(),
+            );
+            jobs.insert(
+                *node,
+                Job {
+                    node: *node,
+                    op: ops.remove(node).unwrap(),
+                    src_ids,
+                    dependents: self
+                        .graph
+                        .edges_directed(*node, Direction::Outgoing)
+                        .map(|e| e.target())
+                        .filter(|n| to_run.contains(n))
+                        .collect(),
+                },
+            );
+        }
+        let mut scheduler = Scheduler {
+            tensors: self
+                .tensors
+                .drain()
+                .map(|(k, t)| (k, Arc::new(ThreadSafe(t))))
+                .collect(),
+            consumers: self.consumers_map.as_ref().unwrap().clone(),
+            ready: remaining_deps
+                .iter()
+                .filter(|(_, d)| **d == 0)
+                .map(|(n, _)| *n)
+                .sorted()
+                .collect(),
+            remaining_deps,
+            remaining: jobs.len(),
+            panic: None,
+            profile: profile.then(|| (Instant::now(), vec![])),
+        };
+
+        if deterministic {
+            self.run_waves(&mut scheduler, &jobs, num_threads);
+        } else {
+            self.run_dynamic(&mut scheduler, &jobs, num_threads);
+        }
+
+        self.tensors = scheduler
+            .tensors
+            .into_iter()
+            .map(|(k, t)| {
+                let Ok(ThreadSafe(t)) = Arc::try_unwrap(t) else {
+                    unreachable!("All workers have finished")
+                };
+                (k, t)
+            })
+            .collect();
+        self.linearized_graph = Some(linearized);
+        if let Some(panic) = scheduler.panic {
+            resume_unwind(panic);
+        }
+        self.reset();
+        scheduler
+            .profile
+            .map(|(start, events)| Profile {
+                events,
+                total_time: start.elapsed(),
+            })
+            .unwrap_or_default()
+    }
+
+    /// Workers pull ready nodes off a shared queue as soon as they're available
+    fn run_dynamic(
+        &self,
+        scheduler: &mut Scheduler,
+        jobs: &FxHashMap<NodeIndex, Job>,
+        num_threads: usize,
+    ) {
+        let state = Mutex::new(std::mem::take(scheduler));
+        let wakeup = Condvar::new();
+        let jobs = ThreadSafe(jobs);
+        let (dyn_map, no_delete) = (&self.dyn_map, &self.no_delete);
+        std::thread::scope(|s| {
+            let (state, wakeup, jobs) = (&state, &wakeup, &jobs);
+            for thread in 0..num_threads {
+                s.spawn(move || {
+                    let mut stack = vec![];
+                    loop {
+                        let mut guard = state.lock().unwrap();
+                        while guard.ready.is_empty() && guard.remaining > 0 && guard.panic.is_none()
+                        {
+                            guard = wakeup.wait(guard).unwrap();
+                        }
+                        if guard.remaining == 0 || guard.panic.is_some() {
+                            return;
+                        }
+                        let job = &jobs.0[&guard.ready.pop_front().unwrap()];
+                        let inputs = guard.claim_inputs(job.src_ids, no_delete);
+                        let mut profiler = guard
+                            .profile
+                            .as_ref()
+                            .map(|(start, _)| Profiler::new(*start, thread));
+                        drop(guard);
+
+                        let result = catch_unwind(AssertUnwindSafe(|| {
+                            run_job(job, inputs, dyn_map, &mut stack, profiler.as_mut())
+                        }));
+
+                        let mut guard = state.lock().unwrap();
+                        match result {
+                            Ok(outputs) => {
+                                if let (Some((_, events)), Some(profiler)) =
+                                    (&mut guard.profile, profiler)
+                                {
+                                    events.extend(profiler.events);
+                                }
+                                guard.finish(job.node, outputs, &job.dependents)
+                            }
+                            Err(panic) => guard.panic = Some(panic),
+                        }
+                        wakeup.notify_all();
+                    }
+                });
+            }
+        });
+        *scheduler = state.into_inner().unwrap();
+    }
+
+    /// Run all ready nodes in index order as one wave, split across the workers, then collect the next wave
+    fn run_waves(
+        &self,
+        scheduler: &mut Scheduler,
+        jobs: &FxHashMap<NodeIndex, Job>,
+        num_threads: usize,
+    ) {
+        let (dyn_map, no_delete) = (&self.dyn_map, &self.no_delete);
+        let start = scheduler.profile.as_ref().map(|(s, _)| *s);
+        while !scheduler.ready.is_empty() {
+            let wave = scheduler.ready.drain(..).sorted().collect_vec();
+            let claimed = wave
+                .iter()
+                .map(|n| {
+                    let job = &jobs[n];
+                    ThreadSafe((job, scheduler.claim_inputs(job.src_ids, no_delete)))
+                })
+                .collect_vec();
+            let chunk_size = claimed.len().div_ceil(num_threads);
+            let mut claimed = claimed.into_iter();
+            let results = std::thread::scope(|s| {
+                let handles = (0..num_threads)
+                    .map(|_| claimed.by_ref().take(chunk_size).collect_vec())
+                    .filter(|chunk| !chunk.is_empty())
+                    .enumerate()
+                    .map(|(thread, chunk)| {
+                        s.spawn(move || {
+                            let mut stack = vec![];
+                            let mut profiler = start.map(|s| Profiler::new(s, thread));
+                            let outputs = chunk
+                                .into_iter()
+                                .map(|ThreadSafe((job, inputs))| {
+                                    let outputs = run_job(
+                                        job,
+                                        inputs,
+                                        dyn_map,
+                                        &mut stack,
+                                        profiler.as_mut(),
+                                    );
+                                    ThreadSafe((job.node, outputs))
+                                })
+                                .collect_vec();
+                            (outputs, profiler.map(|p| p.events))
+                        })
+                    })
+                    .collect_vec();
+                handles.into_iter().map(|h| h.join()).collect_vec()
+            });
+            for result in results {
+                match result {
+                    Ok((outputs, chunk_events)) => {
+                        if let (Some((_, events)), Some(chunk_events)) =
+                            (&mut scheduler.profile, chunk_events)
+                        {
+                            events.extend(chunk_events);
+                        }
+                        for ThreadSafe((node, outputs)) in outputs {
+                            scheduler.finish(node, outputs, &jobs[&node].dependents);
+                        }
+                    }
+                    Err(panic) => {
+                        scheduler.panic = Some(panic);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    fn build_graph(cx: &mut Graph) -> (Vec<GraphTensor>, GraphTensor, GraphTensor) {
+        let x = cx.tensor((4, 8)).set(random_vec(32));
+        let w_q = cx.tensor((8, 8)).set(random_vec(64));
+        let w_k = cx.tensor((8, 8)).set(random_vec(64));
+        let w_v = cx.tensor((8, 8)).set(random_vec(64));
+        let (q, k, v) = (x.matmul(w_q), x.matmul(w_k), x.matmul(w_v));
+        let attn = q.matmul(k.permute((1, 0))).softmax(1).matmul(v).retrieve();
+        let out = (attn.sum(1) + x.max(1)).exp2().retrieve();
+        (vec![x, w_q, w_k, w_v], attn, out)
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let mut cx = Graph::new();
+        let (inputs, attn, out) = build_graph(&mut cx);
+        cx.keep_tensors(inputs);
+
+        cx.execute();
+        let (seq_attn, seq_out) = (attn.data(), out.data());
+        let n_tensors = cx.tensors.len();
+
+        for threads in [1, 2, 4] {
+            attn.drop();
+            out.drop();
+            unsafe { cx.execute_parallel(threads) };
+            assert_exact(&attn.data(), &seq_attn);
+            assert_exact(&out.data(), &seq_out);
+            assert_eq!(cx.tensors.len(), n_tensors);
+
+            attn.drop();
+            out.drop();
+            unsafe { cx.execute_parallel_deterministic(threads) };
+            assert_exact(&attn.data(), &seq_attn);
+            assert_exact(&out.data(), &seq_out);
+            assert_eq!(cx.tensors.len(), n_tensors);
+        }
+    }
+
+    #[test]
+    fn test_parallel_dyn_dims() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(('a', 3));
+        let b = (a.exp2() + a.sin()).sum(0).retrieve();
+        let c = (a * 2.).max(1).retrieve();
+
+        a.set_dyn(vec![1., 2., 3., 4., 5., 6.], (2, 3));
+        cx.execute();
+        let (seq_b, seq_c) = (b.data(), c.data());
+        b.drop();
+        c.drop();
+
+        a.set_dyn(vec![1., 2., 3., 4., 5., 6.], (2, 3));
+        unsafe { cx.execute_parallel(3) };
+        assert_exact(&b.data(), &seq_b);
+        assert_exact(&c.data(), &seq_c);
+    }
+
+    #[test]
+    #[should_panic(expected = "You must set a value for this tensor!")]
+    fn test_parallel_panic() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3);
+        let b = cx.tensor(3).set(vec![1., 2., 3.]);
+        (a + b.exp2()).retrieve();
+        unsafe { cx.execute_parallel(2) };
+    }
+}
@@ -0,0 +1,247 @@
+// Recording per-node timings during execution
+
+use std::{
+    io::BufWriter,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{graph::ExecutionHooks, prelude::*};
+
+/// A single node execution
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileEvent {
+    /// The node that ran
+    pub node: usize,
+    /// The op's debug name
+    pub op: String,
+    /// Time from the start of execution until the node started
+    pub start: Duration,
+    /// Time from the start of execution until the node finished
+    pub end: Duration,
+    /// Resolved shapes of each input
+    pub input_shapes: Vec<Vec<usize>>,
+    /// Total bytes of all outputs
+    pub output_bytes: usize,
+    /// The worker thread the node ran on (always 0 for sequential execution)
+    pub thread: usize,
+}
+
+impl ProfileEvent {
+    pub(crate) fn new(
+        node: NodeIndex,
+        op: &dyn Operator,
+        input_shapes: Vec<Vec<usize>>,
+        outputs: &[Tensor],
+        (start, end): (Duration, Duration),
+        thread: usize,
+    ) -> Self {
+        Self {
+            node: node.index(),
+            op: format!("{op:?}"),
+            start,
+            end,
+            input_shapes,
+            output_bytes: outputs.iter().map(|t| t.num_bytes()).sum(),
+            thread,
+        }
+    }
+
+    /// How long the node took to run
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+
+    /// The op type, without any parameters (for instance `SumReduce(2)` -> `SumReduce`)
+    pub fn op_type(&self) -> &str {
+        self.op.split('(').next().unwrap().trim()
+    }
+}
+
+/// Aggregated timings for an op type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpSummary {
+    pub op: String,
+    /// Number of nodes of this type that ran
+    pub count: usize,
+    /// Total time spent in this op type
+    pub total_time: Duration,
+    /// Total bytes output by this op type
+    pub output_bytes: usize,
+}
+
+/// A recording of an execution of the graph
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    /// Every node that ran, in the order it finished
+    pub events: Vec<ProfileEvent>,
+    /// Wall time of the whole execution
+    pub total_time: Duration,
+}
+
+impl Profile {
+    /// Aggregate timings by op type, sorted by total time descending
+    pub fn summary(&self) -> Vec<OpSummary> {
+        let mut summaries: FxHashMap<&str, OpSummary> = FxHashMap::default();
+        for event in &self.events {
+            let summary = summaries
+                .entry(event.op_type())
+                .or_insert_with(|| OpSummary {
+                    op: event.op_type().to_string(),
+                    count: 0,
+                    total_time: Duration::ZERO,
+                    output_bytes: 0,
+                });
+            summary.count += 1;
+            summary.total_time += event.duration();
+            summary.output_bytes += event.output_bytes;
+        }
+        summaries
+            .into_values()
+            .sorted_by(|a, b| b.total_time.cmp(&a.total_time).then(a.op.cmp(&b.op)))
+            .collect()
+    }
+
+    /// Convert to the Chrome `trace_event` format, viewable in chrome://tracing or Perfetto
+    pub fn chrome_trace(&self) -> serde_json::Value {
+        json!({
+            "traceEvents": self
+                .events
+                .iter()
+                .map(|e| json!({
+                    "name": e.op,
+                    "cat": e.op_type(),
+                    "ph": "X",
+                    "ts": e.start.as_secs_f64() * 1e6,
+                    "dur": e.duration().as_secs_f64() * 1e6,
+                    "pid": 0,
+                    "tid": e.thread,
+                    "args": {
+                        "node": e.node,
+                        "input_shapes": e.input_shapes,
+                        "output_bytes": e.output_bytes,
+                    },
+                }))
+                .collect::<Vec<_>>(),
+            "displayTimeUnit": "ms",
+        })
+    }
+
+    /// Write the Chrome trace JSON to a file
+    pub fn save_chrome_trace(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let writer = BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(writer, &self.chrome_trace())?;
+        Ok(())
+    }
+}
+
+/// Executor hooks that record a profile event for every node ran
+pub(crate) struct Profiler {
+    /// The start of execution, which event times are relative to
+    pub(crate) start: Instant,
+    /// The worker thread the nodes run on
+    thread: usize,
+    pub(crate) events: Vec<ProfileEvent>,
+}
+
+impl Profiler {
+    pub(crate) fn new(start: Instant, thread: usize) -> Self {
+        Self {
+            start,
+            thread,
+            events: vec![],
+        }
+    }
+}
+
+impl ExecutionHooks for Profiler {
+    fn process(
+        &mut self,
+        node: NodeIndex,
+        op: &mut dyn Operator,
+        srcs: Vec<(InputTensor, ShapeTracker)>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let input_shapes = srcs.iter().map(|(_, st)| st.shape_usize()).collect_vec();
+        let op_start = self.start.elapsed();
+        let tensors = op.process(srcs);
+        self.events.push(ProfileEvent::new(
+            node,
+            op,
+            input_shapes,
+            &tensors,
+            (op_start, self.start.elapsed()),
+            self.thread,
+        ));
+        Ok(tensors)
+    }
+}
+
+impl Graph {
+    /// Execute the graph, recording when each node ran
+    pub fn execute_profiled(&mut self) -> Profile {
+        let mut profiler = Profiler::new(Instant::now(), 0);
+        self.run_sequential(&[], &mut profiler)
+            .unwrap_or_else(|e| panic!("{e}"));
+        let total_time = profiler.start.elapsed();
+        self.reset();
+        Profile {
+            events: profiler.events,
+            total_time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_profile() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("A", (2, 3)).set(random_vec(6));
+        let b = cx.named_tensor("B", 3).set(random_vec(3));
+        let c = (a.exp2() + b.expand_dim(0, 2)).sum(1).retrieve();
+
+        let profile = cx.execute_profiled();
+        assert_eq!(c.data().len(), 2);
+        assert_eq!(profile.events.len(), cx.graph.node_count());
+        let sum = profile
+            .events
+            .iter()
+            .find(|e| e.op_type() == "SumReduce")
+            .unwrap();
+        assert_eq!(sum.input_shapes, vec![vec![2, 3]]);
+        assert_eq!(sum.output_bytes, 2 * std::mem::size_of::<f32>());
+        assert!(profile.events.iter().all(|e| e.end <= profile.total_time));
+
+        let summary = profile.summary();
+        assert_eq!(
+            summary.iter().map(|s| s.count).sum::<usize>(),
+            profile.events.len()
+        );
+        assert!(summary.iter().any(|s| s.op == "Exp2" && s.count == 1));
+
+        let trace = profile.chrome_trace();
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), profile.events.len());
+        assert!(events.iter().all(|e| e["ph"] == "X"));
+
+        // Parallel profiles record the worker each node ran on
+        #[cfg(feature = "parallel")]
+        {
+            c.drop();
+            // Safety: every op in this graph is a primitive
+            let profile = unsafe { cx.execute_parallel_profiled(2) };
+            assert_eq!(profile.events.len(), cx.graph.node_count());
+            assert!(profile.events.iter().all(|e| e.thread < 2));
+            let round_trip: Profile =
+                serde_json::from_str(&serde_json::to_string(&profile).unwrap()).unwrap();
+            assert_eq!(round_trip, profile);
+        }
+    }
+}
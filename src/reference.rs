@@ -0,0 +1,364 @@
+// Interpreting primitive ops in f64 to use as a numerical reference
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use itertools::{izip, Itertools};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    dtype::with_dtype,
+    op::{
+        Add, ArgSort, Cast, Constant, ConstantValue, Contiguous, Exp2, Function, LessThan, Log2,
+        MaxReduce, Mod, Mul, Recip, Select, Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+/// The outputs of every node the reference interpreter could run, in f64
+#[derive(Debug, Clone, Default)]
+pub struct Reference {
+    /// Node outputs along with the debug name of the op that produced them and the element type the graph uses for them
+    pub outputs: FxHashMap<(NodeIndex, u8), (String, DType, Vec<f64>)>,
+}
+
+/// How far a node's output from a normal run is from the reference output
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceDiff {
+    pub node: NodeIndex,
+    pub op: String,
+    /// Largest absolute difference from the reference
+    pub max_abs_error: f64,
+    /// Largest absolute difference divided by the reference magnitude, over elements where the reference is non-zero
+    pub max_rel_error: f64,
+}
+
+impl Reference {
+    /// Get the reference output of a node
+    pub fn get(&self, node: NodeIndex, output: u8) -> Option<&[f64]> {
+        self.outputs
+            .get(&(node, output))
+            .map(|(_, _, d)| d.as_slice())
+    }
+
+    /// Compare the tensors currently in a graph against the reference. Run the graph with `execute_no_delete` first so every intermediate is available.
+    ///
+    /// Nodes are matched by index and op name, so a reference taken before compiling can be checked against the compiled graph for all nodes the compiler left in place. Results are sorted by node.
+    pub fn compare(&self, graph: &Graph) -> Vec<ReferenceDiff> {
+        let mut diffs = vec![];
+        for ((node, output), (op, _, reference)) in
+            self.outputs.iter().sorted_by_key(|((n, o), _)| (*n, *o))
+        {
+            let Some(actual) = graph.tensors.get(&(*node, *output)).and_then(to_f64) else {
+                continue;
+            };
+            let Some(graph_op) = graph.graph.node_weight(*node) else {
+                continue;
+            };
+            if format!("{graph_op:?}") != *op || actual.len() != reference.len() {
+                continue;
+            }
+            let (mut max_abs_error, mut max_rel_error) = (0., 0.);
+            for (a, r) in actual.iter().zip(reference) {
+                let abs = if a == r || (a.is_nan() && r.is_nan()) {
+                    0.
+                } else {
+                    (a - r).abs()
+                };
+                let abs = if abs.is_nan() { f64::INFINITY } else { abs };
+                max_abs_error = f64::max(max_abs_error, abs);
+                if *r != 0. {
+                    max_rel_error = f64::max(max_rel_error, abs / r.abs());
+                }
+            }
+            diffs.push(ReferenceDiff {
+                node: *node,
+                op: op.clone(),
+                max_abs_error,
+                max_rel_error,
+            });
+        }
+        diffs
+    }
+}
+
+/// Convert a tensor's data to f64 with its dtype
+fn to_f64(tensor: &Tensor) -> Option<Vec<f64>> {
+    with_dtype!(tensor.dtype()?, T => {
+        tensor
+            .downcast_ref::<Vec<T>>()
+            .map(|v| v.iter().map(|x| x.to_f64()).collect())
+    })
+}
+
+/// Round a value the way storing it as the dtype would. Float types are kept in full precision
+fn round_to(dtype: DType, value: f64) -> f64 {
+    match dtype {
+        DType::F32 | DType::F16 | DType::Bf16 => value,
+        _ => with_dtype!(dtype, T => T::from_f64(value).to_f64()),
+    }
+}
+
+/// Read a logical element through a shape tracker, with padded and masked regions reading 0
+fn view(data: &[f64], shape: &ShapeTracker) -> Vec<f64> {
+    let (ind, val) = (shape.index_expression(), shape.valid_expression());
+    let mut stack = vec![];
+    (0..shape.n_elements().to_usize().unwrap())
+        .map(|i| {
+            if val.exec_single_var_stack(i, &mut stack) != 0 {
+                data[ind.exec_single_var_stack(i, &mut stack)]
+            } else {
+                0.
+            }
+        })
+        .collect()
+}
+
+fn reduce(
+    data: &[f64],
+    shape: &ShapeTracker,
+    dim: usize,
+    init: f64,
+    f: fn(f64, f64) -> f64,
+) -> Vec<f64> {
+    let sh = shape.shape_usize();
+    let front_size = sh.iter().take(dim).product::<usize>().max(1);
+    let back_size = sh.iter().skip(dim + 1).product::<usize>().max(1);
+    let dim_size = sh[dim];
+    let mut result = vec![init; front_size * back_size];
+    for i in 0..front_size {
+        for j in 0..back_size {
+            for k in 0..dim_size {
+                let new_index = i * back_size + j;
+                result[new_index] = f(
+                    result[new_index],
+                    data[i * dim_size * back_size + k * back_size + j],
+                );
+            }
+        }
+    }
+    result
+}
+
+impl Graph {
+    /// Interpret the graph's primitive ops in f64, returning the output of every node.
+    ///
+    /// Source tensors are read from the graph (or their loaders are ran) without being consumed, so the graph can still be ran normally afterwards. Ops that aren't primitives, like compiled kernels, can't be interpreted, so they and everything depending on them are left out of the reference.
+    pub fn execute_reference(&self) -> Result<Reference, LuminalError> {
+        let order = petgraph::algo::toposort(&self.graph, None)
+            .map_err(|e| LuminalError::Cycle(e.node_id()))?;
+        let mut reference = Reference::default();
+        for node in order {
+            let op = self.graph.node_weight(node).unwrap();
+            let name = format!("{op:?}");
+            let mut inputs = vec![];
+            for (source, output, mut shape) in self.get_sources(node) {
+                if let Some(dim) = shape
+                    .dyn_dims()
+                    .into_iter()
+                    .find(|d| !self.dyn_map.contains_key(d))
+                {
+                    return Err(LuminalError::UnresolvedDynDim { node, dim });
+                }
+                shape.resolve_global_dyn_dims(&self.dyn_map);
+                inputs.push((reference.outputs.get(&(source, output)), shape));
+            }
+            if inputs.iter().any(|(i, _)| i.is_none()) {
+                continue;
+            }
+            let inputs = inputs
+                .into_iter()
+                .map(|(i, s)| {
+                    let (_, dtype, data) = i.unwrap();
+                    (*dtype, view(data, &s), s)
+                })
+                .collect::<Vec<_>>();
+            let dtype = inputs.first().map(|(d, _, _)| *d).unwrap_or(DType::F32);
+            let unary = |f: fn(f64) -> f64| inputs[0].1.iter().map(|a| f(*a)).collect::<Vec<_>>();
+            let binary = |f: fn(f64, f64) -> f64| {
+                inputs[0]
+                    .1
+                    .iter()
+                    .zip(&inputs[1].1)
+                    .map(|(a, b)| f(*a, *b))
+                    .collect::<Vec<_>>()
+            };
+
+            let op = op.as_any();
+            let (dtype, data) = if let Some(function) = op.downcast_ref::<Function>() {
+                if !inputs.is_empty() {
+                    continue;
+                }
+                let data = match self.tensors.get(&(node, 0)) {
+                    Some(tensor) => to_f64(tensor).zip(tensor.dtype()),
+                    None => {
+                        let run = catch_unwind(AssertUnwindSafe(|| (function.1)(vec![])));
+                        match run {
+                            Ok(tensors) => tensors.first().and_then(|t| to_f64(t).zip(t.dtype())),
+                            Err(payload) => {
+                                return Err(LuminalError::from_panic(node, name, payload))
+                            }
+                        }
+                    }
+                };
+                let Some((data, dtype)) = data else {
+                    continue;
+                };
+                (dtype, data)
+            } else if let Some(constant) = op.downcast_ref::<Constant>() {
+                let value = match &constant.0 {
+                    ConstantValue::Expression(e) => {
+                        e.exec_float(&self.dyn_map).ok_or_else(|| {
+                            LuminalError::UnresolvedDynDim {
+                                node,
+                                dim: e.to_symbols().into_iter().next().unwrap_or('?'),
+                            }
+                        })?
+                    }
+                    ConstantValue::Float(f) => *f as f64,
+                };
+                (DType::F32, vec![value])
+            } else if op.is::<Contiguous>() {
+                (dtype, unary(|a| a))
+            } else if op.is::<Log2>() {
+                (dtype, unary(f64::log2))
+            } else if op.is::<Exp2>() {
+                (dtype, unary(f64::exp2))
+            } else if op.is::<Sin>() {
+                (dtype, unary(f64::sin))
+            } else if op.is::<Recip>() {
+                (dtype, unary(f64::recip))
+            } else if op.is::<Sqrt>() {
+                (dtype, unary(f64::sqrt))
+            } else if let Some(Cast(to)) = op.downcast_ref::<Cast>() {
+                // Casting rounds to the target type, so the reference does too
+                let to = *to;
+                let data = inputs[0]
+                    .1
+                    .iter()
+                    .map(|a| with_dtype!(to, T => T::from_f64(*a).to_f64()))
+                    .collect();
+                (to, data)
+            } else if op.is::<Add>() {
+                (dtype, binary(|a, b| a + b))
+            } else if op.is::<Mul>() {
+                (dtype, binary(|a, b| a * b))
+            } else if op.is::<Mod>() {
+                (dtype, binary(|a, b| a % b))
+            } else if op.is::<LessThan>() {
+                (dtype, binary(|a, b| (a < b) as i32 as f64))
+            } else if op.is::<Select>() {
+                let data = izip!(&inputs[0].1, &inputs[1].1, &inputs[2].1)
+                    .map(|(c, a, b)| if *c != 0. { *a } else { *b })
+                    .collect();
+                (inputs[1].0, data)
+            } else if let Some(SumReduce(dim)) = op.downcast_ref::<SumReduce>() {
+                (
+                    dtype,
+                    reduce(&inputs[0].1, &inputs[0].2, *dim, 0., |a, b| a + b),
+                )
+            } else if let Some(MaxReduce(dim)) = op.downcast_ref::<MaxReduce>() {
+                (
+                    dtype,
+                    reduce(
+                        &inputs[0].1,
+                        &inputs[0].2,
+                        *dim,
+                        f64::NEG_INFINITY,
+                        f64::max,
+                    ),
+                )
+            } else if let Some(argsort) = op.downcast_ref::<ArgSort>() {
+                let sorted = argsort.sort_lanes(&inputs[0].2.shape_usize(), |i| inputs[0].1[i]);
+                (DType::F32, sorted.into_iter().map(|i| i as f64).collect())
+            } else {
+                continue;
+            };
+            let data = data.into_iter().map(|v| round_to(dtype, v)).collect();
+            reference.outputs.insert((node, 0), (name, dtype, data));
+        }
+        Ok(reference)
+    }
+
+    /// Run the graph both normally and with the f64 reference interpreter, returning the error of every node compared to the reference. See `Reference::compare`
+    pub fn compare_to_reference(&mut self) -> Result<Vec<ReferenceDiff>, LuminalError> {
+        let reference = self.execute_reference()?;
+        self.execute_no_delete();
+        let diffs = reference.compare(self);
+        self.reset();
+        Ok(diffs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    crate::test_imports!();
+
+    #[test]
+    fn test_reference_matches() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor(('a', 3))
+            .set_dyn(random_vec_rng(6, &mut rng), (2, 3));
+        let b = cx.tensor((3, 4)).set(random_vec_rng(12, &mut rng));
+        let out = a.matmul(b).softmax(1).layer_norm(1, 1e-5).retrieve();
+        let mask = (a.lt(a.sin()) + a % 0.3).retrieve();
+        let diffs = cx.compare_to_reference().unwrap();
+        assert!(diffs.len() > 10);
+        for diff in &diffs {
+            assert!(
+                diff.max_abs_error < 1e-5 || diff.max_rel_error < 1e-5,
+                "{diff:?}"
+            );
+        }
+        // The graph still runs normally afterwards
+        cx.execute();
+        assert_eq!(out.data().len(), 8);
+        assert_eq!(mask.data().len(), 6);
+    }
+
+    #[test]
+    fn test_reference_precision() {
+        // Accumulating in f32 loses precision compared to f64
+        let mut cx = Graph::new();
+        let mut data = vec![1.; 4096];
+        data[0] = 1e8;
+        let sum = cx.tensor(4096).set(data).sum(0).retrieve();
+        let reference = cx.execute_reference().unwrap();
+        assert_eq!(reference.get(sum.id, 0).unwrap(), &[1e8 + 4095.]);
+        let diffs = cx.compare_to_reference().unwrap();
+        let sum_diff = diffs.iter().find(|d| d.node == sum.id).unwrap();
+        assert!(sum_diff.max_abs_error > 1000.);
+
+        // Casts round like the real types
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(vec![1.7, -2.5, 1e-4]);
+        let half = a.cast(DType::F16).retrieve();
+        let int = a.cast(DType::I32).retrieve();
+        let reference = cx.execute_reference().unwrap();
+        assert_eq!(reference.get(int.id, 0).unwrap(), &[1., -2., 0.]);
+        assert!(cx
+            .compare_to_reference()
+            .unwrap()
+            .iter()
+            .filter(|d| d.node == half.id || d.node == int.id)
+            .all(|d| d.max_abs_error == 0.));
+    }
+
+    #[test]
+    fn test_reference_compiled() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((4, 4)).set(random_vec(16)).keep();
+        let mut out = ((a * 1.) + 0.).exp().sqrt().sum(1).retrieve();
+        let reference = cx.execute_reference().unwrap();
+        cx.compile(GenericCompiler::default(), &mut out);
+        cx.execute_no_delete();
+        let diffs = reference.compare(&cx);
+        assert!(diffs.iter().any(|d| d.node == out.id));
+        for diff in diffs {
+            assert!(diff.max_abs_error < 1e-5, "{diff:?}");
+        }
+    }
+}
@@ -0,0 +1,535 @@
+// Saving and loading graphs to and from disk
+
+use std::{
+    any::TypeId,
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use itertools::Itertools;
+use petgraph::{
+    visit::{EdgeRef, IntoEdgeReferences},
+    Direction,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    error::UNSET_INPUT_MESSAGE,
+    op::{
+        Add, ArgSort, Cast, Constant, ConstantValue, Contiguous, Exp2, Function, LessThan, Log2,
+        MaxReduce, Mod, Mul, Recip, Select, Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+/// The version of the on-disk graph format
+const FORMAT_VERSION: u32 = 1;
+
+/// The registry name given to source (loading) nodes
+const LOAD_OP: &str = "Load";
+
+/// Turn an op into a serialized value
+pub type OpSerializer = fn(&dyn Operator) -> serde_json::Result<Value>;
+/// Reconstruct an op from a serialized value. The graph the op is being loaded into is passed in for ops that reference graph state (like the dyn map)
+pub type OpDeserializer = fn(Value, &Graph) -> serde_json::Result<Box<dyn Operator>>;
+
+/// A registry of ops that can be saved and reconstructed by name.
+///
+/// The default registry contains all primitive ops in `luminal::op`. Backends can register their own ops on top of it.
+#[derive(Clone)]
+pub struct OpRegistry {
+    serializers: FxHashMap<TypeId, (String, OpSerializer)>,
+    deserializers: FxHashMap<String, OpDeserializer>,
+}
+
+impl Default for OpRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register_with::<Constant>(
+            "Constant",
+            |op| serde_json::to_value(&op.as_any().downcast_ref::<Constant>().unwrap().0),
+            |value, graph| {
+                Ok(Box::new(Constant(
+                    serde_json::from_value::<ConstantValue>(value)?,
+                    &graph.dyn_map,
+                )))
+            },
+        );
+        registry.register::<Contiguous>("Contiguous");
+        registry.register::<Log2>("Log2");
+        registry.register::<Exp2>("Exp2");
+        registry.register::<Sin>("Sin");
+        registry.register::<Recip>("Recip");
+        registry.register::<Sqrt>("Sqrt");
+        registry.register::<Cast>("Cast");
+        registry.register::<Add>("Add");
+        registry.register::<Mul>("Mul");
+        registry.register::<Mod>("Mod");
+        registry.register::<LessThan>("LessThan");
+        registry.register::<Select>("Select");
+        registry.register::<SumReduce>("SumReduce");
+        registry.register::<MaxReduce>("MaxReduce");
+        registry.register::<ArgSort>("ArgSort");
+        registry
+    }
+}
+
+impl OpRegistry {
+    /// Create a registry without any ops registered
+    pub fn empty() -> Self {
+        Self {
+            serializers: FxHashMap::default(),
+            deserializers: FxHashMap::default(),
+        }
+    }
+
+    /// Register an op that can be directly serialized with serde
+    pub fn register<O: Operator + Serialize + DeserializeOwned + 'static>(&mut self, name: &str) {
+        fn serialize<O: Operator + Serialize + 'static>(
+            op: &dyn Operator,
+        ) -> serde_json::Result<Value> {
+            serde_json::to_value(op.as_any().downcast_ref::<O>().unwrap())
+        }
+        fn deserialize<O: Operator + DeserializeOwned + 'static>(
+            value: Value,
+            _: &Graph,
+        ) -> serde_json::Result<Box<dyn Operator>> {
+            Ok(Box::new(serde_json::from_value::<O>(value)?))
+        }
+        self.register_with::<O>(name, serialize::<O>, deserialize::<O>);
+    }
+
+    /// Register an op with custom serialization functions
+    pub fn register_with<O: Operator + 'static>(
+        &mut self,
+        name: &str,
+        serialize: OpSerializer,
+        deserialize: OpDeserializer,
+    ) {
+        assert!(
+            name != LOAD_OP,
+            "{LOAD_OP} is reserved for source nodes and cannot be registered"
+        );
+        self.serializers
+            .insert(TypeId::of::<O>(), (name.to_string(), serialize));
+        self.deserializers.insert(name.to_string(), deserialize);
+    }
+
+    /// Check if an op type is registered
+    pub fn contains<O: Operator + 'static>(&self) -> bool {
+        self.serializers.contains_key(&TypeId::of::<O>())
+    }
+}
+
+/// An error encountered when saving or loading a graph
+#[derive(Debug)]
+pub enum SerializationError {
+    /// Reading or writing the file failed
+    Io(std::io::Error),
+    /// The file or an op's data couldn't be encoded / decoded
+    Format(serde_json::Error),
+    /// The file was written with an unsupported format version
+    Version(u32),
+    /// These nodes contain ops that can't be serialized (node, op name)
+    UnserializableOps(Vec<(NodeIndex, String)>),
+    /// The file references an op not present in the registry
+    UnknownOp(String),
+    /// The file's graph structure is inconsistent (edges to missing nodes, bad input orders or shapes, cycles)
+    InvalidGraph(String),
+}
+
+impl Display for SerializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerializationError::Io(e) => write!(f, "IO error: {e}"),
+            SerializationError::Format(e) => write!(f, "Format error: {e}"),
+            SerializationError::Version(v) => write!(
+                f,
+                "Unsupported graph format version {v} (expected {FORMAT_VERSION})"
+            ),
+            SerializationError::UnserializableOps(nodes) => write!(
+                f,
+                "Graph contains ops that can't be serialized: {}",
+                nodes
+                    .iter()
+                    .map(|(n, op)| format!("{op} | {}", n.index()))
+                    .join(", ")
+            ),
+            SerializationError::UnknownOp(op) => {
+                write!(f, "Op {op} isn't registered in the op registry")
+            }
+            SerializationError::InvalidGraph(reason) => write!(f, "Invalid graph: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SerializationError {}
+
+impl From<std::io::Error> for SerializationError {
+    fn from(value: std::io::Error) -> Self {
+        SerializationError::Io(value)
+    }
+}
+
+impl From<serde_json::Error> for SerializationError {
+    fn from(value: serde_json::Error) -> Self {
+        SerializationError::Format(value)
+    }
+}
+
+/// The on-disk representation of a graph
+#[derive(Serialize, Deserialize)]
+struct SerializedGraph {
+    version: u32,
+    /// (Node index, op name, op data)
+    nodes: Vec<(usize, String, Value)>,
+    /// (Source, destination, dependency)
+    edges: Vec<(usize, usize, Dependency)>,
+    no_delete: Vec<usize>,
+    /// (Node index, output index, shape)
+    to_retrieve: Vec<(usize, u8, ShapeTracker)>,
+    dyn_map: Vec<(char, usize)>,
+}
+
+impl Graph {
+    /// Save the graph structure (ops, edges, shapes, kept / retrieved nodes and dyn dims) to a file.
+    ///
+    /// Tensor data is not saved. Source nodes are saved by name and must be set again after loading. Any other op not in the registry (such as `Function` closures) causes an error listing the offending nodes.
+    pub fn save(
+        &self,
+        path: impl AsRef<Path>,
+        registry: &OpRegistry,
+    ) -> Result<(), SerializationError> {
+        let serialized = self.to_serialized(registry)?;
+        serde_json::to_writer(BufWriter::new(File::create(path)?), &serialized)?;
+        Ok(())
+    }
+
+    /// Load a graph saved with `Graph::save`, replacing the current graph structure and clearing all tensors.
+    ///
+    /// Node indexes are preserved, so ids from the saved graph (for instance remapped by `compile`) stay valid.
+    pub fn load(
+        &mut self,
+        path: impl AsRef<Path>,
+        registry: &OpRegistry,
+    ) -> Result<(), SerializationError> {
+        let serialized: SerializedGraph =
+            serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        self.load_serialized(serialized, registry)
+    }
+
+    fn to_serialized(&self, registry: &OpRegistry) -> Result<SerializedGraph, SerializationError> {
+        let mut nodes = vec![];
+        let mut unserializable = vec![];
+        for node in self.graph.node_indices().sorted() {
+            let op = self.graph.node_weight(node).unwrap();
+            if let Some(Function(name, _)) = op.as_any().downcast_ref::<Function>() {
+                // Source functions are loaders, so only keep their name. Functions with inputs are opaque computation
+                if self
+                    .graph
+                    .edges_directed(node, Direction::Incoming)
+                    .all(|e| e.weight().is_schedule())
+                {
+                    nodes.push((
+                        node.index(),
+                        LOAD_OP.to_string(),
+                        Value::from(name.as_str()),
+                    ));
+                } else {
+                    unserializable.push((node, name.clone()));
+                }
+                continue;
+            }
+            match registry.serializers.get(&op.as_any().type_id()) {
+                Some((name, serialize)) => {
+                    nodes.push((node.index(), name.clone(), serialize(op.as_ref())?))
+                }
+                None => unserializable.push((node, format!("{op:?}"))),
+            }
+        }
+        if !unserializable.is_empty() {
+            return Err(SerializationError::UnserializableOps(unserializable));
+        }
+        Ok(SerializedGraph {
+            version: FORMAT_VERSION,
+            nodes,
+            edges: (&self.graph)
+                .edge_references()
+                .map(|e| (e.source().index(), e.target().index(), *e.weight()))
+                .collect(),
+            no_delete: self.no_delete.iter().map(|n| n.index()).sorted().collect(),
+            to_retrieve: self
+                .to_retrieve
+                .iter()
+                .map(|(n, (o, s))| (n.index(), *o, *s))
+                .sorted_by_key(|(n, _, _)| *n)
+                .collect(),
+            dyn_map: self
+                .dyn_map
+                .iter()
+                .map(|(c, v)| (*c, *v))
+                .sorted()
+                .collect(),
+        })
+    }
+
+    fn load_serialized(
+        &mut self,
+        serialized: SerializedGraph,
+        registry: &OpRegistry,
+    ) -> Result<(), SerializationError> {
+        if serialized.version != FORMAT_VERSION {
+            return Err(SerializationError::Version(serialized.version));
+        }
+        validate_structure(&serialized)?;
+        let mut ops = FxHashMap::default();
+        let mut loaders = vec![];
+        for (index, name, value) in serialized.nodes {
+            let op: Box<dyn Operator> = if name == LOAD_OP {
+                loaders.push(NodeIndex::new(index));
+                let name = serde_json::from_value::<String>(value)?;
+                let tensor_name = name.strip_suffix(" Load").unwrap_or(&name).to_string();
+                Box::new(Function(
+                    name,
+                    Box::new(move |_| panic!("{UNSET_INPUT_MESSAGE} ({tensor_name})")),
+                ))
+            } else {
+                let deserialize = registry
+                    .deserializers
+                    .get(&name)
+                    .ok_or(SerializationError::UnknownOp(name))?;
+                deserialize(value, self)?
+            };
+            ops.insert(index, op);
+        }
+
+        // Add nodes in index order, filling holes left by removed nodes with placeholders
+        let mut graph = StorageGraph::default();
+        let n_nodes = ops.keys().max().map(|i| i + 1).unwrap_or_default();
+        let mut holes = vec![];
+        for i in 0..n_nodes {
+            if let Some(op) = ops.remove(&i) {
+                graph.add_node(op);
+            } else {
+                holes.push(graph.add_node(Box::new(Function(
+                    "Placeholder".to_string(),
+                    Box::new(|_| vec![]),
+                ))));
+            }
+        }
+        for hole in holes {
+            graph.remove_node(hole);
+        }
+        for (src, dest, dependency) in serialized.edges {
+            graph.add_edge(NodeIndex::new(src), NodeIndex::new(dest), dependency);
+        }
+        if let Err(cycle) = petgraph::algo::toposort(&graph, None) {
+            return Err(invalid(format!(
+                "node {} is part of a cycle",
+                cycle.node_id().index()
+            )));
+        }
+
+        self.graph = graph;
+        self.input_states = loaders
+            .into_iter()
+            .map(|id| (id, InputState::Unset))
+            .collect();
+        self.tensors.clear();
+        self.no_delete = serialized
+            .no_delete
+            .into_iter()
+            .map(NodeIndex::new)
+            .collect();
+        self.to_retrieve = serialized
+            .to_retrieve
+            .into_iter()
+            .map(|(n, o, s)| (NodeIndex::new(n), (o, s)))
+            .collect();
+        self.dyn_map = serialized.dyn_map.into_iter().collect();
+        self.toposort();
+        Ok(())
+    }
+}
+
+fn invalid(reason: String) -> SerializationError {
+    SerializationError::InvalidGraph(reason)
+}
+
+/// Check that nodes are unique, edges and kept / retrieved nodes reference existing nodes, every node's data inputs are numbered 0..n, and all shapes are well formed
+fn validate_structure(serialized: &SerializedGraph) -> Result<(), SerializationError> {
+    let mut nodes = FxHashSet::default();
+    for (index, _, _) in &serialized.nodes {
+        if !nodes.insert(*index) {
+            return Err(invalid(format!("node {index} is defined twice")));
+        }
+    }
+    let check_node = |node: usize| {
+        if nodes.contains(&node) {
+            Ok(())
+        } else {
+            Err(invalid(format!("node {node} doesn't exist")))
+        }
+    };
+    let mut input_orders = FxHashMap::<usize, Vec<u8>>::default();
+    for (src, dest, dependency) in &serialized.edges {
+        check_node(*src)?;
+        check_node(*dest)?;
+        if let Dependency::Data {
+            input_order, shape, ..
+        } = dependency
+        {
+            if !valid_shape(shape) {
+                return Err(invalid(format!(
+                    "edge {src} -> {dest} has a malformed shape"
+                )));
+            }
+            input_orders.entry(*dest).or_default().push(*input_order);
+        }
+    }
+    for (node, mut orders) in input_orders {
+        orders.sort_unstable();
+        if orders.iter().enumerate().any(|(i, o)| *o as usize != i) {
+            return Err(invalid(format!(
+                "node {node} has inputs numbered {orders:?}, expected 0..{}",
+                orders.len()
+            )));
+        }
+    }
+    for node in &serialized.no_delete {
+        check_node(*node)?;
+    }
+    for (node, _, shape) in &serialized.to_retrieve {
+        check_node(*node)?;
+        if !valid_shape(shape) {
+            return Err(invalid(format!(
+                "retrieved node {node} has a malformed shape"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// A shape is well formed if all its per-dimension arrays have the same length and its indexes are a permutation
+fn valid_shape(shape: &ShapeTracker) -> bool {
+    let len = shape.dims.len();
+    shape.indexes.len() == len
+        && shape.fake.len() == len
+        && shape.mask.len() == len
+        && shape.padding.len() == len
+        && shape.indexes.iter().sorted().copied().eq(0..len)
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use super::SerializedGraph;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("luminal_{name}_{}.json", std::process::id()))
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("A", (2, 's'));
+        let b = cx.named_tensor("B", ('s', 3));
+        let mut c = (a.matmul(b) + 1.).exp2().sum(1).retrieve();
+        cx.compile(GenericCompiler::default(), &mut c);
+
+        let path = temp_path("save_load");
+        cx.save(&path, &OpRegistry::default()).unwrap();
+
+        let mut loaded = Graph::new();
+        loaded.load(&path, &OpRegistry::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.node_count(), cx.node_count());
+        assert!(loaded.to_retrieve.contains_key(&c.id));
+
+        let a_data = random_vec(8);
+        let b_data = random_vec(12);
+        a.set_dyn(a_data.clone(), (2, 4));
+        b.set_dyn(b_data.clone(), (4, 3));
+        cx.execute();
+
+        let loaded_a = GraphTensor::from_id(a.id, a.shape, &mut loaded);
+        let loaded_b = GraphTensor::from_id(b.id, b.shape, &mut loaded);
+        let loaded_c = GraphTensor::from_id(c.id, c.shape, &mut loaded);
+        loaded_a.set_dyn(a_data, (2, 4));
+        loaded_b.set_dyn(b_data, (4, 3));
+        loaded.execute();
+
+        assert_exact(&c.data(), &loaded_c.data());
+    }
+
+    #[test]
+    fn test_unserializable_ops() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let b = (a * 2.).print("b");
+        let _ = (b + 1.).retrieve();
+
+        let Err(SerializationError::UnserializableOps(nodes)) =
+            cx.save(temp_path("unserializable"), &OpRegistry::default())
+        else {
+            panic!("Expected unserializable ops error");
+        };
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].1, "Print");
+    }
+
+    #[test]
+    fn test_load_invalid_structure() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("A", 3);
+        let b = cx.named_tensor("B", 3);
+        let c = (a + b).retrieve();
+        let registry = OpRegistry::default();
+
+        let mut loaded = Graph::new();
+        let mut try_load = |edit: &dyn Fn(&mut SerializedGraph)| {
+            let mut serialized = cx.to_serialized(&registry).unwrap();
+            edit(&mut serialized);
+            match loaded.load_serialized(serialized, &registry) {
+                Err(SerializationError::InvalidGraph(reason)) => reason,
+                _ => panic!("Expected invalid graph error"),
+            }
+        };
+        let add = c.id.index();
+        assert_eq!(try_load(&|s| s.edges[0].1 = 10), "node 10 doesn't exist");
+        assert_eq!(
+            try_load(&|s| s.nodes.push(s.nodes[0].clone())),
+            format!("node {} is defined twice", a.id.index())
+        );
+        assert_eq!(
+            try_load(&|s| {
+                for (_, _, dep) in &mut s.edges {
+                    if let Dependency::Data { input_order, .. } = dep {
+                        *input_order = 1;
+                    }
+                }
+            }),
+            format!("node {add} has inputs numbered [1, 1], expected 0..2")
+        );
+        let reason = try_load(&|s| {
+            if let Dependency::Data { shape, .. } = &mut s.edges[0].2 {
+                shape.indexes[0] = 5;
+            }
+        });
+        assert!(reason.ends_with("has a malformed shape"));
+        let reason = try_load(&|s| s.edges.push((add, a.id.index(), Dependency::Schedule)));
+        assert!(reason.ends_with("is part of a cycle"));
+        assert_eq!(
+            try_load(&|s| s.to_retrieve[0].0 = 10),
+            "node 10 doesn't exist"
+        );
+
+        // Failed loads leave the graph untouched
+        assert_eq!(loaded.node_count(), 0);
+    }
+}
@@ -1,5 +1,5 @@
 use egg::*;
-use generational_box::{AnyStorage, GenerationalBox, Owner, UnsyncStorage};
+use generational_box::{AnyStorage, GenerationalBox, Owner};
 use rustc_hash::FxHashMap;
 use std::{
     cell::RefCell,
@@ -12,8 +12,14 @@ use std::{
 };
 use symbolic_expressions::Sexp;
 
+/// Expressions are only shared between threads by the parallel executor, so only pay for locking storage when it's enabled
+#[cfg(feature = "parallel")]
+type ExpressionStorage = generational_box::SyncStorage;
+#[cfg(not(feature = "parallel"))]
+type ExpressionStorage = generational_box::UnsyncStorage;
+
 thread_local! {
-    static EXPRESSION_OWNER: RefCell<Option<Owner<UnsyncStorage>>> = RefCell::new(Some(UnsyncStorage::owner()));
+    static EXPRESSION_OWNER: RefCell<Option<Owner<ExpressionStorage>>> = RefCell::new(Some(ExpressionStorage::owner()));
 }
 
 /// Clean up symbolic expresion storage
@@ -22,13 +28,13 @@ pub fn expression_cleanup() {
 }
 
 /// Get the thread-local owner of expression storage
-fn expression_owner() -> Owner {
+fn expression_owner() -> Owner<ExpressionStorage> {
     EXPRESSION_OWNER.with(|cell| cell.borrow().clone().unwrap())
 }
 
 #[derive(Clone, Copy)]
 pub struct Expression {
-    pub terms: GenerationalBox<Vec<Term>>,
+    pub terms: GenerationalBox<Vec<Term>, ExpressionStorage>,
 }
 
 impl Expression {
@@ -51,6 +57,18 @@ impl Default for Expression {
     }
 }
 
+impl serde::Serialize for Expression {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.terms.read().serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Expression {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Expression::new(Vec::<Term>::deserialize(deserializer)?))
+    }
+}
+
 /// A single term of a symbolic expression such as a variable, number or operation.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub enum Term {
@@ -313,6 +331,20 @@ impl Expression {
         Expression::new(new_terms)
     }
 
+    /// Substitute expressions for many variables at once, so variables in the substituted expressions aren't replaced again
+    pub fn substitute_all(self, exprs: &FxHashMap<char, Expression>) -> Self {
+        let mut new_terms = vec![];
+        for term in self.terms.read().iter() {
+            match term {
+                Term::Var(c) if exprs.contains_key(c) => {
+                    new_terms.extend(exprs[c].terms.read().iter().copied());
+                }
+                _ => new_terms.push(*term),
+            }
+        }
+        Expression::new(new_terms)
+    }
+
     /// Evaluate the expression with no variables. Returns Some(value) if no variables are required, otherwise returns None.
     pub fn to_usize(&self) -> Option<usize> {
         self.exec(&FxHashMap::default())
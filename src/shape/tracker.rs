@@ -3,7 +3,7 @@ use tinyvec::ArrayVec;
 
 use crate::prelude::*;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct ShapeTracker {
     pub dims: ArrayVec<[Expression; 6]>,
     pub indexes: ArrayVec<[usize; 6]>,
@@ -340,6 +340,24 @@ impl ShapeTracker {
         }
     }
 
+    /// All dynamic dimensions referenced by this shape
+    pub fn dyn_dims(&self) -> Vec<char> {
+        let mut dims = self
+            .dims
+            .iter()
+            .chain(
+                self.padding
+                    .iter()
+                    .chain(self.mask.iter())
+                    .flat_map(|(a, b)| [a, b]),
+            )
+            .flat_map(|e| e.to_symbols())
+            .collect::<Vec<_>>();
+        dims.sort_unstable();
+        dims.dedup();
+        dims
+    }
+
     pub fn is_sliced(&self) -> bool {
         self.mask.iter().any(|(b, e)| {
             b.to_usize().map(|i| i != 0).unwrap_or(true)
@@ -0,0 +1,606 @@
+// Subgraphs defined once and called from many places in another graph
+
+use std::{
+    any::TypeId,
+    fmt::Debug,
+    mem::ManuallyDrop,
+    sync::{Arc, Mutex},
+};
+
+use itertools::Itertools;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::prelude::*;
+
+/// A graph that is defined and compiled once, and then called any number of times from another graph.
+///
+/// Inputs (activations and weights alike) are bound at each call site, so a block repeated many times with different weights only needs to be built and compiled once.
+/// ```rust
+/// use luminal::prelude::*;
+/// let block = SubGraph::new("Block", |cx| {
+///     let x = cx.named_tensor("Input", 3);
+///     let w = cx.named_tensor("Weight", 3);
+///     (vec![x, w], vec![(x * w).sin()])
+/// });
+/// block.compile(GenericCompiler::default());
+///
+/// let mut cx = Graph::new();
+/// let mut x = cx.tensor(3).set(vec![1., 2., 3.]);
+/// for _ in 0..4 {
+///     let w = cx.tensor(3).set(vec![0.5, 0.5, 0.5]);
+///     x = block.call(&[x, w])[0];
+/// }
+/// ```
+#[derive(Clone)]
+pub struct SubGraph {
+    name: String,
+    build: Arc<Builder>,
+    template: Arc<Mutex<Template>>,
+}
+
+type Builder = dyn Fn(&mut Graph) -> (Vec<GraphTensor>, Vec<GraphTensor>);
+
+impl PartialEq for SubGraph {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.template, &other.template)
+    }
+}
+
+struct Template {
+    graph: ManuallyDrop<Box<Graph>>,
+    inputs: Vec<GraphTensor>,
+    outputs: Vec<GraphTensor>,
+    /// Passes already ran on the template by `compile_subgraphs`, which don't need to run again until it's compiled by something else
+    compiled_passes: FxHashSet<TypeId>,
+}
+
+impl Template {
+    /// The shapes of the outputs when called on these inputs. Dyn dims of the template's inputs are replaced with the dims at the call site
+    fn output_shapes(&self, call_inputs: &[GraphTensor], outputs: usize) -> Vec<ShapeTracker> {
+        let mut dims = FxHashMap::default();
+        for (input, call_input) in self.inputs.iter().zip(call_inputs) {
+            for (d, call_d) in input.dims().into_iter().zip(call_input.dims()) {
+                if let [Term::Var(c)] = d.terms.read()[..] {
+                    dims.insert(c, call_d);
+                }
+            }
+        }
+        self.outputs[..outputs]
+            .iter()
+            .map(|o| {
+                let mut shape = o.shape;
+                for d in shape.dims.iter_mut() {
+                    *d = d.substitute_all(&dims);
+                }
+                for (a, b) in shape.mask.iter_mut().chain(shape.padding.iter_mut()) {
+                    *a = a.substitute_all(&dims);
+                    *b = b.substitute_all(&dims);
+                }
+                shape
+            })
+            .collect()
+    }
+}
+
+impl Template {
+    fn compile<C: Compiler>(&mut self, compiler: C) -> C::Output {
+        let Template {
+            graph,
+            inputs,
+            outputs,
+            ..
+        } = self;
+        graph.compile(compiler, (inputs, outputs))
+    }
+}
+
+impl Drop for Template {
+    fn drop(&mut self) {
+        // Dropping a graph normally cleans up all expressions, which the calling graph still uses
+        unsafe { ManuallyDrop::take(&mut self.graph) }.drop_nested();
+    }
+}
+
+impl SubGraph {
+    /// Define a subgraph. The closure builds the graph and returns its (inputs, outputs).
+    ///
+    /// Inputs must be source tensors (like those made with `named_tensor`). Dynamic dimensions in the input shapes are set from the shapes passed in at each call.
+    pub fn new(
+        name: impl ToString,
+        build: impl Fn(&mut Graph) -> (Vec<GraphTensor>, Vec<GraphTensor>) + 'static,
+    ) -> Self {
+        Self::from_builder(name.to_string(), Arc::new(build))
+    }
+
+    /// Define a new subgraph by building this one (uncompiled) and then extending it. The closure gets the graph, inputs and outputs, and returns the new inputs and outputs.
+    pub fn extend(
+        &self,
+        name: impl ToString,
+        extend: impl Fn(
+                &mut Graph,
+                Vec<GraphTensor>,
+                Vec<GraphTensor>,
+            ) -> (Vec<GraphTensor>, Vec<GraphTensor>)
+            + 'static,
+    ) -> Self {
+        let build = self.build.clone();
+        Self::from_builder(
+            name.to_string(),
+            Arc::new(move |cx: &mut Graph| {
+                let (inputs, outputs) = build(cx);
+                extend(cx, inputs, outputs)
+            }),
+        )
+    }
+
+    fn from_builder(name: String, build: Arc<Builder>) -> Self {
+        let mut graph = Box::new(Graph::new());
+        let (inputs, outputs) = build(&mut graph);
+        graph.keep_tensors(&outputs);
+        // Call sites can run on different threads under the parallel executor, so the template is behind a lock
+        #[allow(clippy::arc_with_non_send_sync)]
+        Self {
+            name,
+            build,
+            template: Arc::new(Mutex::new(Template {
+                graph: ManuallyDrop::new(graph),
+                inputs,
+                outputs,
+                compiled_passes: FxHashSet::default(),
+            })),
+        }
+    }
+
+    /// The name of the subgraph
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of inputs and outputs
+    pub fn arity(&self) -> (usize, usize) {
+        let template = self.template.lock().unwrap();
+        (template.inputs.len(), template.outputs.len())
+    }
+
+    /// Compile the subgraph. This only needs to happen once no matter how many times it's called.
+    pub fn compile<C: Compiler>(&self, compiler: C) -> C::Output {
+        let mut template = self.template.lock().unwrap();
+        template.compiled_passes.clear();
+        template.compile(compiler)
+    }
+
+    /// Call the subgraph on a set of inputs from another graph, returning the outputs
+    pub fn call(&self, inputs: &[GraphTensor]) -> Vec<GraphTensor> {
+        let template = self.template.lock().unwrap();
+        assert_eq!(
+            inputs.len(),
+            template.inputs.len(),
+            "{} expects {} inputs",
+            self.name,
+            template.inputs.len()
+        );
+        let outputs = template.output_shapes(inputs, template.outputs.len());
+        drop(template);
+        add_call(Call(self.clone()), inputs, &outputs)
+    }
+
+    /// Run the subgraph in a loop, all within a single op in another graph.
+    ///
+    /// The subgraph's inputs are the loop-carried tensors followed by the loop-invariant tensors (like weights). Its outputs are the next values of the carried tensors, followed by a single-element condition tensor. The loop stops when the condition is 0 or after `max_iterations`, and the final carried tensors are returned.
+    ///
+    /// Carried tensors must keep the same shape between iterations, so growing state (like a KV cache) should be a fixed-size buffer.
+    pub fn call_loop(
+        &self,
+        carried: &[GraphTensor],
+        invariants: &[GraphTensor],
+        max_iterations: usize,
+    ) -> Vec<GraphTensor> {
+        let template = self.template.lock().unwrap();
+        assert_eq!(
+            carried.len() + invariants.len(),
+            template.inputs.len(),
+            "{} expects {} inputs",
+            self.name,
+            template.inputs.len()
+        );
+        assert_eq!(
+            template.outputs.len(),
+            carried.len() + 1,
+            "{} must output each carried tensor followed by a condition",
+            self.name
+        );
+        let outputs = template.output_shapes(&[carried, invariants].concat(), carried.len());
+        assert!(
+            outputs.iter().all(|sh| !sh.is_reshaped()),
+            "Carried outputs of {} must be contiguous",
+            self.name
+        );
+        drop(template);
+        add_call(
+            Loop {
+                body: self.clone(),
+                num_carried: carried.len(),
+                max_iterations,
+            },
+            &[carried, invariants].concat(),
+            &outputs,
+        )
+    }
+
+    /// Run the subgraph on a set of inputs, returning the outputs
+    pub(crate) fn run(&self, inp: &[(InputTensor, ShapeTracker)]) -> Vec<Tensor> {
+        let mut template = self.template.lock().unwrap();
+        let Template {
+            graph,
+            inputs,
+            outputs,
+            ..
+        } = &mut *template;
+        let graph: &mut Graph = graph;
+
+        // Report dyn dim values from the call site to the template
+        for (input, (_, st)) in inputs.iter().zip(inp) {
+            for (d, s) in input.shape.dims().iter().zip(st.shape_usize()) {
+                if let Some(c) = d.to_symbols().pop() {
+                    graph.dyn_map.insert(c, s);
+                }
+            }
+        }
+
+        // Inputs are borrowed from the call site, everything else comes from the template
+        let external = inputs
+            .iter()
+            .zip(inp)
+            .map(|(i, (t, _))| (i.id, t.borrowed()))
+            .collect_vec();
+        graph
+            .run_sequential(&external, &mut ())
+            .unwrap_or_else(|e| panic!("{e}"));
+
+        let outputs = outputs
+            .iter()
+            .map(|o| match inputs.iter().position(|i| i.id == o.id) {
+                Some(i) => inp[i].0.borrowed().clone(),
+                None => graph.tensors.remove(&(o.id, 0)).unwrap(),
+            })
+            .collect();
+        graph.reset();
+        outputs
+    }
+}
+
+impl GraphTensor {
+    /// Run `then_branch` on the inputs if this single-element predicate is nonzero, otherwise run `else_branch`. Only the chosen branch is executed.
+    ///
+    /// Both branches must take the same inputs and have outputs with the same shapes.
+    pub fn cond(
+        self,
+        then_branch: &SubGraph,
+        else_branch: &SubGraph,
+        inputs: &[GraphTensor],
+    ) -> Vec<GraphTensor> {
+        let outputs = {
+            let (then_template, else_template) = (
+                then_branch.template.lock().unwrap(),
+                else_branch.template.lock().unwrap(),
+            );
+            for template in [&then_template, &else_template] {
+                assert_eq!(
+                    inputs.len(),
+                    template.inputs.len(),
+                    "Branches expect {} inputs",
+                    template.inputs.len()
+                );
+            }
+            let outputs = then_template.output_shapes(inputs, then_template.outputs.len());
+            assert_eq!(
+                outputs,
+                else_template.output_shapes(inputs, else_template.outputs.len()),
+                "{} and {} have different output shapes",
+                then_branch.name,
+                else_branch.name
+            );
+            outputs
+        };
+        add_call(
+            If {
+                then_branch: then_branch.clone(),
+                else_branch: else_branch.clone(),
+            },
+            &[&[self], inputs].concat(),
+            &outputs,
+        )
+    }
+}
+
+/// The subgraphs a control flow op runs
+pub fn subgraphs(op: &dyn Operator) -> Vec<&SubGraph> {
+    if let Some(Call(subgraph)) = op.as_any().downcast_ref() {
+        vec![subgraph]
+    } else if let Some(Loop { body, .. }) = op.as_any().downcast_ref() {
+        vec![body]
+    } else if let Some(If {
+        then_branch,
+        else_branch,
+    }) = op.as_any().downcast_ref()
+    {
+        vec![then_branch, else_branch]
+    } else {
+        vec![]
+    }
+}
+
+/// Run a compiler pass on every distinct subgraph used in the graph that it hasn't already ran on
+pub(crate) fn compile_subgraphs<C: Compiler + 'static>(graph: &Graph, compiler: impl Fn() -> C) {
+    for op in graph.graph.node_weights() {
+        for subgraph in subgraphs(op.as_ref()) {
+            let mut template = subgraph.template.lock().unwrap();
+            if template.compiled_passes.insert(TypeId::of::<C>()) {
+                template.compile(compiler());
+            }
+        }
+    }
+}
+
+/// Add an op running a subgraph to the inputs' graph, with a passthrough node per output if there's more than one
+fn add_call<O: Operator + 'static>(
+    op: O,
+    inputs: &[GraphTensor],
+    outputs: &[ShapeTracker],
+) -> Vec<GraphTensor> {
+    let graph = inputs[0].graph();
+    let mut op = graph.add_op(op);
+    for input in inputs {
+        let input = input.contiguous();
+        op = op.input(input.id, 0, input.shape);
+    }
+    let call = op.finish();
+    if outputs.len() == 1 {
+        return vec![GraphTensor::from_id(call, outputs[0], graph)];
+    }
+    outputs
+        .iter()
+        .enumerate()
+        .map(|(i, shape)| {
+            let id = graph
+                .add_op(CallOutput(i))
+                .input(call, i as u8, *shape)
+                .finish();
+            GraphTensor::from_id(id, *shape, graph)
+        })
+        .collect()
+}
+
+/// Runs a subgraph template on its inputs
+#[derive(Clone, PartialEq)]
+pub struct Call(pub SubGraph);
+
+impl Debug for Call {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Call({})", self.0.name)
+    }
+}
+
+impl Operator for Call {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.0.run(&inp)
+    }
+}
+
+/// Passes through a single output of a multi-output call
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallOutput(pub usize);
+
+impl Operator for CallOutput {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![inp.pop().unwrap().0.cloned()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_subgraph_call() {
+        let block = SubGraph::new("Block", |cx| {
+            let x = cx.named_tensor("Input", ('s', 3));
+            let w = cx.named_tensor("Weight", (3, 3));
+            let b = cx.named_tensor("Bias", 3);
+            let h = x.matmul(w) + b.expand_dim(0, 's');
+            (vec![x, w, b], vec![h.sin(), h.sum(1)])
+        });
+        block.compile(GenericCompiler::default());
+
+        let mut cx = Graph::new();
+        let x = cx.tensor(('s', 3)).set_dyn(random_vec(6), (2, 3));
+        let (mut h, mut reference) = (x, x);
+        let mut sums = vec![];
+        for _ in 0..3 {
+            let w = cx.tensor((3, 3)).set(random_vec(9));
+            let b = cx.tensor(3).set(random_vec(3));
+            let out = block.call(&[h, w, b]);
+            h = out[0];
+            sums.push(out[1].retrieve());
+
+            let r = reference.matmul(w) + b.expand_dim(0, 's');
+            sums.push(r.sum(1).retrieve());
+            reference = r.sin();
+        }
+        h.retrieve();
+        reference.retrieve();
+        cx.execute();
+
+        assert_close(&h.data(), &reference.data());
+        for pair in sums.chunks(2) {
+            assert_close(&pair[0].data(), &pair[1].data());
+        }
+        // The template is compiled once, and only appears once per call site
+        assert_eq!(
+            cx.graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<Call>())
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_subgraph_call_dims() {
+        let block = SubGraph::new("Block", |cx| {
+            let x = cx.named_tensor("Input", ('s', 3));
+            (vec![x], vec![x.sum(1), x.pad(((0, 1), (0, 0)))])
+        });
+
+        let mut cx = Graph::new();
+        let x = cx
+            .tensor(('b', 3))
+            .set_dyn(vec![1., 2., 3., 4., 5., 6.], (2, 3));
+        let out = block.call(&[x]);
+        let (sum, padded) = (out[0].retrieve(), out[1].retrieve());
+        assert_eq!(sum.dims(), vec![Expression::from('b')]);
+        // Dropping a subgraph doesn't clean up expressions the calling graph still uses
+        drop(SubGraph::new("Unused", |cx| {
+            let x = cx.named_tensor("Input", 3);
+            (vec![x], vec![x.exp2()])
+        }));
+        cx.execute();
+
+        assert_exact(&sum.data(), &[6., 15.]);
+        assert_exact(&padded.data(), &[1., 2., 3., 4., 5., 6., 0., 0., 0.]);
+    }
+
+    #[test]
+    fn test_loop() {
+        let body = SubGraph::new("Step", |cx| {
+            let counter = cx.named_tensor("Counter", ());
+            let acc = cx.named_tensor("Accumulator", 3);
+            let w = cx.named_tensor("Weight", 3);
+            let next_counter = counter + 1.;
+            let cond = next_counter.lt(cx.constant(4.));
+            (
+                vec![counter, acc, w],
+                vec![next_counter, (acc * w).sin(), cond],
+            )
+        });
+        body.compile(GenericCompiler::default());
+        // Conditions can be any element type
+        let bool_body = SubGraph::new("Step", |cx| {
+            let counter = cx.named_tensor("Counter", ());
+            let next_counter = counter + 1.;
+            let cond = next_counter.lt(cx.constant(4.)).cast(DType::Bool);
+            (vec![counter], vec![next_counter, cond])
+        });
+        bool_body.compile(GenericCompiler::default());
+
+        let mut cx = Graph::new();
+        let counter = cx.tensor(()).set(vec![0.]);
+        let acc = cx.tensor(3).set(random_vec(3));
+        let w = cx.tensor(3).set(random_vec(3));
+        let out = body.call_loop(&[counter, acc], &[w], 100);
+        let (counter_out, acc_out) = (out[0].retrieve(), out[1].retrieve());
+        // Capped at fewer iterations than the condition allows
+        let capped = body.call_loop(&[counter, acc], &[w], 2)[1].retrieve();
+        let bool_counter_out = bool_body.call_loop(&[counter], &[], 100)[0].retrieve();
+        let mut reference = acc;
+        let mut references = vec![];
+        for _ in 0..4 {
+            reference = (reference * w).sin();
+            references.push(reference.retrieve());
+        }
+        cx.execute();
+
+        assert_exact(&counter_out.data(), &[4.]);
+        assert_close(&acc_out.data(), &references[3].data());
+        assert_close(&capped.data(), &references[1].data());
+        assert_exact(&bool_counter_out.data(), &[4.]);
+    }
+
+    #[test]
+    fn test_cond() {
+        let double_branch = |cx: &mut Graph| {
+            let x = cx.named_tensor("Input", 3);
+            let unused = x.sin();
+            unused.exp2();
+            (vec![x], vec![x * 2.])
+        };
+        let double = SubGraph::new("Branch", double_branch);
+        // Running this branch would fail, since it has an input that's never set
+        let unset = SubGraph::new("Branch", |cx| {
+            let x = cx.named_tensor("Input", 3);
+            (vec![x], vec![x * cx.named_tensor("Unset", 3)])
+        });
+        let square = SubGraph::new("Square", |cx| {
+            let x = cx.named_tensor("Input", 3);
+            (vec![x], vec![x * x])
+        });
+
+        let mut cx = Graph::new();
+        let x = cx.tensor(3).set(vec![1., 2., 3.]);
+        let (yes, no) = (cx.tensor(()).set(vec![1.]), cx.tensor(()).set(vec![0.]));
+        let mut a = yes.cond(&double, &unset, &[x])[0].retrieve();
+        let mut b = no.cond(&unset, &double, &[x])[0].retrieve();
+        let mut c = no.cond(&double, &square, &[x])[0].retrieve();
+        // Different subgraphs with the same name aren't merged
+        let mut d = yes.cond(&SubGraph::new("Branch", double_branch), &unset, &[x])[0];
+        d.retrieve();
+        // Predicates can be any element type
+        let (yes_bool, no_int) = (
+            cx.tensor(()).set(vec![true]),
+            cx.tensor(()).set(vec![0_i32]),
+        );
+        let mut e = yes_bool.cond(&double, &unset, &[x])[0].retrieve();
+        let mut f = no_int.cond(&unset, &square, &[x])[0].retrieve();
+        cx.compile(
+            GenericCompiler::default(),
+            (&mut a, &mut b, &mut c, &mut d, &mut e, &mut f),
+        );
+        assert_eq!(
+            cx.graph
+                .node_weights()
+                .filter(|op| op.as_any().is::<If>())
+                .count(),
+            6
+        );
+        // Unused nodes in branches are removed
+        assert_eq!(double.template.lock().unwrap().graph.graph.node_count(), 3);
+        cx.execute();
+
+        assert_exact(&a.data(), &[2., 4., 6.]);
+        assert_exact(&b.data(), &[2., 4., 6.]);
+        assert_exact(&c.data(), &[1., 4., 9.]);
+        assert_exact(&d.data(), &[2., 4., 6.]);
+        assert_exact(&e.data(), &[2., 4., 6.]);
+        assert_exact(&f.data(), &[1., 4., 9.]);
+    }
+
+    #[test]
+    fn test_template_passes_run_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        struct CountRuns;
+        impl Compiler for CountRuns {
+            type Output = ();
+            fn compile<T: ToIdsMut>(&self, _: &mut Graph, _: T) {
+                RUNS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let block = SubGraph::new("Block", |cx| {
+            let x = cx.named_tensor("Input", 3);
+            (vec![x], vec![x.exp2()])
+        });
+        let mut cx = Graph::new();
+        let x = cx.tensor(3).set(vec![1., 2., 3.]);
+        block.call(&block.call(&[x]));
+
+        super::compile_subgraphs(&cx, || CountRuns);
+        super::compile_subgraphs(&cx, || CountRuns);
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+        // Compiling the template directly means passes need to run again
+        block.compile(GenericCompiler::default());
+        super::compile_subgraphs(&cx, || CountRuns);
+        assert_eq!(RUNS.load(Ordering::Relaxed), 2);
+    }
+}
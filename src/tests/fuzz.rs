@@ -0,0 +1,824 @@
+// Fuzzing compilers by comparing compiled and uncompiled runs of random graphs
+
+use std::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rustc_hash::FxHashMap;
+
+use super::random_vec_rng;
+use crate::prelude::*;
+
+/// The largest number of dimensions a generated tensor can have before broadcasting
+const MAX_RANK: usize = 4;
+
+/// A dimension of a generated input tensor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FuzzDim {
+    Static(usize),
+    Dyn(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FuzzUnary {
+    Neg,
+    Sin,
+    Cos,
+    Exp2,
+    Sigmoid,
+    Relu,
+    Abs,
+    /// `sqrt(|x|)`
+    Sqrt,
+    /// `1 / (|x| + 1)`
+    Recip,
+    /// `log2(|x| + 1)`
+    Log2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FuzzBinary {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Maximum,
+    LessThan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FuzzReduce {
+    Sum,
+    Max,
+    Mean,
+}
+
+/// A single step of a generated graph. Operands refer to the results of earlier steps by index
+#[derive(Debug, Clone, PartialEq)]
+pub enum FuzzOp {
+    /// A new source tensor with random data generated from a seed
+    Input {
+        shape: Vec<FuzzDim>,
+        seed: u64,
+    },
+    Unary(usize, FuzzUnary),
+    Binary(usize, usize, FuzzBinary),
+    Reduce(usize, FuzzReduce, usize),
+    Permute(usize, Vec<usize>),
+    /// Slice ranges per dimension. An end of `i32::MAX` keeps the rest of the dimension
+    Slice(usize, Vec<(usize, usize)>),
+    Pad(usize, Vec<(usize, usize)>),
+    /// Broadcast along a new axis with the given size
+    Expand(usize, usize, usize),
+    Matmul(usize, usize),
+    Contiguous(usize),
+}
+
+impl FuzzOp {
+    fn operands(&self) -> Vec<usize> {
+        match self {
+            FuzzOp::Input { .. } => vec![],
+            FuzzOp::Binary(a, b, _) | FuzzOp::Matmul(a, b) => vec![*a, *b],
+            FuzzOp::Unary(a, _)
+            | FuzzOp::Reduce(a, _, _)
+            | FuzzOp::Permute(a, _)
+            | FuzzOp::Slice(a, _)
+            | FuzzOp::Pad(a, _)
+            | FuzzOp::Expand(a, _, _)
+            | FuzzOp::Contiguous(a) => vec![*a],
+        }
+    }
+
+    fn operands_mut(&mut self) -> Vec<&mut usize> {
+        match self {
+            FuzzOp::Input { .. } => vec![],
+            FuzzOp::Binary(a, b, _) | FuzzOp::Matmul(a, b) => vec![a, b],
+            FuzzOp::Unary(a, _)
+            | FuzzOp::Reduce(a, _, _)
+            | FuzzOp::Permute(a, _)
+            | FuzzOp::Slice(a, _)
+            | FuzzOp::Pad(a, _)
+            | FuzzOp::Expand(a, _, _)
+            | FuzzOp::Contiguous(a) => vec![a],
+        }
+    }
+}
+
+/// A randomly generated graph, built from a list of ops over the high level op surface
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzCase {
+    /// Values of the dyn dims used by inputs
+    pub dyn_dims: Vec<(char, usize)>,
+    pub ops: Vec<FuzzOp>,
+    /// Indexes of the ops whose results are retrieved
+    pub outputs: Vec<usize>,
+}
+
+/// A graph that leaves expression storage alone when dropped, so many can be built and dropped on one thread. Only build these inside `scratch_thread`, which cleans up the storage they leave behind
+struct ScratchGraph(ManuallyDrop<Box<Graph>>);
+
+impl ScratchGraph {
+    fn new() -> Self {
+        Self(ManuallyDrop::new(Box::new(Graph::new())))
+    }
+}
+
+impl Deref for ScratchGraph {
+    type Target = Graph;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for ScratchGraph {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for ScratchGraph {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::take(&mut self.0) }.drop_nested();
+    }
+}
+
+/// Run `f` on its own thread, cleaning up the thread's expression storage before it exits. Storage left alive at thread exit aborts the process
+fn scratch_thread<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let out = catch_unwind(AssertUnwindSafe(f));
+            expression_cleanup();
+            out.unwrap_or_else(|e| resume_unwind(e))
+        })
+        .join()
+        .unwrap_or_else(|e| resume_unwind(e))
+    })
+}
+
+fn concrete(t: GraphTensor) -> Vec<usize> {
+    t.dims()
+        .into_iter()
+        .map(|d| d.exec(&t.graph().dyn_map).unwrap())
+        .collect()
+}
+
+fn shape_code(dims: &[String]) -> String {
+    if dims.len() == 1 {
+        format!("({},)", dims[0])
+    } else {
+        format!("({})", dims.join(", "))
+    }
+}
+
+impl FuzzCase {
+    fn dyn_value(&self, c: char) -> usize {
+        self.dyn_dims.iter().find(|(d, _)| *d == c).unwrap().1
+    }
+
+    fn input_dims(&self, shape: &[FuzzDim]) -> Vec<usize> {
+        shape
+            .iter()
+            .map(|d| match d {
+                FuzzDim::Static(s) => *s,
+                FuzzDim::Dyn(c) => self.dyn_value(*c),
+            })
+            .collect()
+    }
+
+    /// Add the case's ops to a graph, returning the result of every op, or None if the case isn't well-formed
+    pub fn build(&self, cx: &mut Graph) -> Option<Vec<GraphTensor>> {
+        for (c, v) in &self.dyn_dims {
+            cx.set_dyn_dim(*c, *v);
+        }
+        let mut tensors: Vec<GraphTensor> = vec![];
+        for op in &self.ops {
+            if op.operands().iter().any(|o| *o >= tensors.len()) {
+                return None;
+            }
+            let t = |i: usize| tensors[i];
+            let out = match op {
+                FuzzOp::Input { shape, seed } => {
+                    if shape.is_empty()
+                        || shape.iter().any(|d| match d {
+                            FuzzDim::Static(s) => *s == 0,
+                            FuzzDim::Dyn(c) => !self.dyn_dims.iter().any(|(d, _)| d == c),
+                        })
+                    {
+                        return None;
+                    }
+                    let dims = self.input_dims(shape);
+                    let exprs = shape
+                        .iter()
+                        .map(|d| match d {
+                            FuzzDim::Static(s) => Expression::from(*s),
+                            FuzzDim::Dyn(c) => Expression::from(*c),
+                        })
+                        .collect::<Vec<_>>();
+                    let mut rng = StdRng::seed_from_u64(*seed);
+                    let data = random_vec_rng(dims.iter().product(), &mut rng);
+                    cx.tensor(exprs).set_dyn(data, dims)
+                }
+                FuzzOp::Unary(a, u) => {
+                    let a = t(*a);
+                    match u {
+                        FuzzUnary::Neg => -a,
+                        FuzzUnary::Sin => a.sin(),
+                        FuzzUnary::Cos => a.cos(),
+                        FuzzUnary::Exp2 => a.exp2(),
+                        FuzzUnary::Sigmoid => a.sigmoid(),
+                        FuzzUnary::Relu => a.relu(),
+                        FuzzUnary::Abs => a.abs(),
+                        FuzzUnary::Sqrt => a.abs().sqrt(),
+                        FuzzUnary::Recip => (a.abs() + 1.).reciprocal(),
+                        FuzzUnary::Log2 => (a.abs() + 1.).log2(),
+                    }
+                }
+                FuzzOp::Binary(a, b, op) => {
+                    let (a, b) = (t(*a), t(*b));
+                    if a.dims() != b.dims() || concrete(a) != concrete(b) {
+                        return None;
+                    }
+                    match op {
+                        FuzzBinary::Add => a + b,
+                        FuzzBinary::Sub => a - b,
+                        FuzzBinary::Mul => a * b,
+                        FuzzBinary::Mod => a % b,
+                        FuzzBinary::Maximum => a.maximum(b),
+                        FuzzBinary::LessThan => a.lt(b),
+                    }
+                }
+                FuzzOp::Reduce(a, r, axis) => {
+                    let a = t(*a);
+                    if a.shape.len() < 2 || *axis >= a.shape.len() {
+                        return None;
+                    }
+                    match r {
+                        FuzzReduce::Sum => a.sum(*axis),
+                        FuzzReduce::Max => a.max(*axis),
+                        FuzzReduce::Mean => a.mean(*axis),
+                    }
+                }
+                FuzzOp::Permute(a, perm) => {
+                    let a = t(*a);
+                    let mut sorted = perm.clone();
+                    sorted.sort();
+                    if sorted != (0..a.shape.len()).collect::<Vec<_>>() {
+                        return None;
+                    }
+                    a.permute(perm.clone())
+                }
+                FuzzOp::Slice(a, ranges) => {
+                    let a = t(*a);
+                    let dims = concrete(a);
+                    if ranges.len() > dims.len()
+                        || ranges
+                            .iter()
+                            .zip(&dims)
+                            .any(|((s, e), d)| (*e).min(*d) <= *s)
+                    {
+                        return None;
+                    }
+                    a.slice(
+                        ranges
+                            .iter()
+                            .map(|(s, e)| (*s, (*e).min(i32::MAX as usize)))
+                            .collect::<Vec<_>>(),
+                    )
+                }
+                FuzzOp::Pad(a, padding) => {
+                    let a = t(*a);
+                    if padding.len() > a.shape.len() {
+                        return None;
+                    }
+                    a.pad(padding.clone())
+                }
+                FuzzOp::Expand(a, axis, size) => {
+                    let a = t(*a);
+                    if *axis > a.shape.len() || a.shape.len() >= 6 || *size == 0 {
+                        return None;
+                    }
+                    a.expand_dim(*axis, *size)
+                }
+                FuzzOp::Matmul(a, b) => {
+                    let (a, b) = (t(*a), t(*b));
+                    let (a_dims, b_dims) = (a.dims(), b.dims());
+                    if !(2..=3).contains(&a_dims.len())
+                        || b_dims.len() != 2
+                        || a_dims.last() != b_dims.first()
+                        || concrete(a).last() != concrete(b).first()
+                    {
+                        return None;
+                    }
+                    a.matmul(b)
+                }
+                FuzzOp::Contiguous(a) => t(*a).contiguous(),
+            };
+            tensors.push(out);
+        }
+        Some(tensors)
+    }
+
+    /// Check if the case builds into a valid graph
+    pub fn is_valid(&self) -> bool {
+        !self.outputs.is_empty()
+            && self.outputs.iter().all(|o| *o < self.ops.len())
+            && scratch_thread(|| {
+                catch_unwind(AssertUnwindSafe(|| {
+                    self.build(&mut ScratchGraph::new()).is_some()
+                }))
+                .unwrap_or(false)
+            })
+    }
+
+    /// Run the case uncompiled and compiled with a fresh compiler, returning a description of any mismatch or panic
+    pub fn check<C: Compiler>(&self, compiler: &(impl Fn() -> C + Sync)) -> Result<(), String> {
+        let run = |compile: bool| {
+            scratch_thread(|| {
+                catch_unwind(AssertUnwindSafe(|| {
+                    let mut cx = ScratchGraph::new();
+                    let tensors = self.build(&mut cx).unwrap();
+                    let mut outputs = self
+                        .outputs
+                        .iter()
+                        .map(|o| tensors[*o].retrieve())
+                        .collect::<Vec<_>>();
+                    if compile {
+                        cx.compile(compiler(), &mut outputs);
+                    }
+                    cx.execute();
+                    outputs.iter().map(|o| o.data()).collect::<Vec<_>>()
+                }))
+            })
+            .map_err(|e| {
+                e.downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_default()
+            })
+        };
+        let expected = run(false).map_err(|e| format!("Uncompiled run panicked: {e}"))?;
+        let actual = run(true).map_err(|e| format!("Compiled run panicked: {e}"))?;
+        for (i, (e, a)) in expected.iter().zip(&actual).enumerate() {
+            if e.len() != a.len() {
+                return Err(format!(
+                    "Output {i} has {} elements, expected {}",
+                    a.len(),
+                    e.len()
+                ));
+            }
+            for (j, (e, a)) in e.iter().zip(a).enumerate() {
+                let close =
+                    (e.is_nan() && a.is_nan()) || e == a || (e - a).abs() <= 1e-3 + 1e-3 * e.abs();
+                if !close {
+                    return Err(format!("Output {i} index {j} is {a}, expected {e}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate a random well-formed case with up to `max_ops` ops after the initial inputs
+    pub fn random<R: Rng + Send>(rng: &mut R, max_ops: usize) -> Self {
+        scratch_thread(|| Self::random_case(rng, max_ops))
+    }
+
+    fn random_case<R: Rng>(rng: &mut R, max_ops: usize) -> Self {
+        let mut case = FuzzCase {
+            dyn_dims: vec![],
+            ops: vec![],
+            outputs: vec![],
+        };
+        for c in ['a', 'b'] {
+            if rng.gen_bool(0.4) {
+                case.dyn_dims.push((c, rng.gen_range(1..=4)));
+            }
+        }
+        let random_shape = |rng: &mut R, case: &FuzzCase| {
+            (0..rng.gen_range(1..=3))
+                .map(|_| {
+                    if !case.dyn_dims.is_empty() && rng.gen_bool(0.3) {
+                        FuzzDim::Dyn(case.dyn_dims.choose(rng).unwrap().0)
+                    } else {
+                        FuzzDim::Static(rng.gen_range(1..=4))
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        for _ in 0..rng.gen_range(1..=2) {
+            let shape = random_shape(rng, &case);
+            case.ops.push(FuzzOp::Input {
+                shape,
+                seed: rng.gen(),
+            });
+        }
+
+        let n_ops = rng.gen_range(1..=max_ops.max(1));
+        while case.ops.len() < n_ops + 1 {
+            let mut cx = ScratchGraph::new();
+            let tensors = case.build(&mut cx).unwrap();
+            let a = rng.gen_range(0..tensors.len());
+            let (a_tensor, dims) = (tensors[a], concrete(tensors[a]));
+            let rank = dims.len();
+            // An input with the same symbolic shape, if it can be made from input dims
+            let input_like = |t: GraphTensor, rng: &mut R| {
+                t.dims()
+                    .into_iter()
+                    .map(|d| {
+                        if let Some(n) = d.as_num() {
+                            Some(FuzzDim::Static(n as usize))
+                        } else {
+                            let symbols = d.to_symbols();
+                            (symbols.len() == 1 && d == Expression::from(symbols[0]))
+                                .then(|| FuzzDim::Dyn(symbols[0]))
+                        }
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .map(|shape| FuzzOp::Input {
+                        shape,
+                        seed: rng.gen(),
+                    })
+            };
+            let op = match rng.gen_range(0..9) {
+                0 => {
+                    let kinds = [
+                        FuzzUnary::Neg,
+                        FuzzUnary::Sin,
+                        FuzzUnary::Cos,
+                        FuzzUnary::Exp2,
+                        FuzzUnary::Sigmoid,
+                        FuzzUnary::Relu,
+                        FuzzUnary::Abs,
+                        FuzzUnary::Sqrt,
+                        FuzzUnary::Recip,
+                        FuzzUnary::Log2,
+                    ];
+                    FuzzOp::Unary(a, *kinds.choose(rng).unwrap())
+                }
+                1 | 2 => {
+                    let kinds = [
+                        FuzzBinary::Add,
+                        FuzzBinary::Sub,
+                        FuzzBinary::Mul,
+                        FuzzBinary::Mod,
+                        FuzzBinary::Maximum,
+                        FuzzBinary::LessThan,
+                    ];
+                    let matching = (0..tensors.len())
+                        .filter(|b| tensors[*b].dims() == a_tensor.dims())
+                        .collect::<Vec<_>>();
+                    let b = if matching.len() > 1 && rng.gen_bool(0.7) {
+                        *matching.choose(rng).unwrap()
+                    } else if let Some(input) = input_like(a_tensor, rng) {
+                        case.ops.push(input);
+                        case.ops.len() - 1
+                    } else {
+                        continue;
+                    };
+                    FuzzOp::Binary(a, b, *kinds.choose(rng).unwrap())
+                }
+                3 if rank >= 2 => {
+                    let kinds = [FuzzReduce::Sum, FuzzReduce::Max, FuzzReduce::Mean];
+                    FuzzOp::Reduce(a, *kinds.choose(rng).unwrap(), rng.gen_range(0..rank))
+                }
+                4 if rank >= 2 => {
+                    let mut perm = (0..rank).collect::<Vec<_>>();
+                    perm.shuffle(rng);
+                    FuzzOp::Permute(a, perm)
+                }
+                5 => FuzzOp::Slice(
+                    a,
+                    dims.iter()
+                        .map(|d| {
+                            let start = rng.gen_range(0..*d);
+                            let end = if rng.gen_bool(0.5) {
+                                i32::MAX as usize
+                            } else {
+                                rng.gen_range(start + 1..=*d)
+                            };
+                            (start, end)
+                        })
+                        .collect(),
+                ),
+                6 => FuzzOp::Pad(
+                    a,
+                    (0..rank)
+                        .map(|_| (rng.gen_range(0..=2), rng.gen_range(0..=2)))
+                        .collect(),
+                ),
+                7 if rank < MAX_RANK => {
+                    FuzzOp::Expand(a, rng.gen_range(0..=rank), rng.gen_range(1..=3))
+                }
+                8 if (2..=3).contains(&rank) => {
+                    let k = a_tensor.dims()[rank - 1];
+                    let k = match (k.as_num(), k.to_symbols().as_slice()) {
+                        (Some(n), _) => FuzzDim::Static(n as usize),
+                        (None, [c]) if k == Expression::from(*c) => FuzzDim::Dyn(*c),
+                        _ => continue,
+                    };
+                    case.ops.push(FuzzOp::Input {
+                        shape: vec![k, FuzzDim::Static(rng.gen_range(1..=4))],
+                        seed: rng.gen(),
+                    });
+                    FuzzOp::Matmul(a, case.ops.len() - 1)
+                }
+                _ => FuzzOp::Contiguous(a),
+            };
+            case.ops.push(op);
+            case.outputs = vec![case.ops.len() - 1];
+            if !case.is_valid() {
+                case.ops.pop();
+            }
+        }
+        // Retrieve the last result along with a few random intermediates
+        case.outputs = vec![case.ops.len() - 1];
+        for _ in 0..rng.gen_range(0..=2) {
+            let o = rng.gen_range(0..case.ops.len());
+            if !case.outputs.contains(&o) {
+                case.outputs.push(o);
+            }
+        }
+        case
+    }
+
+    /// Remove ops that no output depends on
+    fn remove_unused(mut self) -> Self {
+        let mut used = vec![false; self.ops.len()];
+        for o in &self.outputs {
+            used[*o] = true;
+        }
+        for i in (0..self.ops.len()).rev() {
+            if used[i] {
+                for o in self.ops[i].operands() {
+                    used[o] = true;
+                }
+            }
+        }
+        let mut remap = FxHashMap::default();
+        let mut ops = vec![];
+        for (i, mut op) in self.ops.into_iter().enumerate() {
+            if used[i] {
+                for o in op.operands_mut() {
+                    *o = remap[o];
+                }
+                remap.insert(i, ops.len());
+                ops.push(op);
+            }
+        }
+        self.ops = ops;
+        for o in &mut self.outputs {
+            *o = remap[o];
+        }
+        self
+    }
+
+    /// Smaller variations of the case, simplest first
+    fn shrink_candidates(&self) -> Vec<Self> {
+        let mut candidates = vec![];
+        // Drop outputs
+        if self.outputs.len() > 1 {
+            for i in 0..self.outputs.len() {
+                let mut c = self.clone();
+                c.outputs.remove(i);
+                candidates.push(c.remove_unused());
+            }
+        }
+        // Bypass ops by using one of their operands in their place
+        for (i, op) in self.ops.iter().enumerate() {
+            for operand in op.operands() {
+                let mut c = self.clone();
+                for later in &mut c.ops[i + 1..] {
+                    for o in later.operands_mut() {
+                        if *o == i {
+                            *o = operand;
+                        }
+                    }
+                }
+                for o in &mut c.outputs {
+                    if *o == i {
+                        *o = operand;
+                    }
+                }
+                c.outputs.dedup();
+                candidates.push(c.remove_unused());
+            }
+        }
+        // Shrink input dims and dyn dim values
+        for (i, op) in self.ops.iter().enumerate() {
+            if let FuzzOp::Input { shape, .. } = op {
+                for (j, d) in shape.iter().enumerate() {
+                    if let FuzzDim::Static(s) = d {
+                        if *s > 1 {
+                            let mut c = self.clone();
+                            if let FuzzOp::Input { shape, .. } = &mut c.ops[i] {
+                                shape[j] = FuzzDim::Static(s - 1);
+                            }
+                            candidates.push(c);
+                        }
+                    }
+                }
+            }
+        }
+        for i in 0..self.dyn_dims.len() {
+            if self.dyn_dims[i].1 > 1 {
+                let mut c = self.clone();
+                c.dyn_dims[i].1 -= 1;
+                candidates.push(c);
+            }
+        }
+        candidates
+    }
+
+    /// Repeatedly simplify a failing case while it keeps failing
+    pub fn shrink<C: Compiler>(mut self, compiler: &(impl Fn() -> C + Sync)) -> Self {
+        'outer: loop {
+            for candidate in self.shrink_candidates() {
+                if candidate != self && candidate.is_valid() && candidate.check(compiler).is_err() {
+                    self = candidate;
+                    continue 'outer;
+                }
+            }
+            return self;
+        }
+    }
+
+    /// Write the case as a Rust test comparing uncompiled and compiled runs, where `compiler` is an expression constructing the compiler
+    pub fn to_rust(&self, compiler: &str) -> String {
+        let mut code = String::from(
+            "#[test]\nfn fuzz_reproducer() {\n    use luminal::{prelude::*, tests::{assert_close, random_vec_rng}};\n    use rand::{rngs::StdRng, SeedableRng};\n\n    let build = |cx: &mut Graph| {\n",
+        );
+        for (i, op) in self.ops.iter().enumerate() {
+            let expr = match op {
+                FuzzOp::Input { shape, seed } => {
+                    let dims = self.input_dims(shape);
+                    let symbolic = shape
+                        .iter()
+                        .map(|d| match d {
+                            FuzzDim::Static(s) => s.to_string(),
+                            FuzzDim::Dyn(c) => format!("'{c}'"),
+                        })
+                        .collect::<Vec<_>>();
+                    let concrete = dims.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+                    format!(
+                        "cx.tensor({}).set_dyn(random_vec_rng({}, &mut StdRng::seed_from_u64({seed})), {})",
+                        shape_code(&symbolic),
+                        dims.iter().product::<usize>(),
+                        shape_code(&concrete)
+                    )
+                }
+                FuzzOp::Unary(a, u) => match u {
+                    FuzzUnary::Neg => format!("-t{a}"),
+                    FuzzUnary::Sin => format!("t{a}.sin()"),
+                    FuzzUnary::Cos => format!("t{a}.cos()"),
+                    FuzzUnary::Exp2 => format!("t{a}.exp2()"),
+                    FuzzUnary::Sigmoid => format!("t{a}.sigmoid()"),
+                    FuzzUnary::Relu => format!("t{a}.relu()"),
+                    FuzzUnary::Abs => format!("t{a}.abs()"),
+                    FuzzUnary::Sqrt => format!("t{a}.abs().sqrt()"),
+                    FuzzUnary::Recip => format!("(t{a}.abs() + 1.).reciprocal()"),
+                    FuzzUnary::Log2 => format!("(t{a}.abs() + 1.).log2()"),
+                },
+                FuzzOp::Binary(a, b, op) => match op {
+                    FuzzBinary::Add => format!("t{a} + t{b}"),
+                    FuzzBinary::Sub => format!("t{a} - t{b}"),
+                    FuzzBinary::Mul => format!("t{a} * t{b}"),
+                    FuzzBinary::Mod => format!("t{a} % t{b}"),
+                    FuzzBinary::Maximum => format!("t{a}.maximum(t{b})"),
+                    FuzzBinary::LessThan => format!("t{a}.lt(t{b})"),
+                },
+                FuzzOp::Reduce(a, r, axis) => match r {
+                    FuzzReduce::Sum => format!("t{a}.sum({axis})"),
+                    FuzzReduce::Max => format!("t{a}.max({axis})"),
+                    FuzzReduce::Mean => format!("t{a}.mean({axis})"),
+                },
+                FuzzOp::Permute(a, perm) => format!("t{a}.permute(vec!{perm:?})"),
+                FuzzOp::Slice(a, ranges) => format!(
+                    "t{a}.slice(vec![{}])",
+                    ranges
+                        .iter()
+                        .map(|(s, e)| if *e >= i32::MAX as usize {
+                            format!("({s}, i32::MAX)")
+                        } else {
+                            format!("({s}, {e})")
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                FuzzOp::Pad(a, padding) => format!(
+                    "t{a}.pad(vec![{}])",
+                    padding
+                        .iter()
+                        .map(|(s, e)| format!("({s}, {e})"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                FuzzOp::Expand(a, axis, size) => format!("t{a}.expand_dim({axis}, {size})"),
+                FuzzOp::Matmul(a, b) => format!("t{a}.matmul(t{b})"),
+                FuzzOp::Contiguous(a) => format!("t{a}.contiguous()"),
+            };
+            code.push_str(&format!("        let t{i} = {expr};\n"));
+        }
+        code.push_str(&format!(
+            "        vec![{}]\n    }};\n    let mut cx = Graph::new();\n    let outputs = build(&mut cx);\n    cx.execute();\n    let expected = outputs.iter().map(|o| o.data()).collect::<Vec<_>>();\n\n    let mut compiled = Graph::new();\n    let mut outputs = build(&mut compiled);\n    compiled.compile({compiler}, &mut outputs);\n    compiled.execute();\n    for (output, expected) in outputs.iter().zip(&expected) {{\n        assert_close(&output.data(), expected);\n    }}\n}}\n",
+            self.outputs
+                .iter()
+                .map(|o| format!("t{o}.retrieve()"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+        code
+    }
+}
+
+/// Check a compiler against `cases` random graphs, panicking with a minimal reproducer written as a Rust test if any compiled graph gives different results than the uncompiled one.
+///
+/// `compiler_code` is the Rust expression building the compiler, used in the reproducer (like `"CPUCompiler::default()"`)
+pub fn fuzz_compiler<C: Compiler>(
+    compiler: impl Fn() -> C + Sync,
+    compiler_code: &str,
+    cases: usize,
+    seed: u64,
+) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for i in 0..cases {
+        let case = FuzzCase::random(&mut rng, 8);
+        if let Err(error) = case.check(&compiler) {
+            let shrunk = case.shrink(&compiler);
+            let error = shrunk.check(&compiler).err().unwrap_or(error);
+            panic!(
+                "Fuzz case {i} (seed {seed}) failed: {error}\nMinimal reproducer:\n\n{}",
+                shrunk.to_rust(compiler_code)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use petgraph::{visit::EdgeRef, Direction};
+
+    use super::*;
+    use crate::op::LessThan;
+
+    #[test]
+    fn test_fuzz_generic_compiler() {
+        fuzz_compiler(
+            GenericCompiler::default,
+            "GenericCompiler::default()",
+            100,
+            0,
+        );
+    }
+
+    /// A compiler with a bug: it swaps the inputs of every LessThan
+    #[derive(Default)]
+    struct SwapLessThan;
+
+    impl Compiler for SwapLessThan {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+            for node in graph.graph.node_indices().collect::<Vec<_>>() {
+                if !graph
+                    .graph
+                    .node_weight(node)
+                    .unwrap()
+                    .as_any()
+                    .is::<LessThan>()
+                {
+                    continue;
+                }
+                let edges = graph
+                    .graph
+                    .edges_directed(node, Direction::Incoming)
+                    .map(|e| e.id())
+                    .collect::<Vec<_>>();
+                for edge in edges {
+                    if let Dependency::Data { input_order, .. } =
+                        graph.graph.edge_weight_mut(edge).unwrap()
+                    {
+                        *input_order = 1 - *input_order;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let case = (0..1000)
+            .map(|_| FuzzCase::random(&mut rng, 8))
+            .find(|c| c.check(&SwapLessThan::default).is_err())
+            .unwrap();
+        let shrunk = case.clone().shrink(&SwapLessThan::default);
+        assert!(shrunk.check(&SwapLessThan::default).is_err());
+        assert!(shrunk.ops.len() <= case.ops.len());
+        assert_eq!(shrunk.outputs.len(), 1);
+        let code = shrunk.to_rust("SwapLessThan");
+        // An input plus at most a couple of ops built on LessThan should remain
+        assert!(shrunk.ops.len() <= 3, "{code}");
+        assert!(code.contains("compiled.compile(SwapLessThan, &mut outputs);"));
+    }
+}
@@ -1,5 +1,6 @@
 #[cfg(test)]
 mod dynamic;
+pub mod fuzz;
 pub mod test_graphs;
 #[cfg(test)]
 mod test_prim;
@@ -0,0 +1,233 @@
+// Checking a graph for problems before running it
+
+use itertools::Itertools;
+use petgraph::Direction;
+use rustc_hash::FxHashSet;
+
+use crate::{
+    op::{
+        Add, ArgSort, Constant, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce, Mod, Mul,
+        Operator, Recip, Select, Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+/// The number of inputs a primitive op expects, if known
+fn expected_inputs(op: &dyn Operator) -> Option<usize> {
+    macro_rules! is_any {
+        ($($t:ty),*) => {
+            false $(|| op.as_any().is::<$t>())*
+        };
+    }
+    if is_any!(Constant) {
+        Some(0)
+    } else if is_any!(Contiguous, Log2, Exp2, Sin, Recip, Sqrt, SumReduce, MaxReduce, ArgSort) {
+        Some(1)
+    } else if is_any!(Add, Mul, Mod, LessThan) {
+        Some(2)
+    } else if is_any!(Select) {
+        Some(3)
+    } else {
+        None
+    }
+}
+
+impl Graph {
+    /// Check the graph for problems that would cause execution to fail, returning all problems found.
+    ///
+    /// This checks that every source tensor has data or a loader, all dyn dims used are set, primitive ops have the right number of inputs, and elementwise op inputs have matching shapes.
+    pub fn validate(&self) -> Result<(), Vec<LuminalError>> {
+        let mut errors = vec![];
+        let mut missing_dims = FxHashSet::default();
+        for node in self.graph.node_indices().sorted() {
+            let op = self.graph.node_weight(node).unwrap();
+            let srcs = self.get_sources(node);
+
+            if srcs.is_empty() && self.is_unset_input(node) {
+                errors.push(LuminalError::UnsetInput {
+                    node,
+                    name: self.tensor_name(node),
+                });
+            }
+
+            for (src, _, sh) in &srcs {
+                for dim in sh.dyn_dims() {
+                    if !self.dyn_map.contains_key(&dim) && missing_dims.insert(dim) {
+                        errors.push(LuminalError::UnresolvedDynDim { node: *src, dim });
+                    }
+                }
+            }
+
+            if let Some(expected) = expected_inputs(op.as_ref()) {
+                if srcs.len() != expected {
+                    errors.push(LuminalError::InputCount {
+                        node,
+                        op: format!("{op:?}"),
+                        expected,
+                        found: srcs.len(),
+                    });
+                } else if srcs
+                    .iter()
+                    .skip(1)
+                    .any(|(_, _, sh)| !shapes_match(&srcs[0].2, sh))
+                {
+                    errors.push(LuminalError::ShapeMismatch {
+                        node,
+                        op: format!("{op:?}"),
+                        inputs: srcs
+                            .iter()
+                            .map(|(src, _, sh)| (self.tensor_name(*src), sh.dims()))
+                            .collect(),
+                    });
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// A readable name for a node: the tensor name for named source tensors, otherwise the op
+    fn tensor_name(&self, node: NodeIndex) -> String {
+        let op = self.graph.node_weight(node).unwrap();
+        if let Some(Function(name, _)) = op.as_any().downcast_ref::<Function>() {
+            if self
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .all(|e| e.weight().is_schedule())
+            {
+                return name.strip_suffix(" Load").unwrap_or(name).to_string();
+            }
+        }
+        format!("{op:?}")
+    }
+}
+
+/// Check if two (expanded) shapes agree. Symbolic dims that can't be shown to differ are assumed to match
+fn shapes_match(a: &ShapeTracker, b: &ShapeTracker) -> bool {
+    let (a, b) = (a.dims(), b.dims());
+    a.len() == b.len()
+        && a.into_iter().zip(b).all(|(a, b)| {
+            let (a, b) = (a.simplify(), b.simplify());
+            match (a.to_usize(), b.to_usize()) {
+                (Some(a), Some(b)) => a == b,
+                (None, None) => a == b || a.equivalent(&b),
+                _ => false,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use crate::op::{Add, Exp2, Select};
+
+    #[test]
+    fn test_valid_graph() {
+        let mut cx = Graph::new();
+        let a = cx
+            .named_tensor("A", ('s', 3))
+            .set_dyn(random_vec(6), (2, 3));
+        let b = cx.tensor(3);
+        b.set(random_vec(3));
+        (a.exp2() + b.expand_dim(0, 's')).sum(1).retrieve();
+        assert_eq!(cx.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("Input", ('s', 3));
+        let w = cx.named_tensor("Weight", 3).set(random_vec(3));
+        (a * w.expand_dim(0, 's')).retrieve();
+        // Mismatched shapes
+        let x = cx.named_tensor("X", (2, 3)).set(random_vec(6));
+        let y = cx.named_tensor("Y", (3, 2)).set(random_vec(6));
+        let bad_add = cx
+            .add_op(Add)
+            .input(x.id, 0, x.shape)
+            .input(y.id, 0, y.shape)
+            .finish();
+        // Wrong number of inputs
+        let bad_exp = cx
+            .add_op(Exp2)
+            .input(x.id, 0, x.shape)
+            .input(y.id, 0, y.shape)
+            .finish();
+
+        let errors = cx.validate().unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert!(errors.contains(&LuminalError::UnsetInput {
+            node: a.id,
+            name: "Input".to_string()
+        }));
+        assert!(errors.contains(&LuminalError::UnresolvedDynDim {
+            node: a.id,
+            dim: 's'
+        }));
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            LuminalError::ShapeMismatch { node, inputs, .. }
+                if *node == bad_add && inputs[0].0 == "X" && inputs[1].0 == "Y"
+        )));
+        assert!(errors.contains(&LuminalError::InputCount {
+            node: bad_exp,
+            op: "Exp2".to_string(),
+            expected: 1,
+            found: 2
+        }));
+
+        // Setting the input fixes the first two errors
+        a.set_dyn(random_vec(6), (2, 3));
+        assert_eq!(cx.validate().unwrap_err().len(), 2);
+    }
+
+    #[test]
+    fn test_select_shapes() {
+        let mut cx = Graph::new();
+        let c = cx.named_tensor("Cond", (2, 3)).set(random_vec(6));
+        let x = cx.named_tensor("X", (2, 3)).set(random_vec(6));
+        let y = cx.named_tensor("Y", (3, 2)).set(random_vec(6));
+        c.select(x, y.permute((1, 0))).retrieve();
+        assert_eq!(cx.validate(), Ok(()));
+
+        let bad_select = cx
+            .add_op(Select)
+            .input(c.id, 0, c.shape)
+            .input(x.id, 0, x.shape)
+            .input(y.id, 0, y.shape)
+            .finish();
+        let errors = cx.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            &errors[0],
+            LuminalError::ShapeMismatch { node, inputs, .. }
+                if *node == bad_select && inputs.len() == 3 && inputs[2].0 == "Y"
+        ));
+    }
+
+    #[test]
+    fn test_input_states() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3);
+        let b = (a * 2.).retrieve();
+        assert!(cx.is_unset_input(a.id));
+
+        // Data set directly only lasts until it's consumed
+        cx.set_tensor(a.id, 0, crate::op::Tensor::new(vec![1_f32, 2., 3.]));
+        assert!(!cx.is_unset_input(a.id));
+        cx.execute();
+        assert_exact(&b.data(), &[2., 4., 6.]);
+        assert!(cx.is_unset_input(a.id));
+
+        // Loaders provide data every execution
+        cx.set_loader(a.id, |_| vec![crate::op::Tensor::new(vec![0_f32; 3])]);
+        assert!(!cx.is_unset_input(a.id));
+        cx.execute();
+        assert!(!cx.is_unset_input(a.id));
+        assert_eq!(cx.validate(), Ok(()));
+    }
+}
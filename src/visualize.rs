@@ -0,0 +1,728 @@
+// Offline graph visualization as DOT, SVG or HTML
+
+use std::{
+    io::{Error, ErrorKind, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use itertools::Itertools;
+use petgraph::{
+    algo::toposort,
+    stable_graph::{EdgeIndex, StableGraph},
+    visit::{EdgeRef, IntoEdgeReferences},
+    Direction,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    op::{ArgSort, Cast, Select},
+    prelude::*,
+};
+
+/// How to lay out a graph when rendering to SVG or HTML
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Use an installed `dot` binary if there is one, otherwise the bundled layout
+    #[default]
+    Auto,
+    /// Always use an installed `dot` binary, erroring if it isn't found
+    Dot,
+    /// Always use the bundled layered layout
+    Bundled,
+}
+
+/// What to include when visualizing a graph
+#[derive(Debug, Clone)]
+pub struct VisualizeOptions {
+    /// Label data edges with the shapes flowing along them
+    pub shapes: bool,
+    /// Label nodes with their inferred output dtype
+    pub dtypes: bool,
+    /// Nodes to highlight
+    pub mark: Vec<NodeIndex>,
+    /// Modules to collapse into a single node, with the weights belonging to each
+    pub collapse: Vec<(String, Vec<NodeIndex>)>,
+    /// How to lay out SVG and HTML output
+    pub layout: Layout,
+}
+
+impl Default for VisualizeOptions {
+    fn default() -> Self {
+        Self {
+            shapes: true,
+            dtypes: true,
+            mark: vec![],
+            collapse: vec![],
+            layout: Layout::Auto,
+        }
+    }
+}
+
+impl VisualizeOptions {
+    /// Collapse the modules of `model` at each of the given paths (such as "layers/0/attention") into single nodes.
+    ///
+    /// A module covers its weights and every node between them and the last node consuming one of them.
+    pub fn collapse_modules(mut self, model: impl SerializeModule, prefixes: &[&str]) -> Self {
+        let params = param_dict(model);
+        for prefix in prefixes {
+            let weights = params
+                .iter()
+                .filter(|(name, _)| {
+                    name.as_str() == *prefix || name.starts_with(&format!("{prefix}/"))
+                })
+                .map(|(_, id)| *id)
+                .sorted()
+                .collect();
+            self.collapse.push((prefix.to_string(), weights));
+        }
+        self
+    }
+}
+
+/// A node ready for rendering
+struct VizNode {
+    lines: Vec<String>,
+    marked: bool,
+    module: bool,
+}
+
+/// An edge ready for rendering
+struct VizEdge {
+    from: usize,
+    to: usize,
+    label: Option<String>,
+    schedule: bool,
+}
+
+/// The graph after collapsing modules and building labels
+struct VizGraph {
+    nodes: Vec<VizNode>,
+    edges: Vec<VizEdge>,
+}
+
+/// Infer the dtype each node outputs from set tensors, casts and inputs
+fn infer_dtypes(graph: &Graph) -> FxHashMap<NodeIndex, DType> {
+    let mut dtypes = FxHashMap::default();
+    let order =
+        toposort(&graph.graph, None).unwrap_or_else(|_| graph.graph.node_indices().collect());
+    for node in order {
+        let op = graph.graph.node_weight(node).unwrap();
+        let dtype = if let Some(dtype) = graph.tensors.get(&(node, 0)).and_then(|t| t.dtype()) {
+            Some(dtype)
+        } else if let Some(Cast(dtype)) = op.as_any().downcast_ref::<Cast>() {
+            Some(*dtype)
+        } else if op.as_any().is::<ArgSort>() {
+            Some(DType::F32)
+        } else if op.as_any().is::<Select>() {
+            // The condition can have any type, the values decide the output type
+            graph
+                .get_sources(node)
+                .get(1)
+                .and_then(|(src, _, _)| dtypes.get(src).copied())
+        } else if graph.get_sources(node).is_empty() {
+            // Constants and inputs whose data isn't set yet default to f32, like GraphTensor
+            Some(DType::F32)
+        } else {
+            graph
+                .get_sources(node)
+                .first()
+                .and_then(|(src, _, _)| dtypes.get(src).copied())
+        };
+        if let Some(dtype) = dtype {
+            dtypes.insert(node, dtype);
+        }
+    }
+    dtypes
+}
+
+/// Find the nodes making up each collapsed module. Nodes in several modules go to the first one listed
+fn module_members(graph: &Graph, options: &VisualizeOptions) -> FxHashMap<NodeIndex, usize> {
+    let mut members = FxHashMap::default();
+    for (i, (_, weights)) in options.collapse.iter().enumerate() {
+        let weights = weights
+            .iter()
+            .filter(|w| graph.graph.contains_node(**w))
+            .copied()
+            .collect::<FxHashSet<_>>();
+        // Everything downstream of the weights
+        let mut downstream = weights.clone();
+        let mut stack = weights.iter().copied().collect::<Vec<_>>();
+        while let Some(node) = stack.pop() {
+            for dest in graph.graph.neighbors_directed(node, Direction::Outgoing) {
+                if downstream.insert(dest) {
+                    stack.push(dest);
+                }
+            }
+        }
+        // Everything upstream of a node consuming a weight
+        let mut upstream = weights
+            .iter()
+            .flat_map(|w| graph.graph.neighbors_directed(*w, Direction::Outgoing))
+            .collect::<FxHashSet<_>>();
+        let mut stack = upstream.iter().copied().collect::<Vec<_>>();
+        while let Some(node) = stack.pop() {
+            for src in graph.graph.neighbors_directed(node, Direction::Incoming) {
+                if upstream.insert(src) {
+                    stack.push(src);
+                }
+            }
+        }
+        for node in weights
+            .iter()
+            .copied()
+            .chain(downstream.intersection(&upstream).copied())
+        {
+            members.entry(node).or_insert(i);
+        }
+    }
+    members
+}
+
+/// Escape a label for a double-quoted DOT string
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Escape text for SVG / HTML
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl VizGraph {
+    fn new(graph: &Graph, options: &VisualizeOptions) -> Self {
+        let dtypes = if options.dtypes {
+            infer_dtypes(graph)
+        } else {
+            FxHashMap::default()
+        };
+        let members = module_members(graph, options);
+        let mut viz = VizGraph {
+            nodes: vec![],
+            edges: vec![],
+        };
+        // One node per collapsed module, then one per remaining op
+        let mut module_nodes = FxHashMap::default();
+        let mut index = FxHashMap::default();
+        for node in graph.graph.node_indices().sorted() {
+            if let Some(module) = members.get(&node) {
+                let i = *module_nodes.entry(*module).or_insert_with(|| {
+                    viz.nodes.push(VizNode {
+                        lines: vec![options.collapse[*module].0.clone()],
+                        marked: false,
+                        module: true,
+                    });
+                    viz.nodes.len() - 1
+                });
+                viz.nodes[i].marked |= options.mark.contains(&node);
+                index.insert(node, i);
+                continue;
+            }
+            let mut lines = vec![format!(
+                "{:?} | {}",
+                graph.graph.node_weight(node).unwrap(),
+                node.index()
+            )];
+            if let Some(dtype) = dtypes.get(&node) {
+                lines.push(format!("{dtype:?}"));
+            }
+            if options.shapes {
+                if let Some((_, shape)) = graph.to_retrieve.get(&node) {
+                    lines.push(format!("out {:?}", shape.dims()));
+                }
+            }
+            viz.nodes.push(VizNode {
+                lines,
+                marked: options.mark.contains(&node),
+                module: false,
+            });
+            index.insert(node, viz.nodes.len() - 1);
+        }
+        for (module, i) in module_nodes {
+            let count = members.values().filter(|m| **m == module).count();
+            viz.nodes[i].lines.push(format!("{count} nodes"));
+        }
+        // Edges between distinct nodes, merging duplicates between modules
+        let mut seen = FxHashSet::default();
+        for edge in graph
+            .graph
+            .edge_references()
+            .sorted_by_key(|e| (e.target(), e.weight().as_data().map(|d| d.0)))
+        {
+            let (from, to) = (index[&edge.source()], index[&edge.target()]);
+            let label = edge
+                .weight()
+                .as_data()
+                .filter(|_| options.shapes)
+                .map(|(_, _, shape)| format!("{:?}", shape.dims()));
+            if from == to || !seen.insert((from, to, label.clone())) {
+                continue;
+            }
+            viz.edges.push(VizEdge {
+                from,
+                to,
+                label,
+                schedule: edge.weight().is_schedule(),
+            });
+        }
+        viz
+    }
+
+    fn to_dot(&self) -> String {
+        let mut dot = String::from(
+            "digraph {\n    node [shape=box fontname=\"monospace\" fontsize=10]\n    edge [fontname=\"monospace\" fontsize=9]\n",
+        );
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut attrs = format!("label=\"{}\"", escape_dot(&node.lines.join("\n")));
+            if node.module {
+                attrs.push_str(" shape=box3d style=\"filled\" fillcolor=\"lightblue\"");
+            }
+            if node.marked {
+                attrs.push_str(" style=\"filled\" fillcolor=\"yellow\"");
+            }
+            dot.push_str(&format!("    {i} [ {attrs} ]\n"));
+        }
+        for edge in &self.edges {
+            let mut attrs = vec![];
+            if let Some(label) = &edge.label {
+                attrs.push(format!("label=\"{}\"", escape_dot(label)));
+            }
+            if edge.schedule {
+                attrs.push("color=\"green\" style=\"dashed\"".to_string());
+            }
+            dot.push_str(&format!(
+                "    {} -> {} [ {} ]\n",
+                edge.from,
+                edge.to,
+                attrs.join(" ")
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Lay out nodes in ranks by longest path from a source, ordering each rank by the mean position of its neighbours
+    fn to_svg(&self) -> String {
+        const CHAR_WIDTH: f64 = 7.;
+        const LINE_HEIGHT: f64 = 14.;
+        const PADDING: f64 = 8.;
+        const NODE_GAP: f64 = 24.;
+        const RANK_GAP: f64 = 48.;
+
+        let n = self.nodes.len();
+        let (mut preds, mut succs) = (vec![vec![]; n], vec![vec![]; n]);
+        for e in &self.edges {
+            preds[e.to].push(e.from);
+            succs[e.from].push(e.to);
+        }
+        // Rank by longest path, relaxing edges until nothing changes (bounded in case of cycles)
+        let mut rank = vec![0; n];
+        for _ in 0..n {
+            let mut changed = false;
+            for e in &self.edges {
+                if rank[e.to] < rank[e.from] + 1 {
+                    rank[e.to] = rank[e.from] + 1;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        let n_ranks = rank.iter().max().map(|r| r + 1).unwrap_or_default();
+        let mut ranks = vec![vec![]; n_ranks];
+        for (i, r) in rank.iter().enumerate() {
+            ranks[*r].push(i);
+        }
+        // Barycenter ordering sweeps, down then up
+        let mut position = vec![0.; n];
+        for rank in &ranks {
+            for (p, i) in rank.iter().enumerate() {
+                position[*i] = p as f64;
+            }
+        }
+        for sweep in 0..8 {
+            let neighbours = if sweep % 2 == 0 { &preds } else { &succs };
+            let order: Box<dyn Iterator<Item = &mut Vec<usize>>> = if sweep % 2 == 0 {
+                Box::new(ranks.iter_mut())
+            } else {
+                Box::new(ranks.iter_mut().rev())
+            };
+            for rank in order {
+                let key = |i: &usize| {
+                    if neighbours[*i].is_empty() {
+                        position[*i]
+                    } else {
+                        neighbours[*i].iter().map(|j| position[*j]).sum::<f64>()
+                            / neighbours[*i].len() as f64
+                    }
+                };
+                let keys = rank.iter().map(key).collect::<Vec<_>>();
+                let mut sorted = rank.iter().copied().zip(keys).collect::<Vec<_>>();
+                sorted.sort_by(|a, b| a.1.total_cmp(&b.1));
+                *rank = sorted.into_iter().map(|(i, _)| i).collect();
+                for (p, i) in rank.iter().enumerate() {
+                    position[*i] = p as f64;
+                }
+            }
+        }
+        // Sizes and coordinates
+        let size = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let chars = node
+                    .lines
+                    .iter()
+                    .map(|l| l.chars().count())
+                    .max()
+                    .unwrap_or_default();
+                (
+                    chars as f64 * CHAR_WIDTH + 2. * PADDING,
+                    node.lines.len() as f64 * LINE_HEIGHT + PADDING,
+                )
+            })
+            .collect::<Vec<_>>();
+        let rank_widths = ranks
+            .iter()
+            .map(|r| {
+                r.iter().map(|i| size[*i].0).sum::<f64>()
+                    + NODE_GAP * r.len().saturating_sub(1) as f64
+            })
+            .collect::<Vec<_>>();
+        let width = rank_widths.iter().copied().fold(0., f64::max) + 2. * NODE_GAP;
+        let mut xy = vec![(0., 0.); n];
+        let mut y = NODE_GAP;
+        for (rank, rank_width) in ranks.iter().zip(&rank_widths) {
+            let mut x = (width - rank_width) / 2.;
+            for i in rank {
+                xy[*i] = (x, y);
+                x += size[*i].0 + NODE_GAP;
+            }
+            y += rank.iter().map(|i| size[*i].1).fold(0., f64::max) + RANK_GAP;
+        }
+        let height = y - RANK_GAP + NODE_GAP;
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width:.0}\" height=\"{height:.0}\" viewBox=\"0 0 {width:.0} {height:.0}\" font-family=\"monospace\" font-size=\"11\">\n<defs><marker id=\"arrow\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" markerWidth=\"6\" markerHeight=\"6\" orient=\"auto\"><path d=\"M0,0 L10,5 L0,10 z\"/></marker></defs>\n"
+        );
+        for edge in &self.edges {
+            let (from, to) = (edge.from, edge.to);
+            let (x1, y1) = (xy[from].0 + size[from].0 / 2., xy[from].1 + size[from].1);
+            let (x2, y2) = (xy[to].0 + size[to].0 / 2., xy[to].1);
+            let bend = ((y2 - y1) / 2.).max(RANK_GAP / 2.);
+            let style = if edge.schedule {
+                "stroke=\"green\" stroke-dasharray=\"4 3\""
+            } else {
+                "stroke=\"black\""
+            };
+            svg.push_str(&format!(
+                "<path d=\"M{x1:.1},{y1:.1} C{x1:.1},{:.1} {x2:.1},{:.1} {x2:.1},{y2:.1}\" fill=\"none\" {style} marker-end=\"url(#arrow)\"/>\n",
+                y1 + bend,
+                y2 - bend
+            ));
+            if let Some(label) = &edge.label {
+                svg.push_str(&format!(
+                    "<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"9\" fill=\"dimgray\">{}</text>\n",
+                    (x1 + x2) / 2. + 4.,
+                    (y1 + y2) / 2.,
+                    escape_xml(label)
+                ));
+            }
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            let fill = if node.marked {
+                "yellow"
+            } else if node.module {
+                "lightblue"
+            } else {
+                "white"
+            };
+            svg.push_str(&format!(
+                "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" rx=\"4\" fill=\"{fill}\" stroke=\"black\"/>\n",
+                xy[i].0, xy[i].1, size[i].0, size[i].1
+            ));
+            for (l, line) in node.lines.iter().enumerate() {
+                svg.push_str(&format!(
+                    "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\n",
+                    xy[i].0 + size[i].0 / 2.,
+                    xy[i].1 + PADDING / 2. + (l as f64 + 0.8) * LINE_HEIGHT,
+                    escape_xml(line)
+                ));
+            }
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Build from a petgraph debug graph, with schedule edges and marked nodes highlighted
+    fn from_debug_graph(
+        graph: &StableGraph<String, u8>,
+        schedule_edges: &[EdgeIndex],
+        mark_nodes: &[NodeIndex],
+    ) -> Self {
+        let index = graph
+            .node_indices()
+            .enumerate()
+            .map(|(i, n)| (n, i))
+            .collect::<FxHashMap<_, _>>();
+        VizGraph {
+            nodes: graph
+                .node_indices()
+                .map(|n| VizNode {
+                    lines: vec![graph[n].clone()],
+                    marked: mark_nodes.contains(&n),
+                    module: false,
+                })
+                .collect(),
+            edges: graph
+                .edge_references()
+                .map(|e| VizEdge {
+                    from: index[&e.source()],
+                    to: index[&e.target()],
+                    label: None,
+                    schedule: schedule_edges.contains(&e.id()),
+                })
+                .collect(),
+        }
+    }
+
+    fn render_svg(&self, layout: Layout) -> std::io::Result<String> {
+        match layout {
+            Layout::Bundled => Ok(self.to_svg()),
+            Layout::Dot => render_with_dot(&self.to_dot()),
+            Layout::Auto => Ok(render_with_dot(&self.to_dot()).unwrap_or_else(|_| self.to_svg())),
+        }
+    }
+
+    fn render_html(&self, layout: Layout) -> std::io::Result<String> {
+        Ok(format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Luminal graph</title>\n<style>body {{ margin: 0; overflow: auto; }}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+            self.render_svg(layout)?
+        ))
+    }
+
+    /// Write an HTML page to the temp directory and open it in a local browser, printing the path if none can be opened
+    fn display(&self, layout: Layout, key: usize) {
+        let path =
+            std::env::temp_dir().join(format!("luminal_graph_{}_{key}.html", std::process::id()));
+        if let Err(e) = self
+            .render_html(layout)
+            .and_then(|html| std::fs::write(&path, html))
+        {
+            panic!("Error writing graph visualization: {e}");
+        }
+        if webbrowser::open(&path.to_string_lossy()).is_err() {
+            println!("Graph visualization written to {}", path.display());
+        }
+    }
+}
+
+/// Open a petgraph debug graph in a local browser, the same way as [`Graph::display_with`]
+pub(crate) fn display_debug_graph(
+    graph: &StableGraph<String, u8>,
+    schedule_edges: &[EdgeIndex],
+    mark_nodes: &[NodeIndex],
+) {
+    VizGraph::from_debug_graph(graph, schedule_edges, mark_nodes)
+        .display(Layout::Auto, graph as *const _ as usize);
+}
+
+/// Render DOT source to SVG with an installed `dot` binary
+fn render_with_dot(dot: &str) -> std::io::Result<String> {
+    let mut child = Command::new("dot")
+        .arg("-Tsvg")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(dot.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::other(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(Error::other)
+}
+
+impl Graph {
+    /// Render the graph as Graphviz DOT source
+    pub fn to_dot(&self, options: &VisualizeOptions) -> String {
+        VizGraph::new(self, options).to_dot()
+    }
+
+    /// Render the graph as an SVG image, laid out locally
+    pub fn to_svg(&self, options: &VisualizeOptions) -> std::io::Result<String> {
+        VizGraph::new(self, options).render_svg(options.layout)
+    }
+
+    /// Render the graph as a self-contained HTML page
+    pub fn to_html(&self, options: &VisualizeOptions) -> std::io::Result<String> {
+        VizGraph::new(self, options).render_html(options.layout)
+    }
+
+    /// Write a visualization of the graph, picking the format from the file extension (`.dot` / `.gv`, `.svg` or `.html` / `.htm`)
+    pub fn save_visualization(
+        &self,
+        path: impl AsRef<Path>,
+        options: &VisualizeOptions,
+    ) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let contents = match path.extension().and_then(|e| e.to_str()) {
+            Some("dot" | "gv") => self.to_dot(options),
+            Some("svg") => self.to_svg(options)?,
+            Some("html" | "htm") => self.to_html(options)?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unknown visualization format for {}", path.display()),
+                ))
+            }
+        };
+        std::fs::write(path, contents)
+    }
+
+    /// Write an HTML visualization to the temp directory and open it in a local browser, printing the path if none can be opened
+    pub fn display_with(&self, options: &VisualizeOptions) {
+        VizGraph::new(self, options).display(options.layout, self as *const Graph as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    crate::test_imports!();
+
+    struct Linear {
+        weight: GraphTensor,
+        bias: GraphTensor,
+    }
+
+    impl SerializeModule for Linear {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("weight", self.weight);
+            s.tensor("bias", self.bias);
+        }
+    }
+
+    struct Model {
+        first: Linear,
+        second: Linear,
+    }
+
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.module("first", &self.first);
+            s.module("second", &self.second);
+        }
+    }
+
+    #[test]
+    fn test_dot_labels() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(random_vec(6));
+        let b = a.cast(DType::F16).exp2().retrieve();
+        let dot = cx.to_dot(&VisualizeOptions::default());
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains("Exp2"));
+        assert!(dot.contains("F16"));
+        assert!(dot.contains("F32"));
+        assert!(dot.contains("label=\"[2, 3]\""));
+        assert!(dot.contains(&format!("| {}\\nF16\\nout [2, 3]", b.id.index())));
+
+        let dot = cx.to_dot(&VisualizeOptions {
+            shapes: false,
+            dtypes: false,
+            mark: vec![b.id],
+            ..Default::default()
+        });
+        assert!(!dot.contains("\\nF16") && !dot.contains("[2, 3]"));
+        assert!(dot.contains("fillcolor=\"yellow\""));
+    }
+
+    #[test]
+    fn test_debug_graph() {
+        let mut graph = StableGraph::<String, u8>::default();
+        let a = graph.add_node("A".to_string());
+        let removed = graph.add_node("Removed".to_string());
+        let b = graph.add_node("B".to_string());
+        let c = graph.add_node("C".to_string());
+        graph.remove_node(removed);
+        graph.add_edge(a, b, 0);
+        let schedule = graph.add_edge(b, c, 0);
+        let viz = VizGraph::from_debug_graph(&graph, &[schedule], &[c]);
+        let dot = viz.to_dot();
+        assert!(dot.contains("0 -> 1 [  ]"));
+        assert!(dot.contains("1 -> 2 [ color=\"green\" style=\"dashed\" ]"));
+        assert!(dot.contains("fillcolor=\"yellow\""));
+        assert!(viz.to_svg().starts_with("<svg"));
+    }
+
+    #[test]
+    fn test_collapse_modules() {
+        let mut cx = Graph::new();
+        let linear = |cx: &mut Graph| Linear {
+            weight: cx.named_tensor("Weight", (3, 3)).set(random_vec(9)),
+            bias: cx.named_tensor("Bias", 3).set(random_vec(3)),
+        };
+        let model = Model {
+            first: linear(&mut cx),
+            second: linear(&mut cx),
+        };
+        let input = cx.tensor((2, 3)).set(random_vec(6));
+        let hidden = (input.matmul(model.first.weight) + model.first.bias.expand_dim(0, 2)).relu();
+        let output = hidden.matmul(model.second.weight) + model.second.bias.expand_dim(0, 2);
+        output.retrieve();
+
+        let full = cx.to_dot(&VisualizeOptions::default());
+        let options = VisualizeOptions::default().collapse_modules(&model, &["first", "second"]);
+        let collapsed = cx.to_dot(&options);
+        assert!(collapsed.contains("label=\"first\\n"));
+        assert!(collapsed.contains("label=\"second\\n"));
+        assert!(collapsed.contains("shape=box3d"));
+        // The relu between the modules stays visible, the weights don't
+        assert!(collapsed.contains("LessThan"));
+        assert!(!collapsed.contains("Weight"));
+        assert!(collapsed.lines().count() < full.lines().count());
+    }
+
+    #[test]
+    fn test_bundled_svg() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let b = cx.tensor(3).set(vec![1., 2., 3.]);
+        let c = (a * b).sum(0).retrieve();
+        cx.add_schedule_dependency(a.id, b.id);
+        let options = VisualizeOptions {
+            layout: Layout::Bundled,
+            mark: vec![c.id],
+            ..Default::default()
+        };
+        let svg = cx.to_svg(&options).unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert_eq!(svg.matches("<rect").count(), cx.graph.node_count());
+        assert_eq!(svg.matches("marker-end").count(), cx.graph.edge_count());
+        assert!(svg.contains("stroke=\"green\""));
+        assert!(svg.contains("fill=\"yellow\""));
+        assert!(svg.contains("SumReduce(0)"));
+
+        let dir = std::env::temp_dir();
+        let prefix = format!("luminal_visualize_{}", std::process::id());
+        for ext in ["dot", "svg", "html"] {
+            let path = dir.join(format!("{prefix}.{ext}"));
+            cx.save_visualization(&path, &options).unwrap();
+            let contents = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(contents.contains("SumReduce(0)"));
+        }
+        assert!(cx
+            .save_visualization(dir.join(format!("{prefix}.png")), &options)
+            .is_err());
+    }
+}